@@ -0,0 +1,369 @@
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use decide_hal::{linux, I2c};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, report_fault, error::{ClientError, DecideError}};
+
+/// Samples the single-ended inputs of an ADS1115 ADC over I2C. Filtered voltages
+/// are published periodically, and immediately whenever a channel crosses its
+/// threshold.
+pub struct AnalogIn {
+    inputs: Arc<Mutex<Vec<Input>>>,
+    hysteresis: f64,
+    state_sender: Sender<Any>,
+    stop: Arc<AtomicBool>,
+    sampler: Option<thread::JoinHandle<()>>,
+}
+
+struct Input {
+    name: String,
+    channel: u16, // AIN0-3
+    volts: Option<f64>, // filtered, None until the first sample
+    threshold: Option<Threshold>,
+}
+
+fn state(inputs: &[Input], crossed: &str) -> proto::AnalogState {
+    proto::AnalogState {
+        volts: inputs.iter()
+            .filter_map(|i| i.volts.map(|v| (i.name.clone(), v)))
+            .collect(),
+        above: inputs.iter()
+            .filter_map(|i| i.threshold.and_then(|t| t.above).map(|a| (i.name.clone(), a)))
+            .collect(),
+        crossed: crossed.into(),
+    }
+}
+
+#[component(state = proto::AnalogState, params = proto::AnalogParams, config = Config)]
+#[async_trait]
+impl Component for AnalogIn {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        let inputs = config.channels.iter()
+            .map(|c| Input {
+                name: c.name.clone(),
+                channel: c.channel,
+                volts: None,
+                threshold: c.threshold.map(|level| Threshold::new(level, config.hysteresis)),
+            })
+            .collect();
+        AnalogIn {
+            inputs: Arc::new(Mutex::new(inputs)),
+            hysteresis: config.hysteresis,
+            state_sender,
+            stop: Arc::new(AtomicBool::new(false)),
+            sampler: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        if let Some(c) = config.channels.iter().find(|c| c.channel > 3) {
+            tracing::error!("AnalogIn channel {:?} must be between 0 and 3", c.name);
+            return Err(invalid_config("invalid ADS1115 channel"))
+        }
+        if !(config.rate.is_finite() && config.rate > 0.0) {
+            tracing::error!("AnalogIn rate must be positive");
+            return Err(invalid_config("invalid AnalogIn sampling rate"))
+        }
+        let mut adc = Ads1115::new(&config)?;
+        let inputs = self.inputs.clone();
+        let sender = self.state_sender.clone();
+        let stop = self.stop.clone();
+        let period = Duration::from_secs_f64(1.0 / config.rate);
+        let publish = Duration::from_millis(config.interval);
+        let smoothing = config.smoothing;
+        self.sampler = Some(thread::spawn(move || {
+            let mut next = Instant::now();
+            let mut published = Instant::now();
+            let channels: Vec<u16> = inputs.lock().unwrap().iter().map(|i| i.channel).collect();
+            while !stop.load(Ordering::Acquire) {
+                let samples: Vec<f64> = match channels.iter().map(|&c| adc.read(c)).collect() {
+                    Ok(samples) => samples,
+                    Err(e) => return report_fault(&sender, e),
+                };
+                let mut messages = Vec::new();
+                {
+                    let mut inputs = inputs.lock().unwrap();
+                    for (index, sample) in samples.into_iter().enumerate() {
+                        let input = &mut inputs[index];
+                        let volts = input.volts.map_or(sample, |v| v + smoothing * (sample - v));
+                        input.volts = Some(volts);
+                        let crossed = input.threshold.as_mut().is_some_and(|t| t.update(volts));
+                        if crossed {
+                            tracing::debug!("AnalogIn {:?} Crossed Threshold at {:?} V", input.name, volts);
+                            messages.push(state(&inputs, &inputs[index].name));
+                        }
+                    }
+                    if published.elapsed() >= publish {
+                        published = Instant::now();
+                        messages.push(state(&inputs, ""));
+                    }
+                }
+                for message in messages {
+                    if sender.blocking_send(Self::pack_state(&message)).is_err() {
+                        return
+                    }
+                }
+                next += period;
+                let now = Instant::now();
+                if next > now {
+                    thread::sleep(next - now);
+                } else {
+                    // conversions take longer than the rate allows; don't try to catch up
+                    next = now;
+                }
+            }
+        }));
+        tracing::info!("AnalogIn Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        let mut inputs = self.inputs.lock().unwrap();
+        if let Some(name) = params.thresholds.keys().find(|&n| !inputs.iter().any(|i| &i.name == n)) {
+            tracing::error!("AnalogIn channel {:?} does not exist", name);
+            return Err(ClientError::InvalidParams.into())
+        }
+        for input in inputs.iter_mut() {
+            let level = params.thresholds.get(&input.name).copied();
+            // keep which side of an unchanged threshold the channel is on
+            if input.threshold.map(|t| t.level) != level {
+                input.threshold = level.map(|l| Threshold::new(l, self.hysteresis));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        state(&self.inputs.lock().unwrap(), "")
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            thresholds: self.inputs.lock().unwrap().iter()
+                .filter_map(|i| i.threshold.map(|t| (i.name.clone(), t.level)))
+                .collect(),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("AnalogIn sampler thread", self.sampler.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for AnalogIn");
+        self.stop.store(true, Ordering::Release);
+        if let Some(sampler) = self.sampler.take() {
+            match tokio::task::spawn_blocking(move || sampler.join()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => tracing::error!("AnalogIn sampler thread panicked"),
+                Err(e) => tracing::error!("AnalogIn sampler thread could not be joined: {}", e),
+            }
+        }
+    }
+}
+
+/// Error for a config the ADC cannot be set up from
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
+/// Threshold with hysteresis, so that noise around the level does not send a
+/// stream of crossings
+#[derive(Clone, Copy, Debug)]
+struct Threshold {
+    level: f64,
+    hysteresis: f64,
+    above: Option<bool>, // None until the first sample
+}
+
+impl Threshold {
+    fn new(level: f64, hysteresis: f64) -> Self {
+        Threshold { level, hysteresis, above: None }
+    }
+
+    /// Returns true if the sample crossed the threshold
+    fn update(&mut self, volts: f64) -> bool {
+        match self.above {
+            None => {
+                self.above = Some(volts > self.level);
+                false
+            }
+            Some(true) if volts < self.level - self.hysteresis => {
+                self.above = Some(false);
+                true
+            }
+            Some(false) if volts > self.level + self.hysteresis => {
+                self.above = Some(true);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+struct Ads1115 {
+    dev: Box<dyn I2c>,
+    pga: u16, // config bits for the full-scale range
+    range: f64, // V at full scale
+    data_rate: u16, // config bits for the data rate
+    conversion: Duration,
+}
+
+impl Ads1115 {
+    const CONVERSION: u8 = 0x00;
+    const CONFIG: u8 = 0x01;
+
+    fn new(config: &Config) -> decide_protocol::Result<Self> {
+        let pga = match config.range_mv {
+            6144 => 0b000,
+            4096 => 0b001,
+            2048 => 0b010,
+            1024 => 0b011,
+            512 => 0b100,
+            256 => 0b101,
+            _ => {
+                tracing::error!("AnalogIn range_mv must be 6144, 4096, 2048, 1024, 512 or 256");
+                return Err(invalid_config("invalid ADS1115 range"))
+            }
+        };
+        let data_rate = match config.data_rate {
+            8 => 0b000,
+            16 => 0b001,
+            32 => 0b010,
+            64 => 0b011,
+            128 => 0b100,
+            250 => 0b101,
+            475 => 0b110,
+            860 => 0b111,
+            _ => {
+                tracing::error!("AnalogIn data_rate must be one of 8, 16, 32, 64, 128, 250, 475 or 860");
+                return Err(invalid_config("invalid ADS1115 data rate"))
+            }
+        };
+        let dev = linux::i2c(&config.bus, config.address)
+            .map_err(|e| DecideError::Component { source: e })?;
+        Ok(Ads1115 {
+            dev: Box::new(dev),
+            pga,
+            range: config.range_mv as f64 / 1000.0,
+            data_rate,
+            conversion: Duration::from_secs_f64(1.0 / config.data_rate as f64),
+        })
+    }
+
+    /// Runs a single-shot conversion of one input against ground and returns it in V
+    fn read(&mut self, channel: u16) -> decide_hal::Result<f64> {
+        let config: u16 = 0x8000 // start a conversion
+            | (0b100 + channel) << 12 // AINx against GND
+            | self.pga << 9
+            | 0x0100 // single-shot mode
+            | self.data_rate << 5
+            | 0b11; // comparator off
+        self.dev.write(&[Ads1115::CONFIG, (config >> 8) as u8, config as u8])?;
+        thread::sleep(self.conversion);
+        // the start bit reads back as 1 once the conversion is done
+        while self.register(Ads1115::CONFIG)?[0] & 0x80 == 0 {
+            thread::sleep(Duration::from_micros(100));
+        }
+        let raw = i16::from_be_bytes(self.register(Ads1115::CONVERSION)?);
+        Ok(raw as f64 * self.range / 32768.0)
+    }
+
+    fn register(&mut self, register: u8) -> decide_hal::Result<[u8; 2]> {
+        let mut buf = [0u8; 2];
+        self.dev.write_read(&[register], &mut buf)?;
+        Ok(buf)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChannelConfig {
+    name: String, // used in state messages, e.g. "photodiode"
+    channel: u16, // AIN0-3
+    threshold: Option<f64>, // V, initial value of the threshold parameter
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_bus")]
+    bus: String,
+    #[serde(default = "Config::default_address")]
+    address: u16,
+    channels: Vec<ChannelConfig>,
+    rate: f64, // Hz at which the channel set is sampled
+    #[serde(default = "Config::default_data_rate")]
+    data_rate: u32, // samples per second of a single conversion
+    #[serde(default = "Config::default_range")]
+    range_mv: u32, // full-scale range of the programmable gain amplifier
+    #[serde(default = "Config::default_smoothing")]
+    smoothing: f64, // weight of each new sample in the moving average; 1 disables filtering
+    #[serde(default = "Config::default_hysteresis")]
+    hysteresis: f64, // V on either side of a threshold
+    #[serde(default = "Config::default_interval")]
+    interval: u64, // ms between periodic state updates
+}
+
+impl Config {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_address() -> u16 {
+        0x48
+    }
+
+    fn default_data_rate() -> u32 {
+        860
+    }
+
+    fn default_range() -> u32 {
+        4096
+    }
+
+    fn default_smoothing() -> f64 {
+        1.0
+    }
+
+    fn default_hysteresis() -> f64 {
+        0.01
+    }
+
+    fn default_interval() -> u64 {
+        1000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_ignores_noise_within_hysteresis() {
+        let mut threshold = Threshold::new(1.0, 0.1);
+        assert!(!threshold.update(0.5));
+        assert!(!threshold.update(1.05));
+        assert!(threshold.update(1.2));
+        assert!(!threshold.update(0.95));
+        assert!(threshold.update(0.8));
+        assert_eq!(threshold.above, Some(false));
+    }
+
+    #[test]
+    fn first_sample_sets_side_without_crossing() {
+        let mut threshold = Threshold::new(1.0, 0.1);
+        assert!(!threshold.update(2.0));
+        assert_eq!(threshold.above, Some(true));
+    }
+}
@@ -0,0 +1,15 @@
+use std::env;
+use std::io::Result;
+use std::path::PathBuf;
+
+extern crate prost_build;
+
+fn main() -> Result<()> {
+    std::env::set_var("PROTOC", protobuf_src::protoc());
+    // the descriptors are returned to clients that ask to describe the component
+    let descriptors = PathBuf::from(env::var("OUT_DIR").unwrap()).join("file_descriptor_set.bin");
+    prost_build::Config::new()
+        .file_descriptor_set_path(descriptors)
+        .compile_protos(&["src/analog_out.proto"], &["src/"])?;
+    Ok(())
+}
@@ -0,0 +1,283 @@
+use std::sync::{Arc, Mutex, atomic::{AtomicU32, AtomicU64, Ordering}};
+use async_trait::async_trait;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, time::Duration};
+use decide_hal::{linux, Spi};
+use decide_protocol::{component, Component, ComponentHealth, error::{ClientError, DecideError}};
+
+/// Analog control voltages from an MCP4922 style dual 12-bit SPI DAC, e.g. for
+/// dimmable LED drivers or external equipment. Setpoints are converted to
+/// voltages through a calibration table for each channel, and changes of
+/// setpoint can be ramped.
+pub struct AnalogOut {
+    dac: Option<Arc<Dac>>,
+    channels: Vec<ChannelConfig>,
+    vref: f32,
+    ramp: Arc<AtomicU32>, // ms
+    state_sender: Sender<Any>,
+}
+
+struct Dac {
+    spi: Mutex<Box<dyn Spi>>,
+    channels: Vec<ChannelConfig>,
+    vref: f32,
+    values: Mutex<Vec<f32>>, // current setpoints
+    epochs: Vec<AtomicU64>, // bumped by every request for a channel to cancel its running ramp
+}
+
+impl Dac {
+    /// Time between steps during a ramp
+    const RAMP_STEP: Duration = Duration::from_millis(10);
+
+    fn set(&self, index: usize, value: f32) {
+        let channel = &self.channels[index];
+        let code = code(volts(&channel.calibration, value), self.vref);
+        self.spi.lock().unwrap().write(&command(channel.channel, code))
+            .map_err(|e| DecideError::Component { source: e }).unwrap();
+        self.values.lock().unwrap()[index] = value;
+    }
+
+    /// Changes a setpoint linearly over `time`, unless another request for the
+    /// channel takes over first
+    async fn ramp(&self, index: usize, epoch: u64, to: f32, time: Duration) -> bool {
+        let from = self.values.lock().unwrap()[index];
+        let steps = (time.as_millis() / Dac::RAMP_STEP.as_millis()).max(1) as u32;
+        for step in 1..=steps {
+            if self.epochs[index].load(Ordering::Acquire) != epoch {
+                return false
+            }
+            self.set(index, from + (to - from) * step as f32 / steps as f32);
+            if step < steps {
+                tokio::time::sleep(Dac::RAMP_STEP).await;
+            }
+        }
+        true
+    }
+
+    fn state(&self) -> proto::DacState {
+        let values = self.values.lock().unwrap();
+        proto::DacState {
+            channels: self.channels.iter().map(|c| c.name.clone()).zip(values.iter().copied()).collect(),
+        }
+    }
+}
+
+/// Converts a setpoint to volts by linear interpolation in a calibration table
+/// of (setpoint, volts) pairs sorted by setpoint. Setpoints beyond the ends of
+/// the table are clamped to them, and an empty table means setpoints are volts.
+fn volts(calibration: &[(f32, f32)], value: f32) -> f32 {
+    match calibration {
+        [] => value,
+        [(_, v)] => *v,
+        _ => {
+            let i = calibration.windows(2)
+                .position(|w| value < w[1].0)
+                .unwrap_or(calibration.len() - 2);
+            let ((x0, y0), (x1, y1)) = (calibration[i], calibration[i + 1]);
+            let t = ((value - x0) / (x1 - x0)).clamp(0.0, 1.0);
+            y0 + (y1 - y0) * t
+        }
+    }
+}
+
+/// Output code for a voltage, saturating at the ends of the range
+fn code(volts: f32, vref: f32) -> u16 {
+    (volts / vref * 4096.0).round().clamp(0.0, 4095.0) as u16
+}
+
+/// Write command for one channel: unbuffered reference, 1x gain, output active
+fn command(channel: Channel, code: u16) -> [u8; 2] {
+    let select = match channel {
+        Channel::A => 0x0000,
+        Channel::B => 0x8000,
+    };
+    (select | 0x3000 | (code & 0x0FFF)).to_be_bytes()
+}
+
+#[component(state = proto::DacState, params = proto::DacParams, config = Config)]
+#[async_trait]
+impl Component for AnalogOut {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        for channel in config.channels.iter() {
+            if channel.calibration.windows(2).any(|w| w[0].0 >= w[1].0) {
+                tracing::error!("Analog-Out calibration of {:?} must be sorted by setpoint", channel.name);
+                panic!("invalid analog output calibration")
+            }
+        }
+        AnalogOut {
+            dac: None,
+            channels: config.channels,
+            vref: config.vref,
+            ramp: Arc::new(AtomicU32::new(config.ramp)),
+            state_sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let spi = linux::spi(&config.device, config.speed)
+            .map_err(|e| DecideError::Component { source: e })?;
+        let dac = Dac {
+            spi: Mutex::new(Box::new(spi)),
+            channels: config.channels,
+            vref: self.vref,
+            values: Mutex::new(self.channels.iter().map(|c| c.initial).collect()),
+            epochs: self.channels.iter().map(|_| AtomicU64::new(0)).collect(),
+        };
+        for (index, channel) in self.channels.iter().enumerate() {
+            dac.set(index, channel.initial);
+        }
+        self.dac = Some(Arc::new(dac));
+        tracing::info!("Analog-Out Initiated with {:?} channels", self.channels.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let dac = match self.dac.as_ref() {
+            Some(dac) => dac,
+            None => {
+                tracing::error!("Analog-Out has not been initialized");
+                return Err(ClientError::InvalidState.into())
+            }
+        };
+        // check every setpoint before changing any channel
+        let mut changes = Vec::new();
+        for (name, &value) in state.channels.iter() {
+            let index = match self.channels.iter().position(|c| &c.name == name) {
+                Some(index) => index,
+                None => {
+                    tracing::error!("Analog-Out channel {:?} does not exist", name);
+                    return Err(ClientError::InvalidState.into())
+                }
+            };
+            let volts = volts(&self.channels[index].calibration, value);
+            if !(0.0..=self.vref).contains(&volts) {
+                tracing::error!("Analog-Out setpoint {:?} of {:?} is outside 0-{:?} V", value, name, self.vref);
+                return Err(ClientError::InvalidState.into())
+            }
+            changes.push((index, value));
+        }
+        let ramp = Duration::from_millis(self.ramp.load(Ordering::Acquire) as u64);
+        for (index, value) in changes {
+            let epoch = dac.epochs[index].fetch_add(1, Ordering::AcqRel) + 1;
+            let dac = dac.clone();
+            let sender = self.state_sender.clone();
+            tokio::spawn(async move {
+                if dac.ramp(index, epoch, value, ramp).await {
+                    tracing::info!("Analog-Out {:?} Set to {:?}", dac.channels[index].name, value);
+                    sender
+                        .send(Self::pack_state(&dac.state()))
+                        .await
+                        .map_err(|e| DecideError::Component { source: e.into() })
+                        .unwrap();
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        self.ramp.store(params.ramp_ms, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        match self.dac.as_ref() {
+            Some(dac) => dac.state(),
+            None => Self::State::default(),
+        }
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            ramp_ms: self.ramp.load(Ordering::Acquire),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        match &self.dac {
+            Some(_) => ComponentHealth::Healthy,
+            None => ComponentHealth::Failed(String::from("AnalogOut DAC was not opened")),
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Analog-Out");
+        // cancel any ramps and return the outputs to their initial setpoints
+        if let Some(dac) = self.dac.take() {
+            for (index, channel) in self.channels.iter().enumerate() {
+                dac.epochs[index].fetch_add(1, Ordering::AcqRel);
+                dac.set(index, channel.initial);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub enum Channel {
+    A,
+    B,
+}
+
+#[derive(Deserialize)]
+pub struct ChannelConfig {
+    name: String, // used in state messages, e.g. "house_dimmer"
+    channel: Channel, // "A" or "B"
+    #[serde(default)]
+    calibration: Vec<(f32, f32)>, // (setpoint, volts) pairs, sorted by setpoint
+    #[serde(default)]
+    initial: f32, // setpoint at startup and after shutdown
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_device")]
+    device: String,
+    #[serde(default = "Config::default_speed")]
+    speed: u32, // Hz
+    #[serde(default = "Config::default_vref")]
+    vref: f32, // V on the VREF pins; the outputs span 0 to vref
+    channels: Vec<ChannelConfig>,
+    #[serde(default)]
+    ramp: u32, // ms, initial value of the ramp_ms parameter
+}
+
+impl Config {
+    fn default_device() -> String {
+        String::from("/dev/spidev0.0")
+    }
+
+    fn default_speed() -> u32 {
+        1_000_000
+    }
+
+    fn default_vref() -> f32 {
+        3.3
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_interpolates_and_clamps() {
+        let table = [(0.0, 0.5), (50.0, 1.5), (100.0, 3.0)];
+        assert_eq!(volts(&table, 25.0), 1.0);
+        assert_eq!(volts(&table, 75.0), 2.25);
+        assert_eq!(volts(&table, -10.0), 0.5);
+        assert_eq!(volts(&table, 150.0), 3.0);
+        assert_eq!(volts(&[], 1.2), 1.2);
+    }
+
+    #[test]
+    fn commands_select_channel_and_saturate() {
+        assert_eq!(command(Channel::A, code(1.65, 3.3)), [0x38, 0x00]);
+        assert_eq!(command(Channel::B, code(5.0, 3.3)), [0xBF, 0xFF]);
+        assert_eq!(code(-1.0, 3.3), 0);
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use nix::time::{clock_gettime, ClockId};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, Resource, error::{ClientError, DecideError}};
+
+/// Emits TTL pulse trains to trigger camera frames. The time of every rising
+/// edge is published so that video can be aligned with other events offline.
+pub struct CameraTrigger {
+    line: Option<Arc<LineHandle>>, // claimed in init
+    params: proto::TriggerParams,
+    report: Duration, // time between batches of timestamps
+    running: Arc<AtomicBool>,
+    pulses: Arc<AtomicU64>,
+    state_sender: Sender<Any>,
+    train: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::TriggerState, params = proto::TriggerParams, config = Config)]
+#[async_trait]
+impl Component for CameraTrigger {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        CameraTrigger {
+            line: None,
+            params: proto::TriggerParams {
+                rate: config.rate,
+                pulse_width: config.pulse_width,
+                count: 0,
+            },
+            report: Duration::from_millis(config.report_interval),
+            running: Arc::new(AtomicBool::new(false)),
+            pulses: Arc::new(AtomicU64::new(0)),
+            state_sender,
+            train: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let line = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?
+            .get_line(config.offset)
+            .map_err(|e| DecideError::Component { source: e.into() })?
+            .request(LineRequestFlags::OUTPUT, 0, "camera_trigger")
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        self.line = Some(Arc::new(line));
+        tracing::info!("Camera-Trigger Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        if !state.running {
+            self.running.store(false, Ordering::Release);
+            tracing::info!("Camera-Trigger Stop Requested");
+            return Ok(())
+        }
+        if self.running.load(Ordering::Acquire) {
+            tracing::error!("Camera-Trigger train requested while one is already running. Stop it first.");
+            return Err(ClientError::Busy.into())
+        }
+        let line = self.line.clone().ok_or(ClientError::InvalidState)?;
+        self.running.store(true, Ordering::Release);
+        self.pulses.store(0, Ordering::Release);
+        let train = PulseTrain {
+            line,
+            period: Duration::from_secs_f64(1.0 / self.params.rate),
+            width: Duration::from_micros(self.params.pulse_width as u64),
+            count: self.params.count,
+            report: self.report,
+            running: self.running.clone(),
+            pulses: self.pulses.clone(),
+            sender: self.state_sender.clone(),
+        };
+        tracing::info!("Camera-Trigger Train Started at {:?} Hz", self.params.rate);
+        self.train = Some(thread::spawn(move || train.run()));
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        let period = 1e6 / params.rate;
+        if !params.rate.is_finite() || params.rate <= 0.0 || params.pulse_width == 0 || params.pulse_width as f64 >= period {
+            tracing::error!("Camera-Trigger pulse width must be positive and shorter than the period");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // applies to the next train
+        self.params = params;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        Self::State {
+            running: self.running.load(Ordering::Acquire),
+            pulses: self.pulses.load(Ordering::Acquire),
+            timestamps_ns: Vec::new(),
+        }
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("CameraTrigger pulse thread", self.train.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![Resource::line(&config.chip, config.offset)]
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Camera-Trigger");
+        self.running.store(false, Ordering::Release);
+        if let Some(train) = self.train.take() {
+            tokio::task::spawn_blocking(move || train.join())
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap()
+                .unwrap();
+        }
+        if let Some(Err(e)) = self.line.as_ref().map(|line| line.set_value(0)) {
+            tracing::error!("Camera-Trigger line could not be turned off: {}", e);
+        }
+    }
+}
+
+struct PulseTrain {
+    line: Arc<LineHandle>,
+    period: Duration,
+    width: Duration,
+    count: u64, // 0 runs until stopped
+    report: Duration,
+    running: Arc<AtomicBool>,
+    pulses: Arc<AtomicU64>,
+    sender: Sender<Any>,
+}
+
+impl PulseTrain {
+    /// Sleeping is only accurate to about this much, so the rest is spent spinning
+    const SPIN: Duration = Duration::from_micros(1500);
+
+    fn run(self) {
+        let mut next = Instant::now();
+        let mut reported = Instant::now();
+        let mut timestamps = Vec::new();
+        let mut pulses = 0;
+        while self.running.load(Ordering::Acquire) && (self.count == 0 || pulses < self.count) {
+            PulseTrain::wait_until(next);
+            self.set(1);
+            timestamps.push(monotonic_ns());
+            PulseTrain::wait_until(next + self.width);
+            self.set(0);
+            pulses += 1;
+            self.pulses.store(pulses, Ordering::Release);
+            if reported.elapsed() >= self.report {
+                reported = Instant::now();
+                self.send(true, pulses, std::mem::take(&mut timestamps));
+            }
+            next += self.period;
+            if next < Instant::now() {
+                tracing::warn!("Camera-Trigger Missed Pulse Deadline");
+            }
+        }
+        self.running.store(false, Ordering::Release);
+        self.send(false, pulses, timestamps);
+        tracing::info!("Camera-Trigger Train Ended After {:?} Pulses", pulses);
+    }
+
+    fn wait_until(deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now + PulseTrain::SPIN {
+            thread::sleep(deadline - now - PulseTrain::SPIN);
+        }
+        while Instant::now() < deadline {
+            // let other threads run on single-core boards
+            thread::yield_now();
+        }
+    }
+
+    fn set(&self, value: u8) {
+        self.line.set_value(value)
+            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+    }
+
+    fn send(&self, running: bool, pulses: u64, timestamps_ns: Vec<u64>) {
+        let state = proto::TriggerState { running, pulses, timestamps_ns };
+        self.sender.blocking_send(CameraTrigger::pack_state(&state)).map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+    }
+}
+
+/// CLOCK_MONOTONIC in ns, the clock the kernel uses to timestamp GPIO events
+fn monotonic_ns() -> u64 {
+    let ts = clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+    ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    offset: u32,
+    rate: f64, // Hz, initial value of the rate parameter
+    pulse_width: u32, // us, initial value of the pulse_width parameter
+    #[serde(default = "Config::default_report_interval")]
+    report_interval: u64, // ms between state messages while a train runs
+}
+
+impl Config {
+    fn default_report_interval() -> u64 {
+        100
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
@@ -0,0 +1,225 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::time::Duration;
+use async_trait::async_trait;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, task::JoinHandle, time::sleep};
+use decide_protocol::{component, Component, ComponentHealth, error::{ClientError, DecideError}};
+
+/// Checks the health of the system clock, so that clock problems are caught
+/// while the data are being collected. Synchronization comes from the kernel's
+/// NTP state, which chrony, ntpd and systemd-timesyncd all maintain. A state
+/// message is sent on startup and whenever synchronization, the offset alarm or
+/// the RTC changes.
+pub struct ClockStatus {
+    state: Arc<Mutex<proto::ClockState>>,
+    interval: Arc<AtomicU64>, // s between checks
+    max_offset: Arc<Mutex<f64>>, // ms
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+#[component(state = proto::ClockState, params = proto::ClockParams, config = Config)]
+#[async_trait]
+impl Component for ClockStatus {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        ClockStatus {
+            state: Arc::new(Mutex::new(proto::ClockState::default())),
+            interval: Arc::new(AtomicU64::new(config.interval)),
+            max_offset: Arc::new(Mutex::new(config.max_offset)),
+            state_sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let state = self.state.clone();
+        let interval = self.interval.clone();
+        let max_offset = self.max_offset.clone();
+        let sender = self.state_sender.clone();
+        self.task_handle = Some(tokio::spawn(async move {
+            let mut first = true;
+            loop {
+                match kernel_clock() {
+                    Ok(clock) => {
+                        let next = proto::ClockState {
+                            synchronized: clock.synchronized,
+                            offset_ms: clock.offset_ms,
+                            max_error_ms: clock.max_error_ms,
+                            offset_alarm: clock.offset_ms.abs() > *max_offset.lock().unwrap(),
+                            rtc: Path::new(&config.rtc).exists(),
+                        };
+                        let message = {
+                            let mut state = state.lock().unwrap();
+                            let changed = first
+                                || next.synchronized != state.synchronized
+                                || next.offset_alarm != state.offset_alarm
+                                || next.rtc != state.rtc;
+                            if !first {
+                                log_changes(&state, &next);
+                            }
+                            *state = next;
+                            if changed {
+                                Some(Self::pack_state(&state))
+                            } else {
+                                None
+                            }
+                        };
+                        first = false;
+                        if let Some(message) = message {
+                            sender.send(message).await
+                                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                        }
+                    }
+                    Err(e) => tracing::error!("Clock-Status Check Failed: {}", e),
+                }
+                sleep(Duration::from_secs(interval.load(Ordering::Acquire))).await;
+            }
+        }));
+        tracing::info!("Clock-Status Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval <= 0 {
+            tracing::error!("Clock-Status interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        if params.max_offset_ms.is_nan() || params.max_offset_ms <= 0.0 {
+            tracing::error!("Clock-Status max offset must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // takes effect after the current wait
+        self.interval.store(params.interval as u64, Ordering::Release);
+        *self.max_offset.lock().unwrap() = params.max_offset_ms;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            interval: self.interval.load(Ordering::Acquire) as i64,
+            max_offset_ms: *self.max_offset.lock().unwrap(),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("ClockStatus task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Clock-Status");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Clock-Status task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn log_changes(old: &proto::ClockState, new: &proto::ClockState) {
+    match (old.synchronized, new.synchronized) {
+        (true, false) => tracing::warn!("Clock-Status Synchronization Lost"),
+        (false, true) => tracing::info!("Clock-Status Synchronized with Offset {:.3} ms", new.offset_ms),
+        _ => (),
+    }
+    if new.offset_alarm && !old.offset_alarm {
+        tracing::warn!("Clock-Status Offset {:.3} ms Out of Range", new.offset_ms);
+    }
+    if old.rtc != new.rtc {
+        tracing::warn!("Clock-Status RTC {}", if new.rtc { "Appeared" } else { "Disappeared" });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KernelClock {
+    synchronized: bool,
+    offset_ms: f64,
+    max_error_ms: f64,
+}
+
+/// Reads the kernel's NTP state without changing it
+fn kernel_clock() -> io::Result<KernelClock> {
+    // modes = 0 makes adjtimex read-only
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::adjtimex(&mut timex) };
+    if result < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(interpret(result, timex.status, timex.offset as i64, timex.maxerror as i64))
+}
+
+/// Converts the adjtimex clock state and fields. The offset is in ns when
+/// STA_NANO is set and us otherwise; the maximum error is always in us.
+fn interpret(result: libc::c_int, status: libc::c_int, offset: i64, maxerror: i64) -> KernelClock {
+    let offset_ms = if status & libc::STA_NANO != 0 {
+        offset as f64 / 1e6
+    } else {
+        offset as f64 / 1e3
+    };
+    KernelClock {
+        synchronized: result != libc::TIME_ERROR && status & libc::STA_UNSYNC == 0,
+        offset_ms,
+        max_error_ms: maxerror as f64 / 1e3,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_rtc")]
+    rtc: String, // device that exists when an RTC is fitted
+    #[serde(default = "Config::default_interval")]
+    interval: u64, // s, initial value of the interval parameter
+    #[serde(default = "Config::default_max_offset")]
+    max_offset: f64, // ms, initial value of the max_offset_ms parameter
+}
+
+impl Config {
+    fn default_rtc() -> String {
+        String::from("/dev/rtc0")
+    }
+
+    fn default_interval() -> u64 {
+        60
+    }
+
+    fn default_max_offset() -> f64 {
+        10.0
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_units_follow_status() {
+        let clock = interpret(libc::TIME_OK, libc::STA_NANO, 2_500_000, 16_000);
+        assert_eq!(clock, KernelClock { synchronized: true, offset_ms: 2.5, max_error_ms: 16.0 });
+        let clock = interpret(libc::TIME_OK, 0, 2_500, 16_000);
+        assert_eq!(clock.offset_ms, 2.5);
+    }
+
+    #[test]
+    fn unsynchronized_clock() {
+        assert!(!interpret(libc::TIME_ERROR, 0, 0, 0).synchronized);
+        assert!(!interpret(libc::TIME_OK, libc::STA_UNSYNC, 0, 0).synchronized);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use async_trait::async_trait;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, time::Duration};
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
+
+/// RGB cue LED driven from three PWM channels. Colors are gamma corrected and
+/// scaled per channel with the calibration in the config, so that the same
+/// request gives the same color in every box.
+pub struct CueLed {
+    led: Option<Arc<Led>>, // set up in init
+    state: Arc<Mutex<proto::CueState>>,
+    state_sender: Sender<Any>,
+}
+
+struct Channel {
+    pwm: PathBuf, // channel directory
+    gamma: f64,
+    max: f64, // fraction of the period at full brightness
+}
+
+struct Led {
+    channels: [Channel; 3], // red, green, blue
+    period: u64, // ns
+    epoch: AtomicU64, // bumped by every request to cancel the running blink
+}
+
+impl Led {
+    /// Shows the color, or turns the LED off if `on` is false
+    fn show(&self, state: &proto::CueState, on: bool) -> decide_protocol::Result<()> {
+        let levels = [state.red, state.green, state.blue];
+        for (channel, &level) in self.channels.iter().zip(levels.iter()) {
+            let fraction = if on { duty(level, state.brightness, channel.gamma, channel.max) } else { 0.0 };
+            let duty = (self.period as f64 * fraction) as u64;
+            write(&channel.pwm.join("duty_cycle"), duty.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Blinks the color until another request takes over
+    async fn blink(&self, epoch: u64, state: proto::CueState) -> decide_protocol::Result<()> {
+        let on = Duration::from_millis(state.on_ms as u64);
+        let off = Duration::from_millis(state.off_ms as u64);
+        loop {
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                return Ok(())
+            }
+            self.show(&state, true)?;
+            tokio::time::sleep(on).await;
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                return Ok(())
+            }
+            self.show(&state, false)?;
+            tokio::time::sleep(off).await;
+        }
+    }
+}
+
+/// Fraction of the PWM period for a color component (0-255) at a brightness
+/// (0-1), corrected for the perceived brightness of the channel
+fn duty(level: u32, brightness: f32, gamma: f64, max: f64) -> f64 {
+    let linear = level as f64 / 255.0 * brightness as f64;
+    max * linear.powf(gamma)
+}
+
+/// Error for a config the channels cannot be set up from
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> decide_protocol::Result<()> {
+    fs::write(path, contents).map_err(|e| DecideError::Component { source: e.into() })
+}
+
+#[component(state = proto::CueState, params = proto::CueParams, config = Config)]
+#[async_trait]
+impl Component for CueLed {
+    fn new(_config: Self::Config, state_sender: Sender<Any>) -> Self {
+        CueLed {
+            led: None,
+            state: Arc::new(Mutex::new(proto::CueState::default())),
+            state_sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let period = config.period * 1000;
+        let channel = |config: ChannelConfig| -> decide_protocol::Result<Channel> {
+            let pwm = PathBuf::from(&config.pwm_path);
+            if !pwm.exists() {
+                let channel = pwm.file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_prefix("pwm"))
+                    .ok_or_else(|| invalid_config("Cue LED PWM path must end in pwm<channel>"))?;
+                write(&pwm.with_file_name("export"), channel)?;
+            }
+            write(&pwm.join("duty_cycle"), "0")?;
+            write(&pwm.join("period"), period.to_string())?;
+            write(&pwm.join("enable"), "1")?;
+            Ok(Channel { pwm, gamma: config.gamma, max: config.max })
+        };
+        self.led = Some(Arc::new(Led {
+            channels: [channel(config.red)?, channel(config.green)?, channel(config.blue)?],
+            period,
+            epoch: AtomicU64::new(0),
+        }));
+        tracing::info!("Cue LED Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        if state.red > 255 || state.green > 255 || state.blue > 255 {
+            tracing::error!("Cue LED color components must be between 0 and 255");
+            return Err(ClientError::InvalidState.into())
+        }
+        if !(0.0..=1.0).contains(&state.brightness) {
+            tracing::error!("Cue LED brightness must be between 0 and 1");
+            return Err(ClientError::InvalidState.into())
+        }
+        if state.off_ms > 0 && state.on_ms == 0 {
+            tracing::error!("Cue LED blink on time must be positive");
+            return Err(ClientError::InvalidState.into())
+        }
+        let led = self.led.clone().ok_or(ClientError::InvalidState)?;
+        let epoch = led.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        *self.state.lock().unwrap() = state.clone();
+        if state.off_ms > 0 {
+            let blinking = state.clone();
+            let faults = self.state_sender.clone();
+            tokio::spawn(async move {
+                if let Err(e) = led.blink(epoch, blinking).await {
+                    report_fault(&faults, e);
+                }
+            });
+        } else {
+            led.show(&state, true)?;
+        }
+        let sender = self.state_sender.clone();
+        tokio::spawn(async move {
+            Self::send_state(&sender, &state).await.unwrap();
+            tracing::info!("Cue LED State Changed by Request");
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        let gone = self.led.iter().flat_map(|led| led.channels.iter()).find(|channel| !channel.pwm.exists());
+        match gone {
+            Some(channel) => ComponentHealth::Failed(format!("Cue LED PWM channel {:?} has gone", channel.pwm)),
+            None => ComponentHealth::Healthy,
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        [&config.red, &config.green, &config.blue].iter()
+            .map(|channel| Resource::Device(channel.pwm_path.clone()))
+            .collect()
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Cue LED");
+        if let Some(led) = &self.led {
+            led.epoch.fetch_add(1, Ordering::AcqRel);
+            if let Err(e) = led.show(&proto::CueState::default(), false) {
+                tracing::error!("Cue LED could not be turned off: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChannelConfig {
+    pwm_path: String, // e.g. /sys/class/pwm/pwmchip0/pwm0
+    #[serde(default = "ChannelConfig::default_gamma")]
+    gamma: f64,
+    #[serde(default = "ChannelConfig::default_max")]
+    max: f64, // fraction (0-1) of the period at full brightness, to balance the channels
+}
+
+impl ChannelConfig {
+    fn default_gamma() -> f64 {
+        2.2
+    }
+
+    fn default_max() -> f64 {
+        1.0
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    red: ChannelConfig,
+    green: ChannelConfig,
+    blue: ChannelConfig,
+    #[serde(default = "Config::default_period")]
+    period: u64, // us
+}
+
+impl Config {
+    fn default_period() -> u64 {
+        1000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duty_is_gamma_corrected_and_scaled() {
+        assert_eq!(duty(0, 1.0, 2.2, 1.0), 0.0);
+        assert_eq!(duty(255, 1.0, 2.2, 0.8), 0.8);
+        assert!((duty(255, 0.5, 2.0, 1.0) - 0.25).abs() < 1e-9);
+        assert!((duty(51, 1.0, 1.0, 0.5) - 0.1).abs() < 1e-9);
+    }
+}
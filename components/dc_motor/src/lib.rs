@@ -0,0 +1,474 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}};
+use std::thread;
+use std::time::Instant;
+use async_trait::async_trait;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::LinuxI2CDevice;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, time::Duration};
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
+use proto::Direction;
+
+/// Brushed DC motor driven through an H-bridge, with two GPIO lines selecting
+/// the direction and a PWM channel on the enable pin setting the speed. If a
+/// current sense channel is configured, runs are cut off when the motor stalls.
+pub struct DcMotor {
+    drive: Option<Arc<Drive>>, // set up in init
+    params: proto::MotorParams,
+    state_sender: Sender<Any>,
+    stop: Arc<AtomicBool>,
+    sensor: Option<thread::JoinHandle<()>>,
+}
+
+struct Drive {
+    in1: LineHandle,
+    in2: LineHandle,
+    pwm: PathBuf, // channel directory
+    period: u64, // ns
+    output: Mutex<(Direction, f32)>,
+    stalled: AtomicBool,
+    current: AtomicU32, // f32 bits, A
+    epoch: AtomicU64, // bumped by every request and cutoff to cancel running ramps
+}
+
+impl Drive {
+    /// Time between speed changes during a ramp
+    const RAMP_STEP: Duration = Duration::from_millis(10);
+
+    fn set(&self, direction: Direction, speed: f32) -> decide_protocol::Result<()> {
+        let mut output = self.output.lock().unwrap();
+        if output.0 != direction {
+            // never drive both sides of the bridge while switching
+            self.duty(0.0)?;
+            self.line(&self.in1, 0)?;
+            self.line(&self.in2, 0)?;
+            match direction {
+                Direction::Forward => self.line(&self.in1, 1)?,
+                Direction::Reverse => self.line(&self.in2, 1)?,
+                Direction::Stop => (),
+            }
+        }
+        let speed = if direction == Direction::Stop { 0.0 } else { speed };
+        self.duty(speed)?;
+        *output = (direction, speed);
+        Ok(())
+    }
+
+    fn duty(&self, speed: f32) -> decide_protocol::Result<()> {
+        let duty = (self.period as f64 * speed as f64) as u64;
+        write(&self.pwm.join("duty_cycle"), duty.to_string())
+    }
+
+    fn line(&self, line: &LineHandle, value: u8) -> decide_protocol::Result<()> {
+        line.set_value(value)
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+
+    /// Changes speed linearly over `time`, and returns false if another request
+    /// took over the motor before the ramp finished
+    async fn ramp(&self, epoch: u64, direction: Direction, to: f32, time: Duration) -> decide_protocol::Result<bool> {
+        let from = self.output.lock().unwrap().1;
+        let steps = (time.as_millis() / Drive::RAMP_STEP.as_millis()).max(1) as u32;
+        for step in 1..=steps {
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                return Ok(false)
+            }
+            self.set(direction, from + (to - from) * step as f32 / steps as f32)?;
+            if step < steps {
+                tokio::time::sleep(Drive::RAMP_STEP).await;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Ramps down whatever is running, then runs in `direction` at `params` speed
+    async fn run(&self, epoch: u64, direction: Direction, params: proto::MotorParams,
+                 sender: &Sender<Any>) -> decide_protocol::Result<()> {
+        let ramp = Duration::from_millis(params.ramp_ms as u64);
+        let running = self.output.lock().unwrap().0;
+        if running != direction && running != Direction::Stop
+            && !self.ramp(epoch, running, 0.0, ramp).await? {
+            return Ok(())
+        }
+        if direction == Direction::Stop {
+            self.set(Direction::Stop, 0.0)?;
+            self.send_state(sender).await;
+            tracing::info!("DC-Motor Stopped");
+            return Ok(())
+        }
+        if !self.ramp(epoch, direction, params.speed, ramp).await? {
+            return Ok(())
+        }
+        self.send_state(sender).await;
+        tracing::info!("DC-Motor Running {:?} at {:?}", direction, params.speed);
+        if params.duration_ms == 0 {
+            return Ok(())
+        }
+        tokio::time::sleep(Duration::from_millis(params.duration_ms as u64)).await;
+        if self.ramp(epoch, direction, 0.0, ramp).await? {
+            self.set(Direction::Stop, 0.0)?;
+            self.send_state(sender).await;
+            tracing::info!("DC-Motor Stopped After {:?} ms", params.duration_ms);
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> proto::MotorState {
+        let (direction, speed) = *self.output.lock().unwrap();
+        proto::MotorState {
+            direction: direction as i32,
+            speed,
+            stalled: self.stalled.load(Ordering::Acquire),
+            current: f32::from_bits(self.current.load(Ordering::Acquire)),
+        }
+    }
+
+    fn message(&self) -> Any {
+        DcMotor::pack_state(&self.state())
+    }
+
+    async fn send_state(&self, sender: &Sender<Any>) {
+        sender.send(self.message()).await
+            .map_err(|e| DecideError::Component { source: e.into() })
+            .unwrap();
+    }
+}
+
+#[component(state = proto::MotorState, params = proto::MotorParams, config = Config)]
+#[async_trait]
+impl Component for DcMotor {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        DcMotor {
+            drive: None,
+            params: proto::MotorParams {
+                speed: config.speed,
+                ramp_ms: config.ramp,
+                duration_ms: config.duration,
+            },
+            state_sender,
+            stop: Arc::new(AtomicBool::new(false)),
+            sensor: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut request = |offset: u32| chip.get_line(offset)
+            .map_err(|e| DecideError::Component { source: e.into() })?
+            .request(LineRequestFlags::OUTPUT, 0, "dc_motor")
+            .map_err(|e| DecideError::Component { source: e.into() });
+        let (in1, in2) = (request(config.in1_offset)?, request(config.in2_offset)?);
+        let pwm = PathBuf::from(&config.pwm_path);
+        if !pwm.exists() {
+            let channel = pwm.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("pwm"))
+                .ok_or_else(|| invalid_config("DC-Motor PWM path must end in pwm<channel>"))?;
+            write(&pwm.with_file_name("export"), channel)?;
+        }
+        let period = config.period * 1000;
+        write(&pwm.join("duty_cycle"), "0")?;
+        write(&pwm.join("period"), period.to_string())?;
+        write(&pwm.join("enable"), "1")?;
+        let drive = Arc::new(Drive {
+            in1,
+            in2,
+            pwm,
+            period,
+            output: Mutex::new((Direction::Stop, 0.0)),
+            stalled: AtomicBool::new(false),
+            current: AtomicU32::new(0),
+            epoch: AtomicU64::new(0),
+        });
+        self.drive = Some(drive.clone());
+        if let Some(sense) = config.sense {
+            let mut adc = CurrentSense::new(&sense)?;
+            let mut detector = StallDetector::new(sense.stall_current, Duration::from_millis(sense.stall_time));
+            let sender = self.state_sender.clone();
+            let stop = self.stop.clone();
+            let period = Duration::from_secs_f64(1.0 / sense.rate);
+            self.sensor = Some(thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    let amps = match adc.read() {
+                        Ok(amps) => amps,
+                        Err(e) => return report_fault(&sender, e),
+                    };
+                    drive.current.store(amps.to_bits(), Ordering::Release);
+                    let running = drive.output.lock().unwrap().0 != Direction::Stop;
+                    if detector.update(running, amps, Instant::now()) {
+                        drive.epoch.fetch_add(1, Ordering::AcqRel);
+                        if let Err(e) = drive.set(Direction::Stop, 0.0) {
+                            return report_fault(&sender, e)
+                        }
+                        drive.stalled.store(true, Ordering::Release);
+                        tracing::warn!("DC-Motor Stalled at {:?} A", amps);
+                        sender.blocking_send(drive.message())
+                            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                    }
+                    thread::sleep(period);
+                }
+            }));
+        }
+        tracing::info!("DC-Motor Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let direction = Direction::from_i32(state.direction).ok_or_else(|| {
+            tracing::error!("DC-Motor direction {:?} is not valid", state.direction);
+            DecideError::from(ClientError::InvalidState)
+        })?;
+        let drive = self.drive.clone().ok_or(ClientError::InvalidState)?;
+        if direction != Direction::Stop {
+            drive.stalled.store(false, Ordering::Release);
+        }
+        let epoch = drive.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        let params = self.params.clone();
+        let sender = self.state_sender.clone();
+        tokio::spawn(async move {
+            if let Err(e) = drive.run(epoch, direction, params, &sender).await {
+                report_fault(&sender, e);
+            }
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if !(0.0..=1.0).contains(&params.speed) {
+            tracing::error!("DC-Motor speed must be between 0 and 1");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // applies to the next run
+        self.params = params;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.drive.as_ref().map_or_else(Default::default, |drive| drive.state())
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("DcMotor current sensor thread", self.sensor.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![
+            Resource::line(&config.chip, config.in1_offset),
+            Resource::line(&config.chip, config.in2_offset),
+            Resource::Device(config.pwm_path.clone()),
+        ]
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for DC-Motor");
+        if let Some(drive) = &self.drive {
+            drive.epoch.fetch_add(1, Ordering::AcqRel);
+            if let Err(e) = drive.set(Direction::Stop, 0.0) {
+                tracing::error!("DC-Motor could not be stopped: {}", e);
+            }
+        }
+        self.stop.store(true, Ordering::Release);
+        if let Some(sensor) = self.sensor.take() {
+            tokio::task::spawn_blocking(move || sensor.join())
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
+
+/// Error for a config the motor cannot be set up from
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> decide_protocol::Result<()> {
+    fs::write(path, contents).map_err(|e| DecideError::Component { source: e.into() })
+}
+
+/// Cuts the motor off once the current stays above the limit for a while, so
+/// that the inrush at startup is not mistaken for a stall
+struct StallDetector {
+    limit: f32, // A
+    time: Duration,
+    since: Option<Instant>, // start of the current run of samples over the limit
+}
+
+impl StallDetector {
+    fn new(limit: f32, time: Duration) -> Self {
+        StallDetector { limit, time, since: None }
+    }
+
+    /// Returns true when the motor has been over the limit for long enough
+    fn update(&mut self, running: bool, amps: f32, now: Instant) -> bool {
+        if !running || amps <= self.limit {
+            self.since = None;
+            return false
+        }
+        let since = *self.since.get_or_insert(now);
+        if now.duration_since(since) >= self.time {
+            self.since = None;
+            return true
+        }
+        false
+    }
+}
+
+/// One channel of an ADS1115 in continuous conversion mode, measuring the
+/// output of a current sense amplifier
+struct CurrentSense {
+    dev: LinuxI2CDevice,
+    range: f32, // V at full scale
+    volts_per_amp: f32,
+}
+
+impl CurrentSense {
+    const CONVERSION: u8 = 0x00;
+    const CONFIG: u8 = 0x01;
+
+    fn new(config: &SenseConfig) -> decide_protocol::Result<Self> {
+        let mut dev = LinuxI2CDevice::new(&config.bus, config.address)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        if config.channel > 3 {
+            tracing::error!("DC-Motor sense channel must be between 0 and 3");
+            return Err(invalid_config("invalid ADS1115 channel"))
+        }
+        let pga = match config.range_mv {
+            6144 => 0b000,
+            4096 => 0b001,
+            2048 => 0b010,
+            1024 => 0b011,
+            512 => 0b100,
+            256 => 0b101,
+            _ => {
+                tracing::error!("DC-Motor sense range_mv must be 6144, 4096, 2048, 1024, 512 or 256");
+                return Err(invalid_config("invalid ADS1115 range"))
+            }
+        };
+        let bits: u16 = (0b100 + config.channel) << 12 // AINx against GND
+            | pga << 9
+            | 0b111 << 5 // 860 samples per second
+            | 0b11; // comparator off; mode bit clear for continuous conversion
+        dev.write(&[CurrentSense::CONFIG, (bits >> 8) as u8, bits as u8])
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(CurrentSense {
+            dev,
+            range: config.range_mv as f32 / 1000.0,
+            volts_per_amp: config.volts_per_amp,
+        })
+    }
+
+    /// Returns the latest conversion in A
+    fn read(&mut self) -> decide_protocol::Result<f32> {
+        let mut buf = [0u8; 2];
+        self.dev.write(&[CurrentSense::CONVERSION])
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        self.dev.read(&mut buf)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let volts = i16::from_be_bytes(buf) as f32 * self.range / 32768.0;
+        Ok(volts / self.volts_per_amp)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SenseConfig {
+    #[serde(default = "SenseConfig::default_bus")]
+    bus: String,
+    #[serde(default = "SenseConfig::default_address")]
+    address: u16,
+    channel: u16, // ADS1115 AIN0-3
+    #[serde(default = "SenseConfig::default_range")]
+    range_mv: u32, // full-scale range of the programmable gain amplifier
+    volts_per_amp: f32, // gain of the sense circuit
+    stall_current: f32, // A
+    #[serde(default = "SenseConfig::default_stall_time")]
+    stall_time: u64, // ms over stall_current before the motor is cut off
+    #[serde(default = "SenseConfig::default_rate")]
+    rate: f64, // Hz
+}
+
+impl SenseConfig {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_address() -> u16 {
+        0x48
+    }
+
+    fn default_range() -> u32 {
+        4096
+    }
+
+    fn default_stall_time() -> u64 {
+        200
+    }
+
+    fn default_rate() -> f64 {
+        100.0
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    in1_offset: u32, // driven high for forward
+    in2_offset: u32, // driven high for reverse
+    pwm_path: String, // /sys/class/pwm/pwmchip0/pwm0, wired to the bridge enable
+    #[serde(default = "Config::default_period")]
+    period: u64, // us
+    #[serde(default = "Config::default_speed")]
+    speed: f32, // initial value of the speed parameter
+    #[serde(default)]
+    ramp: u32, // ms, initial value of the ramp_ms parameter
+    #[serde(default)]
+    duration: u32, // ms, initial value of the duration_ms parameter
+    sense: Option<SenseConfig>,
+}
+
+impl Config {
+    fn default_period() -> u64 {
+        50 // 20 kHz, above hearing
+    }
+
+    fn default_speed() -> f32 {
+        1.0
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_needs_sustained_overcurrent() {
+        let mut detector = StallDetector::new(1.0, Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(!detector.update(true, 2.0, start));
+        assert!(!detector.update(true, 0.5, start + Duration::from_millis(50)));
+        assert!(!detector.update(true, 2.0, start + Duration::from_millis(60)));
+        assert!(!detector.update(true, 2.0, start + Duration::from_millis(150)));
+        assert!(detector.update(true, 2.0, start + Duration::from_millis(160)));
+    }
+
+    #[test]
+    fn stall_ignored_while_stopped() {
+        let mut detector = StallDetector::new(1.0, Duration::ZERO);
+        assert!(!detector.update(false, 2.0, Instant::now()));
+        assert!(detector.update(true, 2.0, Instant::now()));
+    }
+}
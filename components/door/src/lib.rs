@@ -0,0 +1,345 @@
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Instant;
+use tokio::time::Duration;
+use proto::{Command, Position};
+
+/// Door or gate, e.g. on a nest box or a chamber divider, moved by a relay or a
+/// motor and sensed by limit switches at each end of its travel. Moves stop at
+/// the switch they are heading for, or after a timeout, which is reported as a
+/// fault.
+pub struct Door {
+    door: Option<Arc<Actuator>>, // set up in init
+    timeout: Arc<AtomicU32>, // ms
+    state_sender: Sender<Any>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Open,
+    Closed,
+}
+
+enum Drive {
+    /// a single line, active to open and inactive to close, e.g. a relay
+    /// switching a spring-return actuator
+    Relay(LineHandle),
+    /// one line for each direction, active only while moving
+    Motor { open: LineHandle, close: LineHandle },
+}
+
+struct Actuator {
+    drive: Drive,
+    open_switch: LineHandle,
+    closed_switch: LineHandle,
+    moving: Mutex<Option<Target>>,
+    transit: AtomicU32, // ms
+    fault: AtomicBool,
+    epoch: AtomicU64, // bumped by every request to cancel the running move
+}
+
+impl Actuator {
+    /// Time between checks of the limit switches while moving
+    const POLL: Duration = Duration::from_millis(5);
+
+    fn line(line: &LineHandle, value: u8) -> decide_protocol::Result<()> {
+        line.set_value(value)
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+
+    fn active(line: &LineHandle) -> decide_protocol::Result<bool> {
+        let value = line.get_value()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(value != 0)
+    }
+
+    /// Starts driving towards `target`, or stops the motor if there is none
+    fn drive(&self, target: Option<Target>) -> decide_protocol::Result<()> {
+        match (&self.drive, target) {
+            (Drive::Relay(line), Some(target)) => Actuator::line(line, (target == Target::Open) as u8),
+            // a relay holds the door wherever it was sent
+            (Drive::Relay(_), None) => Ok(()),
+            (Drive::Motor { open, close }, target) => {
+                // never drive both directions while switching
+                Actuator::line(open, 0)?;
+                Actuator::line(close, 0)?;
+                match target {
+                    Some(Target::Open) => Actuator::line(open, 1),
+                    Some(Target::Closed) => Actuator::line(close, 1),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn position(&self) -> decide_protocol::Result<Position> {
+        Ok(match *self.moving.lock().unwrap() {
+            Some(Target::Open) => Position::Opening,
+            Some(Target::Closed) => Position::Closing,
+            None => position(Actuator::active(&self.open_switch)?, Actuator::active(&self.closed_switch)?),
+        })
+    }
+
+    /// Moves to `target` unless another request takes over first, and returns
+    /// false if it did
+    async fn run(&self, epoch: u64, target: Target, timeout: Duration) -> decide_protocol::Result<bool> {
+        let switch = match target {
+            Target::Open => &self.open_switch,
+            Target::Closed => &self.closed_switch,
+        };
+        let start = Instant::now();
+        *self.moving.lock().unwrap() = Some(target);
+        self.drive(Some(target))?;
+        loop {
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                return Ok(false)
+            }
+            if Actuator::active(switch)? {
+                self.transit.store(start.elapsed().as_millis() as u32, Ordering::Release);
+                self.fault.store(false, Ordering::Release);
+                tracing::info!("Door {:?} After {:?} ms", target, start.elapsed().as_millis());
+                break
+            }
+            if start.elapsed() >= timeout {
+                self.fault.store(true, Ordering::Release);
+                tracing::warn!("Door Did Not Reach {:?} Within {:?} ms", target, timeout.as_millis());
+                break
+            }
+            tokio::time::sleep(Actuator::POLL).await;
+        }
+        self.drive(None)?;
+        *self.moving.lock().unwrap() = None;
+        Ok(true)
+    }
+
+    fn state(&self) -> proto::DoorState {
+        proto::DoorState {
+            command: Command::None as i32,
+            // a switch that cannot be read is reported by the health check
+            position: self.position().unwrap_or(Position::Unknown) as i32,
+            transit_ms: self.transit.load(Ordering::Acquire),
+            fault: self.fault.load(Ordering::Acquire),
+        }
+    }
+
+    fn message(&self) -> Any {
+        Door::pack_state(&self.state())
+    }
+}
+
+/// Position from the levels of the open and closed switches
+fn position(open: bool, closed: bool) -> Position {
+    match (open, closed) {
+        (true, false) => Position::Open,
+        (false, true) => Position::Closed,
+        _ => Position::Unknown,
+    }
+}
+
+/// Where a command sends the door from `position`, if anywhere
+fn target(command: Command, position: Position) -> Option<Target> {
+    match (command, position) {
+        (Command::None, _) => None,
+        (Command::Open, _) => Some(Target::Open),
+        (Command::Close, _) => Some(Target::Closed),
+        (Command::Toggle, Position::Open | Position::Opening) => Some(Target::Closed),
+        (Command::Toggle, _) => Some(Target::Open),
+    }
+}
+
+#[component(state = proto::DoorState, params = proto::DoorParams, config = Config)]
+#[async_trait]
+impl Component for Door {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        Door {
+            door: None,
+            timeout: Arc::new(AtomicU32::new(config.timeout)),
+            state_sender: sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut request = |offset: u32, flags: LineRequestFlags| chip.get_line(offset)
+            .map_err(|e| DecideError::Component { source: e.into() })?
+            .request(flags, 0, "door")
+            .map_err(|e| DecideError::Component { source: e.into() });
+        // the drive lines start inactive, leaving a relay-driven door closed
+        let drive = match config.drive {
+            DriveConfig::Relay { offset } => Drive::Relay(request(offset, LineRequestFlags::OUTPUT)?),
+            DriveConfig::Motor { open_offset, close_offset } => Drive::Motor {
+                open: request(open_offset, LineRequestFlags::OUTPUT)?,
+                close: request(close_offset, LineRequestFlags::OUTPUT)?,
+            },
+        };
+        let switch_flags = if config.switches_active_low {
+            LineRequestFlags::INPUT | LineRequestFlags::ACTIVE_LOW
+        } else {
+            LineRequestFlags::INPUT
+        };
+        let door = Actuator {
+            drive,
+            open_switch: request(config.open_switch, switch_flags)?,
+            closed_switch: request(config.closed_switch, switch_flags)?,
+            moving: Mutex::new(None),
+            transit: AtomicU32::new(0),
+            fault: AtomicBool::new(false),
+            epoch: AtomicU64::new(0),
+        };
+        tracing::info!("Door Initiated at {:?}", door.position()?);
+        self.door = Some(Arc::new(door));
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let command = Command::from_i32(state.command).ok_or_else(|| {
+            tracing::error!("Door command {:?} is not valid", state.command);
+            DecideError::from(ClientError::InvalidState)
+        })?;
+        let door = self.door.clone().ok_or(ClientError::InvalidState)?;
+        let target = match target(command, door.position()?) {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let epoch = door.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        let timeout = Duration::from_millis(self.timeout.load(Ordering::Acquire) as u64);
+        let sender = self.state_sender.clone();
+        tracing::info!("Door Moving to {:?} by Request", target);
+        tokio::spawn(async move {
+            // reversing a motor mid-travel goes through stopped
+            if let Err(e) = door.drive(None) {
+                return report_fault(&sender, e)
+            }
+            *door.moving.lock().unwrap() = Some(target);
+            sender.send(door.message()).await
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            match door.run(epoch, target, timeout).await {
+                Ok(true) => sender.send(door.message()).await
+                    .map_err(|e| DecideError::Component { source: e.into() }).unwrap(),
+                Ok(false) => (),
+                Err(e) => report_fault(&sender, e),
+            }
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.timeout_ms == 0 {
+            tracing::error!("Door timeout must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // applies to the next move
+        self.timeout.store(params.timeout_ms, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.door.as_ref().map_or_else(Default::default, |door| door.state())
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            timeout_ms: self.timeout.load(Ordering::Acquire),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        let door = match &self.door {
+            Some(door) => door,
+            None => return ComponentHealth::Healthy,
+        };
+        if let Err(e) = door.open_switch.get_value().and(door.closed_switch.get_value()) {
+            ComponentHealth::Failed(format!("Door end switches cannot be read: {}", e))
+        } else if door.fault.load(Ordering::Acquire) {
+            ComponentHealth::Degraded(String::from("Door did not reach the end of its last move"))
+        } else {
+            ComponentHealth::Healthy
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        let mut resources = match config.drive {
+            DriveConfig::Relay { offset } => vec![Resource::line(&config.chip, offset)],
+            DriveConfig::Motor { open_offset, close_offset } => Resource::lines(&config.chip, &[open_offset, close_offset]),
+        };
+        resources.extend(Resource::lines(&config.chip, &[config.open_switch, config.closed_switch]));
+        resources
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Door");
+        if let Some(door) = &self.door {
+            door.epoch.fetch_add(1, Ordering::AcqRel);
+            if let Err(e) = door.drive(None) {
+                tracing::error!("Door could not be stopped: {}", e);
+            }
+            *door.moving.lock().unwrap() = None;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DriveConfig {
+    Relay {
+        offset: u32,
+    },
+    Motor {
+        open_offset: u32,
+        close_offset: u32,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    drive: DriveConfig,
+    open_switch: u32, // offset of the switch closed at the open end of travel
+    closed_switch: u32, // offset of the switch closed at the closed end of travel
+    #[serde(default)]
+    switches_active_low: bool, // switches pull the lines low when closed
+    #[serde(default = "Config::default_timeout")]
+    timeout: u32, // ms, initial value of the timeout_ms parameter
+}
+
+impl Config {
+    fn default_timeout() -> u32 {
+        5000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_give_position() {
+        assert_eq!(position(true, false), Position::Open);
+        assert_eq!(position(false, true), Position::Closed);
+        assert_eq!(position(false, false), Position::Unknown);
+        assert_eq!(position(true, true), Position::Unknown);
+    }
+
+    #[test]
+    fn toggle_reverses_travel() {
+        assert_eq!(target(Command::Toggle, Position::Open), Some(Target::Closed));
+        assert_eq!(target(Command::Toggle, Position::Opening), Some(Target::Closed));
+        assert_eq!(target(Command::Toggle, Position::Closing), Some(Target::Open));
+        assert_eq!(target(Command::Toggle, Position::Unknown), Some(Target::Open));
+        assert_eq!(target(Command::None, Position::Open), None);
+    }
+}
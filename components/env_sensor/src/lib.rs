@@ -0,0 +1,369 @@
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::thread;
+use std::time::Duration;
+use async_trait::async_trait;
+use decide_hal::{linux, I2c};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, task::JoinHandle, time::sleep};
+use decide_protocol::{component, Component, ComponentHealth, error::{ClientError, DecideError}};
+
+/// Logs temperature and humidity from an I2C sensor, with alarm flags for
+/// readings outside the configured limits.
+pub struct EnvSensor {
+    state: Arc<Mutex<proto::EnvState>>,
+    interval: Arc<AtomicU64>, // s between readings
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+#[component(state = proto::EnvState, params = proto::EnvParams, config = Config)]
+#[async_trait]
+impl Component for EnvSensor {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        EnvSensor {
+            state: Arc::new(Mutex::new(proto::EnvState::default())),
+            interval: Arc::new(AtomicU64::new(config.interval)),
+            state_sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let dev: Box<dyn I2c> = Box::new(linux::i2c(&config.bus, config.address)
+            .map_err(|e| DecideError::Component { source: e })?);
+        let sensor: Box<dyn Sensor> = match config.model {
+            Model::Sht31 => Box::new(Sht31 { dev }),
+            Model::Bme280 => Box::new(Bme280::new(dev)
+                .map_err(|e| DecideError::Component { source: e })?),
+        };
+        let sensor = Arc::new(Mutex::new(sensor));
+        let state = self.state.clone();
+        let interval = self.interval.clone();
+        let sender = self.state_sender.clone();
+        let limits = config.limits;
+
+        self.task_handle = Some(tokio::spawn(async move {
+            loop {
+                let sensor = sensor.clone();
+                // I2C transfers and conversion delays block, so keep them off the runtime
+                let reading = tokio::task::spawn_blocking(move || sensor.lock().unwrap().read())
+                    .await
+                    .map_err(|e| DecideError::Component { source: e.into() })
+                    .unwrap();
+                match reading {
+                    Ok(reading) => {
+                        let message = {
+                            let mut state = state.lock().unwrap();
+                            let alarms = limits.check(&reading);
+                            if alarms.0 && !state.temperature_alarm {
+                                tracing::warn!("Env-Sensor Temperature {:?} C Out of Range", reading.temperature);
+                            }
+                            if alarms.1 && !state.humidity_alarm {
+                                tracing::warn!("Env-Sensor Humidity {:?} % Out of Range", reading.humidity);
+                            }
+                            *state = proto::EnvState {
+                                temperature: reading.temperature,
+                                humidity: reading.humidity,
+                                pressure: reading.pressure.unwrap_or(0.0),
+                                temperature_alarm: alarms.0,
+                                humidity_alarm: alarms.1,
+                            };
+                            Self::pack_state(&state)
+                        };
+                        sender.send(message).await
+                            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                    }
+                    Err(e) => tracing::error!("Env-Sensor Read Failed: {}", e),
+                }
+                sleep(Duration::from_secs(interval.load(Ordering::Acquire))).await;
+            }
+        }));
+        tracing::info!("Env-Sensor Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval <= 0 {
+            tracing::error!("Env-Sensor interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // takes effect after the current wait
+        self.interval.store(params.interval as u64, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            interval: self.interval.load(Ordering::Acquire) as i64,
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("EnvSensor task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Env-Sensor");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Env-Sensor task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reading {
+    temperature: f64, // C
+    humidity: f64, // %RH
+    pressure: Option<f64>, // hPa
+}
+
+type ReadResult = Result<Reading, Box<dyn std::error::Error + Send + Sync>>;
+
+trait Sensor: Send {
+    fn read(&mut self) -> ReadResult;
+}
+
+/// Sensirion SHT31, read in single-shot mode
+struct Sht31 {
+    dev: Box<dyn I2c>,
+}
+
+impl Sensor for Sht31 {
+    fn read(&mut self) -> ReadResult {
+        // high repeatability without clock stretching
+        self.dev.write(&[0x24, 0x00])?;
+        thread::sleep(Duration::from_millis(16));
+        let mut buf = [0u8; 6];
+        self.dev.read(&mut buf)?;
+        if crc8(&buf[0..2]) != buf[2] || crc8(&buf[3..5]) != buf[5] {
+            return Err("SHT31 checksum mismatch".into())
+        }
+        let t = u16::from_be_bytes([buf[0], buf[1]]) as f64;
+        let rh = u16::from_be_bytes([buf[3], buf[4]]) as f64;
+        Ok(Reading {
+            temperature: -45.0 + 175.0 * t / 65535.0,
+            humidity: 100.0 * rh / 65535.0,
+            pressure: None,
+        })
+    }
+}
+
+/// CRC-8 used by Sensirion sensors: polynomial 0x31, initial value 0xFF
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0xFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 }
+        })
+    })
+}
+
+/// Bosch BME280, read in forced mode with 1x oversampling
+struct Bme280 {
+    dev: Box<dyn I2c>,
+    cal: Bme280Calibration,
+}
+
+#[derive(Debug, Default)]
+struct Bme280Calibration {
+    t: [f64; 3],
+    p: [f64; 9],
+    h: [f64; 6],
+}
+
+impl Bme280 {
+    fn new(mut dev: Box<dyn I2c>) -> decide_hal::Result<Self> {
+        let id = Bme280::registers(&mut *dev, 0xD0, 1)?[0];
+        if id != 0x60 {
+            tracing::warn!("Env-Sensor Chip ID {:#x} is not a BME280", id);
+        }
+        let tp = Bme280::registers(&mut *dev, 0x88, 26)?;
+        let h = Bme280::registers(&mut *dev, 0xE1, 7)?;
+        Ok(Bme280 { dev, cal: Bme280Calibration::parse(&tp, &h) })
+    }
+
+    fn registers(dev: &mut dyn I2c, start: u8, len: usize) -> decide_hal::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        dev.write_read(&[start], &mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl Bme280Calibration {
+    /// Parses the trimming parameters in registers 0x88-0xA1 and 0xE1-0xE7
+    fn parse(tp: &[u8], h: &[u8]) -> Self {
+        let u = |i: usize| u16::from_le_bytes([tp[i], tp[i + 1]]) as f64;
+        let s = |i: usize| i16::from_le_bytes([tp[i], tp[i + 1]]) as f64;
+        Bme280Calibration {
+            t: [u(0), s(2), s(4)],
+            p: [u(6), s(8), s(10), s(12), s(14), s(16), s(18), s(20), s(22)],
+            h: [
+                tp[25] as f64,
+                i16::from_le_bytes([h[0], h[1]]) as f64,
+                h[2] as f64,
+                (((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16) as f64,
+                (((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16) as f64,
+                h[6] as i8 as f64,
+            ],
+        }
+    }
+
+    /// Returns the temperature in C and the fine temperature used by the other
+    /// compensations, using the floating point formulas in the datasheet
+    fn temperature(&self, adc: i32) -> (f64, f64) {
+        let [t1, t2, t3] = self.t;
+        let adc = adc as f64;
+        let var1 = (adc / 16384.0 - t1 / 1024.0) * t2;
+        let var2 = (adc / 131072.0 - t1 / 8192.0).powi(2) * t3;
+        let t_fine = var1 + var2;
+        (t_fine / 5120.0, t_fine)
+    }
+
+    /// Returns the pressure in Pa
+    fn pressure(&self, adc: i32, t_fine: f64) -> f64 {
+        let [p1, p2, p3, p4, p5, p6, p7, p8, p9] = self.p;
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p6 / 32768.0;
+        var2 += var1 * p5 * 2.0;
+        var2 = var2 / 4.0 + p4 * 65536.0;
+        var1 = (p3 * var1 * var1 / 524288.0 + p2 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p1;
+        if var1 == 0.0 {
+            return 0.0
+        }
+        let mut p = 1048576.0 - adc as f64;
+        p = (p - var2 / 4096.0) * 6250.0 / var1;
+        var1 = p9 * p * p / 2147483648.0;
+        var2 = p * p8 / 32768.0;
+        p + (var1 + var2 + p7) / 16.0
+    }
+
+    /// Returns the relative humidity in %
+    fn humidity(&self, adc: i32, t_fine: f64) -> f64 {
+        let [h1, h2, h3, h4, h5, h6] = self.h;
+        let mut h = t_fine - 76800.0;
+        h = (adc as f64 - (h4 * 64.0 + h5 / 16384.0 * h))
+            * (h2 / 65536.0 * (1.0 + h6 / 67108864.0 * h * (1.0 + h3 / 67108864.0 * h)));
+        h *= 1.0 - h1 * h / 524288.0;
+        h.clamp(0.0, 100.0)
+    }
+}
+
+impl Sensor for Bme280 {
+    fn read(&mut self) -> ReadResult {
+        // humidity oversampling only takes effect after ctrl_meas is written
+        self.dev.write(&[0xF2, 0x01])?;
+        self.dev.write(&[0xF4, 0b0010_0101])?; // 1x temperature and pressure oversampling, forced mode
+        thread::sleep(Duration::from_millis(10));
+        let buf = Bme280::registers(&mut *self.dev, 0xF7, 8)?;
+        let adc_p = ((buf[0] as i32) << 12) | ((buf[1] as i32) << 4) | (buf[2] as i32 >> 4);
+        let adc_t = ((buf[3] as i32) << 12) | ((buf[4] as i32) << 4) | (buf[5] as i32 >> 4);
+        let adc_h = ((buf[6] as i32) << 8) | buf[7] as i32;
+        let (temperature, t_fine) = self.cal.temperature(adc_t);
+        Ok(Reading {
+            temperature,
+            humidity: self.cal.humidity(adc_h, t_fine),
+            pressure: Some(self.cal.pressure(adc_p, t_fine) / 100.0),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Model {
+    Sht31,
+    Bme280,
+}
+
+/// Acceptable ranges; a missing bound is not checked
+#[derive(Deserialize, Default, Clone, Copy)]
+pub struct Limits {
+    temperature_min: Option<f64>,
+    temperature_max: Option<f64>,
+    humidity_min: Option<f64>,
+    humidity_max: Option<f64>,
+}
+
+impl Limits {
+    /// Returns whether the temperature and the humidity are out of range
+    fn check(&self, reading: &Reading) -> (bool, bool) {
+        let outside = |value: f64, min: Option<f64>, max: Option<f64>| {
+            min.is_some_and(|min| value < min) || max.is_some_and(|max| value > max)
+        };
+        (outside(reading.temperature, self.temperature_min, self.temperature_max),
+         outside(reading.humidity, self.humidity_min, self.humidity_max))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_bus")]
+    bus: String,
+    address: u16, // 0x44 or 0x45 for the SHT31, 0x76 or 0x77 for the BME280
+    model: Model, // "sht31" or "bme280"
+    #[serde(default = "Config::default_interval")]
+    interval: u64, // s, initial value of the interval parameter
+    #[serde(default)]
+    limits: Limits,
+}
+
+impl Config {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_interval() -> u64 {
+        60
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensirion_crc() {
+        // example from the SHT3x datasheet
+        assert_eq!(crc8(&[0xBE, 0xEF]), 0x92);
+    }
+
+    #[test]
+    fn bosch_compensation() {
+        // example from the Bosch BMP280 datasheet, which shares these formulas
+        let cal = Bme280Calibration {
+            t: [27504.0, 26435.0, -1000.0],
+            p: [36477.0, -10685.0, 3024.0, 2855.0, 140.0, -7.0, 15500.0, -14600.0, 6000.0],
+            ..Default::default()
+        };
+        let (temperature, t_fine) = cal.temperature(519888);
+        assert!((temperature - 25.08).abs() < 0.01);
+        assert!((cal.pressure(415148, t_fine) - 100653.27).abs() < 1.0);
+    }
+
+    #[test]
+    fn limits_flag_each_quantity() {
+        let limits = Limits { temperature_max: Some(30.0), humidity_min: Some(20.0), ..Default::default() };
+        let reading = Reading { temperature: 31.0, humidity: 40.0, pressure: None };
+        assert_eq!(limits.check(&reading), (true, false));
+        let reading = Reading { temperature: 22.0, humidity: 10.0, pressure: None };
+        assert_eq!(limits.check(&reading), (false, true));
+    }
+}
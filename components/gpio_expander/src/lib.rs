@@ -0,0 +1,425 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+};
+use decide_hal::{linux, I2c};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use tokio::{self, task::JoinHandle, time::Duration};
+
+/// Named input and output lines on an MCP23017 or PCF8574 I2C port expander,
+/// for boxes with more peripherals than the SoC has GPIOs. Inputs are read when
+/// the expander's interrupt line signals a change, or polled if it is not
+/// connected, and a state message is sent whenever an input changes.
+pub struct GpioExpander {
+    pins: Arc<Vec<PinConfig>>,
+    expander: Option<Arc<Mutex<Expander>>>,
+    inputs: Arc<Mutex<u16>>, // levels of the input pins at the last read
+    poll: Arc<AtomicU32>, // ms
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    Mcp23017,
+    Pcf8574,
+}
+
+/// Register access for both devices. Levels are bit masks with pin 0 in the
+/// lowest bit; on the MCP23017 pins 0-7 are GPA0-7 and 8-15 are GPB0-7.
+struct Expander {
+    dev: Box<dyn I2c>,
+    device: Device,
+    inputs: u16, // bit mask of the pins used as inputs
+    latch: u16, // output levels
+}
+
+impl Expander {
+    const IODIRA: u8 = 0x00;
+    const GPINTENA: u8 = 0x04;
+    const INTCON: u8 = 0x08;
+    const IOCON: u8 = 0x0A;
+    const GPPUA: u8 = 0x0C;
+    const GPIOA: u8 = 0x12;
+    const OLATA: u8 = 0x14;
+    const MIRROR: u8 = 0x40; // INTA and INTB both signal changes on either port
+
+    fn new(dev: Box<dyn I2c>, device: Device, inputs: u16, pullups: u16, latch: u16) -> decide_hal::Result<Self> {
+        let mut expander = Expander {
+            dev,
+            device,
+            inputs,
+            latch,
+        };
+        if let Device::Mcp23017 = device {
+            // the B registers follow the A registers with the default IOCON.BANK = 0
+            let [inputs_a, inputs_b] = inputs.to_le_bytes();
+            let [pullups_a, pullups_b] = pullups.to_le_bytes();
+            expander.dev.write(&[Expander::IOCON, Expander::MIRROR])?;
+            expander.write(latch)?;
+            expander.dev.write(&[Expander::IODIRA, inputs_a, inputs_b])?;
+            expander.dev.write(&[Expander::GPPUA, pullups_a, pullups_b])?;
+            // interrupt on any change from the previous level
+            expander.dev.write(&[Expander::INTCON, 0x00, 0x00])?;
+            expander.dev.write(&[Expander::GPINTENA, inputs_a, inputs_b])?;
+        } else {
+            expander.write(latch)?;
+        }
+        Ok(expander)
+    }
+
+    /// Reads the levels of all pins, which also clears the interrupt
+    fn read(&mut self) -> decide_hal::Result<u16> {
+        match self.device {
+            Device::Mcp23017 => {
+                let mut buf = [0u8; 2];
+                self.dev.write_read(&[Expander::GPIOA], &mut buf)?;
+                Ok(u16::from_le_bytes(buf))
+            }
+            Device::Pcf8574 => {
+                let mut buf = [0u8; 1];
+                self.dev.read(&mut buf)?;
+                Ok(buf[0] as u16)
+            }
+        }
+    }
+
+    fn write(&mut self, latch: u16) -> decide_hal::Result<()> {
+        match self.device {
+            Device::Mcp23017 => {
+                let [a, b] = latch.to_le_bytes();
+                self.dev.write(&[Expander::OLATA, a, b])?;
+            }
+            // the PCF8574 has no direction register; pins written high are weakly
+            // pulled up and can be used as inputs
+            Device::Pcf8574 => self.dev.write(&[pcf8574_byte(latch, self.inputs)])?,
+        }
+        self.latch = latch;
+        Ok(())
+    }
+}
+
+fn pcf8574_byte(latch: u16, inputs: u16) -> u8 {
+    ((latch | inputs) & 0xFF) as u8
+}
+
+/// Sets or clears the bit of a pin for an active or inactive level
+fn set_bit(levels: u16, pin: &PinConfig, active: bool) -> u16 {
+    if active != pin.active_low {
+        levels | 1 << pin.pin
+    } else {
+        levels & !(1 << pin.pin)
+    }
+}
+
+fn is_active(levels: u16, pin: &PinConfig) -> bool {
+    (levels >> pin.pin & 1 == 1) != pin.active_low
+}
+
+/// Requests falling-edge events on the SoC line connected to the interrupt output
+fn interrupt_events(interrupt: &InterruptConfig) -> Result<AsyncLineEventHandle, gpio_cdev::Error> {
+    let mut chip = Chip::new(&interrupt.chip)?;
+    // the interrupt output is active low
+    let events = chip.get_line(interrupt.offset)?
+        .events(LineRequestFlags::INPUT, EventRequestFlags::FALLING_EDGE, "gpio_expander")?;
+    AsyncLineEventHandle::new(events)
+}
+
+/// Error for a config the expander cannot be set up from
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
+#[component(state = proto::ExpanderState, params = proto::ExpanderParams, config = Config)]
+#[async_trait]
+impl Component for GpioExpander {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        GpioExpander {
+            pins: Arc::new(config.pins),
+            expander: None,
+            inputs: Arc::new(Mutex::new(0)),
+            poll: Arc::new(AtomicU32::new(config.poll)),
+            state_sender: sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let pin_count = match config.device {
+            Device::Mcp23017 => 16,
+            Device::Pcf8574 => 8,
+        };
+        if let Some(pin) = self.pins.iter().find(|p| p.pin >= pin_count) {
+            tracing::error!("GpioExpander pin {:?} of {:?} does not exist", pin.pin, pin.name);
+            return Err(invalid_config("invalid gpio expander pin"))
+        }
+        let mut inputs = 0;
+        let mut pullups = 0;
+        let mut latch = 0;
+        for pin in self.pins.iter() {
+            match pin.direction {
+                Direction::Input => {
+                    inputs |= 1 << pin.pin;
+                    if pin.pullup {
+                        pullups |= 1 << pin.pin;
+                    }
+                }
+                Direction::Output => latch = set_bit(latch, pin, pin.initial),
+            }
+        }
+        let mut expander = linux::i2c(&config.bus, config.address)
+            .and_then(|dev| Expander::new(Box::new(dev), config.device, inputs, pullups, latch))
+            .map_err(|e| DecideError::Component { source: e })?;
+        *self.inputs.lock().unwrap() = expander.read()
+            .map_err(|e| DecideError::Component { source: e })? & inputs;
+        let mut events = config.interrupt.as_ref()
+            .map(interrupt_events)
+            .transpose()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let expander = Arc::new(Mutex::new(expander));
+        self.expander = Some(expander.clone());
+
+        let pins = self.pins.clone();
+        let last = self.inputs.clone();
+        let poll = self.poll.clone();
+        let sender = self.state_sender.clone();
+        self.task_handle = Some(tokio::spawn(async move {
+            loop {
+                match events.as_mut() {
+                    Some(events) => match events.next().await {
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => return report_fault(&sender, e),
+                        None => break,
+                    },
+                    None => tokio::time::sleep(Duration::from_millis(poll.load(Ordering::Acquire) as u64)).await,
+                }
+                let state = {
+                    let mut expander = expander.lock().unwrap();
+                    let levels = match expander.read() {
+                        Ok(levels) => levels & inputs,
+                        Err(e) => return report_fault(&sender, e),
+                    };
+                    let mut last = last.lock().unwrap();
+                    if levels == *last {
+                        continue
+                    }
+                    *last = levels;
+                    state_of(&pins, levels | expander.latch)
+                };
+                tracing::debug!("GpioExpander Inputs Changed");
+                if sender.send(Self::pack_state(&state)).await.is_err() {
+                    break
+                }
+            }
+        }));
+        tracing::info!("GpioExpander Initiated with {:?} pins", self.pins.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let expander = match self.expander.as_ref() {
+            Some(expander) => expander,
+            None => {
+                tracing::error!("GpioExpander has not been initialized");
+                return Err(ClientError::InvalidState.into())
+            }
+        };
+        let mut expander = expander.lock().unwrap();
+        // resolve every name before writing anything
+        let mut latch = expander.latch;
+        for (name, &active) in state.pins.iter() {
+            match self.pins.iter().find(|p| &p.name == name) {
+                Some(pin) if pin.direction == Direction::Output => latch = set_bit(latch, pin, active),
+                Some(_) => {
+                    tracing::error!("GpioExpander pin {:?} is an input", name);
+                    return Err(ClientError::InvalidState.into())
+                }
+                None => {
+                    tracing::error!("GpioExpander pin {:?} does not exist", name);
+                    return Err(ClientError::InvalidState.into())
+                }
+            }
+        }
+        expander.write(latch)
+            .map_err(|e| DecideError::Component { source: e })?;
+        let state = state_of(&self.pins, *self.inputs.lock().unwrap() | latch);
+        let sender = self.state_sender.clone();
+        tokio::spawn(async move {
+            // the controller may already be shutting down
+            if sender.send(Self::pack_state(&state)).await.is_ok() {
+                tracing::info!("GpioExpander State Changed by Request");
+            }
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.poll_ms == 0 {
+            tracing::error!("GpioExpander poll interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        self.poll.store(params.poll_ms, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        let latch = self.expander.as_ref().map_or(0, |e| e.lock().unwrap().latch);
+        state_of(&self.pins, *self.inputs.lock().unwrap() | latch)
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            poll_ms: self.poll.load(Ordering::Acquire),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("GpioExpander task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        config.interrupt.iter()
+            .map(|interrupt| Resource::line(&interrupt.chip, interrupt.offset))
+            .collect()
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for GpioExpander");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("GpioExpander task panicked: {}", e);
+                }
+            }
+        }
+        // return the outputs to their initial levels
+        if let Some(expander) = self.expander.take() {
+            let latch = self.pins.iter()
+                .filter(|p| p.direction == Direction::Output)
+                .fold(0, |latch, pin| set_bit(latch, pin, pin.initial));
+            if let Err(e) = expander.lock().unwrap().write(latch) {
+                tracing::error!("GpioExpander could not reset the outputs: {}", e);
+            }
+        }
+    }
+}
+
+fn state_of(pins: &[PinConfig], levels: u16) -> proto::ExpanderState {
+    proto::ExpanderState {
+        pins: pins.iter().map(|p| (p.name.clone(), is_active(levels, p))).collect(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+#[derive(Deserialize)]
+pub struct PinConfig {
+    name: String, // used in state messages, e.g. "left_key"
+    pin: u8, // 0-15 on the MCP23017 (GPA0 is 0, GPB0 is 8), 0-7 on the PCF8574
+    direction: Direction,
+    #[serde(default)]
+    active_low: bool,
+    #[serde(default)]
+    pullup: bool, // inputs only; the PCF8574 always has a weak pull-up
+    #[serde(default)]
+    initial: bool, // outputs only, level at startup and after shutdown
+}
+
+#[derive(Deserialize)]
+pub struct InterruptConfig {
+    chip: String,
+    offset: u32, // SoC line connected to INTA or INTB of an MCP23017, or INT of a PCF8574
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_bus")]
+    bus: String,
+    address: u16, // 0x20-0x27 for both devices, depending on the address pins
+    device: Device,
+    pins: Vec<PinConfig>,
+    interrupt: Option<InterruptConfig>, // inputs are polled without one
+    #[serde(default = "Config::default_poll")]
+    poll: u32, // ms, initial value of the poll_ms parameter
+}
+
+impl Config {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_poll() -> u32 {
+        20
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(pin: u8, active_low: bool) -> PinConfig {
+        PinConfig {
+            name: format!("pin{}", pin),
+            pin,
+            direction: Direction::Output,
+            active_low,
+            pullup: false,
+            initial: false,
+        }
+    }
+
+    #[test]
+    fn levels_follow_polarity() {
+        let (high, low) = (pin(9, false), pin(3, true));
+        let levels = set_bit(set_bit(0, &high, true), &low, true);
+        assert_eq!(levels, 1 << 9);
+        assert!(is_active(levels, &high) && is_active(levels, &low));
+        let levels = set_bit(levels, &low, false);
+        assert_eq!(levels, 1 << 9 | 1 << 3);
+        assert!(!is_active(levels, &low));
+    }
+
+    #[test]
+    fn mcp23017_setup_and_read() {
+        let bus = decide_hal::mock::MockI2c::default();
+        let mut expander = Expander::new(Box::new(bus.clone()), Device::Mcp23017, 0x0101, 0x0100, 0x0002).unwrap();
+        assert_eq!(bus.written(), vec![
+            vec![Expander::IOCON, Expander::MIRROR],
+            vec![Expander::OLATA, 0x02, 0x00],
+            vec![Expander::IODIRA, 0x01, 0x01],
+            vec![Expander::GPPUA, 0x00, 0x01],
+            vec![Expander::INTCON, 0x00, 0x00],
+            vec![Expander::GPINTENA, 0x01, 0x01],
+        ]);
+        bus.queue_reply(&[0x03, 0x01]);
+        assert_eq!(expander.read().unwrap(), 0x0103);
+        assert_eq!(bus.written().last(), Some(&vec![Expander::GPIOA]));
+    }
+
+    #[test]
+    fn pcf8574_inputs_stay_high() {
+        assert_eq!(pcf8574_byte(0b0000_0001, 0b1100_0000), 0b1100_0001);
+    }
+}
@@ -0,0 +1,216 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+                EventType
+};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use tokio::{self, task::JoinHandle};
+
+/// Named input lines such as beam breaks, lick sensors and door switches. Every
+/// edge is published with the name of the line, its new level and the kernel
+/// timestamp of the event.
+pub struct GpioIn {
+    lines: Arc<Lines>,
+    state_sender: Sender<Any>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+/// State shared between the component and the tasks watching each line
+struct Lines {
+    names: Vec<String>,
+    levels: Vec<AtomicBool>,
+    changed: Mutex<(Option<usize>, bool, u64)>, // line, level and time of the last event
+}
+
+impl Lines {
+    fn state(&self) -> proto::GpioInState {
+        let (changed, level, timestamp_ns) = *self.changed.lock().unwrap();
+        proto::GpioInState {
+            lines: self.names.iter().cloned()
+                .zip(self.levels.iter().map(|l| l.load(Ordering::Acquire)))
+                .collect(),
+            changed: changed.map(|i| self.names[i].clone()).unwrap_or_default(),
+            level,
+            timestamp_ns,
+        }
+    }
+}
+
+#[component(state = proto::GpioInState, params = proto::GpioInParams, config = Config)]
+#[async_trait]
+impl Component for GpioIn {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        let lines = Lines {
+            names: config.lines.iter().map(|line| line.name.clone()).collect(),
+            levels: config.lines.iter().map(|_| AtomicBool::new(false)).collect(),
+            changed: Mutex::new((None, false, 0)),
+        };
+        GpioIn {
+            lines: Arc::new(lines),
+            state_sender: sender,
+            task_handles: Vec::new(),
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        for (index, line) in config.lines.iter().enumerate() {
+            let flags = line.bias.map_or(LineRequestFlags::INPUT, |bias| LineRequestFlags::INPUT | bias.flags());
+            let events = AsyncLineEventHandle::new(
+                chip.get_line(line.offset)
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+                    .events(flags, line.edge.flags(), "gpio_in")
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+            ).map_err(|e| DecideError::Component { source: e.into() })?;
+            let raw = events.as_ref().get_value()
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+            self.lines.levels[index].store((raw != 0) != line.active_low, Ordering::Release);
+            let lines = self.lines.clone();
+            let sender = self.state_sender.clone();
+            let active_low = line.active_low;
+            self.task_handles.push(tokio::spawn(async move {
+                GpioIn::watch(index, events, active_low, lines, sender).await
+            }));
+        }
+        tracing::info!("GpioIn Initiated with {:?} lines", config.lines.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // resetting the state is harmless, but the lines cannot be driven
+        if !state.lines.is_empty() {
+            tracing::error!("GpioIn lines are inputs and cannot be changed by request");
+            return Err(ClientError::InvalidState.into())
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.lines.state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("GpioIn line task", self.task_handles.iter().any(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.chip, config.lines.iter().map(|line| &line.offset))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for GpioIn");
+        for task_handle in self.task_handles.drain(..) {
+            task_handle.abort();
+            // the task may already have stopped after reporting a fault
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("GpioIn task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl GpioIn {
+    /// Publishes every event on one line until its event stream closes
+    async fn watch(index: usize, mut events: AsyncLineEventHandle, active_low: bool,
+                   lines: Arc<Lines>, sender: Sender<Any>) {
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => return report_fault(&sender, e),
+            };
+            let level = (event.event_type() == EventType::RisingEdge) != active_low;
+            lines.levels[index].store(level, Ordering::Release);
+            *lines.changed.lock().unwrap() = (Some(index), level, event.timestamp());
+            tracing::debug!("GpioIn Line {:?} {:?}", lines.names[index], level);
+            let message = Self::pack_state(&lines.state());
+            if sender.send(message).await.is_err() {
+                break
+            }
+        }
+    }
+}
+
+/// Edges of the physical line that generate events
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Edge {
+    Rising,
+    Falling,
+    #[default]
+    Both,
+}
+
+impl Edge {
+    fn flags(&self) -> EventRequestFlags {
+        match self {
+            Edge::Rising => EventRequestFlags::RISING_EDGE,
+            Edge::Falling => EventRequestFlags::FALLING_EDGE,
+            Edge::Both => EventRequestFlags::BOTH_EDGES,
+        }
+    }
+}
+
+/// Bias applied to an input line, for sensors without external pull resistors
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Bias {
+    PullUp,
+    PullDown,
+    Disabled,
+}
+
+impl Bias {
+    /// gpio-cdev does not define the bias flags yet, so they are built from the
+    /// GPIOHANDLE_REQUEST_BIAS_* values in the kernel's uAPI
+    fn flags(&self) -> LineRequestFlags {
+        let bits = match self {
+            Bias::PullUp => 1 << 5,
+            Bias::PullDown => 1 << 6,
+            Bias::Disabled => 1 << 7,
+        };
+        // SAFETY: the flags are only passed to the kernel, which rejects ones it does not support
+        unsafe { LineRequestFlags::from_bits_unchecked(bits) }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LineConfig {
+    name: String, // used in state messages, e.g. "beam"
+    offset: u32,
+    #[serde(default)]
+    edge: Edge, // "rising", "falling" or "both" (default)
+    bias: Option<Bias>, // "pull_up", "pull_down" or "disabled"; left as is if unset
+    #[serde(default)]
+    active_low: bool, // the line reads 0 when active
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    lines: Vec<LineConfig>,
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
@@ -0,0 +1,180 @@
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Named output lines for one-off actuators such as shutters, relays and
+/// indicator LEDs. Clients set lines by name, or several at once through the
+/// groups in the config.
+pub struct GpioOut {
+    lines: Vec<OutputLine>,
+    groups: HashMap<String, Vec<usize>>, // indices into lines
+    state_sender: Sender<Any>,
+}
+
+struct OutputLine {
+    name: String,
+    handle: LineHandle,
+    initial: bool,
+}
+
+impl OutputLine {
+    fn level(&self) -> decide_protocol::Result<bool> {
+        let value = self.handle.get_value()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(value != 0)
+    }
+
+    fn set(&self, active: bool) -> decide_protocol::Result<()> {
+        self.handle.set_value(active as u8)
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+}
+
+#[component(state = proto::GpioOutState, params = proto::GpioOutParams, config = Config)]
+#[async_trait]
+impl Component for GpioOut {
+    fn new(_config: Self::Config, sender: Sender<Any>) -> Self {
+        GpioOut {
+            lines: Vec::new(),
+            groups: HashMap::new(),
+            state_sender: sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut groups = HashMap::new();
+        for (group, names) in config.groups.iter() {
+            let indices = names.iter()
+                .map(|name| config.lines.iter().position(|l| &l.name == name)
+                    .ok_or_else(|| {
+                        tracing::error!("GpioOut group {:?} refers to unknown line {:?}", group, name);
+                        DecideError::Component {
+                            source: std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                                        "unknown line in gpio_out group").into()
+                        }
+                    }))
+                .collect::<decide_protocol::Result<_>>()?;
+            groups.insert(group.clone(), indices);
+        }
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut lines = Vec::with_capacity(config.lines.len());
+        for line in config.lines.iter() {
+            let flags = if line.active_low {
+                LineRequestFlags::OUTPUT | LineRequestFlags::ACTIVE_LOW
+            } else {
+                LineRequestFlags::OUTPUT
+            };
+            let handle = chip.get_line(line.offset)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .request(flags, line.initial as u8, "gpio_out")
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+            lines.push(OutputLine { name: line.name.clone(), handle, initial: line.initial });
+        }
+        self.lines = lines;
+        self.groups = groups;
+        tracing::info!("GpioOut Initiated with {:?} lines", self.lines.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // resolve every name before touching any line; groups go first so that
+        // individual lines in the same message override them
+        let mut changes = Vec::new();
+        for (group, &active) in state.groups.iter() {
+            match self.groups.get(group) {
+                Some(indices) => changes.extend(indices.iter().map(|&i| (i, active))),
+                None => {
+                    tracing::error!("GpioOut group {:?} does not exist", group);
+                    return Err(ClientError::InvalidState.into())
+                }
+            }
+        }
+        for (name, &active) in state.lines.iter() {
+            match self.lines.iter().position(|l| &l.name == name) {
+                Some(index) => changes.push((index, active)),
+                None => {
+                    tracing::error!("GpioOut line {:?} does not exist", name);
+                    return Err(ClientError::InvalidState.into())
+                }
+            }
+        }
+        for (index, active) in changes {
+            self.lines[index].set(active)?;
+        }
+        let sender = self.state_sender.clone();
+        let state = self.get_state();
+        tokio::spawn(async move {
+            sender
+                .send(Self::pack_state(&state))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+            tracing::info!("GpioOut State Changed by Request");
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        Self::State {
+            // a line that cannot be read is left out, and reported by the health check
+            lines: self.lines.iter().filter_map(|l| Some((l.name.clone(), l.level().ok()?))).collect(),
+            groups: HashMap::new(),
+        }
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        match self.lines.iter().find_map(|line| line.handle.get_value().err().map(|e| (&line.name, e))) {
+            Some((name, e)) => ComponentHealth::Failed(format!("GpioOut line {:?} cannot be read: {}", name, e)),
+            None => ComponentHealth::Healthy,
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.chip, config.lines.iter().map(|line| &line.offset))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for GpioOut");
+        for line in self.lines.iter() {
+            if let Err(e) = line.set(line.initial) {
+                tracing::error!("GpioOut line {:?} could not be reset: {}", line.name, e);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LineConfig {
+    name: String, // used in state messages, e.g. "shutter"
+    offset: u32,
+    #[serde(default)]
+    active_low: bool, // the line is driven low when active
+    #[serde(default)]
+    initial: bool, // level at startup and after shutdown
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    lines: Vec<LineConfig>,
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>, // group name -> line names
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
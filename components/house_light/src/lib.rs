@@ -1,10 +1,10 @@
 use std::fs;
 use std::path::PathBuf;
-use std::sync::{Arc, atomic::{AtomicBool, AtomicU8, Ordering}};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering}};
 use std::time::{SystemTime, UNIX_EPOCH};
 use chrono::prelude::*;
 use async_trait::async_trait;
-use prost::Message;
+use gpio_cdev::{Chip, LineRequestFlags};
 use prost_types::Any;
 use serde::Deserialize;
 use tokio::{self,
@@ -12,7 +12,7 @@ use tokio::{self,
             time::{Duration, sleep}
 };
 use tokio::task::JoinHandle;
-use decide_protocol::{Component, error::DecideError};
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
 
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/_.rs"));
@@ -22,46 +22,62 @@ pub struct HouseLight {
     manual: Arc<AtomicBool>, // true if manual input of brightness
     dyson: Arc<AtomicBool>, // true if lights governed by sun position at lat/lon
     brightness: Arc<AtomicU8>,
+    max_brightness: Arc<AtomicU8>, // brightness at solar noon or halfway between fake dawn and dusk
     daytime: Arc<AtomicBool>,
-    interval: u64,
+    interval: Arc<AtomicU64>, // s between brightness updates in clock mode
+    output: Arc<Mutex<Option<Box<dyn Output>>>>, // claimed in init
     config: Config,
     state_sender: Sender<Any>,
     task_handle: Option<JoinHandle<()>>,
 }
 
+#[component(state = proto::HlState, params = proto::HlParams, config = Config)]
 #[async_trait]
 impl Component for HouseLight {
-    type State = proto::HlState;
-    type Params = proto::HlParams;
-    type Config = Config;
-    const STATE_TYPE_URL: &'static str = "type.googleapis.com/HlState";
-    const PARAMS_TYPE_URL: &'static str = "type.googleapis.com/HlParams";
-
     fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
         HouseLight {
             manual: Arc::new(AtomicBool::new(false)),
             dyson: Arc::new(AtomicBool::new(true)),
             brightness: Arc::new(AtomicU8::new(0)),
+            max_brightness: Arc::new(AtomicU8::new(config.max_brightness)),
             daytime: Arc::new(AtomicBool::new(false)),
-            interval: 300,
-            config: config,
+            interval: Arc::new(AtomicU64::new(300)),
+            output: Arc::new(Mutex::new(None)),
+            config,
             state_sender,
             task_handle: None,
         }
     }
 
-    async fn init(&mut self, config: Self::Config) {
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let output: Box<dyn Output> = match config.output {
+            OutputKind::Led => Box::new(LedOutput {
+                path: fs::canonicalize(PathBuf::from(config.device_path.clone()))
+                    .map_err(|e| DecideError::Component { source: e.into() })?,
+            }),
+            OutputKind::Pwm => Box::new(PwmOutput::new(PathBuf::from(config.device_path.clone()),
+                                                       config.period)?),
+            OutputKind::Gpio => {
+                let line = config.line.ok_or_else(|| {
+                    tracing::error!("House-Light gpio output needs a line offset");
+                    invalid_config("house light config is missing line")
+                })?;
+                Box::new(GpioOutput::new(&config.device_path, line, Duration::from_micros(config.period),
+                                         self.state_sender.clone())?)
+            }
+        };
+        *self.output.lock().unwrap() = Some(output);
 
         let manual = self.manual.clone();
         let fake_sun = self.dyson.clone();
         let brightness = self.brightness.clone();
+        let max_brightness = self.max_brightness.clone();
         let daytime = self.daytime.clone();
-        let interval = self.interval;
+        let interval = self.interval.clone();
+        let output = self.output.clone();
         let sender = self.state_sender.clone();
-        //let period = config.period;
 
         self.task_handle = Some(tokio::spawn(async move {
-            let dev_path = config.device_path.clone();
             loop {
                 if manual.load(Ordering::Acquire) {
                     let bt = brightness.load(Ordering::Acquire);
@@ -73,13 +89,10 @@ impl Component for HouseLight {
                         brightness: bt as i32,
                         daytime: dt
                     };
-                    let message = Any {
-                        value: state.encode_to_vec(),
-                        type_url: Self::STATE_TYPE_URL.into(),
-                    };
-                    sender.send(message).await
-                        .map_err(|e| DecideError::Component { source: e.into() })
-                        .unwrap();
+                    let message = Self::pack_state(&state);
+                    if sender.send(message).await.is_err() {
+                        break
+                    }
                 } else {
 
                     let dyson  = fake_sun.load(Ordering::Relaxed);
@@ -88,13 +101,14 @@ impl Component for HouseLight {
                                                              config.fake_dusk,
                                                              config.lat,
                                                              config.lon);
-                    let new_brightness = HouseLight::calc_brightness(altitude, config.max_brightness);
+                    let new_brightness = HouseLight::calc_brightness(altitude,
+                                                                     max_brightness.load(Ordering::Acquire));
                     let dt = new_brightness > 0;
                     daytime.store(dt, Ordering::Release);
 
-                    //let duty = HouseLight::get_duty(period, new_brightness);
-                    let path = fs::canonicalize(PathBuf::from(dev_path.clone())).unwrap();
-                    fs::write(path, new_brightness.to_string()).expect("Unable to write brightness");
+                    if let Err(e) = HouseLight::set_output(&output, new_brightness) {
+                        return report_fault(&sender, e)
+                    }
                     tracing::info!("House-Light Brightness Set to {:?} ", new_brightness);
                     brightness.store(new_brightness, Ordering::Relaxed);
 
@@ -104,32 +118,27 @@ impl Component for HouseLight {
                         brightness: new_brightness as i32,
                         daytime: dt
                     };
-                    let message = Any {
-                        value: state.encode_to_vec(),
-                        type_url: Self::STATE_TYPE_URL.into(),
-                    };
-                    sender.send(message).await
-                        .map_err(|e| DecideError::Component { source: e.into() })
-                        .unwrap();
+                    let message = Self::pack_state(&state);
+                    if sender.send(message).await.is_err() {
+                        break
+                    }
                 }
-                sleep(Duration::from_secs(interval)).await;
+                sleep(Duration::from_secs(interval.load(Ordering::Acquire))).await;
             }
         }));
         tracing::info!("House-Light Initiated");
+        Ok(())
     }
 
     fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
         let sender = self.state_sender.clone();
-        let dev_path = self.config.device_path.clone();
         self.manual.store(state.manual, Ordering::Relaxed);
         self.dyson.store(state.dyson, Ordering::Relaxed);
 
         // Change brightness immediately
         let new_brightness = if state.manual {
             let new_brightness = state.brightness as u8;
-            //let duty = HouseLight::get_duty(self.period, new_brightness);
-            fs::write(dev_path, new_brightness.to_string())
-                .expect("Unable to write brightness");
+            HouseLight::set_output(&self.output, new_brightness)?;
             tracing::info!("House-Light Brightness Set to {:?} Manually", new_brightness);
             self.brightness.store(new_brightness, Ordering::Relaxed);
 	    new_brightness
@@ -140,12 +149,11 @@ impl Component for HouseLight {
                                                      self.config.fake_dusk,
                                                      self.config.lat,
                                                      self.config.lon);
-            let new_brightness = HouseLight::calc_brightness(altitude, self.config.max_brightness);
+            let new_brightness = HouseLight::calc_brightness(altitude,
+                                                             self.max_brightness.load(Ordering::Acquire));
             let dt = new_brightness > 0;
             self.daytime.store(dt, Ordering::Release);
-            //let duty = HouseLight::get_duty(period, new_brightness);
-            let path = fs::canonicalize(PathBuf::from(dev_path.clone())).unwrap();
-            fs::write(path, new_brightness.to_string()).expect("Unable to write brightness");
+            HouseLight::set_output(&self.output, new_brightness)?;
             tracing::info!("House-Light Brightness Set to {:?} ", new_brightness);
             self.brightness.store(new_brightness, Ordering::Relaxed);
 	    new_brightness
@@ -158,22 +166,27 @@ impl Component for HouseLight {
             daytime: self.daytime.load(Ordering::Relaxed)
         };
         tokio::spawn(async move {
-            sender
-                .send(Any {
-                    type_url: String::from(Self::STATE_TYPE_URL),
-                    value: new_state.encode_to_vec(),
-                })
-                .await
-                .map_err(|e| DecideError::Component { source: e.into() })
-                .unwrap();
-            tracing::debug!("House-Light State Changed by Request");
+            // the controller may already be shutting down
+            if sender.send(Self::pack_state(&new_state)).await.is_ok() {
+                tracing::debug!("House-Light State Changed by Request");
+            }
         });
 
         Ok(())
     }
 
     fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
-        self.interval = params.clock_interval as u64;
+        if params.clock_interval < 0 || params.max_brightness.is_some_and(|b| b > u8::MAX as u32) {
+            tracing::error!("House-Light clock_interval must not be negative and max_brightness at most 255");
+            return Err(ClientError::InvalidParams.into());
+        }
+        // both take effect at the next update in clock mode
+        if params.clock_interval > 0 {
+            self.interval.store(params.clock_interval as u64, Ordering::Release);
+        }
+        if let Some(max_brightness) = params.max_brightness {
+            self.max_brightness.store(max_brightness as u8, Ordering::Release);
+        }
         Ok(())
     }
 
@@ -188,24 +201,41 @@ impl Component for HouseLight {
 
     fn get_parameters(&self) -> Self::Params {
         Self::Params {
-            clock_interval: self.interval as i64
+            clock_interval: self.interval.load(Ordering::Acquire) as i64,
+            max_brightness: Some(self.max_brightness.load(Ordering::Acquire) as u32),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("House-Light task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        match (config.output, config.line) {
+            (OutputKind::Gpio, Some(line)) => vec![Resource::line(&config.device_path, line)],
+            (OutputKind::Gpio, None) => Vec::new(),
+            _ => vec![Resource::Device(config.device_path.clone())],
         }
     }
 
     async fn shutdown(&mut self) {
         if let Some(task_handle) = self.task_handle.take() {
             task_handle.abort();
-            task_handle.await
-                .map_err(|e| DecideError::Component { source: e.into() })
-                .unwrap_err();
+            // the task may already have stopped after reporting a fault
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("House Light task panicked: {}", e);
+                }
+            }
         }
     }
 }
 
 impl HouseLight {
-    //fn get_duty(period: u32, brightness: u8) -> String {
-    //    (period * (brightness as u32)/100).to_string()
-    //}
+    /// Sets the brightness of the output claimed in init
+    fn set_output(output: &Mutex<Option<Box<dyn Output>>>, brightness: u8) -> decide_protocol::Result<()> {
+        output.lock().unwrap().as_mut().map_or(Ok(()), |output| output.set(brightness))
+    }
 
     fn calc_brightness(altitude: f64, max_brightness: u8) -> u8 {
         let x = (altitude.sin() * (max_brightness as f64)).round() as u8;
@@ -216,7 +246,7 @@ impl HouseLight {
         if dyson {
             let now = chrono::offset::Local::now();
             tracing::debug!("Fake Clock specified, time is {:?}", now);
-            let now = (now.hour() + (now.minute() / 60) + (now.second() / 3600)) as f64;
+            let now = now.hour() as f64 + now.minute() as f64 / 60.0 + now.second() as f64 / 3600.0;
             let x: f64 = (now + 24.0 - dawn) % 24.0;
             let y: f64 = (dusk + 24.0 - dawn) % 24.0;
             (x / y) * std::f64::consts::PI
@@ -228,13 +258,136 @@ impl HouseLight {
     }
 }
 
+/// Drives the light panel at a brightness from 0 (off) to 255 (full)
+trait Output: Send {
+    fn set(&mut self, brightness: u8) -> decide_protocol::Result<()>;
+}
+
+/// Error for a config the output cannot be set up from
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
+fn write(path: &std::path::Path, contents: impl AsRef<[u8]>) -> decide_protocol::Result<()> {
+    fs::write(path, contents).map_err(|e| DecideError::Component { source: e.into() })
+}
+
+/// LED class device, which takes the brightness directly
+struct LedOutput {
+    path: PathBuf, // brightness file
+}
+
+impl Output for LedOutput {
+    fn set(&mut self, brightness: u8) -> decide_protocol::Result<()> {
+        write(&self.path, brightness.to_string())
+    }
+}
+
+/// sysfs PWM channel
+struct PwmOutput {
+    path: PathBuf, // channel directory
+    period: u64, // ns
+}
+
+impl PwmOutput {
+    /// Exports the channel if needed and enables it with the lights off
+    fn new(path: PathBuf, period: u64) -> decide_protocol::Result<Self> {
+        if !path.exists() {
+            let channel = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("pwm"))
+                .ok_or_else(|| invalid_config("House-Light PWM path must end in pwm<channel>"))?;
+            write(&path.with_file_name("export"), channel)?;
+        }
+        let period = period * 1000;
+        write(&path.join("duty_cycle"), "0")?;
+        write(&path.join("period"), period.to_string())?;
+        write(&path.join("enable"), "1")?;
+        Ok(PwmOutput { path, period })
+    }
+}
+
+impl Output for PwmOutput {
+    fn set(&mut self, brightness: u8) -> decide_protocol::Result<()> {
+        let duty = self.period * brightness as u64 / u8::MAX as u64;
+        write(&self.path.join("duty_cycle"), duty.to_string())
+    }
+}
+
+/// Software PWM on a GPIO line, for panels that are not wired to a PWM channel.
+/// The line is toggled by a dedicated thread, which stops and turns the line off
+/// when the output is dropped, or reports a fault if the line cannot be set.
+struct GpioOutput {
+    level: Arc<AtomicU8>,
+}
+
+impl GpioOutput {
+    fn new(chip: &str, offset: u32, period: Duration, faults: Sender<Any>) -> decide_protocol::Result<Self> {
+        let line = Chip::new(chip)
+            .and_then(|mut chip| chip.get_line(offset))
+            .and_then(|line| line.request(LineRequestFlags::OUTPUT, 0, "decide-rs"))
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let level = Arc::new(AtomicU8::new(0));
+        let thread_level = level.clone();
+        std::thread::spawn(move || {
+            let toggle = || -> Result<(), gpio_cdev::Error> {
+                while Arc::strong_count(&thread_level) > 1 {
+                    let on = period * thread_level.load(Ordering::Acquire) as u32 / u8::MAX as u32;
+                    if !on.is_zero() {
+                        line.set_value(1)?;
+                        std::thread::sleep(on);
+                    }
+                    if on < period {
+                        line.set_value(0)?;
+                        std::thread::sleep(period - on);
+                    }
+                }
+                line.set_value(0)
+            };
+            if let Err(e) = toggle() {
+                report_fault(&faults, e);
+            }
+        });
+        Ok(GpioOutput { level })
+    }
+}
+
+impl Output for GpioOutput {
+    fn set(&mut self, brightness: u8) -> decide_protocol::Result<()> {
+        self.level.store(brightness, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    /// device_path is the brightness file of an LED class device
+    #[default]
+    Led,
+    /// device_path is a sysfs PWM channel, e.g. /sys/class/pwm/pwmchip0/pwm0
+    Pwm,
+    /// device_path is a gpiochip, and the panel is switched on `line` by software PWM
+    Gpio,
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     device_path: String, // /sys/class/leds/starboard::lights/brightness
+    #[serde(default)]
+    output: OutputKind, // "led" (default), "pwm", or "gpio"
+    #[serde(default = "Config::default_period")]
+    period: u64, // us; PWM period of the pwm and gpio outputs
+    line: Option<u32>, // line offset for the gpio output
     fake_dawn: f64,
     fake_dusk: f64,
     lat: f64,
     lon: f64,
-    //period: u32,
-    max_brightness: u8 //255
+    max_brightness: u8 //255, initial value of the max_brightness parameter
+}
+
+impl Config {
+    fn default_period() -> u64 {
+        10_000
+    }
 }
@@ -0,0 +1,253 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use async_trait::async_trait;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_hal::{linux, Spi};
+use decide_protocol::{component, Component, ComponentHealth, Resource, error::{ClientError, DecideError}};
+use proto::Pattern;
+
+/// Addressable LED strip (WS2812/NeoPixel) driven from the MOSI pin of a SPI
+/// bus. Clients set the colors of individual pixels or select an animated
+/// pattern, which runs on its own thread.
+pub struct LedStrip {
+    state: proto::StripState,
+    params: proto::StripParams,
+    count: usize,
+    state_sender: Sender<Any>,
+    show_sender: Option<mpsc::Sender<(proto::StripState, proto::StripParams)>>,
+    animator: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::StripState, params = proto::StripParams, config = Config)]
+#[async_trait]
+impl Component for LedStrip {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        LedStrip {
+            state: proto::StripState::default(),
+            params: proto::StripParams {
+                brightness: config.brightness,
+                rate: config.rate,
+            },
+            count: config.count,
+            state_sender,
+            show_sender: None,
+            animator: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut spi: Box<dyn Spi> = Box::new(linux::spi(&config.device, SPI_HZ)
+            .map_err(|e| DecideError::Component { source: e })?);
+        let (show_sender, shows) = mpsc::channel::<(proto::StripState, proto::StripParams)>();
+        let mut show = (self.state.clone(), self.params.clone());
+        let count = self.count;
+        self.animator = Some(thread::spawn(move || {
+            let mut step = 0;
+            loop {
+                let (state, params) = &show;
+                let frame = encode(&render(state, count, step), params.brightness as u8);
+                spi.write(&frame)
+                    .map_err(|e| DecideError::Component { source: e }).unwrap();
+                let next = match state.pattern() {
+                    Pattern::Blink | Pattern::Chase => {
+                        shows.recv_timeout(Duration::from_secs_f32(1.0 / params.rate))
+                    }
+                    // static patterns are only redrawn when they change
+                    _ => shows.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                match next {
+                    Ok(next) => {
+                        if next.0 != show.0 {
+                            step = 0;
+                        }
+                        show = next;
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => step += 1,
+                    // the component was shut down
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            spi.write(&encode(&vec![0; count], 0))
+                .map_err(|e| DecideError::Component { source: e }).unwrap();
+        }));
+        self.show_sender = Some(show_sender);
+        tracing::info!("LED-Strip Initiated with {:?} pixels", self.count);
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        if Pattern::from_i32(state.pattern).is_none() {
+            tracing::error!("LED-Strip pattern {:?} is not valid", state.pattern);
+            return Err(ClientError::InvalidState.into())
+        }
+        if state.pixels.len() > self.count {
+            tracing::error!("LED-Strip has {:?} pixels but {:?} colors were sent", self.count, state.pixels.len());
+            return Err(ClientError::InvalidState.into())
+        }
+        self.state = state;
+        self.show();
+        let sender = self.state_sender.clone();
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            sender
+                .send(Self::pack_state(&state))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+            tracing::info!("LED-Strip State Changed by Request");
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.brightness > u8::MAX as u32 {
+            tracing::error!("LED-Strip brightness must be between 0 and 255");
+            return Err(ClientError::InvalidParams.into())
+        }
+        if !params.rate.is_finite() || params.rate <= 0.0 {
+            tracing::error!("LED-Strip rate must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        self.params = params;
+        self.show();
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("LED Strip animator thread", self.animator.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![Resource::Device(config.device.clone())]
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for LED-Strip");
+        // closing the channel stops the animator, which blanks the strip
+        self.show_sender.take();
+        if let Some(animator) = self.animator.take() {
+            tokio::task::spawn_blocking(move || animator.join())
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
+
+impl LedStrip {
+    fn show(&self) {
+        if let Some(show_sender) = &self.show_sender {
+            show_sender.send((self.state.clone(), self.params.clone()))
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        }
+    }
+}
+
+/// Each WS2812 bit is sent as three SPI bits, 110 for a one and 100 for a zero,
+/// so the bus runs at three times the 800 kHz data rate of the strip
+const SPI_HZ: u32 = 2_400_000;
+/// Holding the line low for 300 us latches the colors, even on WS2812B strips
+const RESET_BYTES: usize = 90;
+
+/// Returns the color (0xRRGGBB) of each pixel at a step of the pattern
+fn render(state: &proto::StripState, count: usize, step: usize) -> Vec<u32> {
+    (0..count)
+        .map(|i| match state.pattern() {
+            Pattern::Off => 0,
+            Pattern::Pixels => state.pixels.get(i).copied().unwrap_or(0),
+            Pattern::Solid => state.color,
+            Pattern::Blink if step.is_multiple_of(2) => state.color,
+            Pattern::Blink => 0,
+            Pattern::Chase if i == step % count => state.color,
+            Pattern::Chase => 0,
+        })
+        .collect()
+}
+
+/// Encodes colors as SPI data in the strip's GRB order, followed by the reset
+fn encode(colors: &[u32], brightness: u8) -> Vec<u8> {
+    let mut data = Vec::with_capacity(colors.len() * 9 + RESET_BYTES);
+    for &color in colors {
+        let [_, r, g, b] = color.to_be_bytes();
+        for channel in [g, r, b] {
+            let level = (channel as u32 * brightness as u32 / u8::MAX as u32) as u8;
+            let bits = (0..8).rev().fold(0u32, |bits, i| {
+                (bits << 3) | if level & (1 << i) != 0 { 0b110 } else { 0b100 }
+            });
+            data.extend_from_slice(&bits.to_be_bytes()[1..]);
+        }
+    }
+    data.resize(data.len() + RESET_BYTES, 0);
+    data
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_device")]
+    device: String,
+    count: usize, // pixels on the strip
+    #[serde(default = "Config::default_brightness")]
+    brightness: u32, // initial value of the brightness parameter
+    #[serde(default = "Config::default_rate")]
+    rate: f32, // initial value of the rate parameter
+}
+
+impl Config {
+    fn default_device() -> String {
+        String::from("/dev/spidev0.0")
+    }
+
+    fn default_brightness() -> u32 {
+        255
+    }
+
+    fn default_rate() -> f32 {
+        2.0
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_grb_with_three_bits_per_bit() {
+        let data = encode(&[0xFF0000], 255);
+        assert_eq!(&data[..9], &[0x92, 0x49, 0x24, 0xDB, 0x6D, 0xB6, 0x92, 0x49, 0x24]);
+        assert_eq!(data.len(), 9 + RESET_BYTES);
+        assert!(data[9..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn brightness_scales_colors() {
+        assert_eq!(encode(&[0x0000FF], 0), encode(&[0], 255));
+        assert_eq!(encode(&[0x000080], 255), encode(&[0x0000FF], 128));
+    }
+
+    #[test]
+    fn chase_moves_one_pixel() {
+        let state = proto::StripState {
+            pattern: Pattern::Chase as i32,
+            pixels: Vec::new(),
+            color: 0x00FF00,
+        };
+        assert_eq!(render(&state, 3, 0), vec![0x00FF00, 0, 0]);
+        assert_eq!(render(&state, 3, 4), vec![0, 0x00FF00, 0]);
+    }
+}
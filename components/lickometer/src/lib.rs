@@ -0,0 +1,372 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+                EventType
+};
+use futures::stream::StreamExt;
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use nix::time::{clock_gettime, ClockId};
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource,
+                   error::DecideError};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::thread;
+use std::time::Duration;
+use tokio::{self, task::JoinHandle};
+
+/// Detects licks on one or more spouts, either as contact closures on GPIO
+/// lines or as touches on an MPR121 capacitive sensor. Every onset and offset is
+/// published with its time, along with the number of licks this session.
+pub struct Lickometer {
+    spouts: Arc<Spouts>,
+    state_sender: Sender<Any>,
+    task_handles: Vec<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+    poller: Option<thread::JoinHandle<()>>,
+}
+
+/// State shared between the component and the tasks watching the spouts
+struct Spouts {
+    names: Vec<String>,
+    licking: Vec<AtomicBool>,
+    counts: Vec<AtomicU64>,
+    last: Mutex<(Option<usize>, bool, u64)>, // spout, onset and time of the last event
+}
+
+impl Spouts {
+    fn state(&self) -> proto::LickState {
+        let (spout, onset, timestamp_ns) = *self.last.lock().unwrap();
+        proto::LickState {
+            licking: self.names.iter().cloned()
+                .zip(self.licking.iter().map(|l| l.load(Ordering::Acquire)))
+                .collect(),
+            counts: self.names.iter().cloned()
+                .zip(self.counts.iter().map(|c| c.load(Ordering::Acquire)))
+                .collect(),
+            spout: spout.map(|i| self.names[i].clone()).unwrap_or_default(),
+            onset,
+            timestamp_ns,
+        }
+    }
+
+    /// Records an onset or offset and returns the message announcing it, or None
+    /// if the spout was already in that state
+    fn record(&self, index: usize, onset: bool, timestamp_ns: u64) -> Option<Any> {
+        if self.licking[index].swap(onset, Ordering::AcqRel) == onset {
+            return None
+        }
+        if onset {
+            self.counts[index].fetch_add(1, Ordering::AcqRel);
+        }
+        *self.last.lock().unwrap() = (Some(index), onset, timestamp_ns);
+        tracing::debug!("Lickometer {:?} {}", self.names[index], if onset { "Onset" } else { "Offset" });
+        Some(Lickometer::pack_state(&self.state()))
+    }
+}
+
+#[component(state = proto::LickState, params = proto::LickParams, config = Config)]
+#[async_trait]
+impl Component for Lickometer {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        let names: Vec<String> = match &config {
+            Config::Contact { spouts, .. } => spouts.iter().map(|s| s.name.clone()).collect(),
+            Config::Mpr121 { spouts, .. } => spouts.iter().map(|s| s.name.clone()).collect(),
+        };
+        let spouts = Spouts {
+            licking: names.iter().map(|_| AtomicBool::new(false)).collect(),
+            counts: names.iter().map(|_| AtomicU64::new(0)).collect(),
+            names,
+            last: Mutex::new((None, false, 0)),
+        };
+        Lickometer {
+            spouts: Arc::new(spouts),
+            state_sender: sender,
+            task_handles: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+            poller: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        match config {
+            Config::Contact { chip, spouts, active_low } => {
+                let mut chip = Chip::new(&chip)
+                    .map_err(|e| DecideError::Component { source: e.into() })?;
+                for (index, spout) in spouts.iter().enumerate() {
+                    let mut events = AsyncLineEventHandle::new(
+                        chip.get_line(spout.offset)
+                            .map_err(|e| DecideError::Component { source: e.into() })?
+                            .events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "lickometer")
+                            .map_err(|e| DecideError::Component { source: e.into() })?
+                    ).map_err(|e| DecideError::Component { source: e.into() })?;
+                    let spouts = self.spouts.clone();
+                    let sender = self.state_sender.clone();
+                    self.task_handles.push(tokio::spawn(async move {
+                        while let Some(event) = events.next().await {
+                            let event = event.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                            let onset = (event.event_type() == EventType::RisingEdge) != active_low;
+                            if let Some(message) = spouts.record(index, onset, event.timestamp()) {
+                                if sender.send(message).await.is_err() {
+                                    break
+                                }
+                            }
+                        }
+                    }));
+                }
+            }
+            Config::Mpr121 { bus, address, spouts, touch_threshold, release_threshold, poll } => {
+                let electrodes: Vec<u8> = spouts.iter().map(|s| s.electrode).collect();
+                let mut sensor = Mpr121::new(&bus, address, &electrodes, touch_threshold, release_threshold)
+                    .map_err(|e| DecideError::Component { source: e.into() })?;
+                let spouts = self.spouts.clone();
+                let sender = self.state_sender.clone();
+                let stop = self.stop.clone();
+                let poll = Duration::from_millis(poll);
+                self.poller = Some(thread::spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        let touched = match sensor.touched() {
+                            Ok(touched) => touched,
+                            Err(e) => {
+                                tracing::error!("Lickometer MPR121 Read Failed: {}", e);
+                                thread::sleep(poll);
+                                continue
+                            }
+                        };
+                        let timestamp_ns = monotonic_ns();
+                        for (index, &electrode) in electrodes.iter().enumerate() {
+                            let onset = touched & (1 << electrode) != 0;
+                            if let Some(message) = spouts.record(index, onset, timestamp_ns) {
+                                sender.blocking_send(message)
+                                    .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                            }
+                        }
+                        thread::sleep(poll);
+                    }
+                }));
+            }
+        }
+        tracing::info!("Lickometer Initiated with {:?} spouts", self.spouts.names.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        for count in self.spouts.counts.iter() {
+            count.store(0, Ordering::Release);
+        }
+        let state = self.spouts.state();
+        let sender = self.state_sender.clone();
+        tokio::spawn(async move {
+            sender
+                .send(Self::pack_state(&state))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+            tracing::info!("Lickometer Session Started by Request");
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.spouts.state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        let poller = self.poller.as_ref().is_some_and(|h| h.is_finished());
+        ComponentHealth::running("Lickometer spout task", poller || self.task_handles.iter().any(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        match config {
+            Config::Contact { chip, spouts, .. } => Resource::lines(chip, spouts.iter().map(|spout| &spout.offset)),
+            Config::Mpr121 { .. } => Vec::new(),
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Lickometer");
+        for task_handle in self.task_handles.drain(..) {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Lickometer task panicked: {}", e);
+                }
+            }
+        }
+        self.stop.store(true, Ordering::Release);
+        if let Some(poller) = self.poller.take() {
+            tokio::task::spawn_blocking(move || poller.join())
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
+
+/// NXP MPR121 capacitive touch sensor
+struct Mpr121 {
+    dev: LinuxI2CDevice,
+}
+
+impl Mpr121 {
+    const TOUCH_STATUS: u8 = 0x00;
+    const TOUCH_THRESHOLD: u8 = 0x41; // release threshold follows at 0x42, then the next electrode
+    const ECR: u8 = 0x5E;
+    const SOFT_RESET: u8 = 0x80;
+
+    fn new(bus: &str, address: u16, electrodes: &[u8], touch: u8, release: u8) -> Result<Self, LinuxI2CError> {
+        let mut dev = LinuxI2CDevice::new(bus, address)?;
+        dev.write(&[Mpr121::SOFT_RESET, 0x63])?;
+        thread::sleep(Duration::from_millis(1));
+        // registers can only be written in stop mode
+        dev.write(&[Mpr121::ECR, 0x00])?;
+        for electrode in 0..12 {
+            dev.write(&[Mpr121::TOUCH_THRESHOLD + 2 * electrode, touch])?;
+            dev.write(&[Mpr121::TOUCH_THRESHOLD + 2 * electrode + 1, release])?;
+        }
+        // baseline filtering from the NXP quick start guide (AN3944)
+        for &(register, value) in &[
+            (0x2B, 0x01), (0x2C, 0x01), (0x2D, 0x0E), (0x2E, 0x00),
+            (0x2F, 0x01), (0x30, 0x05), (0x31, 0x01), (0x32, 0x00),
+            (0x33, 0x00), (0x34, 0x00), (0x35, 0x00),
+            (0x5B, 0x00), (0x5C, 0x10), (0x5D, 0x20),
+        ] {
+            dev.write(&[register, value])?;
+        }
+        // electrodes are enabled as a block from ELE0 up to the highest one in use
+        let enabled = electrodes.iter().max().map_or(0, |&e| e + 1);
+        dev.write(&[Mpr121::ECR, 0x80 | enabled])?;
+        Ok(Mpr121 { dev })
+    }
+
+    /// Returns a bit mask of the touched electrodes
+    fn touched(&mut self) -> Result<u16, LinuxI2CError> {
+        let mut buf = [0u8; 2];
+        self.dev.write(&[Mpr121::TOUCH_STATUS])?;
+        self.dev.read(&mut buf)?;
+        Ok(u16::from_le_bytes(buf) & 0x0FFF)
+    }
+}
+
+/// CLOCK_MONOTONIC in ns, to match the kernel timestamps of contact events
+fn monotonic_ns() -> u64 {
+    let ts = clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+    ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64
+}
+
+#[derive(Deserialize)]
+pub struct ContactSpout {
+    name: String, // used in state messages, e.g. "left"
+    offset: u32,
+}
+
+#[derive(Deserialize)]
+pub struct CapacitiveSpout {
+    name: String,
+    electrode: u8, // 0-11
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "sensor", rename_all = "snake_case")]
+pub enum Config {
+    /// contact closure circuits on GPIO inputs
+    Contact {
+        chip: String,
+        spouts: Vec<ContactSpout>,
+        #[serde(default)]
+        active_low: bool, // the line reads 0 during contact
+    },
+    /// electrodes of an MPR121 on an I2C bus
+    Mpr121 {
+        #[serde(default = "Config::default_bus")]
+        bus: String,
+        #[serde(default = "Config::default_address")]
+        address: u16,
+        spouts: Vec<CapacitiveSpout>,
+        #[serde(default = "Config::default_touch_threshold")]
+        touch_threshold: u8,
+        #[serde(default = "Config::default_release_threshold")]
+        release_threshold: u8,
+        #[serde(default = "Config::default_poll")]
+        poll: u64, // ms between reads of the touch status
+    },
+}
+
+impl Config {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_address() -> u16 {
+        0x5A
+    }
+
+    fn default_touch_threshold() -> u8 {
+        12
+    }
+
+    fn default_release_threshold() -> u8 {
+        6
+    }
+
+    fn default_poll() -> u64 {
+        5
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spouts() -> Spouts {
+        let names = vec![String::from("left"), String::from("right")];
+        Spouts {
+            licking: names.iter().map(|_| AtomicBool::new(false)).collect(),
+            counts: names.iter().map(|_| AtomicU64::new(0)).collect(),
+            names,
+            last: Mutex::new((None, false, 0)),
+        }
+    }
+
+    #[test]
+    fn counts_onsets_only() {
+        let spouts = spouts();
+        assert!(spouts.record(1, true, 10).is_some());
+        assert!(spouts.record(1, false, 20).is_some());
+        assert!(spouts.record(1, true, 30).is_some());
+        let state = spouts.state();
+        assert_eq!(state.counts["right"], 2);
+        assert_eq!(state.counts["left"], 0);
+        assert_eq!((state.spout.as_str(), state.onset, state.timestamp_ns), ("right", true, 30));
+    }
+
+    #[test]
+    fn ignores_repeated_levels() {
+        let spouts = spouts();
+        assert!(spouts.record(0, false, 10).is_none());
+        assert!(spouts.record(0, true, 20).is_some());
+        assert!(spouts.record(0, true, 30).is_none());
+        assert_eq!(spouts.state().counts["left"], 1);
+    }
+}
@@ -0,0 +1,309 @@
+use std::sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}};
+use std::thread;
+use std::time::Duration;
+use async_trait::async_trait;
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, task::JoinHandle, time::sleep};
+use decide_protocol::{component, Component, ComponentHealth, error::{ClientError, DecideError}};
+use proto::Expect;
+
+/// Measures the light in a box from an I2C sensor, so that the house light
+/// schedule can be checked against what actually happened. Clients say whether
+/// the lights should be on, and the component flags readings that disagree.
+pub struct LightSensor {
+    state: Arc<Mutex<proto::LightState>>,
+    interval: Arc<AtomicU32>, // ms
+    threshold: Arc<Mutex<f64>>, // lux
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+#[component(state = proto::LightState, params = proto::LightParams, config = Config)]
+#[async_trait]
+impl Component for LightSensor {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        LightSensor {
+            state: Arc::new(Mutex::new(proto::LightState::default())),
+            interval: Arc::new(AtomicU32::new(config.interval)),
+            threshold: Arc::new(Mutex::new(config.threshold)),
+            state_sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let dev = LinuxI2CDevice::new(&config.bus, config.address)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let sensor: Box<dyn Sensor> = match config.model {
+            Model::Bh1750 => Box::new(Bh1750::new(dev)
+                .map_err(|e| DecideError::Component { source: e.into() })?),
+            Model::Tcs34725 => Box::new(Tcs34725::new(dev)
+                .map_err(|e| DecideError::Component { source: e.into() })?),
+        };
+        let sensor = Arc::new(Mutex::new(sensor));
+        let state = self.state.clone();
+        let interval = self.interval.clone();
+        let threshold = self.threshold.clone();
+        let sender = self.state_sender.clone();
+
+        self.task_handle = Some(tokio::spawn(async move {
+            loop {
+                let sensor = sensor.clone();
+                // I2C transfers and integration times block, so keep them off the runtime
+                let reading = tokio::task::spawn_blocking(move || sensor.lock().unwrap().read())
+                    .await
+                    .map_err(|e| DecideError::Component { source: e.into() })
+                    .unwrap();
+                match reading {
+                    Ok(reading) => {
+                        let message = {
+                            let mut state = state.lock().unwrap();
+                            let (red, green, blue) = reading.rgb.unwrap_or_default();
+                            let fault = fault(state.expect(), reading.lux, *threshold.lock().unwrap());
+                            if fault && !state.fault {
+                                tracing::warn!("Light-Sensor Reads {:.1} lux but Expected {:?}", reading.lux, state.expect());
+                            }
+                            state.lux = reading.lux;
+                            state.red = red;
+                            state.green = green;
+                            state.blue = blue;
+                            state.fault = fault;
+                            Self::pack_state(&state)
+                        };
+                        sender.send(message).await
+                            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                    }
+                    Err(e) => tracing::error!("Light-Sensor Read Failed: {}", e),
+                }
+                sleep(Duration::from_millis(interval.load(Ordering::Acquire) as u64)).await;
+            }
+        }));
+        tracing::info!("Light-Sensor Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let expect = Expect::from_i32(state.expect).ok_or_else(|| {
+            tracing::error!("Light-Sensor expect {:?} is not valid", state.expect);
+            DecideError::from(ClientError::InvalidState)
+        })?;
+        // the next reading is checked against the new expectation
+        let mut current = self.state.lock().unwrap();
+        current.set_expect(expect);
+        current.fault = false;
+        tracing::info!("Light-Sensor Expecting {:?}", expect);
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval_ms == 0 {
+            tracing::error!("Light-Sensor interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // takes effect after the current wait
+        self.interval.store(params.interval_ms, Ordering::Release);
+        *self.threshold.lock().unwrap() = params.threshold_lux;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            interval_ms: self.interval.load(Ordering::Acquire),
+            threshold_lux: *self.threshold.lock().unwrap(),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Light Sensor task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Light-Sensor");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Light-Sensor task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a reading disagrees with what the lights should be doing
+fn fault(expect: Expect, lux: f64, threshold: f64) -> bool {
+    match expect {
+        Expect::Any => false,
+        Expect::Light => lux < threshold,
+        Expect::Dark => lux >= threshold,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reading {
+    lux: f64,
+    rgb: Option<(u32, u32, u32)>, // raw counts
+}
+
+type ReadResult = Result<Reading, Box<dyn std::error::Error + Send + Sync>>;
+
+trait Sensor: Send {
+    fn read(&mut self) -> ReadResult;
+}
+
+/// ROHM BH1750 ambient light sensor, read in one-time high resolution mode
+struct Bh1750 {
+    dev: LinuxI2CDevice,
+}
+
+impl Bh1750 {
+    const POWER_ON: u8 = 0x01;
+    const ONE_TIME_HIGH_RES: u8 = 0x20;
+
+    fn new(mut dev: LinuxI2CDevice) -> Result<Self, LinuxI2CError> {
+        dev.write(&[Bh1750::POWER_ON])?;
+        Ok(Bh1750 { dev })
+    }
+}
+
+impl Sensor for Bh1750 {
+    fn read(&mut self) -> ReadResult {
+        // the sensor powers down after each one-time measurement
+        self.dev.write(&[Bh1750::POWER_ON])?;
+        self.dev.write(&[Bh1750::ONE_TIME_HIGH_RES])?;
+        thread::sleep(Duration::from_millis(180));
+        let mut buf = [0u8; 2];
+        self.dev.read(&mut buf)?;
+        Ok(Reading {
+            lux: u16::from_be_bytes(buf) as f64 / 1.2,
+            rgb: None,
+        })
+    }
+}
+
+/// AMS TCS34725 color sensor, integrating continuously
+struct Tcs34725 {
+    dev: LinuxI2CDevice,
+}
+
+impl Tcs34725 {
+    const COMMAND: u8 = 0x80;
+    const AUTO_INCREMENT: u8 = 0x20;
+    const ENABLE: u8 = 0x00;
+    const ATIME: u8 = 0x01;
+    const CONTROL: u8 = 0x0F;
+    const ID: u8 = 0x12;
+    const CDATAL: u8 = 0x14;
+    const CYCLES: u8 = 42; // integration cycles of 2.4 ms
+    const GAIN: f64 = 4.0;
+
+    fn new(mut dev: LinuxI2CDevice) -> Result<Self, LinuxI2CError> {
+        let mut id = [0u8; 1];
+        dev.write(&[Tcs34725::COMMAND | Tcs34725::ID])?;
+        dev.read(&mut id)?;
+        if id[0] != 0x44 && id[0] != 0x4D {
+            tracing::warn!("Light-Sensor Chip ID {:#x} is not a TCS34725", id[0]);
+        }
+        dev.write(&[Tcs34725::COMMAND | Tcs34725::ATIME, (256 - Tcs34725::CYCLES as u16) as u8])?;
+        dev.write(&[Tcs34725::COMMAND | Tcs34725::CONTROL, 0x01])?; // 4x gain
+        // the oscillator needs 2.4 ms after power on before the ADCs are enabled
+        dev.write(&[Tcs34725::COMMAND | Tcs34725::ENABLE, 0x01])?;
+        thread::sleep(Duration::from_millis(3));
+        dev.write(&[Tcs34725::COMMAND | Tcs34725::ENABLE, 0x03])?;
+        Ok(Tcs34725 { dev })
+    }
+}
+
+impl Sensor for Tcs34725 {
+    fn read(&mut self) -> ReadResult {
+        let mut buf = [0u8; 8];
+        self.dev.write(&[Tcs34725::COMMAND | Tcs34725::AUTO_INCREMENT | Tcs34725::CDATAL])?;
+        self.dev.read(&mut buf)?;
+        let channel = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]) as u32;
+        let (clear, red, green, blue) = (channel(0), channel(2), channel(4), channel(6));
+        let integration = Tcs34725::CYCLES as f64 * 2.4;
+        Ok(Reading {
+            lux: tcs34725_lux(clear, red, green, blue, integration, Tcs34725::GAIN),
+            rgb: Some((red, green, blue)),
+        })
+    }
+}
+
+/// Lux from the TCS34725 channels, following the AMS DN40 application note
+/// with its coefficients for open air (no glass over the sensor)
+fn tcs34725_lux(clear: u32, red: u32, green: u32, blue: u32, integration_ms: f64, gain: f64) -> f64 {
+    let (c, r, g, b) = (clear as f64, red as f64, green as f64, blue as f64);
+    let ir = ((r + g + b - c) / 2.0).max(0.0);
+    let g2 = 0.136 * (r - ir) + (g - ir) - 0.444 * (b - ir);
+    let counts_per_lux = integration_ms * gain / 310.0;
+    (g2 / counts_per_lux).max(0.0)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Model {
+    Bh1750,
+    Tcs34725,
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_bus")]
+    bus: String,
+    address: u16, // 0x23 or 0x5C for the BH1750, 0x29 for the TCS34725
+    model: Model, // "bh1750" or "tcs34725"
+    #[serde(default = "Config::default_interval")]
+    interval: u32, // ms, initial value of the interval_ms parameter
+    #[serde(default = "Config::default_threshold")]
+    threshold: f64, // lux, initial value of the threshold_lux parameter
+}
+
+impl Config {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_interval() -> u32 {
+        10000
+    }
+
+    fn default_threshold() -> f64 {
+        10.0
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcs34725_removes_infrared() {
+        // equal IR in every channel cancels out of the green weighting
+        let lux = tcs34725_lux(1000, 400, 500, 300, 100.8, 4.0);
+        let (ir, counts_per_lux) = (100.0, 100.8 * 4.0 / 310.0);
+        let expected = (0.136 * (400.0 - ir) + (500.0 - ir) - 0.444 * (300.0 - ir)) / counts_per_lux;
+        assert!((lux - expected).abs() < 1e-9);
+        assert_eq!(tcs34725_lux(0, 0, 0, 0, 100.8, 4.0), 0.0);
+    }
+
+    #[test]
+    fn faults_follow_expectation() {
+        assert!(!fault(Expect::Any, 0.0, 10.0));
+        assert!(fault(Expect::Light, 5.0, 10.0));
+        assert!(!fault(Expect::Light, 200.0, 10.0));
+        assert!(fault(Expect::Dark, 200.0, 10.0));
+        assert!(!fault(Expect::Dark, 0.5, 10.0));
+    }
+}
@@ -1,6 +1,5 @@
 use async_trait::async_trait;
-use decide_protocol::{error::DecideError, Component};
-use prost::Message;
+use decide_protocol::{component, error::DecideError, Component, ComponentHealth};
 use prost_types::Any;
 use serde::Deserialize;
 use std::sync::{
@@ -33,14 +32,10 @@ pub struct Lights {
     task_handle: Option<JoinHandle<()>>,
 }
 
+#[component(state = proto::State, params = proto::Params, config = LightsConfig,
+            state_type_url = "melizalab.org/proto/lights_state", params_type_url = "melizalab.org/proto/lights_params")]
 #[async_trait]
 impl Component for Lights {
-    type State = proto::State;
-    type Params = proto::Params;
-    type Config = LightsConfig;
-    const STATE_TYPE_URL: &'static str = "melizalab.org/proto/lights_state";
-    const PARAMS_TYPE_URL: &'static str = "melizalab.org/proto/lights_params";
-
     fn new(config: Self::Config, state_sender: mpsc::Sender<Any>) -> Self {
         println!("Lights with config {:?}", config);
         Lights {
@@ -51,7 +46,7 @@ impl Component for Lights {
         }
     }
 
-    async fn init(&mut self, _config: Self::Config) {
+    async fn init(&mut self, _config: Self::Config) -> decide_protocol::Result<()> {
         let blink = Arc::clone(&self.blink);
         let on = Arc::clone(&self.on);
         let sender = self.state_sender.clone();
@@ -62,15 +57,13 @@ impl Component for Lights {
                     let old_state = on.fetch_xor(true, Ordering::AcqRel);
                     let new_state = !old_state;
                     let state = Self::State { on: new_state };
-                    let message = Any {
-                        value: state.encode_to_vec(),
-                        type_url: Self::STATE_TYPE_URL.into(),
-                    };
+                    let message = Self::pack_state(&state);
                     sender.send(message).await.unwrap();
                 }
                 sleep(Duration::from_millis(100)).await;
             }
         }));
+        Ok(())
     }
 
     fn change_state(&mut self, state: Self::State) -> Result<(), DecideError> {
@@ -78,10 +71,7 @@ impl Component for Lights {
         let sender = self.state_sender.clone();
         tokio::spawn(async move {
             sender
-                .send(Any {
-                    type_url: String::from(Self::STATE_TYPE_URL),
-                    value: state.encode_to_vec(),
-                })
+                .send(Self::pack_state(&state))
                 .await
                 .map_err(|e| DecideError::Component { source: e.into() })
                 .unwrap();
@@ -107,10 +97,18 @@ impl Component for Lights {
         }
     }
 
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Lights task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
     async fn shutdown(&mut self) {
         if let Some(task_handle) = self.task_handle.take() {
             task_handle.abort();
-            task_handle.await.unwrap_err();
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    error!("Lights task panicked: {}", e);
+                }
+            }
         }
     }
 }
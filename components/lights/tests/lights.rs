@@ -1,13 +1,12 @@
 use decide_core::{run, ComponentCollection};
 use decide_protocol::{
-    proto::{reply, ComponentParams, Config, Pub, Reply, StateChange},
+    proto::{error, reply, ComponentParams, Config, Pub, Reply, RequestMeta, StateChange},
     Component, ComponentName, ComponentRequest, GeneralRequest, Request, RequestType, PUB_ENDPOINT,
     REQ_ENDPOINT,
 };
 use futures::{Stream, StreamExt};
 use lights::Lights;
 use prost::Message;
-use prost_types::Any;
 use tmq::{request, subscribe, Context, Multipart};
 use tokio::test;
 #[macro_use]
@@ -32,7 +31,13 @@ fn decide() -> Decide {
               config:
                 pin: 4";
         let (components, state_stream) = ComponentCollection::from_reader(config.as_bytes())?;
-        let res = run::launch_decide(components, state_stream)?;
+        let res = run::launch_decide(
+            components,
+            state_stream,
+            &Default::default(),
+            None,
+            Vec::new(),
+        )?;
         res.await
     });
     return Decide;
@@ -80,6 +85,7 @@ macro_rules! lock {
             request_type: RequestType::General(GeneralRequest::RequestLock),
             component: None,
             body: config.encode_to_vec(),
+            meta: Default::default(),
         };
         let result = send_request(request).await?;
         result
@@ -92,6 +98,7 @@ macro_rules! unlock {
             request_type: RequestType::General(GeneralRequest::ReleaseLock),
             component: None,
             body: vec![],
+            meta: Default::default(),
         };
         let result = send_request(request).await?;
         result
@@ -104,10 +111,13 @@ async fn locking_behavior(decide: &Decide) -> anyhow::Result<()> {
     let result = lock!();
     assert_eq!(result, reply::Result::Ok(()));
     let result = lock!();
-    assert_eq!(
-        result,
-        reply::Result::Error(String::from("controller is already locked"))
-    );
+    match result {
+        reply::Result::Error(e) => {
+            assert_eq!(e.code(), error::Code::Locked);
+            assert_eq!(e.message, "controller is already locked");
+        }
+        _ => panic!("expected an error, got {:?}", result),
+    }
     let result = unlock!();
     assert_eq!(result, reply::Result::Ok(()));
     let result = lock!();
@@ -119,10 +129,7 @@ async fn locking_behavior(decide: &Decide) -> anyhow::Result<()> {
 #[rstest]
 #[test]
 async fn parameters(decide: &Decide) {
-    let params = Any {
-        type_url: String::from(Lights::PARAMS_TYPE_URL),
-        value: lights::proto::Params { blink: false }.encode_to_vec(),
-    };
+    let params = Lights::pack_params(&lights::proto::Params { blink: false });
     let params_message = ComponentParams {
         parameters: Some(params.clone()),
     };
@@ -130,6 +137,7 @@ async fn parameters(decide: &Decide) {
         request_type: RequestType::Component(ComponentRequest::SetParameters),
         component: Some(ComponentName(String::from("house-lights"))),
         body: params_message.encode_to_vec(),
+        meta: Default::default(),
     };
     let result = send_request(request).await.unwrap();
     assert_eq!(result, reply::Result::Ok(()));
@@ -137,6 +145,7 @@ async fn parameters(decide: &Decide) {
         request_type: RequestType::Component(ComponentRequest::GetParameters),
         component: Some(ComponentName::from("house-lights")),
         body: vec![],
+        meta: Default::default(),
     };
     let result = send_request(request).await.unwrap();
     assert_eq!(result, reply::Result::Params(params));
@@ -145,24 +154,58 @@ async fn parameters(decide: &Decide) {
 #[rstest]
 #[test]
 async fn state(decide: &Decide) {
-    let state = Any {
-        type_url: String::from(Lights::STATE_TYPE_URL),
-        value: lights::proto::State { on: true }.encode_to_vec(),
-    };
+    let state = Lights::pack_state(&lights::proto::State { on: true });
     let state_message = StateChange {
         state: Some(state.clone()),
+        not_after: None,
     };
     let request = Request {
         request_type: RequestType::Component(ComponentRequest::ChangeState),
         component: Some(ComponentName::from("house-lights")),
         body: state_message.encode_to_vec(),
+        meta: Default::default(),
     };
     // the subscriber must be initialized before the state change is
     // sent because the publish socket doesn't buffer messages
-    let mut state_stream = pub_stream(b"state/house-lights").unwrap();
+    let mut state_stream = pub_stream(b"state/house-lights/").unwrap();
     let result = send_request(request).await.unwrap();
     assert_eq!(result, reply::Result::Ok(()));
     trace!("waiting for pub");
     let state_update = state_stream.next().await.unwrap();
     assert_eq!(state_update.state.unwrap(), state);
 }
+
+#[rstest]
+#[test]
+async fn idempotency_keys_are_per_client(decide: &Decide) {
+    // only this test turns the lights off
+    let state = Lights::pack_state(&lights::proto::State { on: false });
+    let state_message = StateChange {
+        state: Some(state.clone()),
+        not_after: None,
+    };
+    let request = Request {
+        request_type: RequestType::Component(ComponentRequest::ChangeState),
+        component: Some(ComponentName::from("house-lights")),
+        body: state_message.encode_to_vec(),
+        meta: RequestMeta {
+            idempotency_key: String::from("lights-off"),
+            ..Default::default()
+        },
+    };
+    let state_stream = pub_stream(b"state/house-lights/").unwrap();
+    // each request is sent from a new socket, and so from a different client
+    for _ in 0..2 {
+        let result = send_request(request.clone()).await.unwrap();
+        assert_eq!(result, reply::Result::Ok(()));
+    }
+    // a replayed reply would not change the state again
+    let changes = state_stream
+        .filter(|update| futures::future::ready(update.state.as_ref() == Some(&state)))
+        .take(2)
+        .count();
+    let changes = tokio::time::timeout(std::time::Duration::from_secs(1), changes)
+        .await
+        .expect("the second request was not executed");
+    assert_eq!(changes, 2);
+}
@@ -0,0 +1,340 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU32, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use alsa::{pcm::{Access, Format, HwParams, PCM}, ValueOr};
+use async_trait::async_trait;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
+
+/// Records from an ALSA capture device into timestamped WAV files. The device
+/// is read continuously, so that the level can be monitored between recordings
+/// and the audio just before each recording starts can be kept.
+pub struct MicCapture {
+    state: Arc<Mutex<proto::MicState>>,
+    recording: Arc<AtomicBool>, // requested by the client
+    interval: Arc<AtomicU32>, // ms
+    clip_level: Arc<AtomicU32>, // f32 bits
+    state_sender: Sender<Any>,
+    stop: Arc<AtomicBool>,
+    capture: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::MicState, params = proto::MicParams, config = Config)]
+#[async_trait]
+impl Component for MicCapture {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        MicCapture {
+            state: Arc::new(Mutex::new(proto::MicState::default())),
+            recording: Arc::new(AtomicBool::new(false)),
+            interval: Arc::new(AtomicU32::new(config.interval)),
+            clip_level: Arc::new(AtomicU32::new(config.clip_level.to_bits())),
+            state_sender,
+            stop: Arc::new(AtomicBool::new(false)),
+            capture: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let state = self.state.clone();
+        let recording = self.recording.clone();
+        let interval = self.interval.clone();
+        let clip_level = self.clip_level.clone();
+        let sender = self.state_sender.clone();
+        let stop = self.stop.clone();
+        // claim the device here, so a missing or busy one fails init
+        let pcm = PCM::new(&config.device, alsa::Direction::Capture, false)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        {
+            let hwp = HwParams::any(&pcm)
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+            hwp.set_channels(config.channels)
+                .and_then(|_| hwp.set_rate(config.sample_rate, ValueOr::Nearest))
+                .and_then(|_| hwp.set_access(Access::RWInterleaved))
+                .and_then(|_| hwp.set_format(Format::s16()))
+                .and_then(|_| pcm.hw_params(&hwp))
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+        }
+        let spec = WavSpec {
+            channels: config.channels as u16,
+            sample_rate: pcm.hw_params_current()
+                .and_then(|hwp| hwp.get_rate())
+                .map_err(|e| DecideError::Component { source: e.into() })?,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        tracing::info!("Mic-Capture Initiated on {:?}", config.device);
+        let faults = self.state_sender.clone();
+        self.capture = Some(thread::spawn(move || {
+            let capture = move || -> decide_protocol::Result<()> {
+                let io = pcm.io_i16()
+                    .map_err(|e| DecideError::Component { source: e.into() })?;
+                let channels = config.channels as usize;
+                let pre_frames = config.pre_trigger as usize * spec.sample_rate as usize / 1000;
+                let mut buffer = vec![0i16; MicCapture::CHUNK * channels];
+                let mut pre_trigger = PreTrigger::new(pre_frames * channels);
+                let mut level = Level::default();
+                let mut reported = Instant::now();
+                let mut writer: Option<WavWriter<BufWriter<File>>> = None;
+                let send = |state: &proto::MicState| {
+                    sender.blocking_send(Self::pack_state(state)).map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                };
+                while !stop.load(Ordering::Acquire) {
+                    let frames = match io.readi(&mut buffer) {
+                        Ok(frames) => frames,
+                        Err(e) => {
+                            tracing::warn!("Mic-Capture recovering from {}", e);
+                            pcm.recover(e.errno() as std::os::raw::c_int, true)
+                                .map_err(|e| DecideError::Component { source: e.into() })?;
+                            continue
+                        }
+                    };
+                    let samples = &buffer[..frames * channels];
+                    level.push(samples);
+
+                    let requested = recording.load(Ordering::Acquire);
+                    if requested && writer.is_none() {
+                        let name = chrono::Local::now().format("%Y%m%d-%H%M%S%.3f.wav").to_string();
+                        let path = PathBuf::from(&config.directory).join(name);
+                        let mut wav = WavWriter::create(&path, spec)
+                            .map_err(|e| DecideError::Component { source: e.into() })?;
+                        for &sample in pre_trigger.samples.iter() {
+                            wav.write_sample(sample)
+                                .map_err(|e| DecideError::Component { source: e.into() })?;
+                        }
+                        pre_trigger.samples.clear();
+                        writer = Some(wav);
+                        let mut state = state.lock().unwrap();
+                        state.recording = true;
+                        state.file = path.to_string_lossy().into_owned();
+                        tracing::info!("Mic-Capture Recording to {:?}", state.file);
+                        send(&state);
+                    }
+                    if let Some(wav) = writer.as_mut() {
+                        for &sample in samples {
+                            wav.write_sample(sample)
+                                .map_err(|e| DecideError::Component { source: e.into() })?;
+                        }
+                        state.lock().unwrap().frames = wav.duration() as u64;
+                    } else {
+                        pre_trigger.push(samples);
+                    }
+                    if !requested {
+                        if let Some(wav) = writer.take() {
+                            wav.finalize()
+                                .map_err(|e| DecideError::Component { source: e.into() })?;
+                            let mut state = state.lock().unwrap();
+                            state.recording = false;
+                            tracing::info!("Mic-Capture Recorded {:?} Frames to {:?}", state.frames, state.file);
+                            send(&state);
+                        }
+                    }
+
+                    if reported.elapsed() >= Duration::from_millis(interval.load(Ordering::Acquire) as u64) {
+                        reported = Instant::now();
+                        let (rms_db, peak) = level.take();
+                        let mut state = state.lock().unwrap();
+                        state.rms_db = rms_db;
+                        state.clipped = peak >= f32::from_bits(clip_level.load(Ordering::Acquire));
+                        if state.clipped {
+                            tracing::debug!("Mic-Capture Clipped");
+                        }
+                        send(&state);
+                    }
+                }
+                if let Some(wav) = writer.take() {
+                    wav.finalize()
+                        .map_err(|e| DecideError::Component { source: e.into() })?;
+                }
+                Ok(())
+            };
+            if let Err(e) = capture() {
+                report_fault(&faults, e);
+            }
+        }));
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // the capture thread opens or closes the file at the end of the current read
+        self.recording.store(state.recording, Ordering::Release);
+        tracing::info!("Mic-Capture Recording {} by Request", if state.recording { "Started" } else { "Stopped" });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval_ms == 0 {
+            tracing::error!("Mic-Capture interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        if !(0.0..=1.0).contains(&params.clip_level) {
+            tracing::error!("Mic-Capture clip level must be between 0 and 1");
+            return Err(ClientError::InvalidParams.into())
+        }
+        self.interval.store(params.interval_ms, Ordering::Release);
+        self.clip_level.store(params.clip_level.to_bits(), Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            interval_ms: self.interval.load(Ordering::Acquire),
+            clip_level: f32::from_bits(self.clip_level.load(Ordering::Acquire)),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Mic-Capture thread", self.capture.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![Resource::Pcm { device: config.device.clone(), capture: true }]
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Mic-Capture");
+        self.stop.store(true, Ordering::Release);
+        if let Some(capture) = self.capture.take() {
+            match tokio::task::spawn_blocking(move || capture.join()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => tracing::error!("Mic-Capture thread panicked"),
+                Err(e) => tracing::error!("Mic-Capture thread could not be joined: {}", e),
+            }
+        }
+    }
+}
+
+impl MicCapture {
+    const CHUNK: usize = 1024; // frames per read
+}
+
+/// Circular buffer of the most recent samples, written to the start of each file
+struct PreTrigger {
+    capacity: usize, // samples
+    samples: VecDeque<i16>,
+}
+
+impl PreTrigger {
+    fn new(capacity: usize) -> Self {
+        PreTrigger { capacity, samples: VecDeque::with_capacity(capacity) }
+    }
+
+    fn push(&mut self, samples: &[i16]) {
+        let samples = &samples[samples.len().saturating_sub(self.capacity)..];
+        let overflow = (self.samples.len() + samples.len()).saturating_sub(self.capacity);
+        self.samples.drain(..overflow);
+        self.samples.extend(samples);
+    }
+}
+
+/// Accumulates the RMS and peak level of the samples between updates
+#[derive(Default)]
+struct Level {
+    sum_squares: f64,
+    count: u64,
+    peak: u16,
+}
+
+impl Level {
+    fn push(&mut self, samples: &[i16]) {
+        for &sample in samples {
+            self.sum_squares += (sample as f64).powi(2);
+            self.peak = self.peak.max(sample.unsigned_abs());
+        }
+        self.count += samples.len() as u64;
+    }
+
+    /// Returns the RMS in dBFS and the peak as a fraction of full scale, and
+    /// starts a new interval
+    fn take(&mut self) -> (f32, f32) {
+        let rms = (self.sum_squares / self.count.max(1) as f64).sqrt() / i16::MAX as f64;
+        let peak = self.peak as f32 / i16::MAX as f32;
+        *self = Level::default();
+        ((20.0 * rms.log10()).max(Level::FLOOR) as f32, peak)
+    }
+
+    /// Reported for silence, instead of negative infinity
+    const FLOOR: f64 = -120.0;
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_device")]
+    device: String,
+    #[serde(default = "Config::default_sample_rate")]
+    sample_rate: u32,
+    #[serde(default = "Config::default_channels")]
+    channels: u32,
+    directory: String, // where recordings are saved
+    #[serde(default = "Config::default_pre_trigger")]
+    pre_trigger: u32, // ms of audio before the start request kept in each recording
+    #[serde(default = "Config::default_interval")]
+    interval: u32, // ms, initial value of the interval_ms parameter
+    #[serde(default = "Config::default_clip_level")]
+    clip_level: f32, // initial value of the clip_level parameter
+}
+
+impl Config {
+    fn default_device() -> String {
+        String::from("default")
+    }
+
+    fn default_sample_rate() -> u32 {
+        48000
+    }
+
+    fn default_channels() -> u32 {
+        1
+    }
+
+    fn default_pre_trigger() -> u32 {
+        500
+    }
+
+    fn default_interval() -> u32 {
+        1000
+    }
+
+    fn default_clip_level() -> f32 {
+        0.99
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_trigger_keeps_latest_samples() {
+        let mut pre_trigger = PreTrigger::new(4);
+        pre_trigger.push(&[1, 2, 3]);
+        pre_trigger.push(&[4, 5]);
+        assert_eq!(pre_trigger.samples, [2, 3, 4, 5]);
+        pre_trigger.push(&[6, 7, 8, 9, 10]);
+        assert_eq!(pre_trigger.samples, [7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn level_of_full_scale_square_wave() {
+        let mut level = Level::default();
+        level.push(&[i16::MAX, -i16::MAX, i16::MAX, -i16::MAX]);
+        let (rms_db, peak) = level.take();
+        assert!(rms_db.abs() < 1e-3);
+        assert_eq!(peak, 1.0);
+        assert_eq!(level.take(), (Level::FLOOR as f32, 0.0));
+    }
+}
@@ -0,0 +1,292 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                MultiLineHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::{
+    self, task::JoinHandle, time::Duration
+};
+
+/// IR beam-break sensors across nest box entrances, hoppers and the like. A
+/// visit starts once a beam has stayed broken for `enter_ms` and ends once it
+/// has stayed clear for `exit_ms`, so that birds shuffling in the opening do not
+/// produce a stream of events.
+pub struct NestBox {
+    sensors: Arc<Sensors>,
+    emitters: Option<MultiLineHandle>, // IR emitters stay on while this is held
+    enter: Arc<AtomicU32>, // ms
+    exit: Arc<AtomicU32>, // ms
+    state_sender: Sender<Any>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+/// State shared between the component and the tasks watching each sensor
+struct Sensors {
+    names: Vec<String>,
+    occupied: Vec<AtomicBool>,
+    entered: Vec<AtomicU64>, // ns timestamp of the current visit
+    total: Vec<AtomicU64>, // ms
+    last: Mutex<(Option<usize>, bool, u64, u64)>, // sensor, entry, time and duration of the last event
+}
+
+impl Sensors {
+    fn state(&self) -> proto::NestState {
+        let (sensor, entered, timestamp_ns, duration_ms) = *self.last.lock().unwrap();
+        proto::NestState {
+            occupied: self.names.iter().cloned()
+                .zip(self.occupied.iter().map(|o| o.load(Ordering::Acquire)))
+                .collect(),
+            total_ms: self.names.iter().cloned()
+                .zip(self.total.iter().map(|t| t.load(Ordering::Acquire)))
+                .collect(),
+            sensor: sensor.map(|i| self.names[i].clone()).unwrap_or_default(),
+            entered,
+            timestamp_ns,
+            duration_ms,
+        }
+    }
+
+    /// Records an entry or exit and returns the state announcing it
+    fn record(&self, index: usize, entered: bool, timestamp_ns: u64) -> proto::NestState {
+        self.occupied[index].store(entered, Ordering::Release);
+        let duration_ms = if entered {
+            self.entered[index].store(timestamp_ns, Ordering::Release);
+            0
+        } else {
+            let duration_ms = timestamp_ns.saturating_sub(self.entered[index].load(Ordering::Acquire)) / 1_000_000;
+            self.total[index].fetch_add(duration_ms, Ordering::AcqRel);
+            duration_ms
+        };
+        *self.last.lock().unwrap() = (Some(index), entered, timestamp_ns, duration_ms);
+        self.state()
+    }
+}
+
+#[component(state = proto::NestState, params = proto::NestParams, config = Config)]
+#[async_trait]
+impl Component for NestBox {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        let sensors = Sensors {
+            names: config.sensors.iter().map(|s| s.name.clone()).collect(),
+            occupied: config.sensors.iter().map(|_| AtomicBool::new(false)).collect(),
+            entered: config.sensors.iter().map(|_| AtomicU64::new(0)).collect(),
+            total: config.sensors.iter().map(|_| AtomicU64::new(0)).collect(),
+            last: Mutex::new((None, false, 0, 0)),
+        };
+        NestBox {
+            sensors: Arc::new(sensors),
+            emitters: None,
+            enter: Arc::new(AtomicU32::new(config.enter)),
+            exit: Arc::new(AtomicU32::new(config.exit)),
+            state_sender: sender,
+            task_handles: Vec::new(),
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        if !config.ir_offsets.is_empty() {
+            self.emitters = Some(chip.get_lines(&config.ir_offsets)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .request(LineRequestFlags::OUTPUT, &vec![1; config.ir_offsets.len()], "nest_box_ir")
+                .map_err(|e| DecideError::Component { source: e.into() })?);
+        }
+        for (index, sensor) in config.sensors.iter().enumerate() {
+            let events = AsyncLineEventHandle::new(
+                chip.get_line(sensor.offset)
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+                    .events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "nest_box")
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+            ).map_err(|e| DecideError::Component { source: e.into() })?;
+            let watcher = SensorWatcher {
+                index,
+                events,
+                active_low: config.active_low,
+                enter: self.enter.clone(),
+                exit: self.exit.clone(),
+                sensors: self.sensors.clone(),
+                sender: self.state_sender.clone(),
+            };
+            self.task_handles.push(tokio::spawn(watcher.run()));
+        }
+        tracing::info!("NestBox Initiated with {:?} sensors", config.sensors.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // resetting the state is harmless, but occupancy cannot be set
+        if !state.occupied.is_empty() {
+            tracing::error!("NestBox occupancy comes from the sensors and cannot be changed by request");
+            return Err(ClientError::InvalidState.into())
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        self.enter.store(params.enter_ms, Ordering::Release);
+        self.exit.store(params.exit_ms, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.sensors.state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            enter_ms: self.enter.load(Ordering::Acquire),
+            exit_ms: self.exit.load(Ordering::Acquire),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("NestBox sensor task", self.task_handles.iter().any(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        let mut resources = Resource::lines(&config.chip, config.sensors.iter().map(|sensor| &sensor.offset));
+        resources.extend(Resource::lines(&config.chip, &config.ir_offsets));
+        resources
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for NestBox");
+        for task_handle in self.task_handles.drain(..) {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("NestBox task panicked: {}", e);
+                }
+            }
+        }
+        self.emitters.take();
+    }
+}
+
+struct SensorWatcher {
+    index: usize,
+    events: AsyncLineEventHandle,
+    active_low: bool,
+    enter: Arc<AtomicU32>,
+    exit: Arc<AtomicU32>,
+    sensors: Arc<Sensors>,
+    sender: Sender<Any>,
+}
+
+impl SensorWatcher {
+    async fn run(mut self) {
+        let mut broken = self.broken();
+        if broken {
+            // a bird already inside at startup is counted from now
+            let timestamp_ns = self.now_ns();
+            self.sensors.record(self.index, true, timestamp_ns);
+        }
+        while let Some(event) = self.events.next().await {
+            let event = event.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            let window = if broken { &self.exit } else { &self.enter };
+            let window = Duration::from_millis(window.load(Ordering::Acquire) as u64);
+            // wait for the beam to settle for a full window
+            while let Ok(Some(_)) = tokio::time::timeout(window, self.events.next()).await {}
+            if self.broken() == broken {
+                tracing::trace!("Ignoring brief beam {}", if broken { "clearing" } else { "break" });
+                continue
+            }
+            broken = !broken;
+            let state = self.sensors.record(self.index, broken, event.timestamp());
+            if broken {
+                tracing::info!("NestBox {:?} Entered", self.sensors.names[self.index]);
+            } else {
+                tracing::info!("NestBox {:?} Exited After {:?} ms", self.sensors.names[self.index], state.duration_ms);
+            }
+            let message = NestBox::pack_state(&state);
+            if self.sender.send(message).await.is_err() {
+                break
+            }
+        }
+    }
+
+    fn broken(&self) -> bool {
+        let level = self.events.as_ref().get_value()
+            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        (level == 0) == self.active_low
+    }
+
+    /// CLOCK_MONOTONIC in ns, to match the kernel event timestamps
+    fn now_ns(&self) -> u64 {
+        let ts = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SensorConfig {
+    name: String, // used in state messages, e.g. "nest"
+    offset: u32, // input line of the IR detector
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    sensors: Vec<SensorConfig>,
+    #[serde(default)]
+    ir_offsets: Vec<u32>, // output lines held high to power the IR emitters
+    #[serde(default)]
+    active_low: bool, // inputs read 0 while the beam is broken
+    #[serde(default = "Config::default_enter")]
+    enter: u32, // ms, initial value of the enter_ms parameter
+    #[serde(default = "Config::default_exit")]
+    exit: u32, // ms, initial value of the exit_ms parameter
+}
+
+impl Config {
+    fn default_enter() -> u32 {
+        50
+    }
+
+    fn default_exit() -> u32 {
+        500
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exits_add_visit_to_total() {
+        let sensors = Sensors {
+            names: vec![String::from("nest")],
+            occupied: vec![AtomicBool::new(false)],
+            entered: vec![AtomicU64::new(0)],
+            total: vec![AtomicU64::new(0)],
+            last: Mutex::new((None, false, 0, 0)),
+        };
+        let state = sensors.record(0, true, 1_000_000_000);
+        assert!(state.occupied["nest"] && state.entered);
+        assert_eq!(state.duration_ms, 0);
+        let state = sensors.record(0, false, 3_500_000_000);
+        assert_eq!((state.duration_ms, state.total_ms["nest"]), (2500, 2500));
+        sensors.record(0, true, 4_000_000_000);
+        let state = sensors.record(0, false, 4_250_000_000);
+        assert_eq!((state.duration_ms, state.total_ms["nest"]), (250, 2750));
+        assert!(!state.occupied["nest"]);
+    }
+}
@@ -0,0 +1,279 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineHandle,
+                MultiLineHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+                EventType
+};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::{
+    self, task::JoinHandle, time::Duration
+};
+
+/// A row of infrared peck keys, each with an optional cue LED. Every settled change
+/// of a key is published with the name of the key and the kernel timestamp of its
+/// first edge.
+pub struct PeckPort {
+    keys: Arc<Keys>,
+    cues: Vec<Option<LineHandle>>, // indexed like keys, claimed in init
+    emitters: Option<MultiLineHandle>, // IR emitters stay on while this is held
+    debounce: Arc<AtomicU64>, // ms
+    state_sender: Sender<Any>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+/// State shared between the component and the tasks watching each key
+struct Keys {
+    names: Vec<String>,
+    pecked: Vec<AtomicBool>,
+    cued: Vec<AtomicBool>,
+    changed: Mutex<(Option<usize>, u64)>, // key that changed last and when
+}
+
+impl Keys {
+    fn state(&self) -> proto::PortState {
+        let (changed, timestamp_ns) = *self.changed.lock().unwrap();
+        proto::PortState {
+            keys: self.names.iter().cloned()
+                .zip(self.pecked.iter().map(|p| p.load(Ordering::Acquire)))
+                .collect(),
+            cues: self.names.iter().cloned()
+                .zip(self.cued.iter().map(|c| c.load(Ordering::Acquire)))
+                .collect(),
+            changed: changed.map(|i| self.names[i].clone()).unwrap_or_default(),
+            timestamp_ns,
+        }
+    }
+}
+
+#[component(state = proto::PortState, params = proto::PortParams, config = Config)]
+#[async_trait]
+impl Component for PeckPort {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        let keys = Keys {
+            names: config.keys.iter().map(|key| key.name.clone()).collect(),
+            pecked: config.keys.iter().map(|_| AtomicBool::new(false)).collect(),
+            cued: config.keys.iter().map(|_| AtomicBool::new(false)).collect(),
+            changed: Mutex::new((None, 0)),
+        };
+        PeckPort {
+            keys: Arc::new(keys),
+            cues: config.keys.iter().map(|_| None).collect(),
+            emitters: None,
+            debounce: Arc::new(AtomicU64::new(config.debounce)),
+            state_sender: sender,
+            task_handles: Vec::new(),
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut cue_chip = Chip::new(config.cue_chip.as_ref().unwrap_or(&config.chip))
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        for (cue, key) in self.cues.iter_mut().zip(config.keys.iter()) {
+            if let Some(offset) = key.cue {
+                *cue = Some(cue_chip.get_line(offset)
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+                    .request(LineRequestFlags::OUTPUT, 0, "peck_port_cue")
+                    .map_err(|e| DecideError::Component { source: e.into() })?);
+            }
+        }
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        if !config.ir_offsets.is_empty() {
+            self.emitters = Some(chip.get_lines(&config.ir_offsets)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .request(LineRequestFlags::OUTPUT, &vec![1; config.ir_offsets.len()], "peck_port_ir")
+                .map_err(|e| DecideError::Component { source: e.into() })?);
+        }
+        for (index, key) in config.keys.iter().enumerate() {
+            let events = AsyncLineEventHandle::new(
+                chip.get_line(key.offset)
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+                    .events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "peck_port_key")
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+            ).map_err(|e| DecideError::Component { source: e.into() })?;
+            let watcher = KeyWatcher {
+                index,
+                events,
+                active_low: config.active_low,
+                debounce: self.debounce.clone(),
+                keys: self.keys.clone(),
+                sender: self.state_sender.clone(),
+            };
+            self.task_handles.push(tokio::spawn(watcher.run()));
+        }
+        tracing::info!("PeckPort Initiated with {:?} keys", config.keys.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // validate everything before touching any line
+        let mut cues = Vec::with_capacity(state.cues.len());
+        for (name, &on) in state.cues.iter() {
+            match self.keys.names.iter().position(|n| n == name) {
+                Some(index) if self.cues[index].is_some() => cues.push((index, on)),
+                _ => {
+                    tracing::error!("PeckPort has no cue for key {:?}", name);
+                    return Err(ClientError::InvalidState.into())
+                }
+            }
+        }
+        for (index, on) in cues {
+            if let Some(line) = &self.cues[index] {
+                line.set_value(on as u8)
+                    .map_err(|e| DecideError::Component { source: e.into() })?;
+            }
+            self.keys.cued[index].store(on, Ordering::Release);
+        }
+        let state = self.keys.state();
+        let sender = self.state_sender.clone();
+        tokio::spawn(async move {
+            sender
+                .send(Self::pack_state(&state))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+            tracing::info!("PeckPort State Changed by Request");
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        self.debounce.store(params.debounce_ms as u64, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.keys.state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            debounce_ms: self.debounce.load(Ordering::Acquire) as u32,
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("PeckPort key task", self.task_handles.iter().any(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        let cue_chip = config.cue_chip.as_ref().unwrap_or(&config.chip);
+        let mut resources = Resource::lines(&config.chip, config.keys.iter().map(|key| &key.offset));
+        resources.extend(Resource::lines(cue_chip, config.keys.iter().filter_map(|key| key.cue.as_ref())));
+        resources.extend(Resource::lines(&config.chip, &config.ir_offsets));
+        resources
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for PeckPort");
+        for task_handle in self.task_handles.drain(..) {
+            task_handle.abort();
+            // the task may already have stopped after reporting a fault
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("PeckPort task panicked: {}", e);
+                }
+            }
+        }
+        for line in self.cues.iter().flatten() {
+            if let Err(e) = line.set_value(0) {
+                tracing::error!("PeckPort cue could not be turned off: {}", e);
+            }
+        }
+        self.emitters = None;
+    }
+}
+
+/// Publishes the settled changes of one key
+struct KeyWatcher {
+    index: usize,
+    events: AsyncLineEventHandle,
+    active_low: bool,
+    debounce: Arc<AtomicU64>,
+    keys: Arc<Keys>,
+    sender: Sender<Any>,
+}
+
+impl KeyWatcher {
+    async fn run(mut self) {
+        if let Err(e) = self.watch().await {
+            report_fault(&self.sender, e);
+        }
+    }
+
+    async fn watch(&mut self) -> Result<(), gpio_cdev::Error> {
+        let mut level = self.events.as_ref().get_value()?;
+        self.keys.pecked[self.index].store(self.pecked(level), Ordering::Release);
+        while let Some(event) = self.events.next().await {
+            let event = event?;
+            let debounce = Duration::from_millis(self.debounce.load(Ordering::Acquire));
+            let new_level = if debounce.is_zero() {
+                if event.event_type() == EventType::RisingEdge { 1 } else { 0 }
+            } else {
+                // wait for the line to go quiet for a full debounce window
+                while let Ok(Some(_)) = tokio::time::timeout(debounce, self.events.next()).await {}
+                self.events.as_ref().get_value()?
+            };
+            if new_level == level {
+                tracing::trace!("Ignoring peck key chatter");
+                continue
+            }
+            level = new_level;
+            let pecked = self.pecked(level);
+            self.keys.pecked[self.index].store(pecked, Ordering::Release);
+            *self.keys.changed.lock().unwrap() = (Some(self.index), event.timestamp());
+            tracing::info!("PeckPort Key {:?} {}", self.keys.names[self.index],
+                           if pecked { "Pecked" } else { "Released" });
+            let message = PeckPort::pack_state(&self.keys.state());
+            if self.sender.send(message).await.is_err() {
+                break
+            }
+        }
+        Ok(())
+    }
+
+    fn pecked(&self, level: u8) -> bool {
+        (level == 0) == self.active_low
+    }
+}
+
+#[derive(Deserialize)]
+pub struct KeyConfig {
+    name: String, // used in state messages, e.g. "left"
+    offset: u32, // input line of the IR detector
+    cue: Option<u32>, // output line of the cue LED
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String, // chip with the key input lines
+    cue_chip: Option<String>, // chip with the cue LED lines, if not the same as chip
+    keys: Vec<KeyConfig>,
+    #[serde(default)]
+    ir_offsets: Vec<u32>, // output lines held high to power the IR emitters
+    #[serde(default)]
+    active_low: bool, // inputs read 0 while the beam is broken
+    #[serde(default = "Config::default_debounce")]
+    debounce: u64, // ms, initial value of the debounce_ms parameter
+}
+
+impl Config {
+    fn default_debounce() -> u64 {
+        20
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
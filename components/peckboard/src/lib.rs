@@ -8,9 +8,8 @@ use gpio_cdev::{Chip, AsyncLineEventHandle,
 use futures::stream::StreamExt;
 use tokio::sync::mpsc::Sender;
 use async_trait::async_trait;
-use decide_protocol::{Component,
-                   error::DecideError};
-use prost::Message;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
 use prost_types::Any;
 use serde::Deserialize;
 use std::sync::{
@@ -24,7 +23,7 @@ use tokio::{
 };
 
 pub struct PeckLeds {
-    handles: MultiLineHandle,
+    handles: Option<MultiLineHandle>, // claimed in init
     led_state: LedColor,
     state_sender: mpsc::Sender<Any>,
 }
@@ -37,43 +36,37 @@ pub struct PeckKeys {
     task_handle: Option<JoinHandle<()>>,
 }
 
+#[component(state = proto::LedState, params = proto::LedParams, config = LedConfig)]
 #[async_trait]
 impl Component for PeckLeds {
-    type State = proto::LedState;
-    type Params = proto::LedParams;
-    type Config = LedConfig;
-    const STATE_TYPE_URL: &'static str = "type.googleapis.com/LedState";
-    const PARAMS_TYPE_URL: &'static str =  "type.googleapis.com/LedParams";
+    fn new(_config: Self::Config, sender: Sender<Any>) -> Self {
+        PeckLeds {
+            handles: None,
+            led_state: LedColor::Off,
+            state_sender: sender,
+        }
+    }
 
-    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
         use std::fs;
         use std::path::{Path, PathBuf};
         use std::time::Duration;
-        use std::thread;
 
         if !Path::new("/sys/class/i2c-adapter/i2c-1/1-0020").exists() {
-            let sysfs_chip = fs::canonicalize(
-                PathBuf::from("/sys/class/i2c-adapter/i2c-1/new_device")).unwrap();
-            fs::write(sysfs_chip, "pcf8575 0x20").expect("Unable to write to i2c-adapter for peckboard");
+            fs::canonicalize(PathBuf::from("/sys/class/i2c-adapter/i2c-1/new_device"))
+                .and_then(|sysfs_chip| fs::write(sysfs_chip, "pcf8575 0x20"))
+                .map_err(|e| DecideError::Component { source: e.into() })?;
             tracing::debug!("Peckboard Chip Initiated");
-            assert!(Path::new("/sys/class/i2c-adapter/i2c-1/1-0020").exists());
         }
-        thread::sleep(Duration::from_secs(2));
+        tokio::time::sleep(Duration::from_secs(2)).await;
         let mut chip4 = Chip::new(config.peckboard_chip.clone())
-            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-        let handles = chip4.get_lines(&config.led_offsets.clone())
-            .map_err(|e| DecideError::Component { source: e.into() }).unwrap()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        self.handles = Some(chip4.get_lines(&config.led_offsets.clone())
+            .map_err(|e| DecideError::Component { source: e.into() })?
             .request(LineRequestFlags::OUTPUT, &[0,0,0], "PeckLeds")
-            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-        PeckLeds {
-            handles,
-            led_state: LedColor::Off,
-            state_sender: sender,
-        }
-    }
-
-    async fn init(&mut self, _config: Self::Config) {
-        tracing::info!("PeckLed Initiated")
+            .map_err(|e| DecideError::Component { source: e.into() })?);
+        tracing::info!("PeckLed Initiated");
+        Ok(())
     }
 
     fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
@@ -86,15 +79,13 @@ impl Component for PeckLeds {
             _ => {tracing::error!("PeckLed State received is invalid string {:?}", state.led_state.as_str());}
         }
         let lines_value = self.led_state.as_value();
-        self.handles.set_values(&lines_value)
-            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        self.handles.as_ref().ok_or(ClientError::InvalidState)?
+            .set_values(&lines_value)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
         let sender = self.state_sender.clone();
         tokio::spawn(async move {
             sender
-                .send(Any {
-                    type_url: String::from(Self::STATE_TYPE_URL),
-                    value: state.encode_to_vec(),
-                })
+                .send(Self::pack_state(&state))
                 .await
                 .map_err(|e| DecideError::Component { source: e.into() })
                 .unwrap();
@@ -123,21 +114,28 @@ impl Component for PeckLeds {
         Self::Params{}
     }
 
+    fn healthy(&self) -> ComponentHealth {
+        match self.handles.as_ref().map_or(Ok(Vec::new()), |handles| handles.get_values()) {
+            Ok(_) => ComponentHealth::Healthy,
+            Err(e) => ComponentHealth::Failed(format!("PeckLed lines cannot be read: {}", e)),
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.peckboard_chip, &config.led_offsets)
+    }
+
     async fn shutdown(&mut self) {
         tracing::debug!("Shutdown called for PeckLed");
-        self.handles.set_values(&LedColor::Off.as_value())
-            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        if let Some(Err(e)) = self.handles.as_ref().map(|handles| handles.set_values(&LedColor::Off.as_value())) {
+            tracing::error!("PeckLed lines could not be turned off: {}", e);
+        }
     }
 }
 
+#[component(state = proto::KeyState, params = proto::KeyParams, config = KeyConfig)]
 #[async_trait]
 impl Component for PeckKeys {
-    type State = proto::KeyState;
-    type Params = proto::KeyParams;
-    type Config = KeyConfig;
-    const STATE_TYPE_URL: &'static str = "type.googleapis.com/KeyState";
-    const PARAMS_TYPE_URL: &'static str = "type.googleapis.com/KeyParams";
-
     fn new(_config: Self::Config, sender: Sender<Any>) -> Self {
         PeckKeys {
             peck_left: Arc::new(AtomicBool::new(false)),
@@ -148,21 +146,21 @@ impl Component for PeckKeys {
         }
     }
 
-    async fn init(&mut self, config: Self::Config) {
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
         let sender = self.state_sender.clone();
 
-        self.task_handle = Some(tokio::spawn(async move {
-            let mut chip2 = Chip::new(&config.interrupt_chip)
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-            let interrupt_offset = chip2.get_line(config.interrupt_offset.clone())
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-            let mut interrupt = AsyncLineEventHandle::new(interrupt_offset.events(
-                LineRequestFlags::INPUT,
-                EventRequestFlags::BOTH_EDGES, // we're interested in capturing FALLING_EDGE
-                "Peckboard_Interrupt"          // but oddly setting flags to FALLING_EDGE still
-            ).unwrap())                         // gives us both edges.
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        let mut chip2 = Chip::new(&config.interrupt_chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let interrupt_offset = chip2.get_line(config.interrupt_offset)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut interrupt = AsyncLineEventHandle::new(interrupt_offset.events(
+            LineRequestFlags::INPUT,
+            EventRequestFlags::BOTH_EDGES, // we're interested in capturing FALLING_EDGE
+            "Peckboard_Interrupt"          // but oddly setting flags to FALLING_EDGE still
+        ).map_err(|e| DecideError::Component { source: e.into() })?) // gives us both edges.
+            .map_err(|e| DecideError::Component { source: e.into() })?;
 
+        self.task_handle = Some(tokio::spawn(async move {
             let mut chip4 = loop {
                 let chip_result = Chip::new(config.peckboard_chip.clone());
                 match chip_result {
@@ -170,41 +168,39 @@ impl Component for PeckKeys {
                     Err(_) => {continue}
                 }
             };
-            chip4.get_lines(&config.ir_offsets)
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap()
-                .request(LineRequestFlags::OUTPUT, &[1,1,1], "peckboard_ir")
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-            let key_handles: MultiLineHandle = chip4.get_lines(&config.key_offsets)
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap()
-                .request(LineRequestFlags::INPUT, &[0,0,0], "peck_keys")
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            let lines = chip4.get_lines(&config.ir_offsets)
+                .and_then(|lines| lines.request(LineRequestFlags::OUTPUT, &[1,1,1], "peckboard_ir"))
+                .and_then(|_| chip4.get_lines(&config.key_offsets))
+                .and_then(|lines| lines.request(LineRequestFlags::INPUT, &[0,0,0], "peck_keys"));
+            let key_handles: MultiLineHandle = match lines {
+                Ok(key_handles) => key_handles,
+                Err(e) => return report_fault(&sender, e),
+            };
 
             loop {
                 match interrupt.next().await {
                     Some(event) => {
-                        match event.unwrap().event_type() {
-                            EventType::FallingEdge => {
-                                let values = key_handles.get_values()
-                                    .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-                                let first = values[0];
-                                if values.iter().all(|&i| i == first) {
-                                    continue
-                                } else {
-                                    tracing::info!("PeckKey Interrupted - Event {:?} Registered", values);
-                                    let state = Self::State {
-                                        peck_left: values[2] != 0,
-                                        peck_center: values[1] != 0,
-                                        peck_right: values[0] != 0,
-                                    };
-                                    let message = Any {
-                                        value: state.encode_to_vec(),
-                                        type_url: Self::STATE_TYPE_URL.into(),
-                                    };
-                                    sender.send(message).await
-                                        .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-                                }
-                            }
-                            EventType::RisingEdge => { continue }
+                        let values = match event.and_then(|event| match event.event_type() {
+                            EventType::FallingEdge => key_handles.get_values().map(Some),
+                            EventType::RisingEdge => Ok(None),
+                        }) {
+                            Ok(Some(values)) => values,
+                            Ok(None) => continue,
+                            Err(e) => return report_fault(&sender, e),
+                        };
+                        let first = values[0];
+                        if values.iter().all(|&i| i == first) {
+                            continue
+                        } else {
+                            tracing::info!("PeckKey Interrupted - Event {:?} Registered", values);
+                            let state = Self::State {
+                                peck_left: values[2] != 0,
+                                peck_center: values[1] != 0,
+                                peck_right: values[0] != 0,
+                            };
+                            let message = Self::pack_state(&state);
+                            sender.send(message).await
+                                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
                         }
                     }
                     None => {tracing::error!("PeckKey Interrupted - No Event Registered");continue},
@@ -212,6 +208,7 @@ impl Component for PeckKeys {
             }
         }));
         tracing::info!("PeckKeys Initiated");
+        Ok(())
     }
 
     fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
@@ -222,10 +219,7 @@ impl Component for PeckKeys {
         let sender = self.state_sender.clone();
         tokio::spawn(async move {
             sender
-                .send(Any {
-                    type_url: String::from(Self::STATE_TYPE_URL),
-                    value: state.encode_to_vec(),
-                })
+                .send(Self::pack_state(&state))
                 .await
                 .map_err(|e| DecideError::Component { source: e.into() })
                 .unwrap();
@@ -251,12 +245,26 @@ impl Component for PeckKeys {
         Self::Params {}
     }
 
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("PeckKeys task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        let mut resources = vec![Resource::line(&config.interrupt_chip, config.interrupt_offset)];
+        resources.extend(Resource::lines(&config.peckboard_chip, config.key_offsets.iter().chain(&config.ir_offsets)));
+        resources
+    }
+
     async fn shutdown(&mut self) {
         tracing::debug!("Shutdown called for PeckKeys");
         if let Some(task_handle) = self.task_handle.take() {
             task_handle.abort();
-            task_handle.await
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap_err();
+            // the task may already have stopped after reporting a fault
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("PeckKeys task panicked: {}", e);
+                }
+            }
         }
     }
 }
@@ -0,0 +1,336 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Instant;
+use tokio::{
+    self, task::JoinHandle, time::Duration
+};
+
+/// Pellet dispenser with an IR drop sensor below the chute. Each pellet is
+/// verified by the sensor, and the dispenser is pulsed again if nothing drops
+/// in time. A pellet that never drops marks the dispenser as jammed.
+pub struct PelletDispenser {
+    dispenser: Arc<Dispenser>,
+    params: Arc<Mutex<proto::PelletParams>>,
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+trait Feeder: Send + Sync {
+    fn pulse(&self, on: bool) -> decide_protocol::Result<()>;
+    /// number of times the drop sensor has been broken
+    fn drops(&self) -> u64;
+}
+
+struct Dispenser {
+    line: Option<LineHandle>, // claimed in init
+    drops: AtomicU64,
+    dispensing: AtomicBool,
+    delivered: AtomicU64,
+    retries: AtomicU64,
+    jammed: AtomicBool,
+}
+
+impl Feeder for Dispenser {
+    fn pulse(&self, on: bool) -> decide_protocol::Result<()> {
+        self.line.as_ref().ok_or(ClientError::InvalidState)?
+            .set_value(on as u8)
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+
+    fn drops(&self) -> u64 {
+        self.drops.load(Ordering::Acquire)
+    }
+}
+
+impl Dispenser {
+    fn new(line: Option<LineHandle>) -> Self {
+        Dispenser {
+            line,
+            drops: AtomicU64::new(0),
+            dispensing: AtomicBool::new(false),
+            delivered: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            jammed: AtomicBool::new(false),
+        }
+    }
+
+    fn state(&self) -> proto::PelletState {
+        proto::PelletState {
+            dispensing: self.dispensing.load(Ordering::Acquire),
+            delivered: self.delivered.load(Ordering::Acquire),
+            retries: self.retries.load(Ordering::Acquire),
+            jammed: self.jammed.load(Ordering::Acquire),
+        }
+    }
+
+    fn message(&self) -> Any {
+        PelletDispenser::pack_state(&self.state())
+    }
+}
+
+/// Time between checks of the drop count while waiting for a pellet
+const POLL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Outcome {
+    delivered: bool,
+    retries: u32,
+}
+
+/// Pulses the feeder until a pellet drops or the retries run out
+async fn dispense(feeder: &impl Feeder, pulse: Duration, timeout: Duration,
+                  retries: u32) -> decide_protocol::Result<Outcome> {
+    for attempt in 0..=retries {
+        let before = feeder.drops();
+        let start = Instant::now();
+        feeder.pulse(true)?;
+        tokio::time::sleep(pulse).await;
+        feeder.pulse(false)?;
+        while start.elapsed() < timeout {
+            if feeder.drops() > before {
+                return Ok(Outcome { delivered: true, retries: attempt })
+            }
+            tokio::time::sleep(POLL).await;
+        }
+        // a pellet can land just as the wait ends
+        if feeder.drops() > before {
+            return Ok(Outcome { delivered: true, retries: attempt })
+        }
+        tracing::debug!("Pellet Dispenser saw no drop after pulse {:?}", attempt + 1);
+    }
+    Ok(Outcome { delivered: false, retries })
+}
+
+#[component(state = proto::PelletState, params = proto::PelletParams, config = Config)]
+#[async_trait]
+impl Component for PelletDispenser {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        PelletDispenser {
+            dispenser: Arc::new(Dispenser::new(None)),
+            params: Arc::new(Mutex::new(proto::PelletParams {
+                pulse_ms: config.pulse,
+                timeout_ms: config.timeout,
+                retries: config.retries,
+            })),
+            state_sender: sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let line = chip.get_line(config.output)
+            .map_err(|e| DecideError::Component { source: e.into() })?
+            .request(LineRequestFlags::OUTPUT, 0, "pellet_dispenser")
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        self.dispenser = Arc::new(Dispenser::new(Some(line)));
+        // count the edge where the beam is broken
+        let edge = if config.active_low {
+            EventRequestFlags::FALLING_EDGE
+        } else {
+            EventRequestFlags::RISING_EDGE
+        };
+        let mut events = AsyncLineEventHandle::new(
+            chip.get_line(config.sensor)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .events(LineRequestFlags::INPUT, edge, "pellet_dispenser_sensor")
+                .map_err(|e| DecideError::Component { source: e.into() })?
+        ).map_err(|e| DecideError::Component { source: e.into() })?;
+        let dispenser = self.dispenser.clone();
+        self.task_handle = Some(tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                event.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                dispenser.drops.fetch_add(1, Ordering::AcqRel);
+                if !dispenser.dispensing.load(Ordering::Acquire) {
+                    tracing::debug!("Pellet Dispenser drop sensor broken while idle");
+                }
+            }
+        }));
+        tracing::info!("Pellet Dispenser Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        if !state.dispensing {
+            if self.dispenser.jammed.swap(false, Ordering::AcqRel) {
+                tracing::info!("Pellet Dispenser Jam Cleared by Request");
+                let dispenser = self.dispenser.clone();
+                let sender = self.state_sender.clone();
+                tokio::spawn(async move {
+                    sender.send(dispenser.message()).await
+                        .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                });
+            }
+            return Ok(())
+        }
+        if self.dispenser.jammed.load(Ordering::Acquire) {
+            tracing::error!("Pellet Dispenser is jammed");
+            return Err(ClientError::InvalidState.into())
+        }
+        if self.dispenser.dispensing.swap(true, Ordering::AcqRel) {
+            tracing::error!("Pellet Dispenser is still dispensing");
+            return Err(ClientError::Busy.into())
+        }
+        let params = self.params.lock().unwrap().clone();
+        let dispenser = self.dispenser.clone();
+        let sender = self.state_sender.clone();
+        tracing::info!("Pellet Dispenser Dispensing by Request");
+        tokio::spawn(async move {
+            sender.send(dispenser.message()).await
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            let outcome = match dispense(dispenser.as_ref(),
+                                         Duration::from_millis(params.pulse_ms as u64),
+                                         Duration::from_millis(params.timeout_ms as u64),
+                                         params.retries).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    dispenser.dispensing.store(false, Ordering::Release);
+                    return report_fault(&sender, e)
+                }
+            };
+            dispenser.retries.fetch_add(outcome.retries as u64, Ordering::AcqRel);
+            if outcome.delivered {
+                dispenser.delivered.fetch_add(1, Ordering::AcqRel);
+                tracing::info!("Pellet Delivered after {:?} Retries", outcome.retries);
+            } else {
+                dispenser.jammed.store(true, Ordering::Release);
+                tracing::warn!("Pellet Dispenser Jammed: no drop after {:?} pulses", outcome.retries + 1);
+            }
+            dispenser.dispensing.store(false, Ordering::Release);
+            sender.send(dispenser.message()).await
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.pulse_ms == 0 || params.timeout_ms < params.pulse_ms {
+            tracing::error!("Pellet Dispenser pulse must be positive and no longer than the timeout");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // applies to the next pellet
+        *self.params.lock().unwrap() = params;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.dispenser.state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.lock().unwrap().clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Pellet Dispenser drop sensor task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.chip, &[config.output, config.sensor])
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Pellet Dispenser");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped after reporting a fault
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Pellet Dispenser task panicked: {}", e);
+                }
+            }
+        }
+        if let Err(e) = self.dispenser.pulse(false) {
+            tracing::error!("Pellet Dispenser line could not be turned off: {}", e);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    output: u32, // line pulsed to release a pellet
+    sensor: u32, // input line of the drop sensor
+    #[serde(default)]
+    active_low: bool, // sensor reads 0 while the beam is broken
+    #[serde(default = "Config::default_pulse")]
+    pulse: u32, // ms, initial value of the pulse_ms parameter
+    #[serde(default = "Config::default_timeout")]
+    timeout: u32, // ms, initial value of the timeout_ms parameter
+    #[serde(default = "Config::default_retries")]
+    retries: u32, // initial value of the retries parameter
+}
+
+impl Config {
+    fn default_pulse() -> u32 {
+        50
+    }
+
+    fn default_timeout() -> u32 {
+        1000
+    }
+
+    fn default_retries() -> u32 {
+        2
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    /// Drops a pellet on the given pulse, counting from 1
+    struct MockFeeder {
+        pulses: AtomicU32,
+        drops_on: u32,
+    }
+
+    impl Feeder for MockFeeder {
+        fn pulse(&self, on: bool) -> decide_protocol::Result<()> {
+            if on {
+                self.pulses.fetch_add(1, Ordering::AcqRel);
+            }
+            Ok(())
+        }
+
+        fn drops(&self) -> u64 {
+            (self.pulses.load(Ordering::Acquire) >= self.drops_on) as u64
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_until_pellet_drops() {
+        let feeder = MockFeeder { pulses: AtomicU32::new(0), drops_on: 2 };
+        let outcome = dispense(&feeder, Duration::from_millis(1), Duration::from_millis(20), 3).await.unwrap();
+        assert_eq!(outcome, Outcome { delivered: true, retries: 1 });
+        assert_eq!(feeder.pulses.load(Ordering::Acquire), 2);
+    }
+
+    #[tokio::test]
+    async fn jams_after_last_retry() {
+        let feeder = MockFeeder { pulses: AtomicU32::new(0), drops_on: u32::MAX };
+        let outcome = dispense(&feeder, Duration::from_millis(1), Duration::from_millis(10), 2).await.unwrap();
+        assert_eq!(outcome, Outcome { delivered: false, retries: 2 });
+        assert_eq!(feeder.pulses.load(Ordering::Acquire), 3);
+    }
+}
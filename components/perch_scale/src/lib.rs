@@ -0,0 +1,315 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI64, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
+
+mod visits;
+pub use visits::PerchVisits;
+
+/// Weighs the animal on a perch-mounted load cell read through an HX711 ADC,
+/// whose serial interface is bit-banged on two GPIO lines.
+pub struct PerchScale {
+    raw: Arc<AtomicI64>, // filtered counts
+    calibration: Arc<Mutex<Calibration>>,
+    state_sender: Sender<Any>,
+    stop: Arc<AtomicBool>,
+    reader: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Clone, Copy)]
+struct Calibration {
+    offset: i64, // counts
+    scale: f64, // counts per gram
+}
+
+impl Calibration {
+    fn grams(&self, raw: i64) -> f64 {
+        (raw - self.offset) as f64 / self.scale
+    }
+}
+
+#[component(state = proto::ScaleState, params = proto::ScaleParams, config = Config)]
+#[async_trait]
+impl Component for PerchScale {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        PerchScale {
+            raw: Arc::new(AtomicI64::new(0)),
+            calibration: Arc::new(Mutex::new(Calibration { offset: config.offset, scale: config.scale })),
+            state_sender,
+            stop: Arc::new(AtomicBool::new(false)),
+            reader: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let hx711 = Hx711::new(&config.chip, config.dout_offset, config.sck_offset, config.gain)?;
+        let raw = self.raw.clone();
+        let calibration = self.calibration.clone();
+        let sender = self.state_sender.clone();
+        let stop = self.stop.clone();
+        let interval = Duration::from_millis(config.interval);
+        let mut average = MovingAverage::new(config.window);
+        self.reader = Some(thread::spawn(move || {
+            let mut published = Instant::now();
+            while !stop.load(Ordering::Acquire) {
+                let reading = match hx711.read() {
+                    Ok(Some(reading)) => reading,
+                    Ok(None) => continue,
+                    Err(e) => return report_fault(&sender, e),
+                };
+                let filtered = average.push(reading);
+                raw.store(filtered, Ordering::Release);
+                if published.elapsed() < interval {
+                    continue
+                }
+                published = Instant::now();
+                let state = proto::ScaleState {
+                    grams: calibration.lock().unwrap().grams(filtered),
+                    raw: filtered,
+                };
+                tracing::trace!("PerchScale {:?} g", state.grams);
+                if let Err(e) = sender.blocking_send(Self::pack_state(&state)) {
+                    return report_fault(&sender, e)
+                }
+            }
+            if let Err(e) = hx711.power_down() {
+                tracing::error!("PerchScale could not power down the HX711: {}", e);
+            }
+        }));
+        tracing::info!("PerchScale Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.scale == 0.0 || !params.scale.is_finite() {
+            tracing::error!("PerchScale scale must be a nonzero number of counts per gram");
+            return Err(ClientError::InvalidParams.into())
+        }
+        let offset = if params.tare {
+            let offset = self.raw.load(Ordering::Acquire);
+            tracing::info!("PerchScale Tared at {:?} counts", offset);
+            offset
+        } else {
+            params.offset
+        };
+        *self.calibration.lock().unwrap() = Calibration { offset, scale: params.scale };
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        let raw = self.raw.load(Ordering::Acquire);
+        Self::State {
+            grams: self.calibration.lock().unwrap().grams(raw),
+            raw,
+        }
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        let calibration = self.calibration.lock().unwrap();
+        Self::Params {
+            offset: calibration.offset,
+            scale: calibration.scale,
+            tare: false,
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("PerchScale reader thread", self.reader.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.chip, &[config.dout_offset, config.sck_offset])
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for PerchScale");
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            match tokio::task::spawn_blocking(move || reader.join()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => tracing::error!("PerchScale reader thread panicked"),
+                Err(e) => tracing::error!("PerchScale reader thread could not be joined: {}", e),
+            }
+        }
+    }
+}
+
+struct Hx711 {
+    dout: LineHandle,
+    sck: LineHandle,
+    gain_pulses: usize, // clock pulses after the data that select the next channel and gain
+}
+
+impl Hx711 {
+    const POLL: Duration = Duration::from_millis(1);
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn new(chip: &str, dout_offset: u32, sck_offset: u32, gain: u32) -> decide_protocol::Result<Self> {
+        let gain_pulses = match gain {
+            128 => 1,
+            32 => 2,
+            64 => 3,
+            _ => {
+                tracing::error!("PerchScale gain must be 128, 64 (channel A) or 32 (channel B)");
+                return Err(DecideError::Component {
+                    source: std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid HX711 gain").into()
+                })
+            }
+        };
+        let mut chip = Chip::new(chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(Hx711 {
+            dout: chip.get_line(dout_offset)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .request(LineRequestFlags::INPUT, 0, "perch_scale_dout")
+                .map_err(|e| DecideError::Component { source: e.into() })?,
+            sck: chip.get_line(sck_offset)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .request(LineRequestFlags::OUTPUT, 0, "perch_scale_sck")
+                .map_err(|e| DecideError::Component { source: e.into() })?,
+            gain_pulses,
+        })
+    }
+
+    /// Holding the clock high for more than 60 us powers the HX711 down
+    fn power_down(&self) -> decide_protocol::Result<()> {
+        self.sck.set_value(1)
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+
+    /// Waits for a conversion and clocks it out, or returns None if none is ready
+    /// within the timeout.
+    fn read(&self) -> decide_protocol::Result<Option<i64>> {
+        let start = Instant::now();
+        // DOUT goes low when a conversion is ready
+        while self.level(&self.dout)? != 0 {
+            if start.elapsed() > Hx711::TIMEOUT {
+                tracing::warn!("PerchScale HX711 is not responding");
+                return Ok(None)
+            }
+            thread::sleep(Hx711::POLL);
+        }
+        let mut value: u32 = 0;
+        for _ in 0..24 {
+            value = (value << 1) | self.pulse()? as u32;
+        }
+        for _ in 0..self.gain_pulses {
+            self.pulse()?;
+        }
+        Ok(Some(decode(value)))
+    }
+
+    /// Clocks one bit out of the HX711
+    fn pulse(&self) -> decide_protocol::Result<u8> {
+        self.sck.set_value(1)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let bit = self.level(&self.dout)?;
+        self.sck.set_value(0)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(bit)
+    }
+
+    fn level(&self, line: &LineHandle) -> decide_protocol::Result<u8> {
+        line.get_value()
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+}
+
+/// Sign-extends a 24-bit two's complement reading
+fn decode(value: u32) -> i64 {
+    (((value << 8) as i32) >> 8) as i64
+}
+
+struct MovingAverage {
+    window: usize,
+    samples: VecDeque<i64>,
+    sum: i64,
+}
+
+impl MovingAverage {
+    fn new(window: usize) -> Self {
+        let window = window.max(1);
+        MovingAverage { window, samples: VecDeque::with_capacity(window), sum: 0 }
+    }
+
+    /// Adds a sample and returns the mean of the window
+    fn push(&mut self, sample: i64) -> i64 {
+        if self.samples.len() == self.window {
+            self.sum -= self.samples.pop_front().unwrap();
+        }
+        self.samples.push_back(sample);
+        self.sum += sample;
+        self.sum / self.samples.len() as i64
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    dout_offset: u32,
+    sck_offset: u32,
+    #[serde(default = "Config::default_gain")]
+    gain: u32, // 128 or 64 for channel A, 32 for channel B
+    #[serde(default = "Config::default_window")]
+    window: usize, // readings in the moving average
+    #[serde(default = "Config::default_interval")]
+    interval: u64, // ms between state updates
+    #[serde(default)]
+    offset: i64, // initial value of the offset parameter
+    #[serde(default = "Config::default_scale")]
+    scale: f64, // initial value of the scale parameter
+}
+
+impl Config {
+    fn default_gain() -> u32 {
+        128
+    }
+
+    fn default_window() -> usize {
+        10
+    }
+
+    fn default_interval() -> u64 {
+        1000
+    }
+
+    fn default_scale() -> f64 {
+        1.0
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_negative_readings() {
+        assert_eq!(decode(0x000001), 1);
+        assert_eq!(decode(0x7FFFFF), 8_388_607);
+        assert_eq!(decode(0xFFFFFF), -1);
+        assert_eq!(decode(0x800000), -8_388_608);
+    }
+
+    #[test]
+    fn moving_average_drops_old_samples() {
+        let mut average = MovingAverage::new(3);
+        assert_eq!(average.push(3), 3);
+        assert_eq!(average.push(6), 4);
+        assert_eq!(average.push(9), 6);
+        assert_eq!(average.push(12), 9);
+    }
+}
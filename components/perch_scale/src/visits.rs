@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI64, Ordering}};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, report_fault, error::ClientError};
+use super::{proto, Calibration, Hx711, MovingAverage};
+
+/// Weighs birds on a perch-mounted load cell once per visit. A visit lasts
+/// while the weight is above `min_weight`; its weight is the median of the
+/// readings taken while the load was steady, so landing and take-off do not
+/// bias it. One state message is sent at the end of each visit, carrying the
+/// identity given by clients during the visit, if any.
+pub struct PerchVisits {
+    raw: Arc<AtomicI64>, // filtered counts, for taring
+    params: Arc<Mutex<proto::VisitParams>>,
+    tag: Arc<Mutex<String>>,
+    state: Arc<Mutex<proto::VisitState>>,
+    occupied: Arc<AtomicBool>,
+    state_sender: Sender<Any>,
+    stop: Arc<AtomicBool>,
+    reader: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::VisitState, params = proto::VisitParams, config = VisitConfig)]
+#[async_trait]
+impl Component for PerchVisits {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        PerchVisits {
+            raw: Arc::new(AtomicI64::new(0)),
+            params: Arc::new(Mutex::new(proto::VisitParams {
+                offset: config.offset,
+                scale: config.scale,
+                tare: false,
+                min_weight: config.min_weight,
+                tolerance: config.tolerance,
+            })),
+            tag: Arc::new(Mutex::new(String::new())),
+            state: Arc::new(Mutex::new(proto::VisitState::default())),
+            occupied: Arc::new(AtomicBool::new(false)),
+            state_sender,
+            stop: Arc::new(AtomicBool::new(false)),
+            reader: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let hx711 = Hx711::new(&config.chip, config.dout_offset, config.sck_offset, config.gain)?;
+        let raw = self.raw.clone();
+        let params = self.params.clone();
+        let tag = self.tag.clone();
+        let state = self.state.clone();
+        let occupied = self.occupied.clone();
+        let sender = self.state_sender.clone();
+        let stop = self.stop.clone();
+        let mut average = MovingAverage::new(config.window);
+        let mut bouts = Bouts::new(config.window, config.min_duration, config.release);
+        self.reader = Some(thread::spawn(move || {
+            let start = Instant::now();
+            let mut started_ms = 0;
+            while !stop.load(Ordering::Acquire) {
+                let reading = match hx711.read() {
+                    Ok(Some(reading)) => reading,
+                    Ok(None) => continue,
+                    Err(e) => return report_fault(&sender, e),
+                };
+                raw.store(average.push(reading), Ordering::Release);
+                let (grams, min_weight, tolerance) = {
+                    let params = params.lock().unwrap();
+                    let calibration = Calibration { offset: params.offset, scale: params.scale };
+                    (calibration.grams(reading), params.min_weight, params.tolerance)
+                };
+                let visit = bouts.push(start.elapsed().as_millis() as u64, grams, min_weight, tolerance);
+                if bouts.occupied() && !occupied.swap(true, Ordering::AcqRel) {
+                    started_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+                    tracing::debug!("PerchVisits Perch Occupied");
+                }
+                if bouts.occupied() {
+                    continue
+                }
+                occupied.store(false, Ordering::Release);
+                let visit = match visit {
+                    Some(visit) => visit,
+                    None => continue,
+                };
+                let message = {
+                    let mut state = state.lock().unwrap();
+                    *state = proto::VisitState {
+                        tag: std::mem::take(&mut *tag.lock().unwrap()),
+                        grams: visit.grams,
+                        samples: visit.samples,
+                        start_ms: started_ms,
+                        duration_ms: visit.duration as u32,
+                        occupied: false,
+                    };
+                    tracing::info!("PerchVisits Visit by {:?} of {:?} ms Weighed {:.2} g",
+                                   state.tag, state.duration_ms, state.grams);
+                    Self::pack_state(&state)
+                };
+                if let Err(e) = sender.blocking_send(message) {
+                    return report_fault(&sender, e)
+                }
+            }
+            if let Err(e) = hx711.power_down() {
+                tracing::error!("PerchVisits could not power down the HX711: {}", e);
+            }
+        }));
+        tracing::info!("PerchVisits Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // the identity is attached to the visit in progress, or to the next one
+        // if the tag was read before the bird settled
+        if !state.tag.is_empty() {
+            tracing::debug!("PerchVisits Tagged {:?}", state.tag);
+            *self.tag.lock().unwrap() = state.tag;
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, mut params: Self::Params) -> decide_protocol::Result<()> {
+        if params.scale == 0.0 || !params.scale.is_finite() {
+            tracing::error!("PerchVisits scale must be a nonzero number of counts per gram");
+            return Err(ClientError::InvalidParams.into())
+        }
+        if params.tolerance <= 0.0 || params.tolerance.is_nan() {
+            tracing::error!("PerchVisits tolerance must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        if params.tare {
+            params.offset = self.raw.load(Ordering::Acquire);
+            params.tare = false;
+            tracing::info!("PerchVisits Tared at {:?} counts", params.offset);
+        }
+        *self.params.lock().unwrap() = params;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        Self::State {
+            occupied: self.occupied.load(Ordering::Acquire),
+            ..self.state.lock().unwrap().clone()
+        }
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.lock().unwrap().clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("PerchVisits reader thread", self.reader.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for PerchVisits");
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            match tokio::task::spawn_blocking(move || reader.join()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => tracing::error!("PerchVisits reader thread panicked"),
+                Err(e) => tracing::error!("PerchVisits reader thread could not be joined: {}", e),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Visit {
+    duration: u64, // ms
+    grams: f64,
+    samples: u32,
+}
+
+struct Bout {
+    start: u64, // ms
+    last: u64, // ms, time of the last reading above the minimum weight
+    stable: Vec<f64>,
+}
+
+/// Splits the readings into bouts of occupancy
+struct Bouts {
+    window: usize, // readings that must agree for the latest to be stable
+    min_duration: u64, // ms, shorter bouts are not visits
+    release: u64, // ms below the minimum weight that end a bout
+    recent: VecDeque<f64>,
+    current: Option<Bout>,
+}
+
+impl Bouts {
+    fn new(window: usize, min_duration: u64, release: u64) -> Self {
+        let window = window.max(1);
+        Bouts { window, min_duration, release, recent: VecDeque::with_capacity(window), current: None }
+    }
+
+    fn occupied(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Adds a reading taken at `time` ms, and returns the visit it ends, if any
+    fn push(&mut self, time: u64, grams: f64, min_weight: f64, tolerance: f64) -> Option<Visit> {
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(grams);
+        if grams >= min_weight {
+            let bout = self.current.get_or_insert(Bout { start: time, last: time, stable: Vec::new() });
+            bout.last = time;
+            let (min, max) = self.recent.iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &g| (min.min(g), max.max(g)));
+            if self.recent.len() == self.window && max - min <= tolerance {
+                bout.stable.push(grams);
+            }
+            return None
+        }
+        match &self.current {
+            Some(bout) if time - bout.last >= self.release => {
+                let mut bout = self.current.take().unwrap();
+                let duration = bout.last - bout.start;
+                if duration < self.min_duration {
+                    tracing::trace!("PerchVisits ignored a bout of {:?} ms", duration);
+                    return None
+                }
+                Some(Visit {
+                    duration,
+                    grams: median(&mut bout.stable).unwrap_or(0.0),
+                    samples: bout.stable.len() as u32,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = values.len() / 2;
+    if values.len() % 2 == 1 {
+        Some(values[mid])
+    } else {
+        Some((values[mid - 1] + values[mid]) / 2.0)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VisitConfig {
+    chip: String,
+    dout_offset: u32,
+    sck_offset: u32,
+    #[serde(default = "VisitConfig::default_gain")]
+    gain: u32, // 128 or 64 for channel A, 32 for channel B
+    #[serde(default = "VisitConfig::default_window")]
+    window: usize, // readings that must agree within the tolerance to be stable
+    #[serde(default = "VisitConfig::default_min_duration")]
+    min_duration: u64, // ms, shorter bouts are not reported
+    #[serde(default = "VisitConfig::default_release")]
+    release: u64, // ms the weight must stay below min_weight to end a visit
+    #[serde(default)]
+    offset: i64, // initial value of the offset parameter
+    #[serde(default = "VisitConfig::default_scale")]
+    scale: f64, // initial value of the scale parameter
+    min_weight: f64, // g, initial value of the min_weight parameter
+    #[serde(default = "VisitConfig::default_tolerance")]
+    tolerance: f64, // g, initial value of the tolerance parameter
+}
+
+impl VisitConfig {
+    fn default_gain() -> u32 {
+        128
+    }
+
+    fn default_window() -> usize {
+        10
+    }
+
+    fn default_min_duration() -> u64 {
+        1000
+    }
+
+    fn default_release() -> u64 {
+        500
+    }
+
+    fn default_scale() -> f64 {
+        1.0
+    }
+
+    fn default_tolerance() -> f64 {
+        0.5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visit_weighed_from_stable_readings() {
+        let mut bouts = Bouts::new(3, 100, 40);
+        // landing transient, then steady around 25 g
+        let readings = [0.0, 40.0, 30.0, 25.1, 24.9, 25.0, 25.2, 10.0, 0.0];
+        for (i, &grams) in readings.iter().enumerate() {
+            assert_eq!(bouts.push(i as u64 * 20, grams, 5.0, 0.5), None);
+        }
+        assert!(bouts.occupied());
+        let visit = bouts.push(180, 0.0, 5.0, 0.5).unwrap();
+        assert_eq!((visit.duration, visit.samples), (120, 2));
+        assert!((visit.grams - 25.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn short_bouts_are_not_visits() {
+        let mut bouts = Bouts::new(1, 100, 0);
+        bouts.push(0, 20.0, 5.0, 0.5);
+        assert!(bouts.occupied());
+        assert_eq!(bouts.push(50, 0.0, 5.0, 0.5), None);
+        assert!(!bouts.occupied());
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), Some(2.0));
+    }
+}
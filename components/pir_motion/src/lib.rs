@@ -0,0 +1,234 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+                EventType
+};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use tokio::{self, task::JoinHandle, time::Duration};
+
+/// Passive infrared motion detector for general activity monitoring. The
+/// detector output is grouped into bouts of activity: a bout starts at the
+/// first trigger and ends once the output has stayed low for the hold-off
+/// period, so re-triggers within a bout do not produce messages of their own.
+pub struct PirMotion {
+    activity: Arc<Mutex<Activity>>,
+    holdoff: Arc<AtomicU32>, // ms
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Activity {
+    active: bool,
+    start: u64, // ns
+    end: u64, // ns, last falling edge
+    triggers: u32,
+    bouts: u64,
+}
+
+impl Activity {
+    /// Counts a trigger and returns true if it starts a new bout
+    fn trigger(&mut self, timestamp: u64) -> bool {
+        let started = !self.active;
+        if started {
+            self.active = true;
+            self.start = timestamp;
+            self.end = timestamp;
+            self.triggers = 0;
+            self.bouts += 1;
+        }
+        self.triggers += 1;
+        started
+    }
+
+    fn release(&mut self, timestamp: u64) {
+        self.end = timestamp;
+    }
+
+    fn finish(&mut self) {
+        self.active = false;
+    }
+
+    fn state(&self) -> proto::MotionState {
+        proto::MotionState {
+            active: self.active,
+            start_ns: self.start,
+            duration_ms: if self.active { 0 } else { self.end.saturating_sub(self.start) / 1_000_000 },
+            triggers: self.triggers,
+            bouts: self.bouts,
+        }
+    }
+}
+
+#[component(state = proto::MotionState, params = proto::MotionParams, config = Config)]
+#[async_trait]
+impl Component for PirMotion {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        PirMotion {
+            activity: Arc::new(Mutex::new(Activity::default())),
+            holdoff: Arc::new(AtomicU32::new(config.holdoff)),
+            state_sender: sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let events = AsyncLineEventHandle::new(
+            chip.get_line(config.offset)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "pir_motion")
+                .map_err(|e| DecideError::Component { source: e.into() })?
+        ).map_err(|e| DecideError::Component { source: e.into() })?;
+        let activity = self.activity.clone();
+        let holdoff = self.holdoff.clone();
+        let sender = self.state_sender.clone();
+        self.task_handle = Some(tokio::spawn(async move {
+            PirMotion::watch(events, activity, holdoff, sender).await
+        }));
+        tracing::info!("PIR-Motion Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // activity comes from the detector, so the only valid request is a reset
+        if state != Self::State::default() {
+            tracing::error!("PIR-Motion state cannot be changed by request");
+            return Err(ClientError::InvalidState.into())
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        self.holdoff.store(params.holdoff_ms, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.activity.lock().unwrap().state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            holdoff_ms: self.holdoff.load(Ordering::Acquire),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("PirMotion task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![Resource::line(&config.chip, config.offset)]
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for PIR-Motion");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("PIR-Motion task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl PirMotion {
+    async fn watch(mut events: AsyncLineEventHandle, activity: Arc<Mutex<Activity>>,
+                   holdoff: Arc<AtomicU32>, sender: Sender<Any>) {
+        loop {
+            let active = activity.lock().unwrap().active;
+            let event = if active {
+                let holdoff = Duration::from_millis(holdoff.load(Ordering::Acquire) as u64);
+                tokio::time::timeout(holdoff, events.next()).await
+            } else {
+                Ok(events.next().await)
+            };
+            let state = match event {
+                Ok(None) => break,
+                Ok(Some(event)) => {
+                    let event = event.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                    let mut activity = activity.lock().unwrap();
+                    match event.event_type() {
+                        EventType::RisingEdge if activity.trigger(event.timestamp()) => {
+                            tracing::info!("PIR-Motion Bout Started");
+                            activity.state()
+                        }
+                        EventType::RisingEdge => continue,
+                        EventType::FallingEdge => {
+                            activity.release(event.timestamp());
+                            continue
+                        }
+                    }
+                }
+                Err(_) => {
+                    // a detector held high by continuous motion keeps the bout going
+                    let level = events.as_ref().get_value()
+                        .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                    if level != 0 {
+                        continue
+                    }
+                    let mut activity = activity.lock().unwrap();
+                    activity.finish();
+                    let state = activity.state();
+                    tracing::info!("PIR-Motion Bout Ended After {:?} ms and {:?} Triggers",
+                                   state.duration_ms, state.triggers);
+                    state
+                }
+            };
+            sender.send(Self::pack_state(&state)).await.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    offset: u32, // detector output, high while motion is detected
+    #[serde(default = "Config::default_holdoff")]
+    holdoff: u32, // ms, initial value of the holdoff_ms parameter
+}
+
+impl Config {
+    fn default_holdoff() -> u32 {
+        5000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retriggers_extend_bout() {
+        let mut activity = Activity::default();
+        assert!(activity.trigger(1_000_000_000));
+        activity.release(2_000_000_000);
+        assert!(!activity.trigger(3_000_000_000));
+        activity.release(4_500_000_000);
+        assert_eq!(activity.state().duration_ms, 0);
+        activity.finish();
+        let state = activity.state();
+        assert_eq!((state.duration_ms, state.triggers, state.bouts), (3500, 2, 1));
+        assert!(activity.trigger(9_000_000_000));
+        assert_eq!((activity.state().triggers, activity.state().bouts), (1, 2));
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}};
+use std::time::Duration;
+use async_trait::async_trait;
+use decide_hal::{linux, I2c};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, task::JoinHandle, time::sleep};
+use decide_protocol::{component, Component, ComponentHealth, error::{ClientError, DecideError}};
+
+/// Monitors the supply of a box from an INA219 on the I2C bus, for field
+/// deployments on battery power. Readings are published periodically, with an
+/// alarm flag while the voltage is low so the experiment can be shut down
+/// before the battery gives out.
+pub struct PowerMonitor {
+    state: Arc<Mutex<proto::PowerState>>,
+    interval: Arc<AtomicU32>, // ms
+    low_voltage: Arc<Mutex<f64>>, // V
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+#[component(state = proto::PowerState, params = proto::PowerParams, config = Config)]
+#[async_trait]
+impl Component for PowerMonitor {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        PowerMonitor {
+            state: Arc::new(Mutex::new(proto::PowerState::default())),
+            interval: Arc::new(AtomicU32::new(config.interval)),
+            low_voltage: Arc::new(Mutex::new(config.low_voltage)),
+            state_sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let ina219 = linux::i2c(&config.bus, config.address)
+            .and_then(|dev| Ina219::new(Box::new(dev)))
+            .map_err(|e| DecideError::Component { source: e })?;
+        let ina219 = Arc::new(Mutex::new(ina219));
+        let state = self.state.clone();
+        let interval = self.interval.clone();
+        let low_voltage = self.low_voltage.clone();
+        let sender = self.state_sender.clone();
+        let shunt = config.shunt;
+        let hysteresis = config.hysteresis;
+
+        self.task_handle = Some(tokio::spawn(async move {
+            loop {
+                let ina219 = ina219.clone();
+                // I2C transfers block, so keep them off the runtime
+                let reading = tokio::task::spawn_blocking(move || ina219.lock().unwrap().read(shunt))
+                    .await
+                    .map_err(|e| DecideError::Component { source: e.into() })
+                    .unwrap();
+                match reading {
+                    Ok(reading) => {
+                        let message = {
+                            let mut state = state.lock().unwrap();
+                            let threshold = *low_voltage.lock().unwrap();
+                            let alarm = low_voltage_alarm(state.low_voltage_alarm, reading.voltage, threshold, hysteresis);
+                            if alarm && !state.low_voltage_alarm {
+                                tracing::warn!("Power-Monitor Supply Low at {:.2} V", reading.voltage);
+                            } else if !alarm && state.low_voltage_alarm {
+                                tracing::info!("Power-Monitor Supply Recovered at {:.2} V", reading.voltage);
+                            }
+                            *state = proto::PowerState {
+                                voltage: reading.voltage,
+                                current: reading.current,
+                                power: reading.voltage * reading.current,
+                                low_voltage_alarm: alarm,
+                            };
+                            Self::pack_state(&state)
+                        };
+                        sender.send(message).await
+                            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                    }
+                    Err(e) => tracing::error!("Power-Monitor Read Failed: {}", e),
+                }
+                sleep(Duration::from_millis(interval.load(Ordering::Acquire) as u64)).await;
+            }
+        }));
+        tracing::info!("Power-Monitor Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval_ms == 0 {
+            tracing::error!("Power-Monitor interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // takes effect after the current wait
+        self.interval.store(params.interval_ms, Ordering::Release);
+        *self.low_voltage.lock().unwrap() = params.low_voltage;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            interval_ms: self.interval.load(Ordering::Acquire),
+            low_voltage: *self.low_voltage.lock().unwrap(),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Power Monitor task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Power-Monitor");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Power-Monitor task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether the alarm should be raised, given whether it is now. Once
+/// raised, it stays up until the voltage is `hysteresis` above the threshold,
+/// so that the sag under load does not toggle it.
+fn low_voltage_alarm(raised: bool, voltage: f64, threshold: f64, hysteresis: f64) -> bool {
+    if raised {
+        voltage < threshold + hysteresis
+    } else {
+        voltage < threshold
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reading {
+    voltage: f64, // V
+    current: f64, // A
+}
+
+/// TI INA219 in continuous mode. The current is computed from the shunt
+/// voltage rather than through the calibration register.
+struct Ina219 {
+    dev: Box<dyn I2c>,
+}
+
+impl Ina219 {
+    const CONFIG: u8 = 0x00;
+    const SHUNT_VOLTAGE: u8 = 0x01;
+    const BUS_VOLTAGE: u8 = 0x02;
+
+    fn new(mut dev: Box<dyn I2c>) -> decide_hal::Result<Self> {
+        // 32 V bus range, 320 mV shunt range, 12-bit conversions of both, continuous
+        dev.write(&[Ina219::CONFIG, 0x39, 0x9F])?;
+        Ok(Ina219 { dev })
+    }
+
+    fn register(&mut self, register: u8) -> decide_hal::Result<u16> {
+        let mut buf = [0u8; 2];
+        self.dev.write_read(&[register], &mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn read(&mut self, shunt: f64) -> Result<Reading, Box<dyn std::error::Error + Send + Sync>> {
+        let shunt_voltage = self.register(Ina219::SHUNT_VOLTAGE)?;
+        let bus_voltage = self.register(Ina219::BUS_VOLTAGE)?;
+        if bus_voltage & 0x01 != 0 {
+            return Err("INA219 power or current out of range".into())
+        }
+        Ok(decode(shunt_voltage, bus_voltage, shunt))
+    }
+}
+
+/// Converts the shunt voltage (signed, 10 uV per bit) and bus voltage (upper 13
+/// bits, 4 mV per bit) registers
+fn decode(shunt_voltage: u16, bus_voltage: u16, shunt: f64) -> Reading {
+    Reading {
+        voltage: (bus_voltage >> 3) as f64 * 0.004,
+        current: shunt_voltage as i16 as f64 * 10e-6 / shunt,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_bus")]
+    bus: String,
+    #[serde(default = "Config::default_address")]
+    address: u16, // 0x40-0x4F depending on the address pins
+    #[serde(default = "Config::default_shunt")]
+    shunt: f64, // ohms
+    #[serde(default = "Config::default_hysteresis")]
+    hysteresis: f64, // V above low_voltage the supply must recover to clear the alarm
+    #[serde(default = "Config::default_interval")]
+    interval: u32, // ms, initial value of the interval_ms parameter
+    low_voltage: f64, // V, initial value of the low_voltage parameter
+}
+
+impl Config {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_address() -> u16 {
+        0x40
+    }
+
+    fn default_shunt() -> f64 {
+        0.1
+    }
+
+    fn default_hysteresis() -> f64 {
+        0.2
+    }
+
+    fn default_interval() -> u32 {
+        10000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_decode_to_volts_and_amps() {
+        // 12.0 V on the bus with the CNVR bit set, and -5 mV across 0.1 ohms
+        let reading = decode(-500i16 as u16, 3000 << 3 | 0x02, 0.1);
+        assert!((reading.voltage - 12.0).abs() < 1e-9);
+        assert!((reading.current + 0.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn alarm_has_hysteresis() {
+        assert!(!low_voltage_alarm(false, 11.6, 11.5, 0.2));
+        assert!(low_voltage_alarm(false, 11.4, 11.5, 0.2));
+        assert!(low_voltage_alarm(true, 11.6, 11.5, 0.2));
+        assert!(!low_voltage_alarm(true, 11.8, 11.5, 0.2));
+    }
+
+    #[test]
+    fn ina219_configures_and_reads_both_registers() {
+        let bus = decide_hal::mock::MockI2c::default();
+        let mut ina219 = Ina219::new(Box::new(bus.clone())).unwrap();
+        assert_eq!(bus.written(), vec![vec![Ina219::CONFIG, 0x39, 0x9F]]);
+        bus.queue_reply(&(-500i16).to_be_bytes());
+        bus.queue_reply(&(3000u16 << 3 | 0x02).to_be_bytes());
+        let reading = ina219.read(0.1).unwrap();
+        assert!((reading.voltage - 12.0).abs() < 1e-9);
+        assert!((reading.current + 0.05).abs() < 1e-9);
+        assert_eq!(&bus.written()[1..], &[vec![Ina219::SHUNT_VOLTAGE], vec![Ina219::BUS_VOLTAGE]]);
+    }
+}
@@ -0,0 +1,343 @@
+use chrono::NaiveDate;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::thread;
+use std::time::Instant;
+use tokio::time::Duration;
+
+/// Syringe or peristaltic pump delivering doses of liquid reward. Clients
+/// request a volume, which is converted to steps or run time with the
+/// calibration parameter. Doses that would take the day's total over the
+/// configured maximum are refused.
+pub struct Pump {
+    pump: Option<Arc<Doser>>, // set up in init
+    calibration: Arc<Mutex<f64>>,
+    daily_max: f64, // uL
+    state_sender: Sender<Any>,
+}
+
+enum Drive {
+    /// step input of a stepper driver, pulsed once per step
+    Stepper { step: Arc<LineHandle>, interval: Duration },
+    /// a line switching a DC motor on for the length of the dose
+    Dc(LineHandle),
+}
+
+struct Doser {
+    drive: Drive,
+    dose: Mutex<Option<f64>>, // uL, while a dose is running
+    totals: Mutex<Totals>,
+    epoch: AtomicU64, // bumped by every stop request to cancel the running dose
+}
+
+impl Doser {
+    /// Time between checks for a stop request while a DC pump runs
+    const POLL: Duration = Duration::from_millis(5);
+    /// Width of the step pulses
+    const PULSE: Duration = Duration::from_micros(10);
+
+    fn line(line: &LineHandle, value: u8) -> decide_protocol::Result<()> {
+        line.set_value(value)
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+
+    /// Pulses the step line until `steps` have been taken or the dose is
+    /// cancelled, and returns the number taken. Blocks for the whole dose.
+    fn step(&self, line: &LineHandle, interval: Duration, epoch: u64, steps: u64) -> decide_protocol::Result<u64> {
+        for taken in 0..steps {
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                return Ok(taken)
+            }
+            Doser::line(line, 1)?;
+            thread::sleep(Doser::PULSE);
+            Doser::line(line, 0)?;
+            thread::sleep(interval);
+        }
+        Ok(steps)
+    }
+
+    /// Delivers `volume` unless the dose is cancelled, and returns the volume
+    /// actually delivered
+    async fn deliver(self: Arc<Self>, epoch: u64, volume: f64, calibration: f64) -> decide_protocol::Result<f64> {
+        match &self.drive {
+            Drive::Stepper { step, interval } => {
+                let (doser, line, interval) = (self.clone(), step.clone(), *interval);
+                // step timing is too fine for the runtime's timers
+                let taken = tokio::task::spawn_blocking(move || doser.step(&line, interval, epoch, steps(volume, calibration)))
+                    .await
+                    .map_err(|e| DecideError::Component { source: e.into() })??;
+                Ok(taken as f64 / calibration)
+            }
+            Drive::Dc(line) => {
+                let duration = Duration::from_secs_f64(volume / calibration);
+                let start = Instant::now();
+                Doser::line(line, 1)?;
+                while self.epoch.load(Ordering::Acquire) == epoch && start.elapsed() < duration {
+                    tokio::time::sleep(Doser::POLL.min(duration.saturating_sub(start.elapsed()))).await;
+                }
+                Doser::line(line, 0)?;
+                Ok(start.elapsed().min(duration).as_secs_f64() * calibration)
+            }
+        }
+    }
+
+    fn stop(&self) -> decide_protocol::Result<()> {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        if let Drive::Dc(line) = &self.drive {
+            Doser::line(line, 0)?;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> proto::PumpState {
+        let dose = *self.dose.lock().unwrap();
+        let mut totals = self.totals.lock().unwrap();
+        totals.roll(today());
+        proto::PumpState {
+            running: dose.is_some(),
+            dose_ul: dose.unwrap_or(0.0),
+            delivered_ul: totals.session,
+            today_ul: totals.today,
+        }
+    }
+
+    fn message(&self) -> Any {
+        Pump::pack_state(&self.state())
+    }
+}
+
+fn today() -> NaiveDate {
+    chrono::Local::now().naive_local().date()
+}
+
+/// Steps needed to deliver `volume` at `calibration` steps per uL
+fn steps(volume: f64, calibration: f64) -> u64 {
+    (volume * calibration).round() as u64
+}
+
+/// Volume delivered since startup and since the start of the current day
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Totals {
+    session: f64,
+    today: f64,
+    day: NaiveDate,
+}
+
+impl Totals {
+    fn new(day: NaiveDate) -> Self {
+        Totals { session: 0.0, today: 0.0, day }
+    }
+
+    /// Starts a new daily total once the date changes
+    fn roll(&mut self, day: NaiveDate) {
+        if day != self.day {
+            self.day = day;
+            self.today = 0.0;
+        }
+    }
+
+    fn allows(&mut self, day: NaiveDate, volume: f64, daily_max: f64) -> bool {
+        self.roll(day);
+        self.today + volume <= daily_max
+    }
+
+    fn add(&mut self, day: NaiveDate, volume: f64) {
+        self.roll(day);
+        self.session += volume;
+        self.today += volume;
+    }
+}
+
+#[component(state = proto::PumpState, params = proto::PumpParams, config = Config)]
+#[async_trait]
+impl Component for Pump {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        Pump {
+            pump: None,
+            calibration: Arc::new(Mutex::new(config.calibration)),
+            daily_max: config.daily_max,
+            state_sender: sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut request = |offset: u32| chip.get_line(offset)
+            .map_err(|e| DecideError::Component { source: e.into() })?
+            .request(LineRequestFlags::OUTPUT, 0, "pump")
+            .map_err(|e| DecideError::Component { source: e.into() });
+        let drive = match config.drive {
+            DriveConfig::Stepper { offset, interval } => Drive::Stepper {
+                step: Arc::new(request(offset)?),
+                interval: Duration::from_micros(interval),
+            },
+            DriveConfig::Dc { offset } => Drive::Dc(request(offset)?),
+        };
+        self.pump = Some(Arc::new(Doser {
+            drive,
+            dose: Mutex::new(None),
+            totals: Mutex::new(Totals::new(today())),
+            epoch: AtomicU64::new(0),
+        }));
+        tracing::info!("Pump Initiated with Daily Maximum of {:?} uL", self.daily_max);
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let pump = self.pump.clone().ok_or(ClientError::InvalidState)?;
+        if state.dose_ul == 0.0 {
+            if pump.dose.lock().unwrap().is_some() {
+                tracing::info!("Pump Dose Stopped by Request");
+                pump.stop()?;
+            }
+            return Ok(())
+        }
+        if !state.dose_ul.is_finite() || state.dose_ul < 0.0 {
+            tracing::error!("Pump dose {:?} uL is not valid", state.dose_ul);
+            return Err(ClientError::InvalidState.into())
+        }
+        let mut dose = pump.dose.lock().unwrap();
+        if dose.is_some() {
+            tracing::error!("Pump is still delivering a dose");
+            return Err(ClientError::Busy.into())
+        }
+        if !pump.totals.lock().unwrap().allows(today(), state.dose_ul, self.daily_max) {
+            tracing::error!("Pump dose of {:?} uL would exceed the daily maximum of {:?} uL",
+                            state.dose_ul, self.daily_max);
+            return Err(ClientError::InvalidState.into())
+        }
+        *dose = Some(state.dose_ul);
+        drop(dose);
+        let epoch = pump.epoch.load(Ordering::Acquire);
+        let calibration = *self.calibration.lock().unwrap();
+        let sender = self.state_sender.clone();
+        tracing::info!("Pump Delivering {:?} uL by Request", state.dose_ul);
+        tokio::spawn(async move {
+            // the controller may already be shutting down, but the dose is delivered regardless
+            let _ = sender.send(pump.message()).await;
+            let delivered = match pump.clone().deliver(epoch, state.dose_ul, calibration).await {
+                Ok(delivered) => delivered,
+                Err(e) => {
+                    *pump.dose.lock().unwrap() = None;
+                    return report_fault(&sender, e)
+                }
+            };
+            pump.totals.lock().unwrap().add(today(), delivered);
+            *pump.dose.lock().unwrap() = None;
+            tracing::info!("Pump Delivered {:.1} uL", delivered);
+            let _ = sender.send(pump.message()).await;
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if !params.calibration.is_finite() || params.calibration <= 0.0 {
+            tracing::error!("Pump calibration must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // applies to the next dose
+        *self.calibration.lock().unwrap() = params.calibration;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.pump.as_ref().map_or_else(Default::default, |pump| pump.state())
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            calibration: *self.calibration.lock().unwrap(),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        let line = match self.pump.as_ref().map(|pump| &pump.drive) {
+            Some(Drive::Stepper { step, .. }) => step.as_ref(),
+            Some(Drive::Dc(line)) => line,
+            None => return ComponentHealth::Healthy,
+        };
+        match line.get_value() {
+            Ok(_) => ComponentHealth::Healthy,
+            Err(e) => ComponentHealth::Failed(format!("Pump drive line cannot be read: {}", e)),
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        match config.drive {
+            DriveConfig::Stepper { offset, .. } | DriveConfig::Dc { offset } => vec![Resource::line(&config.chip, offset)],
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Pump");
+        if let Some(Err(e)) = self.pump.as_ref().map(|pump| pump.stop()) {
+            tracing::error!("Pump could not be stopped: {}", e);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DriveConfig {
+    Stepper {
+        offset: u32, // step input of the driver
+        #[serde(default = "DriveConfig::default_interval")]
+        interval: u64, // us between steps
+    },
+    Dc {
+        offset: u32,
+    },
+}
+
+impl DriveConfig {
+    fn default_interval() -> u64 {
+        1000
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    drive: DriveConfig,
+    calibration: f64, // initial value of the calibration parameter
+    daily_max: f64, // uL that can be delivered between midnights
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_converts_to_steps() {
+        assert_eq!(steps(10.0, 32.0), 320);
+        assert_eq!(steps(2.5, 3.0), 8);
+        assert_eq!(steps(0.01, 32.0), 0);
+    }
+
+    #[test]
+    fn daily_total_resets_at_midnight() {
+        let monday = NaiveDate::from_ymd_opt(2022, 10, 3).unwrap();
+        let tuesday = NaiveDate::from_ymd_opt(2022, 10, 4).unwrap();
+        let mut totals = Totals::new(monday);
+        totals.add(monday, 900.0);
+        assert!(totals.allows(monday, 100.0, 1000.0));
+        assert!(!totals.allows(monday, 100.5, 1000.0));
+        assert!(totals.allows(tuesday, 1000.0, 1000.0));
+        totals.add(tuesday, 50.0);
+        assert_eq!(totals, Totals { session: 950.0, today: 50.0, day: tuesday });
+    }
+}
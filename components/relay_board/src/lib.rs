@@ -0,0 +1,249 @@
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::time::Duration;
+
+/// Multi-channel relay board for mains-powered devices such as heat lamps and
+/// pumps. Each relay can have a maximum continuous on-time, after which it is
+/// switched off, and relays in the same interlock group are never on together.
+pub struct RelayBoard {
+    relays: Arc<Vec<Relay>>,
+    interlocks: Vec<(String, Vec<usize>)>, // indices into relays
+    state_sender: Sender<Any>,
+}
+
+struct Relay {
+    name: String,
+    handle: LineHandle,
+    max_on: Option<Duration>,
+    epoch: AtomicU64, // bumped on every switch to cancel the running cutoff timer
+}
+
+impl Relay {
+    fn level(&self) -> decide_protocol::Result<bool> {
+        let value = self.handle.get_value()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(value != 0)
+    }
+
+    fn set(&self, active: bool) -> decide_protocol::Result<()> {
+        self.handle.set_value(active as u8)
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+}
+
+fn relay_state(relays: &[Relay], cutoff: &str) -> proto::RelayState {
+    proto::RelayState {
+        // a relay that cannot be read is left out, and reported by the health check
+        relays: relays.iter().filter_map(|r| Some((r.name.clone(), r.level().ok()?))).collect(),
+        cutoff: cutoff.into(),
+    }
+}
+
+/// Returns the name of an interlock group with more than one relay on, and the
+/// relays involved
+fn conflict<'a>(on: &[bool], interlocks: &'a [(String, Vec<usize>)]) -> Option<(&'a str, Vec<usize>)> {
+    interlocks.iter().find_map(|(group, indices)| {
+        let active: Vec<usize> = indices.iter().copied().filter(|&i| on[i]).collect();
+        if active.len() > 1 { Some((group.as_str(), active)) } else { None }
+    })
+}
+
+#[component(state = proto::RelayState, params = proto::RelayParams, config = Config)]
+#[async_trait]
+impl Component for RelayBoard {
+    fn new(_config: Self::Config, sender: Sender<Any>) -> Self {
+        RelayBoard {
+            relays: Arc::new(Vec::new()),
+            interlocks: Vec::new(),
+            state_sender: sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut interlocks = Vec::with_capacity(config.interlocks.len());
+        for (group, names) in config.interlocks.iter() {
+            let indices = names.iter()
+                .map(|name| config.relays.iter().position(|r| &r.name == name)
+                    .ok_or_else(|| {
+                        tracing::error!("RelayBoard interlock {:?} refers to unknown relay {:?}", group, name);
+                        DecideError::Component {
+                            source: std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                                        "unknown relay in relay_board interlock").into()
+                        }
+                    }))
+                .collect::<decide_protocol::Result<_>>()?;
+            interlocks.push((group.clone(), indices));
+        }
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        // relays always start off
+        let mut relays = Vec::with_capacity(config.relays.len());
+        for relay in config.relays.iter() {
+            let flags = if relay.active_low {
+                LineRequestFlags::OUTPUT | LineRequestFlags::ACTIVE_LOW
+            } else {
+                LineRequestFlags::OUTPUT
+            };
+            let handle = chip.get_line(relay.offset)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .request(flags, 0, "relay_board")
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+            relays.push(Relay {
+                name: relay.name.clone(),
+                handle,
+                max_on: relay.max_on.map(|ms| Duration::from_millis(ms as u64)),
+                epoch: AtomicU64::new(0),
+            });
+        }
+        self.relays = Arc::new(relays);
+        self.interlocks = interlocks;
+        tracing::info!("RelayBoard Initiated with {:?} relays", self.relays.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // resolve every name and check the interlocks against the levels after
+        // the whole request before switching anything
+        let mut on = self.relays.iter().map(Relay::level).collect::<decide_protocol::Result<Vec<bool>>>()?;
+        let mut changes = Vec::new();
+        for (name, &active) in state.relays.iter() {
+            match self.relays.iter().position(|r| &r.name == name) {
+                Some(index) => {
+                    on[index] = active;
+                    changes.push((index, active));
+                }
+                None => {
+                    tracing::error!("RelayBoard relay {:?} does not exist", name);
+                    return Err(ClientError::InvalidState.into())
+                }
+            }
+        }
+        if let Some((group, active)) = conflict(&on, &self.interlocks) {
+            let names: Vec<&str> = active.iter().map(|&i| self.relays[i].name.as_str()).collect();
+            tracing::error!("RelayBoard interlock {:?} would have {:?} on together", group, names);
+            return Err(ClientError::InvalidState.into())
+        }
+        // switch off first so interlocked relays are never on together, even briefly
+        changes.sort_by_key(|&(_, active)| active);
+        for (index, active) in changes {
+            let relay = &self.relays[index];
+            // repeating a request must not restart the on-time limit
+            if relay.level()? == active {
+                continue
+            }
+            let epoch = relay.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+            relay.set(active)?;
+            if let (true, Some(max_on)) = (active, relay.max_on) {
+                let relays = self.relays.clone();
+                let sender = self.state_sender.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(max_on).await;
+                    let relay = &relays[index];
+                    if relay.epoch.load(Ordering::Acquire) != epoch {
+                        return
+                    }
+                    if let Err(e) = relay.set(false) {
+                        return report_fault(&sender, e)
+                    }
+                    tracing::warn!("RelayBoard {:?} Switched Off After {:?} ms", relay.name, max_on.as_millis());
+                    sender
+                        .send(Self::pack_state(&relay_state(&relays, &relay.name)))
+                        .await
+                        .map_err(|e| DecideError::Component { source: e.into() })
+                        .unwrap();
+                });
+            }
+        }
+        let sender = self.state_sender.clone();
+        let state = self.get_state();
+        tokio::spawn(async move {
+            sender
+                .send(Self::pack_state(&state))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+            tracing::info!("RelayBoard State Changed by Request");
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        relay_state(&self.relays, "")
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        match self.relays.iter().find_map(|relay| relay.handle.get_value().err().map(|e| (&relay.name, e))) {
+            Some((name, e)) => ComponentHealth::Failed(format!("RelayBoard relay {:?} cannot be read: {}", name, e)),
+            None => ComponentHealth::Healthy,
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.chip, config.relays.iter().map(|relay| &relay.offset))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for RelayBoard");
+        for relay in self.relays.iter() {
+            relay.epoch.fetch_add(1, Ordering::AcqRel);
+            if let Err(e) = relay.set(false) {
+                tracing::error!("RelayBoard {:?} could not be switched off: {}", relay.name, e);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RelayConfig {
+    name: String, // used in state messages, e.g. "heat_lamp"
+    offset: u32,
+    #[serde(default)]
+    active_low: bool, // the line is driven low to energize the relay
+    max_on: Option<u32>, // ms the relay may stay on before it is switched off
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    relays: Vec<RelayConfig>,
+    #[serde(default)]
+    interlocks: HashMap<String, Vec<String>>, // group name -> relays that may not be on together
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interlocks_allow_one_relay_per_group() {
+        let interlocks = vec![
+            (String::from("heat"), vec![0, 1]),
+            (String::from("water"), vec![2, 3]),
+        ];
+        assert_eq!(conflict(&[true, false, false, true], &interlocks), None);
+        assert_eq!(conflict(&[true, false, true, true], &interlocks), Some(("water", vec![2, 3])));
+        assert_eq!(conflict(&[false; 4], &interlocks), None);
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use nix::sys::termios::{self, BaudRate, SetArg, SpecialCharacterIndices};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::DecideError};
+
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+
+/// Identifies animals from the tags read by a UART RFID module. A tag that stays
+/// in range is reported once, and again only after it has been out of range
+/// for the repeat window.
+pub struct RfidReader {
+    state: Arc<Mutex<proto::RfidState>>,
+    state_sender: Sender<Any>,
+    stop: Arc<AtomicBool>,
+    reader: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::RfidState, params = proto::RfidParams, config = Config)]
+#[async_trait]
+impl Component for RfidReader {
+    fn new(_config: Self::Config, state_sender: Sender<Any>) -> Self {
+        RfidReader {
+            state: Arc::new(Mutex::new(proto::RfidState::default())),
+            state_sender,
+            stop: Arc::new(AtomicBool::new(false)),
+            reader: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let port = RfidReader::open_port(&config.port, config.baud)?;
+        tracing::info!("RFID Reader Initiated on {:?}", config.port);
+        let state = self.state.clone();
+        let sender = self.state_sender.clone();
+        let stop = self.stop.clone();
+        self.reader = Some(thread::spawn(move || {
+            let mut port = port;
+            let mut framer = Framer::new(config.format.frame_len());
+            let repeat = Duration::from_millis(config.repeat);
+            let mut last: Option<(String, Instant)> = None;
+            let mut buf = [0u8; 64];
+            while !stop.load(Ordering::Acquire) {
+                // returns 0 when the read times out, so that stop is checked regularly
+                let n = match port.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) => return report_fault(&sender, e),
+                };
+                for &byte in &buf[..n] {
+                    let tag = match framer.push(byte).and_then(|frame| config.format.parse(&frame)) {
+                        Some(tag) => tag,
+                        None => continue,
+                    };
+                    let now = Instant::now();
+                    let repeated = matches!(&last, Some((prev, at)) if *prev == tag && now - *at < repeat);
+                    last = Some((tag.clone(), now));
+                    if repeated {
+                        continue
+                    }
+                    tracing::info!("RFID Tag {:?} Read", tag);
+                    let message = {
+                        let mut state = state.lock().unwrap();
+                        state.tag = tag;
+                        state.timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH)
+                            .unwrap_or_default().as_millis() as u64;
+                        state.reads += 1;
+                        Self::pack_state(&state)
+                    };
+                    if sender.blocking_send(message).is_err() {
+                        return
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("RFID reader thread", self.reader.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![Resource::Device(config.port.clone())]
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for RFID Reader");
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            match tokio::task::spawn_blocking(move || reader.join()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => tracing::error!("RFID Reader thread panicked"),
+                Err(e) => tracing::error!("RFID Reader thread could not be joined: {}", e),
+            }
+        }
+    }
+}
+
+impl RfidReader {
+    /// Opens the serial port in raw mode with reads that time out after 0.5 s
+    fn open_port(path: &str, baud: u32) -> decide_protocol::Result<File> {
+        let baud = match baud {
+            9600 => BaudRate::B9600,
+            19200 => BaudRate::B19200,
+            38400 => BaudRate::B38400,
+            57600 => BaudRate::B57600,
+            115200 => BaudRate::B115200,
+            _ => {
+                tracing::error!("RFID Reader baud rate {:?} is not supported", baud);
+                return Err(DecideError::Component {
+                    source: std::io::Error::new(std::io::ErrorKind::InvalidInput, "unsupported baud rate").into()
+                })
+            }
+        };
+        let port = OpenOptions::new().read(true).write(true).open(path)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut tty = termios::tcgetattr(port.as_raw_fd())
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        termios::cfmakeraw(&mut tty);
+        termios::cfsetspeed(&mut tty, baud)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        tty.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        tty.control_chars[SpecialCharacterIndices::VTIME as usize] = 5; // tenths of a second
+        termios::tcsetattr(port.as_raw_fd(), SetArg::TCSANOW, &tty)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(port)
+    }
+}
+
+/// Splits the byte stream from the module into fixed-length frames that start
+/// with STX and end with ETX. The length is used rather than the ETX byte because
+/// binary checksums can contain either marker.
+struct Framer {
+    len: usize,
+    buf: Vec<u8>,
+}
+
+impl Framer {
+    fn new(len: usize) -> Self {
+        Framer { len, buf: Vec::with_capacity(len) }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if self.buf.is_empty() && byte != STX {
+            return None
+        }
+        self.buf.push(byte);
+        if self.buf.len() < self.len {
+            return None
+        }
+        let frame = std::mem::take(&mut self.buf);
+        if frame[self.len - 1] == ETX {
+            return Some(frame)
+        }
+        // lost sync: start over from the next STX inside the bad frame
+        tracing::trace!("RFID Reader dropped a malformed frame");
+        if let Some(i) = frame[1..].iter().position(|&b| b == STX) {
+            self.buf.extend_from_slice(&frame[i + 1..]);
+        }
+        None
+    }
+}
+
+/// Frame format of the RFID module
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagFormat {
+    /// 125 kHz modules such as the RDM6300: STX, 10 hex digits, 2 hex digits of
+    /// checksum, ETX
+    Em4100,
+    /// 134.2 kHz ISO 11784/5 modules such as the WL-134: STX, 10 hex digits of
+    /// national ID and 4 of country code (both least significant digit first),
+    /// 12 flag and reserved bytes, checksum, inverted checksum, ETX
+    FdxB,
+}
+
+impl TagFormat {
+    fn frame_len(&self) -> usize {
+        match self {
+            TagFormat::Em4100 => 14,
+            TagFormat::FdxB => 30,
+        }
+    }
+
+    /// Returns the tag ID in a frame, or None if its checksum does not match
+    fn parse(&self, frame: &[u8]) -> Option<String> {
+        let hex = |digits: &[u8]| std::str::from_utf8(digits).ok()
+            .and_then(|s| u64::from_str_radix(s, 16).ok());
+        match self {
+            TagFormat::Em4100 => {
+                let data = &frame[1..11];
+                let checksum = (0..5)
+                    .map(|i| hex(&data[2 * i..2 * i + 2]))
+                    .try_fold(0u64, |acc, byte| byte.map(|b| acc ^ b))?;
+                if hex(&frame[11..13])? != checksum {
+                    return None
+                }
+                std::str::from_utf8(data).ok().map(|s| s.to_uppercase())
+            }
+            TagFormat::FdxB => {
+                let checksum = frame[1..27].iter().fold(0u8, |acc, b| acc ^ b);
+                if frame[27] != checksum || frame[28] != !checksum {
+                    return None
+                }
+                let reversed = |digits: &[u8]| digits.iter().rev().copied().collect::<Vec<u8>>();
+                let national = hex(&reversed(&frame[1..11]))?;
+                let country = hex(&reversed(&frame[11..15]))?;
+                Some(format!("{:03}{:012}", country, national))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    port: String, // serial device, e.g. /dev/ttyS1
+    #[serde(default = "Config::default_baud")]
+    baud: u32,
+    format: TagFormat, // "em4100" or "fdx_b"
+    #[serde(default = "Config::default_repeat")]
+    repeat: u64, // ms a tag must be out of range before it is reported again
+}
+
+impl Config {
+    fn default_baud() -> u32 {
+        9600
+    }
+
+    fn default_repeat() -> u64 {
+        1000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(format: TagFormat, bytes: &[u8]) -> Vec<Option<String>> {
+        let mut framer = Framer::new(format.frame_len());
+        bytes.iter()
+            .filter_map(|&b| framer.push(b))
+            .map(|frame| format.parse(&frame))
+            .collect()
+    }
+
+    #[test]
+    fn em4100_frame() {
+        let frame = b"\x020B001A2B3C06\x03";
+        assert_eq!(frames(TagFormat::Em4100, frame), vec![Some(String::from("0B001A2B3C"))]);
+    }
+
+    #[test]
+    fn em4100_bad_checksum() {
+        let frame = b"\x020B001A2B3C07\x03";
+        assert_eq!(frames(TagFormat::Em4100, frame), vec![None]);
+    }
+
+    #[test]
+    fn fdx_b_frame() {
+        let mut frame = b"\x020F30000000".to_vec();
+        frame.extend_from_slice(b"7E3010000000000");
+        frame.extend_from_slice(b"0");
+        frame.extend_from_slice(&[0x05, 0xfa, ETX]);
+        assert_eq!(frames(TagFormat::FdxB, &frame), vec![Some(String::from("999000000001008"))]);
+    }
+
+    #[test]
+    fn resyncs_after_noise() {
+        let mut bytes = b"\x02\x0B0\x03".to_vec();
+        bytes.extend_from_slice(b"\x020B001A2B3C06\x03");
+        assert_eq!(frames(TagFormat::Em4100, &bytes), vec![Some(String::from("0B001A2B3C"))]);
+    }
+}
@@ -0,0 +1,307 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+                EventType
+};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use tokio::{self, task::JoinHandle, time::Duration};
+
+/// Quadrature rotary encoder, e.g. on a running wheel. Position is tracked from
+/// the edges of the two channels, and position and velocity are published
+/// periodically while the encoder turns.
+pub struct RotaryEncoder {
+    decoder: Arc<Mutex<Decoder>>,
+    counts_per_rev: f64,
+    interval: Arc<AtomicU32>, // ms
+    state_sender: Sender<Any>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Channel {
+    A,
+    B,
+    Index,
+}
+
+/// Counts the transitions of the A and B channels. A leads B in the forward
+/// direction, so the channels step through 00, 10, 11, 01.
+#[derive(Default)]
+struct Decoder {
+    a: bool,
+    b: bool,
+    count: i64, // never zeroed, so velocity is unaffected by zeroing
+    origin: i64, // count at the last zeroing
+    index: Option<i64>, // count at the first index pulse
+    errors: u64,
+}
+
+impl Decoder {
+    fn update(&mut self, channel: Channel, level: bool, counts_per_rev: i64) {
+        match channel {
+            Channel::A if level != self.a => {
+                self.a = level;
+                self.count += if self.a != self.b { 1 } else { -1 };
+            }
+            Channel::B if level != self.b => {
+                self.b = level;
+                self.count += if self.a == self.b { 1 } else { -1 };
+            }
+            Channel::Index if level => match self.index {
+                None => self.index = Some(self.count),
+                Some(index) => {
+                    // realign to a whole number of revolutions from the first index pulse
+                    let offset = (self.count - index).rem_euclid(counts_per_rev);
+                    let correction = if offset > counts_per_rev / 2 { counts_per_rev - offset } else { -offset };
+                    if correction != 0 {
+                        tracing::debug!("Rotary-Encoder Corrected by {:?} Counts at Index", correction);
+                        self.count += correction;
+                    }
+                }
+            },
+            Channel::Index => (),
+            // the line changed twice since the last event
+            _ => self.errors += 1,
+        }
+    }
+
+    fn position(&self) -> i64 {
+        self.count - self.origin
+    }
+}
+
+#[component(state = proto::EncoderState, params = proto::EncoderParams, config = Config)]
+#[async_trait]
+impl Component for RotaryEncoder {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        if config.counts_per_rev == 0 {
+            tracing::error!("Rotary-Encoder counts_per_rev must be positive");
+            panic!("invalid rotary encoder counts_per_rev")
+        }
+        RotaryEncoder {
+            decoder: Arc::new(Mutex::new(Decoder::default())),
+            counts_per_rev: config.counts_per_rev as f64,
+            interval: Arc::new(AtomicU32::new(config.interval)),
+            state_sender: sender,
+            task_handles: Vec::new(),
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut lines = vec![(Channel::A, config.a_offset), (Channel::B, config.b_offset)];
+        lines.extend(config.index_offset.map(|offset| (Channel::Index, offset)));
+        for (channel, offset) in lines {
+            let events = AsyncLineEventHandle::new(
+                chip.get_line(offset)
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+                    .events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "rotary_encoder")
+                    .map_err(|e| DecideError::Component { source: e.into() })?
+            ).map_err(|e| DecideError::Component { source: e.into() })?;
+            let level = events.as_ref().get_value()
+                .map_err(|e| DecideError::Component { source: e.into() })? != 0;
+            // swapping the channels reverses the direction
+            let channel = match (channel, config.reverse) {
+                (Channel::A, true) => Channel::B,
+                (Channel::B, true) => Channel::A,
+                (channel, _) => channel,
+            };
+            match channel {
+                Channel::A => self.decoder.lock().unwrap().a = level,
+                Channel::B => self.decoder.lock().unwrap().b = level,
+                Channel::Index => (),
+            }
+            let decoder = self.decoder.clone();
+            let counts_per_rev = config.counts_per_rev as i64;
+            self.task_handles.push(tokio::spawn(async move {
+                RotaryEncoder::watch(channel, events, counts_per_rev, decoder).await
+            }));
+        }
+
+        let decoder = self.decoder.clone();
+        let interval = self.interval.clone();
+        let counts_per_rev = self.counts_per_rev;
+        let sender = self.state_sender.clone();
+        self.task_handles.push(tokio::spawn(async move {
+            let mut last = decoder.lock().unwrap().count;
+            let mut moving = false;
+            loop {
+                let period = Duration::from_millis(interval.load(Ordering::Acquire) as u64);
+                tokio::time::sleep(period).await;
+                let state = {
+                    let decoder = decoder.lock().unwrap();
+                    let velocity = (decoder.count - last) as f64 / counts_per_rev / period.as_secs_f64();
+                    last = decoder.count;
+                    state(&decoder, counts_per_rev, velocity)
+                };
+                // one more update once the encoder stops, with zero velocity
+                if state.velocity == 0.0 && !moving {
+                    continue
+                }
+                moving = state.velocity != 0.0;
+                sender.send(Self::pack_state(&state)).await.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            }
+        }));
+        tracing::info!("Rotary-Encoder Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        if state.zero {
+            let mut decoder = self.decoder.lock().unwrap();
+            decoder.origin = decoder.count;
+            tracing::info!("Rotary-Encoder Zeroed by Request");
+        }
+        let state = self.get_state();
+        let sender = self.state_sender.clone();
+        tokio::spawn(async move {
+            sender
+                .send(Self::pack_state(&state))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval_ms == 0 {
+            tracing::error!("Rotary-Encoder interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        self.interval.store(params.interval_ms, Ordering::Release);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        // velocity is only measured by the reporting task
+        state(&self.decoder.lock().unwrap(), self.counts_per_rev, 0.0)
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            interval_ms: self.interval.load(Ordering::Acquire),
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("RotaryEncoder task", self.task_handles.iter().any(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        let mut resources = Resource::lines(&config.chip, &[config.a_offset, config.b_offset]);
+        resources.extend(Resource::lines(&config.chip, &config.index_offset));
+        resources
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Rotary-Encoder");
+        for task_handle in self.task_handles.drain(..) {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Rotary-Encoder task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl RotaryEncoder {
+    /// Feeds the events on one line to the decoder until its event stream closes
+    async fn watch(channel: Channel, mut events: AsyncLineEventHandle, counts_per_rev: i64,
+                   decoder: Arc<Mutex<Decoder>>) {
+        while let Some(event) = events.next().await {
+            let event = event.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            let level = event.event_type() == EventType::RisingEdge;
+            decoder.lock().unwrap().update(channel, level, counts_per_rev);
+        }
+    }
+}
+
+fn state(decoder: &Decoder, counts_per_rev: f64, velocity: f64) -> proto::EncoderState {
+    proto::EncoderState {
+        position: decoder.position(),
+        revolutions: decoder.position() as f64 / counts_per_rev,
+        velocity,
+        zero: false,
+        errors: decoder.errors,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    a_offset: u32,
+    b_offset: u32,
+    index_offset: Option<u32>, // once per revolution; used to correct missed counts
+    counts_per_rev: u32, // edges on both channels per revolution, 4x the encoder's PPR
+    #[serde(default)]
+    reverse: bool, // count B leading A as forward
+    #[serde(default = "Config::default_interval")]
+    interval: u32, // ms, initial value of the interval_ms parameter
+}
+
+impl Config {
+    fn default_interval() -> u32 {
+        100
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_both_directions() {
+        let mut decoder = Decoder::default();
+        // forward through 10, 11, 01, 00
+        decoder.update(Channel::A, true, 4);
+        decoder.update(Channel::B, true, 4);
+        decoder.update(Channel::A, false, 4);
+        decoder.update(Channel::B, false, 4);
+        assert_eq!(decoder.position(), 4);
+        // and back
+        decoder.update(Channel::B, true, 4);
+        decoder.update(Channel::A, true, 4);
+        assert_eq!(decoder.position(), 2);
+        assert_eq!(decoder.errors, 0);
+    }
+
+    #[test]
+    fn missed_edges_are_errors() {
+        let mut decoder = Decoder::default();
+        decoder.update(Channel::A, true, 4);
+        decoder.update(Channel::A, true, 4);
+        assert_eq!((decoder.position(), decoder.errors), (1, 1));
+    }
+
+    #[test]
+    fn index_realigns_to_whole_revolutions() {
+        let mut decoder = Decoder { count: 3, ..Default::default() };
+        decoder.update(Channel::Index, true, 100);
+        decoder.count += 98;
+        decoder.update(Channel::Index, false, 100);
+        decoder.update(Channel::Index, true, 100);
+        assert_eq!(decoder.count, 103);
+        decoder.count += 103;
+        decoder.update(Channel::Index, true, 100);
+        assert_eq!(decoder.count, 203);
+    }
+}
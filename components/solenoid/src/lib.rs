@@ -0,0 +1,227 @@
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Instant;
+use tokio::{self, time::Duration};
+
+/// Valves or feeders driven by GPIO lines. A solenoid opened by a client closes
+/// itself after the requested duration, which can never exceed `max_open`.
+pub struct Solenoid {
+    valves: Arc<Valves>,
+    max_open: Duration,
+    state_sender: Sender<Any>,
+}
+
+struct Valves {
+    valves: Vec<Valve>,
+}
+
+struct Valve {
+    name: String,
+    line: LineHandle,
+    opened: Mutex<Option<Instant>>, // None while closed
+    total: AtomicU64, // us open, not counting the current opening
+    epoch: AtomicU64, // bumped on every open or close to cancel pending timers
+}
+
+impl Valve {
+    /// Opens the solenoid, or keeps it open, and returns the epoch of this opening
+    fn open(&self) -> decide_protocol::Result<u64> {
+        self.line.set_value(1)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut opened = self.opened.lock().unwrap();
+        if opened.is_none() {
+            *opened = Some(Instant::now());
+        }
+        Ok(self.epoch.fetch_add(1, Ordering::AcqRel) + 1)
+    }
+
+    fn close(&self) -> decide_protocol::Result<()> {
+        self.line.set_value(0)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        if let Some(opened) = self.opened.lock().unwrap().take() {
+            self.total.fetch_add(opened.elapsed().as_micros() as u64, Ordering::AcqRel);
+        }
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.opened.lock().unwrap().is_some()
+    }
+
+    fn total_us(&self) -> u64 {
+        let current = self.opened.lock().unwrap()
+            .map_or(0, |opened| opened.elapsed().as_micros() as u64);
+        self.total.load(Ordering::Acquire) + current
+    }
+}
+
+impl Valves {
+    fn state(&self) -> proto::SolState {
+        proto::SolState {
+            open: self.valves.iter().map(|v| (v.name.clone(), v.is_open())).collect(),
+            duration_ms: Default::default(),
+            total_us: self.valves.iter().map(|v| (v.name.clone(), v.total_us())).collect(),
+        }
+    }
+
+    fn send_state(&self, sender: &Sender<Any>) {
+        let sender = sender.clone();
+        let message = Solenoid::pack_state(&self.state());
+        tokio::spawn(async move {
+            sender.send(message).await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+        });
+    }
+}
+
+#[component(state = proto::SolState, params = proto::SolParams, config = Config)]
+#[async_trait]
+impl Component for Solenoid {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        Solenoid {
+            valves: Arc::new(Valves { valves: Vec::new() }),
+            max_open: Duration::from_millis(config.max_open),
+            state_sender: sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let mut valves = Vec::with_capacity(config.solenoids.len());
+        for solenoid in config.solenoids.iter() {
+            let flags = if solenoid.active_low {
+                LineRequestFlags::OUTPUT | LineRequestFlags::ACTIVE_LOW
+            } else {
+                LineRequestFlags::OUTPUT
+            };
+            let line = chip.get_line(solenoid.offset)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .request(flags, 0, "solenoid")
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+            valves.push(Valve {
+                name: solenoid.name.clone(),
+                line,
+                opened: Mutex::new(None),
+                total: AtomicU64::new(0),
+                epoch: AtomicU64::new(0),
+            });
+        }
+        self.valves = Arc::new(Valves { valves });
+        tracing::info!("Solenoid Initiated with {:?} solenoids", self.valves.valves.len());
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // validate everything before touching any line
+        let mut changes = Vec::with_capacity(state.open.len());
+        for (name, &open) in state.open.iter() {
+            let index = match self.valves.valves.iter().position(|v| &v.name == name) {
+                Some(index) => index,
+                None => {
+                    tracing::error!("Solenoid {:?} does not exist", name);
+                    return Err(ClientError::InvalidState.into())
+                }
+            };
+            let duration = state.duration_ms.get(name)
+                .map_or(self.max_open, |&ms| Duration::from_millis(ms as u64));
+            if open && (duration.is_zero() || duration > self.max_open) {
+                tracing::error!("Solenoid {:?} open duration {:?} must be between 0 and {:?}",
+                                name, duration, self.max_open);
+                return Err(ClientError::InvalidState.into())
+            }
+            changes.push((index, open, duration));
+        }
+        for (index, open, duration) in changes {
+            let valve = &self.valves.valves[index];
+            if !open {
+                valve.close()?;
+                tracing::info!("Solenoid {:?} Closed by Request", valve.name);
+                continue
+            }
+            let epoch = valve.open()?;
+            // measured from the line change rather than from when the timer task runs
+            let deadline = tokio::time::Instant::now() + duration;
+            tracing::info!("Solenoid {:?} Opened for {:?}", valve.name, duration);
+            let valves = self.valves.clone();
+            let sender = self.state_sender.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep_until(deadline).await;
+                let valve = &valves.valves[index];
+                // a later open or close has taken over this solenoid
+                if valve.epoch.load(Ordering::Acquire) != epoch {
+                    return
+                }
+                if let Err(e) = valve.close() {
+                    return report_fault(&sender, e)
+                }
+                tracing::info!("Solenoid {:?} Closed", valve.name);
+                valves.send_state(&sender);
+            });
+        }
+        self.valves.send_state(&self.state_sender);
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.valves.state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        match self.valves.valves.iter().find_map(|valve| valve.line.get_value().err().map(|e| (&valve.name, e))) {
+            Some((name, e)) => ComponentHealth::Failed(format!("Solenoid valve {:?} cannot be read: {}", name, e)),
+            None => ComponentHealth::Healthy,
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.chip, config.solenoids.iter().map(|solenoid| &solenoid.offset))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Solenoid");
+        for valve in self.valves.valves.iter() {
+            if let Err(e) = valve.close() {
+                tracing::error!("Solenoid {:?} could not be closed: {}", valve.name, e);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SolenoidConfig {
+    name: String, // used in state messages, e.g. "feeder"
+    offset: u32,
+    #[serde(default)]
+    active_low: bool, // the line is driven low to open
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    solenoids: Vec<SolenoidConfig>,
+    max_open: u64, // ms; no opening lasts longer than this
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
@@ -3,16 +3,16 @@ use std::ffi::OsString;
 use std::sync::{Arc, mpsc as std_mpsc, Mutex};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::thread;
+use std::time::Instant;
 
 use alsa::{Direction, pcm::PCM};
 use async_trait::async_trait;
 use atomic_wait::{wait, wake_all};
-use prost::Message;
 use prost_types::Any;
 use tokio::{self, sync::mpsc::Sender as tkSender};
 
-use decide_protocol::{Component,
-                      error::DecideError
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault,
+                      error::{ClientError, DecideError}
 };
 
 mod tasklets;
@@ -25,20 +25,19 @@ pub struct AlsaPlayback {
     sample_rate: Arc<AtomicU32>,
     playback: Arc<AtomicU32>, //pause or resume
     frames: Arc<AtomicU32>,
+    position: Arc<AtomicU32>, // samples written of the current stim
+    gain: Arc<AtomicU32>, // dB, as f32 bits
+    requested: Arc<Mutex<Instant>>, // time of the last playback request
+    latency: Arc<AtomicU32>, // us from request to first write
     playback_queue: Arc<Mutex<HashMap<OsString, (Vec<i16>, u32)>>>,
     state_sender: tkSender<Any>,
     import_switch: Arc<AtomicU32>,
     shutdown: Option<(std::thread::JoinHandle<()>,std_mpsc::Sender<bool>)>,
 }
 
+#[component(state = proto::SaState, params = proto::SaParams, config = tasklets::Config)]
 #[async_trait]
 impl Component for AlsaPlayback {
-    type State = proto::SaState;
-    type Params = proto::SaParams;
-    type Config = tasklets::Config;
-    const STATE_TYPE_URL: &'static str = "type.googleapis.com/SaState";
-    const PARAMS_TYPE_URL: &'static str = "type.googleapis.com/SaParams";
-
     fn new(_config: Self::Config, state_sender: tkSender<Any>) -> Self {
 
         AlsaPlayback{
@@ -49,6 +48,10 @@ impl Component for AlsaPlayback {
             sample_rate: Arc::new(AtomicU32::new(0)),
             playback: Arc::new(AtomicU32::new(0)), //0: Stopped, 1: Playing
             frames: Arc::new(AtomicU32::new(0)),
+            position: Arc::new(AtomicU32::new(0)),
+            gain: Arc::new(AtomicU32::new(0f32.to_bits())),
+            requested: Arc::new(Mutex::new(Instant::now())),
+            latency: Arc::new(AtomicU32::new(0)),
             playback_queue: Arc::new(Mutex::new(HashMap::new())),
             state_sender,
             import_switch: Arc::new(AtomicU32::new(1)),
@@ -56,11 +59,15 @@ impl Component for AlsaPlayback {
         }
     }
 
-    async fn init(&mut self, config: Self::Config) {
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
         // Playback state message
         let audio_id = self.audio_id.clone();
         let playback = self.playback.clone();
         let frames = self.frames.clone();
+        let position = self.position.clone();
+        let latency = self.latency.clone();
+        let gain = self.gain.clone();
+        let requested = self.requested.clone();
         // Playback thread communication
         let sender = self.state_sender.clone();
         let queue = self.playback_queue.clone();
@@ -76,22 +83,27 @@ impl Component for AlsaPlayback {
             }
         };
         self.sample_rate.store(config.sample_rate, Ordering::Release);
+        // claim the device here, so a missing or busy one fails init
+        let audio_dev = PCM::new(&config.audio_device.clone(), Direction::Playback, false)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        tracing::debug!("AlsaPlayback - pcm device created on {:?}", config.audio_device.clone());
+        tasklets::get_hw_config(&audio_dev, &config).map_err(device_error)?;
         //playback thread
         let handle = thread::spawn(move || {
             tracing::info!("Sound-Alsa: Playback Thread created");
-            let audio_dev = PCM::new(&config.audio_device.clone(), Direction::Playback, false)
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-            tracing::debug!("AlsaPlayback - pcm device created on {:?}", config.audio_device.clone());
-            tasklets::get_hw_config(&audio_dev, &config).unwrap();
             // let mut mmap = audio_dev.direct_mmap_playback::<i16>();
-            let mut io = audio_dev.io_i16()
-                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            let mut io = match audio_dev.io_i16() {
+                Ok(io) => io,
+                Err(e) => return report_fault(&sender, e),
+            };
             tracing::debug!("IO acquired");
 
             'stim: loop {
                 // Check shutdown
                 if sd_rx.try_recv().unwrap_err() == std_mpsc::TryRecvError::Disconnected {
-                    audio_dev.drop().map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                    if let Err(e) = audio_dev.drop() {
+                        tracing::error!("Sound-Alsa: could not drain playback: {}", e);
+                    }
                     break};
 
                 tracing::debug!("Sound_alsa - Awaiting import switch");
@@ -112,37 +124,58 @@ impl Component for AlsaPlayback {
 
                 let frame_count = data.1.clone();
                 frames.store(frame_count.clone(), Ordering::Release);
+                position.store(0, Ordering::Release);
+                let amplitude = 10f32.powf(f32::from_bits(gain.load(Ordering::Acquire)) / 20.0);
+                let requested_at = *requested.lock().unwrap();
 
-                Self::send_state(sender.clone(), Self::State {
-                    audio_id: stim_name.clone().into_string().unwrap(),
-                    playback: true,
-                    frame_count: frame_count.clone()
-                });
                 match audio_dev.prepare() {
                     Ok(n) => n,
                     Err(e) => {
                         tracing::warn!("Audio-playback failed to prepare for playback\
                                         , recovering from {}", e);
-                        audio_dev.recover(e.errno() as std::os::raw::c_int, true)
-                            .unwrap();
+                        if let Err(e) = audio_dev.recover(e.errno() as std::os::raw::c_int, true) {
+                            playback.store(0, Ordering::Release);
+                            return report_fault(&sender, e);
+                        }
                     }
                 }
-                if !tasklets::playback_io(&audio_dev, &mut io, &data.0,
-                                          frame_count, &playback).unwrap() {
-                    continue 'stim
+                // the playing state goes out once the first samples are on their way to the device
+                let started = || {
+                    let elapsed = requested_at.elapsed().as_micros() as u32;
+                    latency.store(elapsed, Ordering::Release);
+                    tracing::debug!("Sound-Alsa: Playback latency {:?} us", elapsed);
+                    Self::send_state(sender.clone(), Self::State {
+                        audio_id: stim_name.clone().into_string().unwrap(),
+                        playback: true,
+                        frame_count: frame_count.clone(),
+                        frame_position: position.load(Ordering::Acquire),
+                        latency_us: elapsed,
+                    });
+                };
+                match tasklets::playback_io(&audio_dev, &mut io, &data.0, amplitude,
+                                            &playback, &position, started) {
+                    Ok(true) => {}
+                    Ok(false) => continue 'stim,
+                    Err(e) => {
+                        playback.store(0, Ordering::Release);
+                        return report_fault(&sender, device_error(e));
+                    }
                 }
                 tracing::info!("Sound-Alsa: Playback Completed!");
-                // playback finished without interruption. Send info about completed stim
+                // playback finished or was stopped. Send info about how far the stim got
                 Self::send_state(sender.clone(), Self::State {
                     audio_id: stim_name.clone().into_string().unwrap(),
                     playback: false,
-                    frame_count: frame_count.clone()
+                    frame_count: frame_count.clone(),
+                    frame_position: position.load(Ordering::Acquire),
+                    latency_us: latency.load(Ordering::Acquire),
                 });
                 playback.store(0, Ordering::Release);
                 //audio_dev.drop().unwrap();
             }
         });
         self.shutdown = Some((handle, sd_tx));
+        Ok(())
     }
 
     fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
@@ -155,6 +188,7 @@ impl Component for AlsaPlayback {
                     0 => {
                         let mut audio_id = self.audio_id.lock().unwrap(); // this will block if playback is underway
                         *audio_id = state.audio_id;
+                        *self.requested.lock().unwrap() = Instant::now();
                         self.playback.store(1, Ordering::Release);
                         let pb = self.playback.as_ref();
                         wake_all(pb);
@@ -183,11 +217,17 @@ impl Component for AlsaPlayback {
     }
 
     fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if !params.gain_db.is_finite() {
+            tracing::error!("Sound-Alsa gain must be a finite number of dB, got {:?}", params.gain_db);
+            return Err(ClientError::InvalidParams.into())
+        }
         //stop playback & initiate import when params are changed:
         self.playback.store(0, Ordering::Release);
+        // applies from the next stim
+        self.gain.store(params.gain_db.to_bits(), Ordering::Release);
 
         //import
-        let current_conf: String = self.conf_path.lock().unwrap().drain(..).collect();
+        let current_conf: String = self.conf_path.lock().unwrap().clone();
 
         tracing::info!("Current Playback Directory :{:?}, Requested Directory {:?}",
             current_conf.clone(), params.conf_path);
@@ -214,6 +254,8 @@ impl Component for AlsaPlayback {
             audio_id: self.audio_id.lock().unwrap().clone(),
             playback: if self.playback.load(Ordering::Acquire)==1 {true} else {false},
             frame_count: self.frames.load(Ordering::Acquire),
+            frame_position: self.position.load(Ordering::Acquire),
+            latency_us: self.latency.load(Ordering::Acquire),
         }
     }
 
@@ -222,9 +264,18 @@ impl Component for AlsaPlayback {
             conf_path: self.conf_path.lock().unwrap().clone(),
             audio_count: self.audio_count.load(Ordering::Relaxed),
             sample_rate: self.sample_rate.load(Ordering::Relaxed),
+            gain_db: f32::from_bits(self.gain.load(Ordering::Acquire)),
         }
     }
 
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("AlsaPlayback thread", self.shutdown.as_ref().is_some_and(|(h, _)| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![Resource::Pcm { device: config.audio_device.clone(), capture: false }]
+    }
+
     async fn shutdown(&mut self) {
         tracing::info!("Sound-Alsa: Shutdown Called");
         if let Some((handle, sender)) = self.shutdown.take() {
@@ -234,12 +285,13 @@ impl Component for AlsaPlayback {
     }
 }
 
+fn device_error(message: String) -> DecideError {
+    DecideError::Component { source: std::io::Error::other(message).into() }
+}
+
 impl AlsaPlayback {
     fn send_state(sender: tkSender<Any>, state: proto::SaState) {
-        let message = Any {
-            value: state.encode_to_vec(),
-            type_url: Self::STATE_TYPE_URL.into(),
-        };
+        let message = Self::pack_state(&state);
         assert!(!sender.is_closed());
         sender.blocking_send(message)
             .map_err(|e| DecideError::Component { source: e.into() })
@@ -118,9 +118,17 @@ pub fn get_hw_config<'a>(pcm: &'a PCM, config: &'a Config) -> std::result::Resul
     Ok(true)
 }
 
-pub fn playback_io(pcm: &alsa::PCM, io: &mut alsa::pcm::IO<i16>, data: &Vec<i16>, frames: u32, playback: &Arc<AtomicU32>)
+/// Writes `data` to the device, scaled by `amplitude`, until it runs out or playback
+/// is stopped. `started` is called once the first samples have been written.
+pub fn playback_io(pcm: &alsa::PCM, io: &mut alsa::pcm::IO<i16>, data: &Vec<i16>, amplitude: f32,
+                   playback: &Arc<AtomicU32>, position: &AtomicU32, started: impl FnOnce())
                -> std::result::Result<bool, String> {
-    let frames: usize = frames.try_into().unwrap();
+    let frames = data.len();
+    let channels = pcm.hw_params_current()
+        .and_then(|hwp| hwp.get_channels())
+        .map_err(|e| e.to_string())? as usize;
+    let mut started = Some(started);
+    let mut scaled = Vec::with_capacity(512);
     let avail = match pcm.avail_update() {
         Ok(n) => n,
         Err(e) => {
@@ -134,7 +142,13 @@ pub fn playback_io(pcm: &alsa::PCM, io: &mut alsa::pcm::IO<i16>, data: &Vec<i16>
     let mut _written: usize = 0;
     //loop while playing
     while (pointer < frames-1) & (playback.load(Ordering::Acquire) == 1) {
-        let slice = if pointer+512>frames {&data[pointer..]} else {&data[pointer..pointer+512]};
+        let mut slice = if pointer+512>frames {&data[pointer..]} else {&data[pointer..pointer+512]};
+        if amplitude != 1.0 {
+            scaled.clear();
+            scaled.extend(slice.iter()
+                .map(|&s| (s as f32 * amplitude).clamp(i16::MIN as f32, i16::MAX as f32) as i16));
+            slice = &scaled;
+        }
         _written = match io.writei(slice) {
             Ok(n) => n,
             Err(e) => {
@@ -143,7 +157,14 @@ pub fn playback_io(pcm: &alsa::PCM, io: &mut alsa::pcm::IO<i16>, data: &Vec<i16>
                 0
             }
         };
-        pointer += _written;
+        // writei counts frames, while data is interleaved samples
+        pointer += _written * channels;
+        position.store(pointer as u32, Ordering::Release);
+        if _written > 0 {
+            if let Some(started) = started.take() {
+                started()
+            }
+        }
         match pcm.state() {
             State::Running => {
             }, // All fine
@@ -0,0 +1,382 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use async_trait::async_trait;
+use jack::{AudioOut, Client, ClientOptions, Control, LatencyType, Port, ProcessScope};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::{self, Sender}};
+
+use decide_protocol::{component, Component, ComponentHealth,
+                      error::{ClientError, DecideError}
+};
+
+mod stimuli;
+
+use stimuli::{Stimuli, Stimulus};
+
+/// Plays stimuli through a JACK server, for experiments that need lower and
+/// more consistent latency than ALSA playback gives. Clients can schedule the
+/// first frame of a stimulus at a JACK time, which is met to the sample.
+pub struct JackPlayback {
+    shared: Arc<Shared>,
+    stimuli: Stimuli,
+    conf_path: String,
+    gain_db: f32,
+    sample_rate: u32,
+    state_sender: Sender<Any>,
+    client: Option<jack::AsyncClient<Notifications, Process>>,
+    relay: Option<tokio::task::JoinHandle<()>>,
+}
+
+/// State shared with the JACK threads
+struct Shared {
+    playback: Mutex<Playback>,
+    latency: AtomicU32, // us
+    xruns: AtomicU32,
+}
+
+#[derive(Default)]
+struct Playback {
+    audio_id: String,
+    stimulus: Option<Arc<Stimulus>>, // None while stopped
+    start: Option<u32>, // requested frame time of the first frame; None for the next cycle
+    started: bool,
+    started_us: u64,
+    position: usize, // frames played
+    frame_count: usize,
+    gain: f32, // linear
+}
+
+impl Shared {
+    fn state(&self) -> proto::SjState {
+        let playback = self.playback.lock().unwrap();
+        proto::SjState {
+            audio_id: playback.audio_id.clone(),
+            playback: playback.stimulus.is_some(),
+            frame_count: playback.frame_count as u32,
+            frame_position: playback.position as u32,
+            start_us: playback.started_us,
+            latency_us: self.latency.load(Ordering::Acquire),
+            xruns: self.xruns.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// Sent from the process callback, which must not block on the state channel
+enum Event {
+    Started,
+    Finished,
+}
+
+struct Process {
+    ports: Vec<Port<AudioOut>>,
+    shared: Arc<Shared>,
+    events: Sender<Event>,
+}
+
+impl jack::ProcessHandler for Process {
+    fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
+        for port in self.ports.iter_mut() {
+            port.as_mut_slice(ps).fill(0.0);
+        }
+        // a cycle of silence is better than blocking the real-time thread
+        let mut playback = match self.shared.playback.try_lock() {
+            Ok(playback) => playback,
+            Err(_) => return Control::Continue,
+        };
+        let stimulus = match playback.stimulus.clone() {
+            Some(stimulus) => stimulus,
+            None => return Control::Continue,
+        };
+        let cycle = ps.last_frame_time();
+        let offset = if playback.started {
+            0
+        } else {
+            match start_offset(cycle, ps.n_frames(), playback.start) {
+                Some(offset) => offset,
+                None => return Control::Continue,
+            }
+        };
+        if !playback.started {
+            playback.started = true;
+            playback.started_us = client.frames_to_time(cycle.wrapping_add(offset as u32));
+            let _ = self.events.try_send(Event::Started);
+        }
+        let position = playback.position;
+        let frames = (ps.n_frames() as usize - offset).min(stimulus.frames() - position);
+        for (index, port) in self.ports.iter_mut().enumerate() {
+            // mono stimuli play on every port
+            let channel = &stimulus.channels[index.min(stimulus.channels.len() - 1)];
+            let out = &mut port.as_mut_slice(ps)[offset..offset + frames];
+            for (out, sample) in out.iter_mut().zip(&channel[position..position + frames]) {
+                *out = sample * playback.gain;
+            }
+        }
+        playback.position += frames;
+        if playback.position >= stimulus.frames() {
+            playback.stimulus = None;
+            let _ = self.events.try_send(Event::Finished);
+        }
+        Control::Continue
+    }
+}
+
+/// Returns the offset into the cycle that starts at frame time `cycle` at which
+/// a stimulus starting at `start` begins, or None if it starts in a later cycle.
+/// Start times that have already passed begin at once.
+fn start_offset(cycle: u32, n_frames: u32, start: Option<u32>) -> Option<usize> {
+    let start = match start {
+        Some(start) => start,
+        None => return Some(0),
+    };
+    // frame times wrap around, so they are compared by their difference
+    let delta = start.wrapping_sub(cycle) as i32;
+    if delta < 0 {
+        Some(0)
+    } else if (delta as u32) < n_frames {
+        Some(delta as usize)
+    } else {
+        None
+    }
+}
+
+struct Notifications {
+    shared: Arc<Shared>,
+}
+
+impl jack::NotificationHandler for Notifications {
+    fn xrun(&mut self, _: &Client) -> Control {
+        self.shared.xruns.fetch_add(1, Ordering::AcqRel);
+        tracing::warn!("Sound-Jack: Xrun reported by the server");
+        Control::Continue
+    }
+}
+
+#[component(state = proto::SjState, params = proto::SjParams, config = Config)]
+#[async_trait]
+impl Component for JackPlayback {
+    fn new(_config: Self::Config, state_sender: Sender<Any>) -> Self {
+        JackPlayback {
+            shared: Arc::new(Shared {
+                playback: Mutex::new(Playback { gain: 1.0, ..Default::default() }),
+                latency: AtomicU32::new(0),
+                xruns: AtomicU32::new(0),
+            }),
+            stimuli: Stimuli::new(),
+            conf_path: String::from("None"),
+            gain_db: 0.0,
+            sample_rate: 0,
+            state_sender,
+            client: None,
+            relay: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let (client, _status) = Client::new(&config.client_name, ClientOptions::NO_START_SERVER)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        self.sample_rate = client.sample_rate() as u32;
+        let ports = (1..=config.channels)
+            .map(|i| client.register_port(&format!("out_{}", i), AudioOut::default()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let names = ports.iter()
+            .map(|port| port.name())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+
+        let (events, mut received) = mpsc::channel(16);
+        let process = Process { ports, shared: self.shared.clone(), events };
+        let notifications = Notifications { shared: self.shared.clone() };
+        let client = client.activate_async(notifications, process)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        // ports can only be connected once the client is active
+        for (name, destination) in names.iter().zip(config.connect.iter()) {
+            client.as_client().connect_ports_by_name(name, destination)
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+        }
+        let latency = names.iter()
+            .filter_map(|name| client.as_client().port_by_name(name))
+            .map(|port| port.get_latency_range(LatencyType::Playback).1)
+            .max()
+            .unwrap_or(0);
+        self.shared.latency.store((latency as u64 * 1_000_000 / self.sample_rate as u64) as u32, Ordering::Release);
+        self.client = Some(client);
+
+        let shared = self.shared.clone();
+        let sender = self.state_sender.clone();
+        self.relay = Some(tokio::spawn(async move {
+            while let Some(event) = received.recv().await {
+                let state = shared.state();
+                match event {
+                    Event::Started => tracing::info!("Sound-Jack: Playback Initiated at {:?} us", state.start_us),
+                    Event::Finished => tracing::info!("Sound-Jack: Playback Completed!"),
+                }
+                sender.send(Self::pack_state(&state)).await.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            }
+        }));
+        tracing::info!("Sound-Jack: Initiated at {:?} Hz with {:?} us latency",
+                       self.sample_rate, self.shared.latency.load(Ordering::Acquire));
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let mut playback = self.shared.playback.lock().unwrap();
+        if !state.playback {
+            if playback.stimulus.take().is_some() {
+                tracing::debug!("Playback requested interrupt.");
+                drop(playback);
+                self.send_state();
+            } else {
+                tracing::info!("Playback requested to stop while already stopped.");
+            }
+            return Ok(())
+        }
+        if playback.stimulus.is_some() {
+            tracing::error!("Requested stim while already playing. Send next or stop first.");
+            return Err(ClientError::Busy.into())
+        }
+        let stimulus = self.stimuli.get(&state.audio_id).ok_or_else(|| {
+            tracing::error!("Requested {:?} from Playlist: {:?}", state.audio_id, self.stimuli.keys());
+            DecideError::from(ClientError::InvalidState)
+        })?;
+        let client = self.client.as_ref().ok_or_else(|| {
+            tracing::error!("Sound-Jack is not connected to a server");
+            DecideError::from(ClientError::InvalidState)
+        })?;
+        *playback = Playback {
+            audio_id: state.audio_id,
+            stimulus: Some(stimulus.clone()),
+            start: (state.start_us > 0).then(|| client.as_client().time_to_frames(state.start_us)),
+            started: false,
+            started_us: 0,
+            position: 0,
+            frame_count: stimulus.frames(),
+            gain: playback.gain,
+        };
+        // the state is published once the process callback schedules the first frame
+        tracing::info!("Sound-Jack: State Changed by Request");
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if !params.gain_db.is_finite() {
+            tracing::error!("Sound-Jack gain must be a finite number of dB, got {:?}", params.gain_db);
+            return Err(ClientError::InvalidParams.into())
+        }
+        if params.conf_path != self.conf_path {
+            let stimuli = stimuli::import(&params.conf_path, self.sample_rate).map_err(|e| {
+                tracing::error!("Sound-Jack could not import {:?}: {}", params.conf_path, e);
+                DecideError::from(ClientError::InvalidParams)
+            })?;
+            // the playing stimulus may not be in the new playlist
+            self.shared.playback.lock().unwrap().stimulus = None;
+            self.stimuli = stimuli;
+            self.conf_path = params.conf_path;
+        }
+        // applies from the next stim
+        self.gain_db = params.gain_db;
+        self.shared.playback.lock().unwrap().gain = 10f32.powf(params.gain_db / 20.0);
+        tracing::info!("Sound-Jack Parameters Changed");
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.shared.state()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {
+            conf_path: self.conf_path.clone(),
+            audio_count: self.stimuli.len() as u32,
+            sample_rate: self.sample_rate,
+            gain_db: self.gain_db,
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("JackPlayback relay task", self.relay.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::info!("Sound-Jack: Shutdown Called");
+        if let Some(client) = self.client.take() {
+            if let Err(e) = client.deactivate() {
+                tracing::error!("Sound-Jack: could not deactivate the JACK client: {}", e);
+            }
+        }
+        if let Some(relay) = self.relay.take() {
+            relay.abort();
+            // the task may already have stopped
+            if let Err(e) = relay.await {
+                if e.is_panic() {
+                    tracing::error!("Sound-Jack relay task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl JackPlayback {
+    fn send_state(&self) {
+        let sender = self.state_sender.clone();
+        let message = Self::pack_state(&self.shared.state());
+        tokio::spawn(async move {
+            sender.send(message).await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+        });
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_client_name")]
+    client_name: String,
+    #[serde(default = "Config::default_channels")]
+    channels: u32, // output ports, named out_1, out_2, ...
+    #[serde(default)]
+    connect: Vec<String>, // ports to connect the outputs to, e.g. ["system:playback_1"]
+}
+
+impl Config {
+    fn default_client_name() -> String {
+        String::from("decide-rs")
+    }
+
+    fn default_channels() -> u32 {
+        2
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_offset_within_cycle() {
+        assert_eq!(start_offset(1000, 256, None), Some(0));
+        assert_eq!(start_offset(1000, 256, Some(1100)), Some(100));
+        assert_eq!(start_offset(1000, 256, Some(1256)), None);
+        // late starts play at once
+        assert_eq!(start_offset(1000, 256, Some(900)), Some(0));
+    }
+
+    #[test]
+    fn start_offset_across_wraparound() {
+        assert_eq!(start_offset(u32::MAX - 99, 256, Some(10)), Some(110));
+        assert_eq!(start_offset(5, 256, Some(u32::MAX)), Some(0));
+    }
+
+    #[test]
+    fn splits_interleaved_channels() {
+        let stimulus = Stimulus::from_interleaved(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6], 2);
+        assert_eq!(stimulus.channels, vec![vec![0.1, 0.3, 0.5], vec![0.2, 0.4, 0.6]]);
+        assert_eq!(stimulus.frames(), 3);
+    }
+}
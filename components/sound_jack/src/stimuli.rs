@@ -0,0 +1,72 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::sync::Arc;
+use serde::Deserialize;
+use walkdir::WalkDir;
+
+/// Audio of one stimulus, with the samples of each channel in their own vector
+pub struct Stimulus {
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl Stimulus {
+    pub fn frames(&self) -> usize {
+        self.channels.first().map_or(0, |c| c.len())
+    }
+
+    /// Splits interleaved samples into channels
+    pub fn from_interleaved(samples: &[f32], channels: usize) -> Self {
+        Stimulus {
+            channels: (0..channels)
+                .map(|c| samples.iter().skip(c).step_by(channels).copied().collect())
+                .collect(),
+        }
+    }
+}
+
+pub type Stimuli = HashMap<String, Arc<Stimulus>>;
+
+/// Loads the WAV files named in an experiment config file, which has the same
+/// format as the one used by sound_alsa
+pub fn import(conf_path: &str, sample_rate: u32) -> Result<Stimuli, Box<dyn Error + Send + Sync>> {
+    tracing::info!("Begin Importing Audio from {:?}", conf_path);
+    let conf: ConfFile = serde_json::from_reader(File::open(conf_path)?)?;
+    tracing::info!("Stimulus Root Specified as {:?}", &conf.stimulus_root);
+    let names: HashSet<&str> = conf.stimuli.iter().map(|s| s.name.as_str()).collect();
+    let mut stimuli = Stimuli::new();
+    for entry in WalkDir::new(&conf.stimulus_root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "wav"))
+    {
+        let name = match entry.path().file_stem().and_then(|s| s.to_str()) {
+            Some(name) if names.contains(name) && !stimuli.contains_key(name) => name,
+            _ => continue,
+        };
+        let mut wav = audrey::open(entry.path())?;
+        let description = wav.description();
+        if description.sample_rate() != sample_rate {
+            tracing::warn!("Sound-Jack {:?} is at {:?} Hz but the server runs at {:?} Hz",
+                           name, description.sample_rate(), sample_rate);
+        }
+        let samples = wav.samples::<f32>().collect::<Result<Vec<f32>, _>>()?;
+        tracing::info!("Importing file {:?}", name);
+        let stimulus = Stimulus::from_interleaved(&samples, description.channel_count() as usize);
+        stimuli.insert(name.to_string(), Arc::new(stimulus));
+    }
+    tracing::info!("Finished importing audio files");
+    Ok(stimuli)
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfFile {
+    stimulus_root: String,
+    stimuli: Vec<Entry>,
+}
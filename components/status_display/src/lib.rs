@@ -0,0 +1,417 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use async_trait::async_trait;
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, error::{ClientError, DecideError}};
+use proto::Mode;
+
+mod font;
+
+/// Small I2C status display on the front of a box, either an SSD1306 OLED or an
+/// HD44780 character LCD behind a PCF8574 backpack. Clients set rows of text
+/// directly, e.g. the subject and trial count, or send summary stats to be laid
+/// out and paged through automatically.
+pub struct StatusDisplay {
+    state: proto::DisplayState,
+    params: proto::DisplayParams,
+    rows: usize,
+    state_sender: Sender<Any>,
+    show_sender: Option<mpsc::Sender<(proto::DisplayState, proto::DisplayParams)>>,
+    painter: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::DisplayState, params = proto::DisplayParams, config = Config)]
+#[async_trait]
+impl Component for StatusDisplay {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        StatusDisplay {
+            state: proto::DisplayState::default(),
+            params: proto::DisplayParams {
+                page_ms: config.page,
+            },
+            rows: config.display.size().0,
+            state_sender,
+            show_sender: None,
+            painter: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut display: Box<dyn Display + Send> = match config.display {
+            DisplayConfig::Ssd1306 { address, height } => Box::new(Ssd1306::new(&config.bus, address, height)
+                .map_err(|e| DecideError::Component { source: e.into() })?),
+            DisplayConfig::Hd44780 { address, rows, columns } => Box::new(Hd44780::new(&config.bus, address, rows, columns)
+                .map_err(|e| DecideError::Component { source: e.into() })?),
+        };
+        let (show_sender, shows) = mpsc::channel::<(proto::DisplayState, proto::DisplayParams)>();
+        let mut show = (self.state.clone(), self.params.clone());
+        self.painter = Some(thread::spawn(move || {
+            let mut page = 0;
+            loop {
+                let (state, params) = &show;
+                let pages = pages(state, display.size().0);
+                display.show(&pages[page % pages.len()])
+                    .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                let next = if pages.len() > 1 {
+                    shows.recv_timeout(Duration::from_millis(params.page_ms as u64))
+                } else {
+                    shows.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+                };
+                match next {
+                    Ok(next) => {
+                        if next.0.mode != show.0.mode {
+                            page = 0;
+                        }
+                        show = next;
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => page += 1,
+                    // the component was shut down
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            display.show(&[])
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        }));
+        self.show_sender = Some(show_sender);
+        tracing::info!("Status-Display Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        if Mode::from_i32(state.mode).is_none() {
+            tracing::error!("Status-Display mode {:?} is not valid", state.mode);
+            return Err(ClientError::InvalidState.into())
+        }
+        if state.lines.len() > self.rows {
+            tracing::error!("Status-Display has {:?} rows but {:?} lines were sent", self.rows, state.lines.len());
+            return Err(ClientError::InvalidState.into())
+        }
+        self.state = state;
+        self.show();
+        let sender = self.state_sender.clone();
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            sender
+                .send(Self::pack_state(&state))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap();
+            tracing::info!("Status-Display State Changed by Request");
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.page_ms == 0 {
+            tracing::error!("Status-Display page time must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        self.params = params;
+        self.show();
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Status Display painter thread", self.painter.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Status-Display");
+        // closing the channel stops the painter, which clears the display
+        self.show_sender.take();
+        if let Some(painter) = self.painter.take() {
+            tokio::task::spawn_blocking(move || painter.join())
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
+
+impl StatusDisplay {
+    fn show(&self) {
+        if let Some(show_sender) = &self.show_sender {
+            show_sender.send((self.state.clone(), self.params.clone()))
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        }
+    }
+}
+
+/// Lays out the rows of each page to show. There is always at least one page.
+fn pages(state: &proto::DisplayState, rows: usize) -> Vec<Vec<String>> {
+    match state.mode() {
+        Mode::Text => vec![state.lines.clone()],
+        Mode::Stats => {
+            let mut stats: Vec<(&String, &String)> = state.stats.iter().collect();
+            stats.sort();
+            let lines: Vec<String> = stats.iter().map(|(name, value)| format!("{}: {}", name, value)).collect();
+            if lines.is_empty() {
+                return vec![Vec::new()]
+            }
+            lines.chunks(rows.max(1)).map(|page| page.to_vec()).collect()
+        }
+    }
+}
+
+trait Display {
+    /// Rows and columns of text
+    fn size(&self) -> (usize, usize);
+    /// Replaces the contents of the display, cutting off text that does not fit
+    fn show(&mut self, lines: &[String]) -> Result<(), LinuxI2CError>;
+}
+
+/// 128 pixel wide monochrome OLED, with 6 pixel wide characters on 8 pixel rows
+struct Ssd1306 {
+    dev: LinuxI2CDevice,
+    pages: usize, // 8 pixel rows
+}
+
+impl Ssd1306 {
+    const WIDTH: usize = 128;
+    const COMMAND: u8 = 0x00;
+    const DATA: u8 = 0x40;
+    const CHUNK: usize = 32; // data bytes per transfer
+
+    fn new(bus: &str, address: u16, height: u8) -> Result<Self, LinuxI2CError> {
+        let mut dev = LinuxI2CDevice::new(bus, address)?;
+        let com_pins = if height == 32 { 0x02 } else { 0x12 };
+        dev.write(&[
+            Ssd1306::COMMAND,
+            0xAE, // display off
+            0xD5, 0x80, // clock divide
+            0xA8, height - 1, // multiplex ratio
+            0xD3, 0x00, // no display offset
+            0x40, // start line 0
+            0x8D, 0x14, // charge pump on
+            0x20, 0x00, // horizontal addressing
+            0xA1, 0xC8, // column 127 and row 0 at the top left
+            0xDA, com_pins,
+            0x81, 0xCF, // contrast
+            0xD9, 0xF1, // precharge
+            0xDB, 0x40, // VCOMH deselect level
+            0xA4, // show RAM contents
+            0xA6, // not inverted
+            0xAF, // display on
+        ])?;
+        Ok(Ssd1306 { dev, pages: height as usize / 8 })
+    }
+}
+
+/// Draws text into a frame buffer of `pages` 8 pixel rows, in the display's
+/// column-major byte order
+fn render(lines: &[String], pages: usize) -> Vec<u8> {
+    let mut frame = vec![0u8; Ssd1306::WIDTH * pages];
+    for (page, line) in lines.iter().take(pages).enumerate() {
+        let row = &mut frame[page * Ssd1306::WIDTH..(page + 1) * Ssd1306::WIDTH];
+        for (column, c) in line.chars().take(Ssd1306::WIDTH / 6).enumerate() {
+            row[column * 6..column * 6 + 5].copy_from_slice(font::glyph(c));
+        }
+    }
+    frame
+}
+
+impl Display for Ssd1306 {
+    fn size(&self) -> (usize, usize) {
+        (self.pages, Ssd1306::WIDTH / 6)
+    }
+
+    fn show(&mut self, lines: &[String]) -> Result<(), LinuxI2CError> {
+        let frame = render(lines, self.pages);
+        self.dev.write(&[Ssd1306::COMMAND, 0x21, 0, (Ssd1306::WIDTH - 1) as u8, 0x22, 0, (self.pages - 1) as u8])?;
+        for chunk in frame.chunks(Ssd1306::CHUNK) {
+            let mut data = Vec::with_capacity(chunk.len() + 1);
+            data.push(Ssd1306::DATA);
+            data.extend_from_slice(chunk);
+            self.dev.write(&data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Character LCD in 4-bit mode through a PCF8574, with the usual backpack
+/// wiring of RS, RW, E and the backlight on P0-P3 and D4-D7 on P4-P7
+struct Hd44780 {
+    dev: LinuxI2CDevice,
+    rows: usize,
+    columns: usize,
+}
+
+impl Hd44780 {
+    const RS: u8 = 0x01;
+    const ENABLE: u8 = 0x04;
+    const BACKLIGHT: u8 = 0x08;
+    const ROW_OFFSETS: [u8; 4] = [0x00, 0x40, 0x14, 0x54];
+
+    fn new(bus: &str, address: u16, rows: usize, columns: usize) -> Result<Self, LinuxI2CError> {
+        let mut lcd = Hd44780 {
+            dev: LinuxI2CDevice::new(bus, address)?,
+            rows: rows.min(Hd44780::ROW_OFFSETS.len()),
+            columns,
+        };
+        // the reset sequence from the datasheet brings the controller into
+        // 8-bit mode from any state, and then switches to 4-bit mode
+        thread::sleep(Duration::from_millis(50));
+        for _ in 0..3 {
+            lcd.nibble(0x03, 0)?;
+            thread::sleep(Duration::from_millis(5));
+        }
+        lcd.nibble(0x02, 0)?;
+        lcd.command(0x28)?; // two or more lines, 5x8 font
+        lcd.command(0x0C)?; // display on, no cursor
+        lcd.command(0x06)?; // move right after each character
+        lcd.command(0x01)?; // clear
+        thread::sleep(Duration::from_millis(2));
+        Ok(lcd)
+    }
+
+    fn nibble(&mut self, nibble: u8, flags: u8) -> Result<(), LinuxI2CError> {
+        let byte = (nibble << 4) | flags | Hd44780::BACKLIGHT;
+        // data is latched on the falling edge of enable
+        self.dev.write(&[byte | Hd44780::ENABLE])?;
+        self.dev.write(&[byte])
+    }
+
+    fn send(&mut self, value: u8, flags: u8) -> Result<(), LinuxI2CError> {
+        self.nibble(value >> 4, flags)?;
+        self.nibble(value & 0x0F, flags)
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), LinuxI2CError> {
+        self.send(command, 0)
+    }
+}
+
+impl Display for Hd44780 {
+    fn size(&self) -> (usize, usize) {
+        (self.rows, self.columns)
+    }
+
+    fn show(&mut self, lines: &[String]) -> Result<(), LinuxI2CError> {
+        // rows are overwritten with padding rather than cleared, which flickers
+        for row in 0..self.rows {
+            self.command(0x80 | Hd44780::ROW_OFFSETS[row])?;
+            let line = lines.get(row).map_or("", |l| l.as_str());
+            let chars = line.chars().chain(std::iter::repeat(' ')).take(self.columns);
+            for c in chars {
+                let c = if (' '..='}').contains(&c) { c as u8 } else { b'?' };
+                self.send(c, Hd44780::RS)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DisplayConfig {
+    Ssd1306 {
+        #[serde(default = "DisplayConfig::default_ssd1306_address")]
+        address: u16,
+        #[serde(default = "DisplayConfig::default_height")]
+        height: u8, // pixels, 32 or 64
+    },
+    Hd44780 {
+        #[serde(default = "DisplayConfig::default_hd44780_address")]
+        address: u16,
+        #[serde(default = "DisplayConfig::default_rows")]
+        rows: usize,
+        #[serde(default = "DisplayConfig::default_columns")]
+        columns: usize,
+    },
+}
+
+impl DisplayConfig {
+    fn size(&self) -> (usize, usize) {
+        match *self {
+            DisplayConfig::Ssd1306 { height, .. } => (height as usize / 8, Ssd1306::WIDTH / 6),
+            DisplayConfig::Hd44780 { rows, columns, .. } => (rows.min(Hd44780::ROW_OFFSETS.len()), columns),
+        }
+    }
+
+    fn default_ssd1306_address() -> u16 {
+        0x3C
+    }
+
+    fn default_height() -> u8 {
+        64
+    }
+
+    fn default_hd44780_address() -> u16 {
+        0x27
+    }
+
+    fn default_rows() -> usize {
+        2
+    }
+
+    fn default_columns() -> usize {
+        16
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_bus")]
+    bus: String,
+    display: DisplayConfig,
+    #[serde(default = "Config::default_page")]
+    page: u32, // ms, initial value of the page_ms parameter
+}
+
+impl Config {
+    fn default_bus() -> String {
+        String::from("/dev/i2c-1")
+    }
+
+    fn default_page() -> u32 {
+        3000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_are_sorted_and_paged() {
+        let state = proto::DisplayState {
+            mode: Mode::Stats as i32,
+            lines: Vec::new(),
+            stats: [("trials", "120"), ("correct", "0.85"), ("subject", "B12")].iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let pages = pages(&state, 2);
+        assert_eq!(pages, vec![
+            vec![String::from("correct: 0.85"), String::from("subject: B12")],
+            vec![String::from("trials: 120")],
+        ]);
+    }
+
+    #[test]
+    fn text_is_drawn_in_columns() {
+        let frame = render(&[String::from("!"), String::from("   A")], 4);
+        assert_eq!(frame.len(), 512);
+        assert_eq!(&frame[..6], &[0x00, 0x00, 0x5F, 0x00, 0x00, 0x00]);
+        assert_eq!(&frame[128 + 18..128 + 23], font::glyph('A'));
+        assert!(frame[256..].iter().all(|&b| b == 0));
+    }
+}
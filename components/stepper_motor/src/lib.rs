@@ -1,192 +1,1238 @@
-use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI32, AtomicI64, AtomicU32, Ordering}};
+use std::thread;
 use std::time::Instant;
 use async_trait::async_trait;
-use futures::stream::StreamExt;
-use gpio_cdev::{AsyncLineEventHandle, Chip,
-                EventRequestFlags,
-                EventType,
-                LineRequestFlags,
-                MultiLineHandle};
-use prost::Message;
+use gpio_cdev::{Chip, LineRequestFlags};
+use decide_hal::{cdev, pwm::SysfsPwm, DigitalInput, DigitalOutputs, Edge, EdgeInput, Pwm};
 use prost_types::Any;
 use serde::Deserialize;
 use tokio::{self, time::Duration, sync::mpsc};
-use decide_protocol::{Component, error::DecideError};
+use decide_protocol::{component, Component, ComponentHealth, Resource, pack, report_fault, error::{ClientError, DecideError}};
 
 pub struct StepperMotor {
-    running: Arc<AtomicBool>,
-    direction: Arc<AtomicBool>,
-    timeout: Arc<AtomicU64>,
+    motors: Vec<Motor>,
+    selected: usize, // motor reported by get_state and get_parameters
     state_sender: mpsc::Sender<Any>,
-    req_sender: Option<mpsc::Sender<[bool; 2]>>,
-    shutdown: Option<(tokio::task::JoinHandle<()>,
+}
+
+/// One of the motors driven by the component, with its own thread and request queue
+struct Motor {
+    status: Arc<Status>,
+    params: Arc<Mutex<proto::SmParams>>, // read by the motor task at the start of each move
+    can_home: bool,
+    req_sender: Option<mpsc::Sender<(Motion, u32)>>,
+    mock: bool, // simulated, with switch presses injected through the parameters
+    switch_interrupt: SwitchInterrupt, // applied to simulated switch presses
+    switch_sender: Option<mpsc::Sender<(Motion, proto::Gesture)>>,
+    switch_tasks: Vec<tokio::task::JoinHandle<()>>,
+    shutdown: Option<(thread::JoinHandle<()>,
                       mpsc::Sender<bool>)>
 }
 
+#[component(state = proto::SmState, params = proto::SmParams, config = Config)]
 #[async_trait]
 impl Component for StepperMotor {
-    type State = proto::SmState;
-    type Params = proto::SmParams;
-    type Config = Config;
-    const STATE_TYPE_URL: &'static str = "type.googleapis.com/SmState";
-    const PARAMS_TYPE_URL: &'static str = "type.googleapis.com/SmParams";
-
-    fn new(_config: Self::Config, state_sender: mpsc::Sender<Any>) -> Self {
-        use std::fs;
+    fn new(config: Self::Config, state_sender: mpsc::Sender<Any>) -> Self {
+        StepperMotor {
+            motors: config.motors().iter()
+                .enumerate()
+                .map(|(id, motor)| Motor::new(id as u32, motor))
+                .collect(),
+            selected: 0,
+            state_sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
         use std::path::Path;
 
-        if Path::new("/sys/class/pwm/pwmchip5").exists() {
-            if !Path::new("/sys/class/pwm/pwmchip5/pwm0").exists() {
-                fs::write("/sys/class/pwm/pwmchip5/export", "0").expect("Unable to export pwmchip5/pwm0");
-            }
-            if !Path::new("/sys/class/pwm/pwmchip5/pwm1").exists() {
-                fs::write("/sys/class/pwm/pwmchip5/export", "1").expect("Unable to export pwmchip5/pwm1");
-            }
-            fs::write("/sys/class/pwm/pwmchip5/pwm0/period", "10000").expect("Unable to write to pwm0 period");
-            fs::write("/sys/class/pwm/pwmchip5/pwm1/period", "10000").expect("Unable to write to pwm1 period");
-            fs::write("/sys/class/pwm/pwmchip5/pwm0/duty_cycle", "6500").expect("Unable to write to pwm0 duty_cycle");
-            fs::write("/sys/class/pwm/pwmchip5/pwm1/duty_cycle", "6500").expect("Unable to write to pwm1 duty_cycle");
-            fs::write("/sys/class/pwm/pwmchip5/pwm0/enable", "1").expect("Unable to write to pwm0 enable");
-            fs::write("/sys/class/pwm/pwmchip5/pwm1/enable", "1").expect("Unable to write to pwm1 enable");
-        } else if Path::new("/sys/class/pwm/pwmchip0").exists() {
-            if !Path::new("/sys/class/pwm/pwmchip0/pwm0").exists() {
-                fs::write("/sys/class/pwm/pwmchip0/export", "0").expect("Unable to export pwmchip0/pwm0");
-            }
-            if !Path::new("/sys/class/pwm/pwmchip0/pwm1").exists() {
-                fs::write("/sys/class/pwm/pwmchip0/export", "1").expect("Unable to export pwmchip0/pwm1");
+        // checked here rather than in new, so that a bad config is returned as an error
+        if config.motors().is_empty() {
+            tracing::error!("No motors configured for stepper motor");
+            return Err(invalid_config("stepper motor config has no motors"))
+        }
+        if let Some(order) = config.motors().iter().find_map(|motor| motor.coil_order.filter(|order| {
+            let mut sorted = *order;
+            sorted.sort_unstable();
+            sorted != [0, 1, 2, 3]
+        })) {
+            tracing::error!("Stepper motor coil_order {:?} is not an ordering of lines 0-3", order);
+            return Err(invalid_config("stepper motor config has an invalid coil_order"))
+        }
+
+        if config.motors().iter().all(|motor| motor.mock) {
+            tracing::info!("Stepper Motor is simulated, skipping PWM setup");
+        } else if let Some(chip) = ["/sys/class/pwm/pwmchip5", "/sys/class/pwm/pwmchip0"].iter()
+            .find(|chip| Path::new(chip).exists()) {
+            for channel in 0..2 {
+                let pwm = SysfsPwm::export(chip, channel)
+                    .map_err(|e| {
+                        tracing::error!("Unable to export {}/pwm{}: {}", chip, channel, e);
+                        DecideError::Component { source: e }
+                    })?;
+                pwm.set_period(10000)
+                    .and_then(|_| pwm.set_duty_cycle(6500))
+                    .and_then(|_| pwm.enable(true))
+                    .map_err(|e| {
+                        tracing::error!("Unable to set up {}/pwm{}: {}", chip, channel, e);
+                        DecideError::Component { source: e }
+                    })?;
             }
-            fs::write("/sys/class/pwm/pwmchip0/pwm0/period", "10000").expect("Unable to write to pwm0 period");
-            fs::write("/sys/class/pwm/pwmchip0/pwm1/period", "10000").expect("Unable to write to pwm1 period");
-            fs::write("/sys/class/pwm/pwmchip0/pwm0/duty_cycle", "6500").expect("Unable to write to pwm0 duty_cycle");
-            fs::write("/sys/class/pwm/pwmchip0/pwm1/duty_cycle", "6500").expect("Unable to write to pwm1 duty_cycle");
-            fs::write("/sys/class/pwm/pwmchip0/pwm0/enable", "1").expect("Unable to write to pwm0 enable");
-            fs::write("/sys/class/pwm/pwmchip0/pwm1/enable", "1").expect("Unable to write to pwm1 enable");
         } else {
             tracing::error!("Found neither pwmchip0 nor pwmchip5 for stepper motor");
-            panic!("stepper motor pwmchip not found :(")
+            return Err(invalid_config("stepper motor pwmchip not found"))
         }
 
-        StepperMotor {
-            running: Arc::new(AtomicBool::new(false)),
-            direction: Arc::new(AtomicBool::new(true)),
-            timeout: Arc::new(AtomicU64::new(500)),
-            state_sender,
+        for (motor, config) in self.motors.iter_mut().zip(config.into_motors()) {
+            motor.init(config, self.state_sender.clone())?;
+        }
+        tracing::info!("Stepper Motor Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let id = state.motor as usize;
+        let motor = self.motors.get(id).ok_or_else(|| {
+            tracing::error!("Stepper Motor {:?} is not configured", id);
+            DecideError::from(ClientError::InvalidState)
+        })?;
+        motor.change_state(state)?;
+        self.selected = id;
+        tracing::info!("Stepper Motor State Changed by Request");
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        let id = params.motor as usize;
+        let motor = self.motors.get(id).ok_or_else(|| {
+            tracing::error!("Stepper Motor {:?} is not configured", id);
+            DecideError::from(ClientError::InvalidParams)
+        })?;
+        motor.set_parameters(params)?;
+        self.selected = id;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        // no motors are configured if init failed
+        let mut state = self.motors.get(self.selected).map_or_else(Default::default, |motor| motor.status.state());
+        state.motors = self.motors.iter()
+            .map(|motor| (motor.status.id, motor.status.state()))
+            .collect();
+        state
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.motors.get(self.selected).map_or_else(Default::default, |motor| motor.params.lock().unwrap().clone())
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        let stopped = self.motors.iter().any(|motor| {
+            motor.shutdown.as_ref().is_some_and(|(h, _)| h.is_finished())
+                || motor.switch_tasks.iter().any(|h| h.is_finished())
+        });
+        ComponentHealth::running("StepperMotor task", stopped)
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        let mut resources = Vec::new();
+        for motor in config.motors().iter().filter(|motor| !motor.mock) {
+            resources.extend(Resource::lines(&motor.chip1, motor.switch_offsets.iter().chain(&motor.motor1_offsets)));
+            resources.extend(Resource::lines(&motor.chip3, &motor.motor3_offsets));
+            resources.extend(Resource::lines(&motor.chip1, motor.home.iter().map(|home| &home.offset)));
+            resources.extend(Resource::lines(&motor.chip1, motor.stall.iter().map(|stall| &stall.offset)));
+        }
+        resources
+    }
+
+    async fn shutdown(&mut self) {
+        for motor in self.motors.iter_mut() {
+            for switch_task in motor.switch_tasks.drain(..) {
+                switch_task.abort();
+            }
+            if let Some((motor_thread, sd_tx)) = motor.shutdown.take() {
+                // cancels the move in progress; closing the channel wakes an idle driver
+                motor.status.epoch.fetch_add(1, Ordering::AcqRel);
+                drop(sd_tx);
+                match tokio::task::spawn_blocking(move || motor_thread.join()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => tracing::error!("Stepper Motor thread panicked"),
+                    Err(e) => tracing::error!("Stepper Motor thread could not be joined: {}", e),
+                }
+            }
+        }
+    }
+}
+
+impl Motor {
+    fn new(id: u32, config: &MotorConfig) -> Self {
+        Motor {
+            status: Arc::new(Status::new(id)),
+            params: Arc::new(Mutex::new(proto::SmParams {
+                timeout: 500,
+                dt: config.dt,
+                start_dt: config.start_dt,
+                ramp_steps: config.ramp_steps,
+                hold: config.hold,
+                hold_duty: config.hold_duty,
+                motor: id,
+                ..Default::default()
+            })),
+            can_home: config.home.is_some() && !config.mock,
             req_sender: None,
+            mock: config.mock,
+            switch_interrupt: config.switch_interrupt,
+            switch_sender: None,
+            switch_tasks: Vec::new(),
             shutdown: None,
         }
     }
 
-    async fn init(&mut self, config: Self::Config) {
-
-        let (req_snd, mut req_rcv) = mpsc::channel(20);
+    /// Claims the motor's GPIO lines and starts the task that drives it. Mock
+    /// motors claim no lines and only simulate the coils.
+    fn init(&mut self, config: MotorConfig, state_sender: mpsc::Sender<Any>) -> decide_protocol::Result<()> {
+        let faults = state_sender.clone();
+        let (req_snd, req_rcv) = mpsc::channel(StepperMotor::QUEUE_SIZE);
         self.req_sender = Some(req_snd);
+        let (switch_snd, switch_rcv) = mpsc::channel(StepperMotor::QUEUE_SIZE);
+        self.switch_sender = Some(switch_snd.clone());
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let mut driver = Driver {
+            coils: Box::new(SimulatedCoils) as Box<dyn Coils>,
+            home_switch: None,
+            home: None,
+            stall: None,
+            duty: config.duty_limit.as_ref().map(DutyLimiter::new),
+            sequence: config.sequence(),
+            limits: config.soft_limits,
+            status: self.status.clone(),
+            params: Arc::clone(&self.params),
+            requests: req_rcv,
+            switches: switch_rcv,
+            emitter: StateEmitter { sender: state_sender, pending: false, closed: false },
+            shutdown: shutdown_rx,
+        };
+        if config.mock {
+            tracing::warn!("Stepper Motor {:?} is simulated; homing and stall detection are disabled",
+                           self.status.id);
+            self.shutdown = Some((driver.spawn()?, shutdown_tx));
+            return Ok(())
+        }
+
         let mut chip1 = Chip::new(config.chip1.clone())
-            .map_err(|e| DecideError::Component { source: e.into() })
-            .unwrap();
+            .map_err(|e| DecideError::Component { source: e.into() })?;
         let mut chip3 = Chip::new(config.chip3.clone())
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let debounce = Duration::from_millis(config.debounce);
+        let bias = config.switch_bias.map_or(LineRequestFlags::empty(), |bias| bias.flags());
+        // all lines are claimed before any task starts, so a failed request leaves nothing running
+        let mut switches = Vec::new();
+        for (&offset, active_low) in config.switch_offsets.iter().zip(config.switch_active_low) {
+            let events = StepperMotor::request_asynclines(&mut chip1, offset, bias)?;
+            switches.push((offset, CapeSwitch::new(Box::new(events), debounce, active_low)?));
+        }
+        driver.coils = Box::new(MotorLines {
+            handle1: Box::new(StepperMotor::request_lines(&mut chip1, &config.motor1_offsets)?),
+            handle3: Box::new(StepperMotor::request_lines(&mut chip3, &config.motor3_offsets)?),
+        });
+        driver.home_switch = config.home.as_ref()
+            .map(|home| StepperMotor::request_inputline(&mut chip1, home.offset)
+                 .map(|line| Box::new(line) as Box<dyn DigitalInput>))
+            .transpose()?;
+        driver.stall = config.stall.as_ref()
+            .map(|stall| StepperMotor::request_inputline(&mut chip1, stall.offset)
+                 .map(|line| StallDetector::new(Box::new(line), Duration::from_millis(stall.timeout))))
+            .transpose()?;
+        // switch 14 runs the motor with direction = false, switch 15 with direction = true
+        self.switch_tasks = switches.into_iter()
+            .zip([false, true])
+            .map(|((offset, switch), direction)| tokio::spawn(SwitchTask {
+                switch,
+                offset,
+                direction,
+                long_press: Duration::from_millis(config.long_press),
+                double_press: Duration::from_millis(config.double_press),
+                interrupt: config.switch_interrupt,
+                status: self.status.clone(),
+                motions: switch_snd.clone(),
+                faults: faults.clone(),
+            }.run()))
+            .collect();
+        driver.home = config.home;
+        self.shutdown = Some((driver.spawn()?, shutdown_tx));
+        Ok(())
+    }
+
+    fn change_state(&self, state: proto::SmState) -> decide_protocol::Result<()> {
+        if state.homing && !self.can_home {
+            tracing::error!("Stepper Motor homing requested but no limit switch is configured");
+            return Err(ClientError::InvalidState.into());
+        }
+        let motion = if state.homing {
+            Some(Motion::Home)
+        } else if let Some(dest) = state.move_to {
+            Some(Motion::MoveTo(dest))
+        } else if let Some(n) = state.steps {
+            Some(Motion::Steps(n))
+        } else if state.running {
+            Some(Motion::Run { direction: state.direction })
+        } else {
+            None
+        };
+        if let Some(motion) = motion {
+            // queue synchronously so that motions run in the order they were requested
+            let epoch = self.status.epoch.load(Ordering::Acquire);
+            self.req_sender.as_ref()
+                .unwrap()
+                .try_send((motion, epoch))
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+            let queued = self.status.queued.fetch_add(1, Ordering::AcqRel) + 1;
+            tracing::debug!("Stepper Motor queued {:?}, {:?} motion(s) waiting", motion, queued);
+        } else {
+            // cancels the current move and anything queued before this request
+            self.status.epoch.fetch_add(1, Ordering::AcqRel);
+            tracing::info!("Stepper Motor stop requested");
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&self, mut params: proto::SmParams) -> decide_protocol::Result<()> {
+        let intervals = [Some(params.dt), params.dt_up, params.dt_down];
+        if intervals.contains(&Some(0)) {
+            tracing::error!("Stepper Motor step interval must be greater than zero");
+            return Err(ClientError::InvalidParams.into());
+        }
+        if params.hold_duty.is_some_and(|duty| duty == 0 || duty > 100) {
+            tracing::error!("Stepper Motor hold duty cycle must be between 1 and 100");
+            return Err(ClientError::InvalidParams.into());
+        }
+        let presses = [params.switch_14.take(), params.switch_15.take()];
+        if presses.iter().any(Option::is_some) && !self.mock {
+            tracing::error!("Stepper Motor switch presses can only be simulated on mock motors");
+            return Err(ClientError::InvalidParams.into());
+        }
+        for (&gesture, direction) in presses.iter().zip([false, true]) {
+            if let Some(gesture) = gesture {
+                let gesture = proto::Gesture::from_i32(gesture).ok_or_else(|| {
+                    tracing::error!("Stepper Motor switch gesture {:?} is not valid", gesture);
+                    DecideError::from(ClientError::InvalidParams)
+                })?;
+                self.simulate_switch(gesture, direction)?;
+            }
+        }
+        *self.params.lock().unwrap() = params;
+        Ok(())
+    }
+
+    /// Acts as if a gesture was made on a cape switch. A long press holds the
+    /// switch down until `NoGesture` is simulated.
+    fn simulate_switch(&self, gesture: proto::Gesture, direction: bool) -> decide_protocol::Result<()> {
+        let long_press = gesture == proto::Gesture::LongPress;
+        if gesture == proto::Gesture::NoGesture {
+            self.status.switch_held.store(false, Ordering::Release);
+            return Ok(())
+        }
+        tracing::info!("Simulating {:?} on Stepper Motor switch", gesture);
+        if !self.status.interrupt_client_motion(self.switch_interrupt) {
+            return Ok(())
+        }
+        self.status.switch_held.store(long_press, Ordering::Release);
+        self.switch_sender.as_ref()
+            .unwrap()
+            .try_send((SwitchTask::motion(gesture, direction), gesture))
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        Ok(())
+    }
+}
+
+/// Drives one motor on its own thread: waits for motion requests and steps the coils
+struct Driver<C> {
+    coils: C,
+    home_switch: Option<Box<dyn DigitalInput>>,
+    home: Option<HomeConfig>,
+    stall: Option<StallDetector>,
+    duty: Option<DutyLimiter>,
+    sequence: Vec<(LinesVal, LinesVal)>,
+    limits: SoftLimits,
+    status: Arc<Status>,
+    params: Arc<Mutex<proto::SmParams>>,
+    requests: mpsc::Receiver<(Motion, u32)>,
+    switches: mpsc::Receiver<(Motion, proto::Gesture)>,
+    emitter: StateEmitter,
+    shutdown: mpsc::Receiver<bool>,
+}
+
+impl<C: Coils> Driver<C> {
+    /// Starts the driver on a dedicated thread with its own runtime, so that
+    /// timing the steps never holds up the controller's tasks
+    fn spawn(self) -> decide_protocol::Result<thread::JoinHandle<()>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        thread::Builder::new()
+            .name(format!("stepper-motor-{}", self.status.id))
+            .spawn(move || runtime.block_on(self.run()))
             .map_err(|e| DecideError::Component { source: e.into() })
-            .unwrap();
-        let motor_1_handle = StepperMotor::request_lines(&mut chip1, &config.motor1_offsets);
-        let motor_3_handle = StepperMotor::request_lines(&mut chip3, &config.motor3_offsets);
-        let mut switch_14 = StepperMotor::request_asynclines(&mut chip1, config.switch_offsets[0]);
-        let mut switch_15 = StepperMotor::request_asynclines(&mut chip1, config.switch_offsets[1]);
-
-        let running = self.running.clone();
-        let direction = self.direction.clone();
-        let mut state_sender = self.state_sender.clone();
-        let timeout = Arc::clone(&self.timeout);
-        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
-        let dt = config.dt;
-
-        let motor_handle = tokio::spawn(async move {
-            let mut step = 0;
-            loop {
-                if shutdown_rx.try_recv().unwrap_err() == mpsc::error::TryRecvError::Disconnected {
-                    StepperMotor::pause_motor(&motor_1_handle, &motor_3_handle);
-                    break}
-
-                let mut state = StepperMotor::poll_change(&mut switch_14,
-                                                      &mut switch_15,
-                                                      &mut req_rcv).await;
-                if state.running {
-                    running.store(state.running, Ordering::Release);
-                    direction.store(state.direction, Ordering::Release);
-                    tracing::debug!("sending state");
-                    StepperMotor::send_state(&state, &mut state_sender).await;
-                    tracing::debug!("Running motor with timeout");
-                    let timer = Instant::now();
-                    while Instant::now().duration_since(timer) <
-                        Duration::from_millis(timeout.load(Ordering::Acquire)) {
-                        step = StepperMotor::run_motor(step, &motor_1_handle,
-                                                       &motor_3_handle, state.direction);
-                        tokio::time::sleep(Duration::from_micros(dt)).await;
+    }
+
+    /// Runs until the shutdown channel is closed. The motor only moves in response
+    /// to a `Motion` from the request queue or the cape switches; requests queued
+    /// while a move is in progress are buffered by the channel and run in order.
+    /// If the coil or switch lines fail, the motor stops and the error is reported
+    /// as a fault of the component.
+    async fn run(self) {
+        let faults = self.emitter.sender.clone();
+        if let Err(e) = self.drive().await {
+            report_fault(&faults, e);
+        }
+    }
+
+    async fn drive(self) -> decide_protocol::Result<()> {
+        let Driver { coils, home_switch, home, mut stall, mut duty, sequence, limits, status, params,
+                     mut requests, mut switches, mut emitter, mut shutdown } = self;
+        // signed count of steps through the coil sequence; only its remainder matters
+        let mut phase: i64 = 0;
+        let mut hold: Option<Hold> = None;
+        loop {
+            // the controller closes the shutdown channel, or has already dropped the state channel
+            if emitter.closed || matches!(shutdown.try_recv(), Err(mpsc::error::TryRecvError::Disconnected)) {
+                StepperMotor::pause_motor(&coils)?;
+                break
+            }
+
+            let (motion, epoch, gesture) = match StepperMotor::poll_change(&mut switches,
+                                                                  &mut requests,
+                                                                  &mut shutdown,
+                                                                  &mut emitter,
+                                                                  &status,
+                                                                  StepperMotor::next_tick(&hold, &mut duty)).await {
+                Some(request) => request,
+                None => {
+                    if let Some(hold) = hold.as_mut() {
+                        hold.update(StepperMotor::coils(&sequence, phase), &coils)?;
+                    }
+                    if status.cooling_down.load(Ordering::Acquire)
+                        && duty.as_mut().and_then(DutyLimiter::cooldown).is_none() {
+                        tracing::info!("Stepper Motor cool-down finished");
+                        status.cooling_down.store(false, Ordering::Release);
+                        emitter.emit(&status);
                     }
-                    StepperMotor::pause_motor(&motor_1_handle, &motor_3_handle);
-                    state.running = false;
-                    running.store(state.running, Ordering::Release);
-                    direction.store(state.direction, Ordering::Release);
-                    tracing::debug!("sending state");
-                    StepperMotor::send_state(&state, &mut state_sender).await;
-                } else {
                     tracing::debug!("Motor state poller triggered but not runned.");
+                    let dt = params.lock().unwrap().dt;
                     tokio::time::sleep(Duration::from_micros(dt)).await;
+                    continue
+                }
+            };
+            if duty.as_mut().and_then(DutyLimiter::cooldown).is_some() {
+                tracing::info!("Stepper Motor delaying {:?} until cool-down finishes", motion);
+                while let Some(wait) = duty.as_mut().and_then(DutyLimiter::cooldown) {
+                    if StepperMotor::stop_requested(&status, epoch) {
+                        break
+                    }
+                    tokio::time::sleep(wait.min(DutyLimiter::POLL)).await;
+                }
+                status.cooling_down.store(false, Ordering::Release);
+                if StepperMotor::stop_requested(&status, epoch) {
+                    tracing::debug!("Discarding {:?} cancelled during cool-down", motion);
+                    emitter.emit(&status);
+                    continue
                 }
             }
-        });
-        self.shutdown = Some((motor_handle, shutdown_tx));
-        tracing::info!("Stepper Motor Initiated");
-    }
+            // relative moves are resolved against the position when the move starts
+            let dest = match motion {
+                Motion::MoveTo(dest) => Some(dest),
+                Motion::Steps(n) => Some(status.position.load(Ordering::Acquire) + n as i64),
+                _ => None,
+            };
+            let dir = match (motion, dest) {
+                (Motion::Run { direction: dir }, _) | (Motion::Jog { direction: dir }, _) => dir,
+                (Motion::Home, _) => home.as_ref().unwrap().direction,
+                (_, Some(dest)) => dest > status.position.load(Ordering::Acquire),
+                _ => status.direction.load(Ordering::Acquire),
+            };
+            status.running.store(true, Ordering::Release);
+            status.direction.store(dir, Ordering::Release);
+            status.stalled.store(false, Ordering::Release);
+            status.limit_hit.store(false, Ordering::Release);
+            status.holding.store(false, Ordering::Release);
+            hold = None;
+            *status.active.lock().unwrap() = Some(motion);
+            status.gesture.store(gesture as i32, Ordering::Release);
+            let source = if gesture == proto::Gesture::NoGesture { proto::Source::Client } else { proto::Source::Switch };
+            status.source.store(source as i32, Ordering::Release);
+            status.interrupted.store(false, Ordering::Release);
+            tracing::debug!("sending state");
+            emitter.emit(&status);
+            if let Some(stall) = stall.as_mut() {
+                stall.reset()?;
+            }
 
-    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
-        self.direction.store(state.direction, Ordering::Release);
-        self.running.store(state.running, Ordering::Release);
-
-        let notify = self.req_sender.clone();
-        if state.running {
-            tokio::spawn(async move {
-                notify
-                    .unwrap()
-                    .send([state.running, state.direction])
-                    .await
-                    .map_err(|e| DecideError::Component { source: e.into() })
-                    .unwrap();
-            });
+            let move_params = params.lock().unwrap().clone();
+            let (move_timeout, dt) = move_params.for_direction(dir);
+            let ramp = Ramp {
+                start_dt: move_params.start_dt,
+                min_dt: dt,
+                steps: move_params.ramp_steps,
+            };
+            let delta = if dir { 1 } else { -1 };
+            // a move toward the limit switch always stops when it triggers
+            let toward_limit = home.as_ref().is_some_and(|home| home.direction == dir);
+            let mut taken = 0;
+            let mut pulses = PulseTimer::new();
+            let started = Instant::now();
+            if let Some(duty) = duty.as_mut() {
+                duty.start(started);
+            }
+            let reason = match (motion, dest) {
+                (Motion::Run { .. }, _) => {
+                    tracing::debug!("Running motor with timeout");
+                    let run_time = Duration::from_millis(move_timeout);
+                    let mut reason = proto::StopReason::Timeout;
+                    while started.elapsed() < run_time {
+                        if let Some(stop) = StepperMotor::should_stop(&mut stall, &mut duty, &limits, &status, dir, epoch)? {
+                            reason = stop;
+                            break
+                        }
+                        phase = StepperMotor::run_motor(phase, &sequence, &coils, dir)?;
+                        status.position.fetch_add(delta, Ordering::AcqRel);
+                        let remaining = ramp.steps_within(run_time.saturating_sub(started.elapsed()));
+                        pulses.wait(ramp.interval(taken, remaining));
+                        taken += 1;
+                    }
+                    reason
+                }
+                (Motion::Jog { .. }, _) => {
+                    tracing::debug!("Running motor until the switch is released");
+                    let mut reason = proto::StopReason::SwitchReleased;
+                    while status.switch_held.load(Ordering::Acquire) {
+                        if let Some(stop) = StepperMotor::should_stop(&mut stall, &mut duty, &limits, &status, dir, epoch)? {
+                            reason = stop;
+                            break
+                        }
+                        phase = StepperMotor::run_motor(phase, &sequence, &coils, dir)?;
+                        status.position.fetch_add(delta, Ordering::AcqRel);
+                        // the release time is unknown, so only accelerate
+                        pulses.wait(ramp.interval(taken, u64::MAX));
+                        taken += 1;
+                    }
+                    reason
+                }
+                (Motion::Home, _) => {
+                    // change_state only sends Home if a limit switch is configured.
+                    // Soft limits are not enforced because the position is not yet known.
+                    let home = home.as_ref().unwrap();
+                    let switch = home_switch.as_deref().unwrap();
+                    tracing::info!("Homing motor toward limit switch {:?}", home.offset);
+                    let reason = loop {
+                        if StepperMotor::limit_reached(switch)? {
+                            break proto::StopReason::LimitSwitch
+                        }
+                        if taken >= home.max_steps {
+                            break proto::StopReason::MaxSteps
+                        }
+                        if StepperMotor::stop_requested(&status, epoch) {
+                            break StepperMotor::stop_cause(&status)
+                        }
+                        if StepperMotor::check_stall(&mut stall, &status)? {
+                            break proto::StopReason::Stalled
+                        }
+                        if StepperMotor::check_duty(&mut duty) {
+                            break proto::StopReason::DutyLimit
+                        }
+                        phase = StepperMotor::run_motor(phase, &sequence, &coils, dir)?;
+                        // the distance to the switch is unknown, so only accelerate
+                        pulses.wait(ramp.interval(taken, u64::MAX));
+                        taken += 1;
+                    };
+                    if reason == proto::StopReason::LimitSwitch {
+                        status.position.store(0, Ordering::Release);
+                        tracing::info!("Motor homed after {:?} steps", taken);
+                    } else {
+                        tracing::error!("Homing gave up after {:?} steps without reaching the limit switch", taken);
+                    }
+                    reason
+                }
+                (_, Some(dest)) => {
+                    tracing::debug!("Moving motor to position {:?}", dest);
+                    let mut reason = proto::StopReason::Completed;
+                    while status.position.load(Ordering::Acquire) != dest {
+                        if toward_limit && StepperMotor::limit_reached(home_switch.as_deref().unwrap())? {
+                            tracing::info!("Move to {:?} interrupted by limit switch", dest);
+                            reason = proto::StopReason::LimitSwitch;
+                            break
+                        }
+                        if let Some(stop) = StepperMotor::should_stop(&mut stall, &mut duty, &limits, &status, dir, epoch)? {
+                            reason = stop;
+                            break
+                        }
+                        phase = StepperMotor::run_motor(phase, &sequence, &coils, dir)?;
+                        let remaining = (dest - status.position.fetch_add(delta, Ordering::AcqRel) - delta)
+                            .unsigned_abs();
+                        pulses.wait(ramp.interval(taken, remaining));
+                        taken += 1;
+                    }
+                    reason
+                }
+                _ => proto::StopReason::Completed,
+            };
+            let stats = MoveStats { steps: taken, duration: started.elapsed(), reason };
+            if let Some(duty) = duty.as_mut() {
+                duty.finish();
+            }
+            status.cooling_down.store(reason == proto::StopReason::DutyLimit, Ordering::Release);
+            let (hold_coils, hold_duty) = {
+                let params = params.lock().unwrap();
+                (params.hold, params.hold_duty)
+            };
+            if hold_coils {
+                // the last step pattern is still applied
+                hold = Some(Hold::new(hold_duty));
+            } else {
+                StepperMotor::pause_motor(&coils)?;
+            }
+            status.holding.store(hold_coils, Ordering::Release);
+            status.jitter.store(pulses.max_jitter.as_micros() as u32, Ordering::Release);
+            tracing::debug!("Step timing jitter up to {:?}", pulses.max_jitter);
+            status.running.store(false, Ordering::Release);
+            *status.active.lock().unwrap() = None;
+            status.gesture.store(proto::Gesture::NoGesture as i32, Ordering::Release);
+            status.source.store(proto::Source::NoSource as i32, Ordering::Release);
+            tracing::debug!("sending state");
+            emitter.emit(&status);
+            tracing::info!("Stepper Motor took {:?} steps in {:?}, stopped by {:?}",
+                           stats.steps, stats.duration, stats.reason);
+            emitter.emit_stats(stats.encode(status.id), &status);
         }
-        tracing::info!("Stepper Motor State Changed by Request");
         Ok(())
     }
+}
 
-    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
-        self.timeout.store(params.timeout, Ordering::Release);
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LinesVal([u8; 2]);
+
+/// Output lines energizing the two motor coils
+trait Coils: Send + 'static {
+    fn apply(&self, pattern: &(LinesVal, LinesVal)) -> decide_protocol::Result<()>;
+}
+
+struct MotorLines {
+    handle1: Box<dyn DigitalOutputs>,
+    handle3: Box<dyn DigitalOutputs>,
+}
+
+/// Stands in for the coil lines of a mock motor
+struct SimulatedCoils;
+
+impl Coils for SimulatedCoils {
+    fn apply(&self, pattern: &(LinesVal, LinesVal)) -> decide_protocol::Result<()> {
+        tracing::trace!("Simulated coils set to {:?}", pattern);
         Ok(())
     }
+}
 
-    fn get_state(&self) -> Self::State {
-        Self::State {
+impl Coils for Box<dyn Coils> {
+    fn apply(&self, pattern: &(LinesVal, LinesVal)) -> decide_protocol::Result<()> {
+        (**self).apply(pattern)
+    }
+}
+
+impl Coils for MotorLines {
+    fn apply(&self, pattern: &(LinesVal, LinesVal)) -> decide_protocol::Result<()> {
+        self.handle1.set(&(pattern.0).0)
+            .map_err(|e| DecideError::Component { source: e })?;
+        self.handle3.set(&(pattern.1).0)
+            .map_err(|e| DecideError::Component { source: e })
+    }
+}
+
+/// Motion requests handled by the motor task, from either the cape switches or clients
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    /// run in the given direction until the timeout parameter elapses
+    Run { direction: bool },
+    /// run in the given direction until the cape switch held down is released
+    Jog { direction: bool },
+    /// run until the position counter reaches the given number of steps
+    MoveTo(i64),
+    /// run the given signed number of steps
+    Steps(i32),
+    /// run toward the limit switch and zero the position counter when it triggers
+    Home,
+}
+
+/// Motor status shared between the component and the motor task
+struct Status {
+    id: u32, // index of the motor in the config
+    running: AtomicBool,
+    direction: AtomicBool,
+    position: AtomicI64, // steps from where the motor was when initialized
+    active: Mutex<Option<Motion>>, // the motion currently being executed, if any
+    stalled: AtomicBool, // the last move was stopped by the stall detector
+    queued: AtomicU32, // motions requested by clients but not yet started
+    limit_hit: AtomicBool, // the last move was stopped at a soft limit
+    epoch: AtomicU32, // incremented by each stop request; motions from earlier epochs are cancelled
+    holding: AtomicBool, // the coils are energized while idle
+    jitter: AtomicU32, // us; largest step lateness during the last move
+    dropped: AtomicU32, // state updates dropped because the channel was full
+    gesture: AtomicI32, // proto::Gesture on a cape switch that started the current motion
+    switch_held: AtomicBool, // a cape switch is held down after a long press
+    cooling_down: AtomicBool, // the duty-cycle limit was reached and the motor is resting
+    source: AtomicI32, // proto::Source of the current motion
+    interrupted: AtomicBool, // the last stop request came from a cape switch
+}
+
+impl Status {
+    fn new(id: u32) -> Self {
+        Status {
+            id,
+            running: AtomicBool::new(false),
+            direction: AtomicBool::new(true),
+            position: AtomicI64::new(0),
+            active: Mutex::new(None),
+            stalled: AtomicBool::new(false),
+            queued: AtomicU32::new(0),
+            limit_hit: AtomicBool::new(false),
+            epoch: AtomicU32::new(0),
+            holding: AtomicBool::new(false),
+            jitter: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+            gesture: AtomicI32::new(proto::Gesture::NoGesture as i32),
+            switch_held: AtomicBool::new(false),
+            cooling_down: AtomicBool::new(false),
+            source: AtomicI32::new(proto::Source::NoSource as i32),
+            interrupted: AtomicBool::new(false),
+        }
+    }
+
+    fn state(&self) -> proto::SmState {
+        let active = *self.active.lock().unwrap();
+        proto::SmState {
             running: self.running.load(Ordering::Acquire),
-            direction: self.direction.load(Ordering::Acquire)
+            direction: self.direction.load(Ordering::Acquire),
+            position: self.position.load(Ordering::Acquire),
+            move_to: match active { Some(Motion::MoveTo(dest)) => Some(dest), _ => None },
+            homing: matches!(active, Some(Motion::Home)),
+            steps: match active { Some(Motion::Steps(n)) => Some(n), _ => None },
+            stalled: self.stalled.load(Ordering::Acquire),
+            queue_len: self.queued.load(Ordering::Acquire),
+            limit_hit: self.limit_hit.load(Ordering::Acquire),
+            holding: self.holding.load(Ordering::Acquire),
+            motor: self.id,
+            jitter_us: self.jitter.load(Ordering::Acquire),
+            dropped_updates: self.dropped.load(Ordering::Acquire),
+            gesture: self.gesture.load(Ordering::Acquire),
+            cooling_down: self.cooling_down.load(Ordering::Acquire),
+            source: self.source.load(Ordering::Acquire),
+            motors: Default::default(),
         }
     }
 
-    fn get_parameters(&self) -> Self::Params {
-        Self::Params {
-            timeout: self.timeout.load(Ordering::Acquire)
+    /// Called when a cape switch is pressed. Stops the current motion if a client
+    /// requested it and `policy` allows switches to interrupt, along with any
+    /// motions queued by clients. Returns false if the press should not start a
+    /// motion of its own.
+    fn interrupt_client_motion(&self, policy: SwitchInterrupt) -> bool {
+        if policy == SwitchInterrupt::Ignore
+            || self.source.load(Ordering::Acquire) != proto::Source::Client as i32 {
+            return true
         }
+        tracing::info!("Cape switch interrupted Stepper Motor {:?} ({:?})", self.id, policy);
+        self.interrupted.store(true, Ordering::Release);
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        policy == SwitchInterrupt::TakeOver
     }
+}
 
-    async fn shutdown(&mut self) {
-        if let Some((motor_handle, sd_tx)) = self.shutdown.take() {
-            drop(sd_tx);
-            motor_handle.abort();
-            motor_handle.await.unwrap_err();
+/// Detects a stalled motor from a feedback line that should keep changing
+/// level while the motor turns (e.g. an optical interrupter on the hopper)
+struct StallDetector {
+    line: Box<dyn DigitalInput>,
+    timeout: Duration,
+    last_value: u8,
+    last_change: Instant,
+}
+
+impl StallDetector {
+    fn new(line: Box<dyn DigitalInput>, timeout: Duration) -> Self {
+        StallDetector { line, timeout, last_value: 0, last_change: Instant::now() }
+    }
+
+    /// start timing a new move
+    fn reset(&mut self) -> decide_protocol::Result<()> {
+        self.last_value = self.read()?;
+        self.last_change = Instant::now();
+        Ok(())
+    }
+
+    /// true if the feedback line has not changed within the timeout
+    fn stalled(&mut self) -> decide_protocol::Result<bool> {
+        let value = self.read()?;
+        if value != self.last_value {
+            self.last_value = value;
+            self.last_change = Instant::now();
         }
+        Ok(self.last_change.elapsed() > self.timeout)
+    }
+
+    fn read(&self) -> decide_protocol::Result<u8> {
+        self.line.get()
+            .map_err(|e| DecideError::Component { source: e })
     }
 }
 
-struct LinesVal([u8; 2]);
+impl proto::SmParams {
+    /// Timeout (ms) and step interval (us) for a move in the given direction.
+    /// Direction-specific values override the shared ones when set.
+    fn for_direction(&self, direction: bool) -> (u64, u64) {
+        if direction {
+            (self.timeout_up.unwrap_or(self.timeout), self.dt_up.unwrap_or(self.dt))
+        } else {
+            (self.timeout_down.unwrap_or(self.timeout), self.dt_down.unwrap_or(self.dt))
+        }
+    }
+}
+
+impl SoftLimits {
+    /// true if taking one step in `direction` from `position` stays within the limits
+    fn allows(&self, position: i64, direction: bool) -> bool {
+        if direction {
+            self.max.is_none_or(|max| position < max)
+        } else {
+            self.min.is_none_or(|min| position > min)
+        }
+    }
+}
+
+/// Cape push-button. Edges that arrive within the debounce window of each
+/// other are treated as chatter, and only changes in the settled level are reported.
+struct CapeSwitch {
+    events: Box<dyn EdgeInput>,
+    debounce: Duration,
+    active_low: bool, // the line reads 0 while the switch is pressed
+    level: u8, // last settled level of the line
+    error: Option<DecideError>, // read error that closed the switch, kept for the task to report
+}
+
+impl CapeSwitch {
+    fn new(events: Box<dyn EdgeInput>, debounce: Duration, active_low: bool) -> decide_protocol::Result<Self> {
+        let level = events.get()
+            .map_err(|e| DecideError::Component { source: e })?;
+        Ok(CapeSwitch { events, debounce, active_low, level, error: None })
+    }
+
+    /// Waits for the next settled transition of the switch and returns whether
+    /// it is now pressed, or returns None if the event stream has closed or
+    /// the line could not be read.
+    async fn transition(&mut self) -> Option<bool> {
+        loop {
+            let edge = match self.events.next_edge().await? {
+                Ok(edge) => edge,
+                Err(e) => return self.fail(e),
+            };
+            if self.debounce.is_zero() {
+                self.level = if edge == Edge::Rising { 1 } else { 0 };
+                return Some(self.pressed())
+            }
+            // wait for the line to go quiet for a full debounce window
+            while let Ok(Some(_)) = tokio::time::timeout(self.debounce, self.events.next_edge()).await {}
+            let level = match self.events.get() {
+                Ok(level) => level,
+                Err(e) => return self.fail(e),
+            };
+            if level != self.level {
+                self.level = level;
+                return Some(self.pressed())
+            }
+            tracing::trace!("Ignoring switch chatter");
+        }
+    }
+
+    fn pressed(&self) -> bool {
+        (self.level == 0) == self.active_low
+    }
+
+    fn fail<T>(&mut self, error: impl Into<DecideError>) -> Option<T> {
+        self.error = Some(error.into());
+        None
+    }
+
+    /// Waits until the switch is pressed (or released), or returns None if the
+    /// event stream has closed
+    async fn wait_until(&mut self, pressed: bool) -> Option<()> {
+        // an earlier wait may have been cancelled after consuming an edge
+        self.level = match self.events.get() {
+            Ok(level) => level,
+            Err(e) => return self.fail(e),
+        };
+        while self.pressed() != pressed {
+            self.transition().await?;
+        }
+        Some(())
+    }
+}
+
+/// Watches one cape switch and turns its gestures into motions for the motor task.
+/// A press runs the motor for the timeout parameter, a double press does the same
+/// in the opposite direction, and a long press runs it until the switch is released.
+struct SwitchTask {
+    switch: CapeSwitch,
+    offset: u32,
+    direction: bool, // direction the motor runs when the switch is pressed
+    long_press: Duration, // 0 disables long presses
+    double_press: Duration, // 0 disables double presses
+    interrupt: SwitchInterrupt,
+    status: Arc<Status>,
+    motions: mpsc::Sender<(Motion, proto::Gesture)>,
+    faults: mpsc::Sender<Any>,
+}
+
+impl SwitchTask {
+    async fn run(mut self) {
+        while let Some(gesture) = self.next_gesture().await {
+            tracing::debug!("Motor Switch {:?} {:?}", self.offset, gesture);
+            let motion = SwitchTask::motion(gesture, self.direction);
+            let long_press = gesture == proto::Gesture::LongPress;
+            if self.status.interrupt_client_motion(self.interrupt) {
+                self.status.switch_held.store(long_press, Ordering::Release);
+                if self.motions.send((motion, gesture)).await.is_err() {
+                    break
+                }
+            }
+            if long_press {
+                let released = self.switch.wait_until(false).await;
+                self.status.switch_held.store(false, Ordering::Release);
+                tracing::debug!("Motor Switch {:?} Released", self.offset);
+                if released.is_none() {
+                    break
+                }
+            }
+        }
+        self.status.switch_held.store(false, Ordering::Release);
+        if let Some(e) = self.switch.error.take() {
+            report_fault(&self.faults, e);
+        }
+    }
+
+    /// Motion started by a gesture on a switch that runs the motor in `direction`
+    fn motion(gesture: proto::Gesture, direction: bool) -> Motion {
+        match gesture {
+            proto::Gesture::LongPress => Motion::Jog { direction },
+            proto::Gesture::DoublePress => Motion::Run { direction: !direction },
+            _ => Motion::Run { direction },
+        }
+    }
+
+    /// Waits for the next press and classifies it. A long press is reported as soon
+    /// as the switch has been held long enough, before it is released.
+    async fn next_gesture(&mut self) -> Option<proto::Gesture> {
+        self.switch.wait_until(true).await?;
+        tracing::info!("Motor Switch {:?} Pressed", self.offset);
+        let mut released = false;
+        if !self.long_press.is_zero() {
+            match tokio::time::timeout(self.long_press, self.switch.wait_until(false)).await {
+                Ok(result) => {
+                    result?;
+                    released = true;
+                }
+                Err(_) => return Some(proto::Gesture::LongPress),
+            }
+        }
+        if !self.double_press.is_zero() {
+            if !released {
+                self.switch.wait_until(false).await?;
+            }
+            if let Ok(result) = tokio::time::timeout(self.double_press, self.switch.wait_until(true)).await {
+                result?;
+                return Some(proto::Gesture::DoublePress)
+            }
+        }
+        Some(proto::Gesture::Press)
+    }
+}
+
+/// Keeps the coils energized while the motor is idle. With a duty cycle the
+/// coils are switched on and off every `PERIOD` to reduce heating.
+struct Hold {
+    duty: Option<u32>,
+    energized: bool,
+    since: Instant,
+}
+
+impl Hold {
+    const PERIOD: Duration = Duration::from_millis(20);
+
+    fn new(duty: Option<u32>) -> Self {
+        Hold { duty, energized: true, since: Instant::now() }
+    }
+
+    /// Time until the coils next need to be switched, or None if holding continuously
+    fn next_toggle(&self) -> Option<Duration> {
+        let duty = self.duty.filter(|duty| *duty < 100)?;
+        let on = Self::PERIOD * duty / 100;
+        let phase = if self.energized { on } else { Self::PERIOD - on };
+        Some(phase.saturating_sub(self.since.elapsed()))
+    }
+
+    /// Switches the coils if the current phase of the duty cycle has ended
+    fn update(&mut self, pattern: &(LinesVal, LinesVal), coils: &impl Coils) -> decide_protocol::Result<()> {
+        if self.next_toggle() != Some(Duration::ZERO) {
+            return Ok(())
+        }
+        self.energized = !self.energized;
+        self.since = Instant::now();
+        if self.energized {
+            coils.apply(pattern)
+        } else {
+            StepperMotor::pause_motor(coils)
+        }
+    }
+}
+
+/// Limits how long the motor runs within a rolling window so the driver board
+/// does not overheat. Once the limit is reached the motor cools down until it has
+/// run for no more than half the limit within the window.
+struct DutyLimiter {
+    max_on: Duration,
+    window: Duration,
+    runs: VecDeque<(Instant, Instant)>, // start and end of recent moves, oldest first
+    current: Option<Instant>, // start of the move in progress
+    cooling: bool,
+}
+
+impl DutyLimiter {
+    /// how often a motion waiting for the end of a cool-down checks for stop requests
+    const POLL: Duration = Duration::from_millis(10);
+
+    fn new(config: &DutyLimitConfig) -> Self {
+        DutyLimiter {
+            max_on: Duration::from_millis(config.max_on),
+            window: Duration::from_millis(config.window),
+            runs: VecDeque::new(),
+            current: None,
+            cooling: false,
+        }
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.current = Some(now);
+    }
+
+    fn finish(&mut self) {
+        if let Some(start) = self.current.take() {
+            self.runs.push_back((start, Instant::now()));
+        }
+    }
+
+    /// Time the motor has run within the window ending at `now`
+    fn on_time(&mut self, now: Instant) -> Duration {
+        let window_start = now.checked_sub(self.window);
+        while self.runs.front().is_some_and(|&(_, end)| Some(end) <= window_start) {
+            self.runs.pop_front();
+        }
+        self.runs.iter()
+            .copied()
+            .chain(self.current.map(|start| (start, now)))
+            .map(|(start, end)| end.saturating_duration_since(window_start.map_or(start, |ws| start.max(ws))))
+            .sum()
+    }
+
+    /// true if the move in progress has used up the limit
+    fn exhausted(&mut self) -> bool {
+        if self.on_time(Instant::now()) >= self.max_on {
+            self.cooling = true;
+        }
+        self.cooling
+    }
+
+    /// Time left in the current cool-down, or None if the motor may run
+    fn cooldown(&mut self) -> Option<Duration> {
+        if !self.cooling {
+            return None
+        }
+        let now = Instant::now();
+        // on-time that has to leave the window before the motor may run again
+        let mut excess = self.on_time(now).saturating_sub(self.max_on / 2);
+        if excess.is_zero() {
+            self.cooling = false;
+            return None
+        }
+        let Some(window_start) = now.checked_sub(self.window) else {
+            return Some(self.window)
+        };
+        for &(start, end) in &self.runs {
+            let start = start.max(window_start);
+            let length = end.saturating_duration_since(start);
+            if length >= excess {
+                return Some((start + excess).saturating_duration_since(window_start))
+            }
+            excess -= length;
+        }
+        Some(self.window)
+    }
+}
+
+/// Publishes state changes from the motor task without waiting on the channel,
+/// so a slow subscriber cannot stall a move. When the channel is full the update
+/// is dropped, and because each update is a full snapshot, the latest state is
+/// sent once there is room again.
+struct StateEmitter {
+    sender: mpsc::Sender<Any>,
+    pending: bool, // an update was dropped and has not been superseded yet
+    closed: bool, // the controller has dropped the receiver, so the driver should stop
+}
+
+impl StateEmitter {
+    fn emit(&mut self, status: &Status) {
+        tracing::debug!("Emiting state change");
+        self.pending = !self.try_send(Self::encode(&status.state()), status) && !self.closed;
+    }
+
+    /// Publishes the statistics of a finished move. These are not resent if the
+    /// channel is full, so they are lost along with the dropped update.
+    fn emit_stats(&mut self, stats: proto::SmMoveStats, status: &Status) {
+        self.try_send(pack(StepperMotor::STATS_TYPE_URL, &stats), status);
+    }
+
+    /// Sends without waiting, or counts the message as dropped and returns false.
+    /// A closed channel is recorded in `closed`.
+    fn try_send(&mut self, message: Any, status: &Status) -> bool {
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                let dropped = status.dropped.fetch_add(1, Ordering::AcqRel) + 1;
+                tracing::warn!("Stepper Motor state channel full, {:?} update(s) dropped", dropped);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::debug!("Stepper Motor state channel closed");
+                self.closed = true;
+                false
+            }
+        }
+    }
+
+    fn encode(state: &proto::SmState) -> Any {
+        StepperMotor::pack_state(state)
+    }
+}
+
+/// Summary of a finished move, published after the final state update
+struct MoveStats {
+    steps: u64,
+    duration: Duration,
+    reason: proto::StopReason,
+}
+
+impl MoveStats {
+    fn encode(&self, motor: u32) -> proto::SmMoveStats {
+        let duration_us = self.duration.as_micros() as u64;
+        proto::SmMoveStats {
+            motor,
+            steps: self.steps,
+            duration_us,
+            mean_interval_us: duration_us.checked_div(self.steps).unwrap_or(0),
+            reason: self.reason as i32,
+        }
+    }
+}
+
+/// Schedules steps against absolute deadlines so that errors in one interval
+/// do not accumulate. The driver has a thread to itself, so it sleeps until
+/// shortly before each deadline and spins for the remainder, which would
+/// otherwise be lost to the latency of waking the thread.
+struct PulseTimer {
+    deadline: Instant,
+    max_jitter: Duration, // largest lateness of any deadline so far
+}
+
+impl PulseTimer {
+    const SPIN: Duration = Duration::from_micros(300);
+
+    fn new() -> Self {
+        PulseTimer { deadline: Instant::now(), max_jitter: Duration::ZERO }
+    }
+
+    /// Waits until `interval` us after the previous deadline
+    fn wait(&mut self, interval: u64) {
+        self.deadline += Duration::from_micros(interval);
+        let now = Instant::now();
+        if self.deadline <= now {
+            // already late; restart the schedule rather than rushing the next steps
+            self.max_jitter = self.max_jitter.max(now - self.deadline);
+            self.deadline = now;
+            return
+        }
+        if let Some(coarse) = (self.deadline - now).checked_sub(Self::SPIN) {
+            thread::sleep(coarse);
+        }
+        while Instant::now() < self.deadline {
+            std::hint::spin_loop();
+        }
+        self.max_jitter = self.max_jitter.max(self.deadline.elapsed());
+    }
+}
+
+/// Coil energizing sequence used to drive the motor. Each entry of the
+/// sequence is one step for the purposes of positions and step counts.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DriveMode {
+    /// one coil energized at a time: 4 steps per cycle, lowest torque
+    Wave,
+    /// both coils energized: 4 steps per cycle, highest torque
+    Full,
+    /// alternates one and two coils energized: 8 steps per cycle, smoothest
+    #[default]
+    Half,
+}
+
+impl DriveMode {
+    fn sequence(&self) -> Vec<(LinesVal, LinesVal)> {
+        // the half-step table alternates two-coil and one-coil entries
+        StepperMotor::HALF_STEPS.iter()
+            .enumerate()
+            .filter(|(i, _)| match self {
+                DriveMode::Wave => i % 2 == 1,
+                DriveMode::Full => i % 2 == 0,
+                DriveMode::Half => true,
+            })
+            .map(|(_, values)| *values)
+            .collect()
+    }
+}
+
+/// Trapezoidal speed profile, expressed as the interval (in us) between steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Ramp {
+    start_dt: u64,
+    min_dt: u64,
+    steps: u64,
+}
+
+impl Ramp {
+    /// Interval to wait after a step, given how many steps have been taken
+    /// and how many remain. The interval shrinks linearly from `start_dt` to `min_dt`
+    /// over `steps` steps, and grows back symmetrically as the move ends.
+    fn interval(&self, taken: u64, remaining: u64) -> u64 {
+        if self.steps == 0 || self.start_dt <= self.min_dt {
+            return self.min_dt
+        }
+        let k = taken.min(remaining).min(self.steps);
+        self.start_dt - (self.start_dt - self.min_dt) * k / self.steps
+    }
+
+    /// Approximate number of ramp steps that can be completed in `time_left`
+    fn steps_within(&self, time_left: Duration) -> u64 {
+        let mean_dt = (self.start_dt.max(self.min_dt) + self.min_dt) / 2;
+        time_left.as_micros() as u64 / mean_dt.max(1)
+    }
+}
 
 impl StepperMotor {
-    const NUM_HALF_STEPS: usize = 8;
+    const QUEUE_SIZE: usize = 20;
+    const STATS_TYPE_URL: &'static str = "type.googleapis.com/SmMoveStats";
     const ALL_OFF: LinesVal = LinesVal([0, 0]);
     const HALF_STEPS: [(LinesVal, LinesVal); 8] = [
         (LinesVal([0, 1]), LinesVal([1, 0])),
@@ -199,135 +1245,704 @@ impl StepperMotor {
         (LinesVal([0, 0]), LinesVal([1, 0]))
     ];
 
-    fn request_lines(chip: &mut Chip, lines: &[u32]) -> MultiLineHandle {
-        return chip
-            .get_lines(lines)
-            .map_err(|e| DecideError::Component { source: e.into() }).unwrap()
-            .request(LineRequestFlags::OUTPUT, &[0, 0], "decide-rs")
-            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+    fn request_lines(chip: &mut Chip, lines: &[u32]) -> decide_protocol::Result<impl DigitalOutputs> {
+        cdev::outputs(chip, lines, "decide-rs")
+            .map_err(|e| DecideError::Component { source: e })
     }
 
-    fn request_asynclines(chip: &mut Chip, lines: u32) -> AsyncLineEventHandle {
-        let line = chip.get_line(lines)
-            .map_err(|e| DecideError::Component { source: e.into() })
-            .unwrap();
-        return AsyncLineEventHandle::new(
-            line.events(
-                LineRequestFlags::INPUT,
-                EventRequestFlags::BOTH_EDGES,
-                "decide-rs"
-            ).map_err(|e| DecideError::Component { source: e.into() }).unwrap()
-        ).map_err(|e| DecideError::Component { source: e.into() }).unwrap();
-
+    fn request_asynclines(chip: &mut Chip, lines: u32, bias: LineRequestFlags) -> decide_protocol::Result<impl EdgeInput> {
+        cdev::edges(chip, lines, bias, "decide-rs")
+            .map_err(|e| DecideError::Component { source: e })
     }
 
-    async fn poll_change(sw14: &mut AsyncLineEventHandle,
-                         sw15: &mut AsyncLineEventHandle,
-                         state_rx: &mut mpsc::Receiver<[bool; 2]>) -> proto::SmState {
-        let mut state = proto::SmState {running: false, direction: false};
-        tokio::select! {
+    fn request_inputline(chip: &mut Chip, line: u32) -> decide_protocol::Result<impl DigitalInput> {
+        cdev::input(chip, line, "decide-rs")
+            .map_err(|e| DecideError::Component { source: e })
+    }
 
-            Some(event) = sw14.next() => {
-                let evt_type = event.map_err(|e| DecideError::Component { source: e.into() })
-                                    .unwrap().event_type();
-                match evt_type {
-                    EventType::RisingEdge => {
-                        tracing::info!("Motor Switch 14 Pressed");
-                        //state.running = false;
-                        //state.direction = false;
-                    }
-                    EventType::FallingEdge => {
-                        tracing::debug!("Motor Switch 14 Depressed");
-                        state.running = true;
-                        //state.direction = false;
+    fn limit_reached(switch: &dyn DigitalInput) -> decide_protocol::Result<bool> {
+        let level = switch.get()
+            .map_err(|e| DecideError::Component { source: e })?;
+        Ok(level != 0)
+    }
 
-                    }
+    async fn poll_change(switches: &mut mpsc::Receiver<(Motion, proto::Gesture)>,
+                         state_rx: &mut mpsc::Receiver<(Motion, u32)>,
+                         shutdown: &mut mpsc::Receiver<bool>,
+                         emitter: &mut StateEmitter,
+                         status: &Status,
+                         tick: Option<Duration>) -> Option<(Motion, u32, proto::Gesture)> {
+        let epoch = status.epoch.load(Ordering::Acquire);
+        tokio::select! {
+            // wakes the motor task to switch the coils or end a cool-down
+            _ = tokio::time::sleep(tick.unwrap_or_default()), if tick.is_some() => None,
+            // sends the latest state once there is room for an update that was dropped
+            permit = emitter.sender.reserve(), if emitter.pending => {
+                match permit {
+                    Ok(permit) => permit.send(StateEmitter::encode(&status.state())),
+                    // the driver stops at the top of its loop
+                    Err(_) => emitter.closed = true,
                 }
+                emitter.pending = false;
+                None
             }
-            Some(event) = sw15.next() => {
-                let evt_type = event.map_err(|e| DecideError::Component { source: e.into() })
-                                    .unwrap().event_type();
-                match evt_type {
-                    EventType::RisingEdge => {
-                        tracing::debug!("Motor Switch 15 Pressed");
-                        //state.running = false;
-                        state.direction = true;
-                    }
-                    EventType::FallingEdge => {
-                        tracing::debug!("Motor Switch 15 Depressed");
-                        state.running = true;
-                        state.direction = true;
 
-                    }
-                }
-            }
-            Some(event) = state_rx.recv() => {
-                if event.len() != 2 {
-                    tracing::error!("Motor event poll received incorrect message");
+            // the channel is closed at shutdown; the driver checks for that next
+            _ = shutdown.recv() => None,
+            Some((motion, gesture)) = switches.recv() => Some((motion, epoch, gesture)),
+            Some((motion, epoch)) = state_rx.recv() => {
+                status.queued.fetch_sub(1, Ordering::AcqRel);
+                if StepperMotor::stop_requested(status, epoch) {
+                    tracing::debug!("Discarding {:?} queued before a stop request", motion);
+                    None
                 } else {
-                    state.running = event[0];
-                    state.direction = event[1];
+                    Some((motion, epoch, proto::Gesture::NoGesture))
                 }
             }
         }
-        return state
     }
 
-    fn run_motor(mut step: usize, handle1: &MultiLineHandle, handle3: &MultiLineHandle, direction: bool) -> usize{
-        if direction {
-            step = (step + 1) % Self::NUM_HALF_STEPS;
-            let step_1_values = &Self::HALF_STEPS[step].0;
-            let step_3_values = &Self::HALF_STEPS[step].1;
-            handle1.set_values(&step_1_values.0)
-                .map_err(|e| DecideError::Component { source: e.into() })
-                .unwrap();
-            handle3.set_values(&step_3_values.0)
-                .map_err(|e| DecideError::Component { source: e.into() })
-                .unwrap();
+    /// Reorders the coil lines of a pattern. The lines are numbered 0-1 for
+    /// motor1_offsets and 2-3 for motor3_offsets, and line i is driven with the
+    /// value the pattern gives line order[i].
+    fn remap(pattern: &(LinesVal, LinesVal), order: &[usize; 4]) -> (LinesVal, LinesVal) {
+        let values = [(pattern.0).0[0], (pattern.0).0[1], (pattern.1).0[0], (pattern.1).0[1]];
+        (LinesVal([values[order[0]], values[order[1]]]), LinesVal([values[order[2]], values[order[3]]]))
+    }
+
+    fn run_motor(phase: i64, sequence: &[(LinesVal, LinesVal)], coils: &impl Coils,
+                 direction: bool) -> decide_protocol::Result<i64> {
+        let phase = StepperMotor::next_phase(phase, direction);
+        coils.apply(StepperMotor::coils(sequence, phase))?;
+        Ok(phase)
+    }
+
+    fn next_phase(phase: i64, direction: bool) -> i64 {
+        if direction { phase + 1 } else { phase - 1 }
+    }
+
+    /// Coil pattern for a phase. The euclidean remainder keeps negative
+    /// phases (from stepping backwards past the start) inside the table.
+    fn coils(sequence: &[(LinesVal, LinesVal)], phase: i64) -> &(LinesVal, LinesVal) {
+        &sequence[phase.rem_euclid(sequence.len() as i64) as usize]
+    }
+
+    fn pause_motor(coils: &impl Coils) -> decide_protocol::Result<()> {
+        coils.apply(&(Self::ALL_OFF, Self::ALL_OFF))
+    }
+
+    /// Checks the conditions that end a move before its next step
+    fn should_stop(stall: &mut Option<StallDetector>, duty: &mut Option<DutyLimiter>, limits: &SoftLimits,
+                   status: &Status, direction: bool, epoch: u32) -> decide_protocol::Result<Option<proto::StopReason>> {
+        if StepperMotor::stop_requested(status, epoch) {
+            return Ok(Some(StepperMotor::stop_cause(status)))
+        }
+        let position = status.position.load(Ordering::Acquire);
+        if !limits.allows(position, direction) {
+            tracing::warn!("Stepper Motor reached soft limit at position {:?}", position);
+            status.limit_hit.store(true, Ordering::Release);
+            return Ok(Some(proto::StopReason::SoftLimit))
+        }
+        if StepperMotor::check_stall(stall, status)? {
+            return Ok(Some(proto::StopReason::Stalled))
+        }
+        if StepperMotor::check_duty(duty) {
+            return Ok(Some(proto::StopReason::DutyLimit))
+        }
+        Ok(None)
+    }
+
+    fn check_duty(duty: &mut Option<DutyLimiter>) -> bool {
+        if duty.as_mut().is_some_and(DutyLimiter::exhausted) {
+            tracing::warn!("Stepper Motor reached its duty-cycle limit, cooling down");
+            true
         } else {
-            step = (step - 1) % Self::NUM_HALF_STEPS;
-            let step_1_values = &Self::HALF_STEPS[step].0;
-            let step_3_values = &Self::HALF_STEPS[step].1;
-            handle1.set_values(&step_1_values.0)
-                .map_err(|e| DecideError::Component { source: e.into() })
-                .unwrap();
-            handle3.set_values(&step_3_values.0)
-                .map_err(|e| DecideError::Component { source: e.into() })
-                .unwrap();
-        }
-        step
-    }
-
-    fn pause_motor(handle1: &MultiLineHandle, handle3: &MultiLineHandle) {
-        let step_1_values = &Self::ALL_OFF;
-        let step_3_values = &Self::ALL_OFF;
-        handle1.set_values(&step_1_values.0)
-            .map_err(|e| DecideError::Component { source: e.into() })
-            .unwrap();
-        handle3.set_values(&step_3_values.0)
-            .map_err(|e| DecideError::Component { source: e.into() })
-            .unwrap();
+            false
+        }
     }
 
-    async fn send_state(state: &proto::SmState, sender: &mut mpsc::Sender<Any>) {
-        tracing::debug!("Emiting state change");
-        sender.send(Any {
-            type_url: String::from(Self::STATE_TYPE_URL),
-            value: state.encode_to_vec(),
-        }).await.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+    /// Time until the motor task next needs to wake while idle: to switch the
+    /// coils while holding, or to report the end of a cool-down
+    fn next_tick(hold: &Option<Hold>, duty: &mut Option<DutyLimiter>) -> Option<Duration> {
+        let hold_tick = hold.as_ref().and_then(Hold::next_toggle);
+        let cooldown = duty.as_mut().and_then(DutyLimiter::cooldown);
+        match (hold_tick, cooldown) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// true if a client has asked to stop since the motion started in `epoch`
+    fn stop_requested(status: &Status, epoch: u32) -> bool {
+        status.epoch.load(Ordering::Acquire) != epoch
+    }
+
+    /// Whether a stop was requested by a client or by a cape switch
+    fn stop_cause(status: &Status) -> proto::StopReason {
+        if status.interrupted.load(Ordering::Acquire) {
+            proto::StopReason::SwitchPressed
+        } else {
+            proto::StopReason::StopRequested
+        }
+    }
+
+    fn check_stall(stall: &mut Option<StallDetector>, status: &Status) -> decide_protocol::Result<bool> {
+        let stalled = match stall.as_mut() {
+            Some(stall) => stall.stalled()?,
+            None => false,
+        };
+        if stalled {
+            tracing::error!("Stepper Motor stalled at position {:?}, stopping",
+                            status.position.load(Ordering::Acquire));
+            status.stalled.store(true, Ordering::Release);
+        }
+        Ok(stalled)
     }
 
 }
+
+/// Error for a config the motors cannot be set up from
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/_.rs"));
 }
 
 #[derive(Deserialize)]
-pub struct Config {
+#[serde(untagged)]
+pub enum Config {
+    /// several motors, addressed in requests by their index in `motors`
+    Multiple { motors: Vec<MotorConfig> },
+    /// a single motor, with index 0
+    Single(Box<MotorConfig>),
+}
+
+impl Config {
+    fn motors(&self) -> &[MotorConfig] {
+        match self {
+            Config::Multiple { motors } => motors,
+            Config::Single(motor) => std::slice::from_ref(&**motor),
+        }
+    }
+
+    fn into_motors(self) -> Vec<MotorConfig> {
+        match self {
+            Config::Multiple { motors } => motors,
+            Config::Single(motor) => vec![*motor],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MotorConfig {
     chip1: String, //"/dev/gpiochip1"
     chip3: String, //"/dev/gpiochip3"
     switch_offsets: [u32; 2], //14,15
     motor1_offsets: [u32; 2], //13, 12
     motor3_offsets: [u32; 2], //19,21
-    dt: u64, //2000
-}
\ No newline at end of file
+    dt: u64, //2000, initial value of the dt parameter
+    #[serde(default)]
+    debounce: u64, // ms the switch lines must be stable before a press or release counts; 0 disables
+    #[serde(default = "MotorConfig::switch_active_low")]
+    switch_active_low: [bool; 2], // per switch; false if the line reads high while pressed
+    switch_bias: Option<Bias>, // pull applied to the switch lines; needs Linux 5.5 or later
+    #[serde(default)]
+    drive_mode: DriveMode, // "wave", "full", or "half" (default)
+    #[serde(default)]
+    invert_direction: bool, // swap which way the coils turn for direction = true
+    coil_order: Option<[usize; 4]>, // line driven in place of each of the 4 coil lines; see StepperMotor::remap
+    #[serde(default)]
+    start_dt: u64, // initial step interval when accelerating; at or below dt disables ramping
+    #[serde(default)]
+    ramp_steps: u64, // steps taken to accelerate from start_dt to dt (and to decelerate)
+    home: Option<HomeConfig>,
+    stall: Option<StallConfig>,
+    duty_limit: Option<DutyLimitConfig>,
+    #[serde(default)]
+    soft_limits: SoftLimits,
+    #[serde(default)]
+    hold: bool, // initial value of the hold parameter
+    hold_duty: Option<u32>, // initial value of the hold_duty parameter
+    #[serde(default)]
+    mock: bool, // simulate the motor without opening the gpiochips
+    #[serde(default)]
+    switch_interrupt: SwitchInterrupt, // what a switch press does to a motion requested by a client
+    #[serde(default)]
+    long_press: u64, // ms a switch must be held to run the motor until release; 0 disables
+    #[serde(default)]
+    double_press: u64, // ms after a release in which a second press reverses direction; 0 disables
+}
+
+impl MotorConfig {
+    fn switch_active_low() -> [bool; 2] {
+        [true, true]
+    }
+
+    /// Coil patterns for the drive mode, adjusted for how this motor is wired
+    fn sequence(&self) -> Vec<(LinesVal, LinesVal)> {
+        let mut sequence = self.drive_mode.sequence();
+        if let Some(order) = &self.coil_order {
+            sequence = sequence.iter().map(|pattern| StepperMotor::remap(pattern, order)).collect();
+        }
+        if self.invert_direction {
+            sequence.reverse();
+        }
+        sequence
+    }
+}
+
+/// What a cape switch press does while the motor is running a motion requested by a client
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchInterrupt {
+    /// the press is queued and runs after the client's motion
+    #[default]
+    Ignore,
+    /// the client's motion stops, and the press does nothing else
+    Abort,
+    /// the client's motion stops and the press runs the motor instead
+    TakeOver,
+}
+
+/// Bias applied to the switch lines, for capes without external pull resistors
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Bias {
+    PullUp,
+    PullDown,
+    Disabled,
+}
+
+impl Bias {
+    /// gpio-cdev does not define the bias flags yet, so they are built from the
+    /// GPIOHANDLE_REQUEST_BIAS_* values in the kernel's uAPI
+    fn flags(&self) -> LineRequestFlags {
+        let bits = match self {
+            Bias::PullUp => 1 << 5,
+            Bias::PullDown => 1 << 6,
+            Bias::Disabled => 1 << 7,
+        };
+        // SAFETY: the flags are only passed to the kernel, which rejects ones it does not support
+        unsafe { LineRequestFlags::from_bits_unchecked(bits) }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+pub struct SoftLimits {
+    min: Option<i64>, // lowest position the motor may step to
+    max: Option<i64>, // highest position the motor may step to
+}
+
+#[derive(Deserialize)]
+pub struct HomeConfig {
+    offset: u32, // limit switch line on chip1
+    direction: bool, // direction that drives the motor toward the switch
+    max_steps: u64, // give up homing after this many steps
+}
+
+#[derive(Deserialize)]
+pub struct StallConfig {
+    offset: u32, // feedback line on chip1 that toggles while the motor turns
+    timeout: u64, // ms without a feedback transition before the motor is considered stalled
+}
+
+#[derive(Deserialize)]
+pub struct DutyLimitConfig {
+    max_on: u64, // ms the motor may run within the window before cooling down
+    window: u64, // ms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decide_hal::mock::{self, MockOutputs};
+
+    const MODES: [DriveMode; 3] = [DriveMode::Wave, DriveMode::Full, DriveMode::Half];
+
+    /// Coil lines that record every pattern applied to them
+    #[derive(Clone, Default)]
+    struct MockCoils {
+        handle1: MockOutputs,
+        handle3: MockOutputs,
+    }
+
+    impl MockCoils {
+        fn lines(&self) -> MotorLines {
+            MotorLines { handle1: Box::new(self.handle1.clone()), handle3: Box::new(self.handle3.clone()) }
+        }
+
+        /// number of patterns applied that energize at least one coil
+        fn steps(&self) -> usize {
+            self.handle1.history().iter()
+                .zip(self.handle3.history())
+                .filter(|(line1, line3)| line1.iter().chain(line3).any(|&value| value != 0))
+                .count()
+        }
+    }
+
+    /// Ends of the driver's channels held by the test
+    struct Channels {
+        states: mpsc::Receiver<Any>,
+        _shutdown: mpsc::Sender<bool>,
+        switches: mpsc::Sender<(Motion, proto::Gesture)>,
+    }
+
+    /// A motor driven by mock coils, with switch gestures injected through `Channels`.
+    /// The channels must be kept alive for the driver to keep running.
+    fn mock_motor(coils: MockCoils) -> (Motor, Driver<MotorLines>, Channels) {
+        let (req_snd, req_rcv) = mpsc::channel(StepperMotor::QUEUE_SIZE);
+        let (switch_snd, switch_rcv) = mpsc::channel(StepperMotor::QUEUE_SIZE);
+        let (state_snd, state_rcv) = mpsc::channel(100);
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let motor = Motor {
+            status: Arc::new(Status::new(0)),
+            params: Arc::new(Mutex::new(proto::SmParams {
+                timeout: 20,
+                dt: 100,
+                ..Default::default()
+            })),
+            can_home: false,
+            req_sender: Some(req_snd),
+            mock: true,
+            switch_interrupt: SwitchInterrupt::TakeOver,
+            switch_sender: Some(switch_snd.clone()),
+            switch_tasks: Vec::new(),
+            shutdown: None,
+        };
+        let driver = Driver {
+            coils: coils.lines(),
+            home_switch: None,
+            home: None,
+            stall: None,
+            duty: None,
+            sequence: DriveMode::Half.sequence(),
+            limits: SoftLimits::default(),
+            status: motor.status.clone(),
+            params: motor.params.clone(),
+            requests: req_rcv,
+            switches: switch_rcv,
+            emitter: StateEmitter { sender: state_snd, pending: false, closed: false },
+            shutdown: shutdown_rx,
+        };
+        (motor, driver, Channels { states: state_rcv, _shutdown: shutdown_tx, switches: switch_snd })
+    }
+
+    fn steps(n: i32) -> proto::SmState {
+        proto::SmState { steps: Some(n), ..Default::default() }
+    }
+
+    /// Waits for the motor to finish all queued motions
+    async fn settle(motor: &Motor) {
+        for _ in 0..200 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let state = motor.status.state();
+            if !state.running && state.queue_len == 0 {
+                return
+            }
+        }
+        panic!("motor did not finish moving");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn motor_does_not_move_without_a_command() {
+        let coils = MockCoils::default();
+        let (motor, driver, _channels) = mock_motor(coils.clone());
+        let task = tokio::spawn(driver.run());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(coils.steps(), 0);
+        assert!(!motor.status.state().running);
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn command_sent_before_task_waits_is_not_missed() {
+        let coils = MockCoils::default();
+        let (motor, driver, _channels) = mock_motor(coils.clone());
+        motor.change_state(steps(10)).unwrap();
+        let task = tokio::spawn(driver.run());
+        settle(&motor).await;
+        assert_eq!(coils.steps(), 10);
+        assert_eq!(motor.status.state().position, 10);
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_queued_command_runs_in_order() {
+        let coils = MockCoils::default();
+        let (motor, driver, _channels) = mock_motor(coils.clone());
+        let task = tokio::spawn(driver.run());
+        motor.change_state(steps(5)).unwrap();
+        motor.change_state(steps(-8)).unwrap();
+        motor.change_state(proto::SmState { move_to: Some(4), ..Default::default() }).unwrap();
+        settle(&motor).await;
+        assert_eq!(coils.steps(), 5 + 8 + 7);
+        assert_eq!(motor.status.state().position, 4);
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stop_cancels_commands_queued_before_it() {
+        let coils = MockCoils::default();
+        let (motor, driver, _channels) = mock_motor(coils.clone());
+        motor.change_state(steps(10)).unwrap();
+        motor.change_state(proto::SmState::default()).unwrap();
+        motor.change_state(steps(3)).unwrap();
+        let task = tokio::spawn(driver.run());
+        settle(&motor).await;
+        assert_eq!(coils.steps(), 3);
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn driver_stops_when_state_channel_closes() {
+        let coils = MockCoils::default();
+        let (motor, driver, channels) = mock_motor(coils.clone());
+        let task = tokio::spawn(driver.run());
+        drop(channels.states);
+        motor.change_state(steps(3)).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), task).await
+            .expect("driver did not stop")
+            .unwrap();
+        assert_eq!(coils.steps(), 3);
+    }
+
+    #[test]
+    fn get_state_reports_every_motor() {
+        let (first, _, _) = mock_motor(MockCoils::default());
+        let (mut second, _, _) = mock_motor(MockCoils::default());
+        second.status = Arc::new(Status::new(1));
+        second.status.position.store(7, Ordering::Release);
+        let (state_sender, _) = mpsc::channel(1);
+        let motors = StepperMotor { motors: vec![first, second], selected: 0, state_sender };
+        let state = motors.get_state();
+        assert_eq!(state.motor, 0);
+        assert_eq!(state.motors.len(), 2);
+        assert_eq!(state.motors[&1].position, 7);
+    }
+
+    /// Receives published messages until the statistics of a move arrive
+    async fn next_stats(channels: &mut Channels) -> proto::SmMoveStats {
+        loop {
+            let message = channels.states.recv().await.unwrap();
+            if message.type_url == StepperMotor::STATS_TYPE_URL {
+                return decide_protocol::unpack(StepperMotor::STATS_TYPE_URL, &message).unwrap()
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn finished_moves_report_statistics() {
+        let coils = MockCoils::default();
+        let (motor, driver, mut channels) = mock_motor(coils.clone());
+        let task = tokio::spawn(driver.run());
+        motor.change_state(steps(10)).unwrap();
+        let stats = next_stats(&mut channels).await;
+        assert_eq!(stats.steps, 10);
+        assert_eq!(stats.reason(), proto::StopReason::Completed);
+        assert!(stats.mean_interval_us >= 100);
+        assert_eq!(stats.duration_us / stats.steps, stats.mean_interval_us);
+        motor.change_state(proto::SmState { running: true, ..Default::default() }).unwrap();
+        let stats = next_stats(&mut channels).await;
+        assert_eq!(stats.reason(), proto::StopReason::Timeout);
+        assert!(stats.duration_us >= 20_000);
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duty_limit_stops_the_motor_to_cool_down() {
+        let coils = MockCoils::default();
+        let (motor, mut driver, mut channels) = mock_motor(coils.clone());
+        driver.duty = Some(DutyLimiter::new(&DutyLimitConfig { max_on: 10, window: 100 }));
+        let task = tokio::spawn(driver.run());
+        motor.change_state(proto::SmState { running: true, ..Default::default() }).unwrap();
+        let stats = next_stats(&mut channels).await;
+        assert_eq!(stats.reason(), proto::StopReason::DutyLimit);
+        assert!(motor.status.state().cooling_down);
+        let stopped = Instant::now();
+        motor.change_state(steps(5)).unwrap();
+        let stats = next_stats(&mut channels).await;
+        assert_eq!(stats.reason(), proto::StopReason::Completed);
+        // half of the 10 ms on-time has to leave the 100 ms window first
+        assert!(stopped.elapsed() >= Duration::from_millis(80));
+        assert!(!motor.status.state().cooling_down);
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn switch_press_takes_over_client_motion() {
+        let coils = MockCoils::default();
+        let (motor, driver, mut channels) = mock_motor(coils.clone());
+        let task = tokio::spawn(driver.run());
+        motor.change_state(proto::SmState { running: true, ..Default::default() }).unwrap();
+        motor.change_state(steps(10)).unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(motor.status.state().source(), proto::Source::Client);
+        motor.simulate_switch(proto::Gesture::LongPress, true).unwrap();
+        let stats = next_stats(&mut channels).await;
+        assert_eq!(stats.reason(), proto::StopReason::SwitchPressed);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let state = motor.status.state();
+        assert_eq!(state.source(), proto::Source::Switch);
+        assert!(state.direction);
+        motor.simulate_switch(proto::Gesture::NoGesture, true).unwrap();
+        let stats = next_stats(&mut channels).await;
+        assert_eq!(stats.reason(), proto::StopReason::SwitchReleased);
+        // the queued client motion was cancelled along with the running one
+        settle(&motor).await;
+        assert_eq!(motor.status.state().source(), proto::Source::NoSource);
+        while let Ok(message) = channels.states.try_recv() {
+            assert_ne!(message.type_url, StepperMotor::STATS_TYPE_URL);
+        }
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn long_press_runs_until_release() {
+        let coils = MockCoils::default();
+        let (motor, driver, channels) = mock_motor(coils.clone());
+        // longer than the timeout parameter
+        motor.params.lock().unwrap().timeout = 5;
+        let task = tokio::spawn(driver.run());
+        motor.status.switch_held.store(true, Ordering::Release);
+        channels.switches.send((Motion::Jog { direction: false }, proto::Gesture::LongPress)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let state = motor.status.state();
+        assert!(state.running);
+        assert_eq!(state.gesture(), proto::Gesture::LongPress);
+        motor.status.switch_held.store(false, Ordering::Release);
+        settle(&motor).await;
+        let state = motor.status.state();
+        assert_eq!(state.gesture(), proto::Gesture::NoGesture);
+        assert_eq!(state.position, -(coils.steps() as i64));
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn switch_press_is_simulated_through_parameters() {
+        let coils = MockCoils::default();
+        let (motor, driver, _channels) = mock_motor(coils.clone());
+        let task = tokio::spawn(driver.run());
+        let mut params = motor.params.lock().unwrap().clone();
+        params.switch_15 = Some(proto::Gesture::Press as i32);
+        motor.set_parameters(params).unwrap();
+        assert_eq!(motor.params.lock().unwrap().switch_15, None);
+        settle(&motor).await;
+        assert!(coils.steps() > 0);
+        assert_eq!(motor.status.state().position, coils.steps() as i64);
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn homing_stops_at_limit_switch() {
+        let coils = MockCoils::default();
+        let (mut motor, mut driver, mut channels) = mock_motor(coils.clone());
+        let limit = mock::MockInput::new(0);
+        motor.can_home = true;
+        driver.home_switch = Some(Box::new(limit.clone()));
+        driver.home = Some(HomeConfig { offset: 0, direction: false, max_steps: 10000 });
+        motor.status.position.store(42, Ordering::Release);
+        let task = tokio::spawn(driver.run());
+        motor.change_state(proto::SmState { homing: true, ..Default::default() }).unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        limit.set(1);
+        let stats = next_stats(&mut channels).await;
+        assert_eq!(stats.reason(), proto::StopReason::LimitSwitch);
+        assert!(coils.steps() > 0);
+        assert_eq!(motor.status.state().position, 0);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn cape_switch_ignores_chatter() {
+        // active low, so the switch starts released
+        let (input, line) = mock::edges(1);
+        let mut switch = CapeSwitch::new(Box::new(input), Duration::from_millis(5), true).unwrap();
+        for level in [0, 1, 0, 1, 0] {
+            line.set(level);
+        }
+        assert_eq!(switch.transition().await, Some(true));
+        line.set(1);
+        line.set(0);
+        line.set(1);
+        assert_eq!(switch.transition().await, Some(false));
+        drop(line);
+        assert_eq!(switch.transition().await, None);
+    }
+
+    #[test]
+    fn reverse_from_start_wraps_to_end_of_table() {
+        for mode in MODES {
+            let sequence = mode.sequence();
+            let phase = StepperMotor::next_phase(0, false);
+            assert_eq!(StepperMotor::coils(&sequence, phase), sequence.last().unwrap());
+        }
+    }
+
+    #[test]
+    fn long_sequences_follow_coil_order() {
+        for mode in MODES {
+            let sequence = mode.sequence();
+            let len = sequence.len();
+            for &direction in &[true, false] {
+                let mut phase = 0;
+                for i in 1..10_000 {
+                    phase = StepperMotor::next_phase(phase, direction);
+                    let expected = if direction { i % len } else { (len - i % len) % len };
+                    assert_eq!(StepperMotor::coils(&sequence, phase), &sequence[expected]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reversing_retraces_the_same_patterns() {
+        let sequence = DriveMode::Half.sequence();
+        let mut phase = 3;
+        let mut forward = Vec::new();
+        for _ in 0..100 {
+            forward.push(*StepperMotor::coils(&sequence, phase));
+            phase = StepperMotor::next_phase(phase, true);
+        }
+        for expected in forward.iter().rev() {
+            phase = StepperMotor::next_phase(phase, false);
+            assert_eq!(StepperMotor::coils(&sequence, phase), expected);
+        }
+        assert_eq!(phase, 3);
+    }
+
+    #[test]
+    fn inverted_direction_retraces_the_sequence() {
+        let sequence = DriveMode::Half.sequence();
+        let mut inverted = sequence.clone();
+        inverted.reverse();
+        for phase in 0..20 {
+            let forward = StepperMotor::coils(&sequence, phase);
+            let backward = StepperMotor::coils(&inverted, -phase - 1);
+            assert_eq!(forward, backward);
+        }
+    }
+
+    #[test]
+    fn coil_order_moves_line_values() {
+        let pattern = (LinesVal([1, 0]), LinesVal([0, 0]));
+        assert_eq!(StepperMotor::remap(&pattern, &[0, 1, 2, 3]), pattern);
+        assert_eq!(StepperMotor::remap(&pattern, &[1, 0, 2, 3]), (LinesVal([0, 1]), LinesVal([0, 0])));
+        assert_eq!(StepperMotor::remap(&pattern, &[2, 3, 0, 1]), (LinesVal([0, 0]), LinesVal([1, 0])));
+    }
+
+    #[test]
+    fn half_steps_change_one_coil_at_a_time() {
+        let sequence = DriveMode::Half.sequence();
+        for phase in -20..20 {
+            let (a1, a3) = StepperMotor::coils(&sequence, phase);
+            let (b1, b3) = StepperMotor::coils(&sequence, phase + 1);
+            let changed = a1.0.iter().chain(a3.0.iter())
+                .zip(b1.0.iter().chain(b3.0.iter()))
+                .filter(|(a, b)| a != b)
+                .count();
+            assert_eq!(changed, 1, "phase {} to {}", phase, phase + 1);
+        }
+    }
+}
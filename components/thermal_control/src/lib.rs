@@ -0,0 +1,296 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_trait::async_trait;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, task::JoinHandle, time::sleep};
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
+use proto::Alarm;
+
+/// Holds an incubator or rearing chamber at a setpoint by switching a heater,
+/// and optionally a cooler, from the readings of a DS18B20 1-wire sensor.
+/// Control is on/off with hysteresis. A state message is sent with each
+/// reading.
+pub struct ThermalControl {
+    outputs: Option<Arc<Outputs>>, // claimed in init
+    state: Arc<Mutex<proto::ThermalState>>,
+    params: Arc<Mutex<proto::ThermalParams>>,
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+struct Outputs {
+    heater: LineHandle,
+    cooler: Option<LineHandle>,
+}
+
+impl Outputs {
+    fn set(&self, heating: bool, cooling: bool) -> Result<(), gpio_cdev::Error> {
+        self.heater.set_value(heating as u8)?;
+        if let Some(cooler) = &self.cooler {
+            cooler.set_value(cooling as u8)?;
+        }
+        Ok(())
+    }
+}
+
+#[component(state = proto::ThermalState, params = proto::ThermalParams, config = Config)]
+#[async_trait]
+impl Component for ThermalControl {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        ThermalControl {
+            outputs: None,
+            state: Arc::new(Mutex::new(proto::ThermalState::default())),
+            params: Arc::new(Mutex::new(proto::ThermalParams {
+                setpoint: config.setpoint,
+                hysteresis: config.hysteresis,
+                alarm_band: config.alarm_band,
+                interval_ms: config.interval,
+            })),
+            state_sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let flags = if config.active_low {
+            LineRequestFlags::OUTPUT | LineRequestFlags::ACTIVE_LOW
+        } else {
+            LineRequestFlags::OUTPUT
+        };
+        // both start switched off
+        let mut request = |offset: u32| chip.get_line(offset)
+            .and_then(|line| line.request(flags, 0, "thermal_control"))
+            .map_err(|e| DecideError::Component { source: e.into() });
+        let outputs = Arc::new(Outputs {
+            heater: request(config.heater)?,
+            cooler: config.cooler.map(&mut request).transpose()?,
+        });
+        self.outputs = Some(outputs.clone());
+        let sensor = PathBuf::from(&config.sensor);
+        let has_cooler = outputs.cooler.is_some();
+        let state = self.state.clone();
+        let params = self.params.clone();
+        let sender = self.state_sender.clone();
+        self.task_handle = Some(tokio::spawn(async move {
+            let mut history = VecDeque::with_capacity(config.duty_window);
+            loop {
+                let path = sensor.clone();
+                // a conversion takes most of a second, so keep it off the runtime
+                let reading = tokio::task::spawn_blocking(move || read_ds18b20(&path))
+                    .await
+                    .map_err(|e| DecideError::Component { source: e.into() })
+                    .unwrap();
+                let params = params.lock().unwrap().clone();
+                let message = {
+                    let mut state = state.lock().unwrap();
+                    match reading {
+                        Ok(temperature) => {
+                            let (heating, cooling) = control(temperature, params.setpoint, params.hysteresis,
+                                                             state.heating, state.cooling);
+                            state.temperature = temperature;
+                            state.heating = heating;
+                            state.cooling = cooling && has_cooler;
+                            let alarm = alarm(temperature, params.setpoint, params.alarm_band);
+                            if alarm != Alarm::None && alarm != state.alarm() {
+                                tracing::warn!("Thermal Control Alarm {:?} at {:.2} C", alarm, temperature);
+                            }
+                            state.set_alarm(alarm);
+                        }
+                        Err(e) => {
+                            if state.alarm() != Alarm::Sensor {
+                                tracing::error!("Thermal Control Sensor Read Failed: {}", e);
+                            }
+                            state.heating = false;
+                            state.cooling = false;
+                            state.set_alarm(Alarm::Sensor);
+                        }
+                    }
+                    if let Err(e) = outputs.set(state.heating, state.cooling) {
+                        return report_fault(&sender, e);
+                    }
+                    if history.len() == config.duty_window {
+                        history.pop_front();
+                    }
+                    history.push_back(state.heating);
+                    state.duty = history.iter().filter(|&&on| on).count() as f64 / history.len() as f64;
+                    Self::pack_state(&state)
+                };
+                sender.send(message).await
+                    .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                sleep(Duration::from_millis(params.interval_ms as u64)).await;
+            }
+        }));
+        tracing::info!("Thermal Control Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval_ms == 0 {
+            tracing::error!("Thermal Control interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        let valid = params.setpoint.is_finite() && params.hysteresis >= 0.0 && params.alarm_band > 0.0;
+        if !valid {
+            tracing::error!("Thermal Control setpoint, hysteresis and alarm band are not valid");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // takes effect at the next reading
+        *self.params.lock().unwrap() = params;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.lock().unwrap().clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        if self.state.lock().unwrap().alarm() == Alarm::Sensor {
+            ComponentHealth::Degraded(String::from("Thermal Control sensor cannot be read"))
+        } else {
+            ComponentHealth::running("Thermal Control task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        let mut resources = vec![Resource::line(&config.chip, config.heater)];
+        resources.extend(Resource::lines(&config.chip, &config.cooler));
+        resources
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Thermal Control");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped after reporting a fault
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Thermal Control task panicked: {}", e);
+                }
+            }
+        }
+        if let Some(outputs) = &self.outputs {
+            if let Err(e) = outputs.set(false, false) {
+                tracing::error!("Thermal Control could not switch off: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns whether the heater and cooler should be on, given whether they are now
+fn control(temperature: f64, setpoint: f64, hysteresis: f64, heating: bool, cooling: bool) -> (bool, bool) {
+    let heating = if heating { temperature < setpoint } else { temperature < setpoint - hysteresis };
+    let cooling = if cooling { temperature > setpoint } else { temperature > setpoint + hysteresis };
+    (heating, cooling)
+}
+
+fn alarm(temperature: f64, setpoint: f64, band: f64) -> Alarm {
+    if temperature < setpoint - band {
+        Alarm::Low
+    } else if temperature > setpoint + band {
+        Alarm::High
+    } else {
+        Alarm::None
+    }
+}
+
+/// Reads degrees C from the sysfs directory of a DS18B20, e.g.
+/// /sys/bus/w1/devices/28-0123456789ab
+fn read_ds18b20(device: &Path) -> io::Result<f64> {
+    let contents = fs::read_to_string(device.join("w1_slave"))?;
+    parse_w1_slave(&contents)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "DS18B20 reading failed its CRC check"))
+}
+
+/// Parses the output of the w1_therm driver: a line of scratchpad bytes ending
+/// in the CRC check result, then a line ending in t= and millidegrees
+fn parse_w1_slave(contents: &str) -> Option<f64> {
+    let mut lines = contents.lines();
+    if !lines.next()?.trim_end().ends_with("YES") {
+        return None
+    }
+    let (_, value) = lines.next()?.rsplit_once("t=")?;
+    value.trim().parse::<i64>().ok().map(|millis| millis as f64 / 1000.0)
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    heater: u32, // output line switching the heater relay
+    cooler: Option<u32>, // output line switching the cooler relay, if fitted
+    #[serde(default)]
+    active_low: bool, // relays switch on when their lines are low
+    sensor: String, // sysfs directory of the DS18B20
+    #[serde(default = "Config::default_duty_window")]
+    duty_window: usize, // readings in the heater duty cycle
+    setpoint: f64, // C, initial value of the setpoint parameter
+    #[serde(default = "Config::default_hysteresis")]
+    hysteresis: f64, // C, initial value of the hysteresis parameter
+    #[serde(default = "Config::default_alarm_band")]
+    alarm_band: f64, // C, initial value of the alarm_band parameter
+    #[serde(default = "Config::default_interval")]
+    interval: u32, // ms, initial value of the interval_ms parameter
+}
+
+impl Config {
+    fn default_duty_window() -> usize {
+        60
+    }
+
+    fn default_hysteresis() -> f64 {
+        0.5
+    }
+
+    fn default_alarm_band() -> f64 {
+        2.0
+    }
+
+    fn default_interval() -> u32 {
+        5000
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heater_and_cooler_have_hysteresis() {
+        assert_eq!(control(37.7, 38.0, 0.5, false, false), (false, false));
+        assert_eq!(control(37.4, 38.0, 0.5, false, false), (true, false));
+        assert_eq!(control(37.9, 38.0, 0.5, true, false), (true, false));
+        assert_eq!(control(38.0, 38.0, 0.5, true, false), (false, false));
+        assert_eq!(control(38.6, 38.0, 0.5, false, false), (false, true));
+        assert_eq!(control(38.2, 38.0, 0.5, false, true), (false, true));
+        assert_eq!(alarm(35.5, 38.0, 2.0), Alarm::Low);
+        assert_eq!(alarm(39.0, 38.0, 2.0), Alarm::None);
+    }
+
+    #[test]
+    fn w1_slave_output_parsed() {
+        let good = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(good), Some(23.125));
+        let bad = "72 01 4b 46 7f ff 0e 10 57 : crc=00 NO\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(bad), None);
+        assert_eq!(parse_w1_slave("ff ff : crc=ff YES\nff ff t=-1250\n"), Some(-1.25));
+    }
+}
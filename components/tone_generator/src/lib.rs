@@ -0,0 +1,448 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use alsa::{pcm::{Access, Format, HwParams, State, PCM}, ValueOr};
+use async_trait::async_trait;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
+
+/// Plays pure tones and click trains, for secondary reinforcers and other cues
+/// that should not need a prepared sound file. Stimuli are either synthesized
+/// into an ALSA device or played on a piezo buzzer driven by a PWM channel.
+pub struct ToneGenerator {
+    params: proto::ToneParams,
+    max_frequency: f32,
+    playing: Arc<AtomicBool>,
+    state_sender: Sender<Any>,
+    requests: Option<mpsc::Sender<proto::ToneParams>>,
+    player: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::ToneState, params = proto::ToneParams, config = Config)]
+#[async_trait]
+impl Component for ToneGenerator {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        let max_frequency = match &config.output {
+            OutputConfig::Alsa { sample_rate, .. } => *sample_rate as f32 / 2.0,
+            OutputConfig::Pwm { .. } => PwmOutput::MAX_FREQUENCY,
+        };
+        let params = proto::ToneParams {
+            frequency: config.frequency,
+            amplitude: config.amplitude,
+            duration_ms: config.duration,
+            ramp_ms: config.ramp,
+            click_rate: config.click_rate,
+        };
+        ToneGenerator {
+            params,
+            max_frequency,
+            playing: Arc::new(AtomicBool::new(false)),
+            state_sender,
+            requests: None,
+            player: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        if let Err(reason) = check(&self.params, self.max_frequency) {
+            tracing::error!("Tone-Generator config is invalid: {}", reason);
+            return Err(invalid_config(&reason))
+        }
+        let (requests, stimuli) = mpsc::channel::<proto::ToneParams>();
+        let (opened_tx, opened_rx) = tokio::sync::oneshot::channel();
+        let playing = self.playing.clone();
+        let sender = self.state_sender.clone();
+        self.player = Some(thread::spawn(move || {
+            // opened on this thread, as the PCM handle cannot be shared between threads
+            let output: decide_protocol::Result<Box<dyn Output>> = match config.output {
+                OutputConfig::Alsa { device, sample_rate } => AlsaOutput::new(&device, sample_rate)
+                    .map(|output| Box::new(output) as Box<dyn Output>),
+                OutputConfig::Pwm { path } => PwmOutput::new(PathBuf::from(path))
+                    .map(|output| Box::new(output) as Box<dyn Output>),
+            };
+            let mut output = match output {
+                Ok(output) => {
+                    let _ = opened_tx.send(Ok(()));
+                    output
+                }
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                    return
+                }
+            };
+            for params in stimuli.iter() {
+                let played = output.play(&params, &playing);
+                playing.store(false, Ordering::Release);
+                if let Err(e) = played {
+                    return report_fault(&sender, e)
+                }
+                tracing::info!("Tone-Generator Stimulus Ended");
+                if sender.blocking_send(Self::pack_state(&proto::ToneState { playing: false })).is_err() {
+                    break
+                }
+            }
+        }));
+        // the receive fails if the player thread panicked while opening the output
+        opened_rx.await.map_err(|e| DecideError::Component { source: e.into() })??;
+        self.requests = Some(requests);
+        tracing::info!("Tone-Generator Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        if !state.playing {
+            self.playing.store(false, Ordering::Release);
+            return Ok(())
+        }
+        if self.playing.swap(true, Ordering::AcqRel) {
+            tracing::error!("Tone-Generator stimulus requested while one is already playing. Stop it first.");
+            return Err(ClientError::Busy.into())
+        }
+        if let Some(requests) = &self.requests {
+            // fails if the player thread has stopped after a fault
+            if let Err(e) = requests.send(self.params.clone()) {
+                self.playing.store(false, Ordering::Release);
+                return Err(DecideError::Component { source: e.into() })
+            }
+        }
+        tracing::info!("Tone-Generator Playing {:?} Hz for {:?} ms", self.params.frequency, self.params.duration_ms);
+        let sender = self.state_sender.clone();
+        tokio::spawn(async move {
+            // the controller may already be shutting down
+            let _ = sender.send(Self::pack_state(&proto::ToneState { playing: true })).await;
+        });
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if let Err(reason) = check(&params, self.max_frequency) {
+            tracing::error!("Tone-Generator {}", reason);
+            return Err(ClientError::InvalidParams.into())
+        }
+        // applies to the next stimulus
+        self.params = params;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        Self::State {
+            playing: self.playing.load(Ordering::Acquire),
+        }
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Tone-Generator thread", self.player.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        match &config.output {
+            OutputConfig::Alsa { device, .. } => vec![Resource::Pcm { device: device.clone(), capture: false }],
+            OutputConfig::Pwm { path } => vec![Resource::Device(path.clone())],
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Tone-Generator");
+        self.playing.store(false, Ordering::Release);
+        // closing the channel ends the player once the current stimulus stops
+        self.requests.take();
+        if let Some(player) = self.player.take() {
+            match tokio::task::spawn_blocking(move || player.join()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => tracing::error!("Tone-Generator player thread panicked"),
+                Err(e) => tracing::error!("Tone-Generator player thread could not be joined: {}", e),
+            }
+        }
+    }
+}
+
+/// Error for a config the generator cannot be set up from
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> decide_protocol::Result<()> {
+    fs::write(path, contents).map_err(|e| DecideError::Component { source: e.into() })
+}
+
+fn alsa_error(e: alsa::Error) -> DecideError {
+    DecideError::Component { source: e.into() }
+}
+
+/// Length of each click in a click train
+const CLICK: f64 = 0.002;
+
+fn check(params: &proto::ToneParams, max_frequency: f32) -> Result<(), String> {
+    if !params.frequency.is_finite() || params.frequency <= 0.0 || params.frequency > max_frequency {
+        return Err(format!("frequency must be between 0 and {:?} Hz", max_frequency))
+    }
+    if !(0.0..=1.0).contains(&params.amplitude) {
+        return Err(String::from("amplitude must be between 0 and 1"))
+    }
+    if params.duration_ms == 0 || 2 * params.ramp_ms > params.duration_ms {
+        return Err(String::from("duration must be positive and at least twice the ramp"))
+    }
+    if !params.click_rate.is_finite() || params.click_rate < 0.0 || params.click_rate as f64 * CLICK >= 1.0 {
+        return Err(format!("click rate must be between 0 and {:?} Hz", 1.0 / CLICK))
+    }
+    Ok(())
+}
+
+/// Returns the gain (0-1) of the stimulus `t` s after onset, which scales the
+/// carrier. This includes the ramps and the gaps between clicks.
+fn envelope(params: &proto::ToneParams, t: f64) -> f64 {
+    let duration = params.duration_ms as f64 / 1000.0;
+    let ramp = params.ramp_ms as f64 / 1000.0;
+    if !(0.0..duration).contains(&t) {
+        return 0.0
+    }
+    let click_rate = params.click_rate as f64;
+    if click_rate > 0.0 && (t * click_rate).fract() >= CLICK * click_rate {
+        return 0.0
+    }
+    let gain = if ramp > 0.0 { (t / ramp).min((duration - t) / ramp).min(1.0) } else { 1.0 };
+    gain * params.amplitude as f64
+}
+
+/// Synthesizes a mono stimulus at `sample_rate`
+fn synthesize(params: &proto::ToneParams, sample_rate: u32) -> Vec<i16> {
+    let samples = params.duration_ms as usize * sample_rate as usize / 1000;
+    (0..samples)
+        .map(|i| {
+            let t = i as f64 / sample_rate as f64;
+            let carrier = (2.0 * PI * params.frequency as f64 * t).sin();
+            (envelope(params, t) * carrier * i16::MAX as f64) as i16
+        })
+        .collect()
+}
+
+trait Output {
+    /// Plays one stimulus, stopping early if `playing` is cleared
+    fn play(&mut self, params: &proto::ToneParams, playing: &AtomicBool) -> decide_protocol::Result<()>;
+}
+
+struct AlsaOutput {
+    pcm: PCM,
+    sample_rate: u32,
+}
+
+impl AlsaOutput {
+    const CHUNK: usize = 512; // frames per write
+
+    fn new(device: &str, sample_rate: u32) -> decide_protocol::Result<Self> {
+        let pcm = PCM::new(device, alsa::Direction::Playback, false).map_err(alsa_error)?;
+        {
+            let hwp = HwParams::any(&pcm).map_err(alsa_error)?;
+            hwp.set_channels(1)
+                .and_then(|_| hwp.set_rate(sample_rate, ValueOr::Nearest))
+                .and_then(|_| hwp.set_access(Access::RWInterleaved))
+                .and_then(|_| hwp.set_format(Format::s16()))
+                .and_then(|_| pcm.hw_params(&hwp))
+                .map_err(alsa_error)?;
+        }
+        // the device may not support the requested rate exactly
+        let sample_rate = pcm.hw_params_current()
+            .and_then(|hwp| hwp.get_rate())
+            .map_err(alsa_error)?;
+        tracing::debug!("Tone-Generator opened {:?} at {:?} Hz", device, sample_rate);
+        Ok(AlsaOutput { pcm, sample_rate })
+    }
+}
+
+impl Output for AlsaOutput {
+    fn play(&mut self, params: &proto::ToneParams, playing: &AtomicBool) -> decide_protocol::Result<()> {
+        let samples = synthesize(params, self.sample_rate);
+        let io = self.pcm.io_i16().map_err(alsa_error)?;
+        self.pcm.prepare().map_err(alsa_error)?;
+        let mut pointer = 0;
+        while pointer < samples.len() {
+            if !playing.load(Ordering::Acquire) {
+                // discards whatever is still buffered
+                return self.pcm.drop().map_err(alsa_error)
+            }
+            let end = (pointer + AlsaOutput::CHUNK).min(samples.len());
+            match io.writei(&samples[pointer..end]) {
+                Ok(written) => pointer += written,
+                Err(e) => {
+                    tracing::warn!("Tone-Generator recovering from {}", e);
+                    self.pcm.recover(e.errno() as std::os::raw::c_int, true).map_err(alsa_error)?;
+                }
+            }
+            if self.pcm.state() == State::Prepared {
+                self.pcm.start().map_err(alsa_error)?;
+            }
+        }
+        self.pcm.drain().map_err(alsa_error)
+    }
+}
+
+/// Piezo buzzer on a PWM channel. The PWM frequency is the frequency of the
+/// tone, and the envelope is applied through the duty cycle.
+struct PwmOutput {
+    path: PathBuf, // channel directory
+}
+
+impl PwmOutput {
+    const MAX_FREQUENCY: f32 = 50_000.0;
+    const STEP: Duration = Duration::from_millis(1); // between duty cycle updates
+
+    fn new(path: PathBuf) -> decide_protocol::Result<Self> {
+        if !path.exists() {
+            let channel = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("pwm"))
+                .ok_or_else(|| invalid_config("Tone-Generator PWM path must end in pwm<channel>"))?;
+            write(&path.with_file_name("export"), channel)?;
+        }
+        write(&path.join("duty_cycle"), "0")?;
+        Ok(PwmOutput { path })
+    }
+
+    fn write(&self, attribute: &str, value: u64) -> decide_protocol::Result<()> {
+        write(&self.path.join(attribute), value.to_string())
+    }
+}
+
+impl Output for PwmOutput {
+    fn play(&mut self, params: &proto::ToneParams, playing: &AtomicBool) -> decide_protocol::Result<()> {
+        let period = (1e9 / params.frequency as f64) as u64; // ns
+        // the duty cycle can never be longer than the period, even briefly
+        self.write("duty_cycle", 0)?;
+        self.write("period", period)?;
+        self.write("enable", 1)?;
+        let start = Instant::now();
+        let duration = Duration::from_millis(params.duration_ms as u64);
+        let mut duty = 0;
+        while playing.load(Ordering::Acquire) && start.elapsed() < duration {
+            // a piezo is loudest at half duty
+            let next = (envelope(params, start.elapsed().as_secs_f64()) * period as f64 / 2.0) as u64;
+            if next != duty {
+                duty = next;
+                self.write("duty_cycle", duty)?;
+            }
+            thread::sleep(PwmOutput::STEP);
+        }
+        self.write("duty_cycle", 0)?;
+        self.write("enable", 0)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputConfig {
+    /// synthesized into an ALSA playback device
+    Alsa {
+        #[serde(default = "OutputConfig::default_device")]
+        device: String,
+        #[serde(default = "OutputConfig::default_sample_rate")]
+        sample_rate: u32,
+    },
+    /// a piezo on a PWM channel, e.g. /sys/class/pwm/pwmchip0/pwm1
+    Pwm {
+        path: String,
+    },
+}
+
+impl OutputConfig {
+    fn default_device() -> String {
+        String::from("default")
+    }
+
+    fn default_sample_rate() -> u32 {
+        44100
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    output: OutputConfig,
+    // initial values of the parameters
+    #[serde(default = "Config::default_frequency")]
+    frequency: f32, // Hz
+    #[serde(default = "Config::default_amplitude")]
+    amplitude: f32,
+    #[serde(default = "Config::default_duration")]
+    duration: u32, // ms
+    #[serde(default = "Config::default_ramp")]
+    ramp: u32, // ms
+    #[serde(default)]
+    click_rate: f32, // Hz
+}
+
+impl Config {
+    fn default_frequency() -> f32 {
+        4000.0
+    }
+
+    fn default_amplitude() -> f32 {
+        0.5
+    }
+
+    fn default_duration() -> u32 {
+        200
+    }
+
+    fn default_ramp() -> u32 {
+        5
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> proto::ToneParams {
+        proto::ToneParams {
+            frequency: 1000.0,
+            amplitude: 0.5,
+            duration_ms: 100,
+            ramp_ms: 10,
+            click_rate: 0.0,
+        }
+    }
+
+    #[test]
+    fn envelope_ramps_on_and_off() {
+        let params = params();
+        assert_eq!(envelope(&params, 0.0), 0.0);
+        assert!((envelope(&params, 0.005) - 0.25).abs() < 1e-9);
+        assert_eq!(envelope(&params, 0.05), 0.5);
+        assert!((envelope(&params, 0.095) - 0.25).abs() < 1e-9);
+        assert_eq!(envelope(&params, 0.1), 0.0);
+    }
+
+    #[test]
+    fn clicks_gate_the_carrier() {
+        let params = proto::ToneParams { ramp_ms: 0, click_rate: 100.0, ..params() };
+        assert_eq!(envelope(&params, 0.001), 0.5);
+        assert_eq!(envelope(&params, 0.005), 0.0);
+        assert_eq!(envelope(&params, 0.011), 0.5);
+    }
+
+    #[test]
+    fn synthesizes_whole_duration() {
+        let samples = synthesize(&params(), 8000);
+        assert_eq!(samples.len(), 800);
+        assert!(samples.iter().all(|&s| s.unsigned_abs() <= i16::MAX as u16 / 2 + 1));
+    }
+
+    #[test]
+    fn rejects_ramps_longer_than_half() {
+        assert!(check(&params(), 20000.0).is_ok());
+        assert!(check(&proto::ToneParams { ramp_ms: 60, ..params() }, 20000.0).is_err());
+        assert!(check(&proto::ToneParams { frequency: 30000.0, ..params() }, 20000.0).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use async_trait::async_trait;
+use nix::poll::{poll, PollFd, PollFlags};
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+use decide_protocol::{component, Component, ComponentHealth, error::DecideError};
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0x00;
+const BTN_TOUCH: u16 = 0x14a;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+
+nix::ioctl_read!(eviocgabs_x, b'E', 0x40 + ABS_X, libc::input_absinfo);
+nix::ioctl_read!(eviocgabs_y, b'E', 0x40 + ABS_Y, libc::input_absinfo);
+nix::ioctl_write_int!(eviocgrab, b'E', 0x90);
+
+/// Reads touches from a Linux evdev touchscreen and reports them by the named
+/// regions of the screen they fall in, e.g. the stimuli of a discrimination
+/// task. A touch is assigned to the region where it starts, and a message is
+/// sent when it starts and when it ends.
+pub struct Touchscreen {
+    state: Arc<Mutex<proto::TouchState>>,
+    state_sender: Sender<Any>,
+    stop: Arc<AtomicBool>,
+    reader: Option<thread::JoinHandle<()>>,
+}
+
+#[component(state = proto::TouchState, params = proto::TouchParams, config = Config)]
+#[async_trait]
+impl Component for Touchscreen {
+    fn new(_config: Self::Config, state_sender: Sender<Any>) -> Self {
+        Touchscreen {
+            state: Arc::new(Mutex::new(proto::TouchState::default())),
+            state_sender,
+            stop: Arc::new(AtomicBool::new(false)),
+            reader: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut device = File::open(&config.device)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let screen = Screen::new(&device, &config);
+        if config.grab {
+            // keeps the touches from reaching the display server
+            unsafe { eviocgrab(device.as_raw_fd(), 1) }
+                .map_err(|e| DecideError::Component { source: e.into() })?;
+        }
+        tracing::info!("Touchscreen Initiated on {:?}", config.device);
+        let regions = config.regions;
+        let state = self.state.clone();
+        let sender = self.state_sender.clone();
+        let stop = self.stop.clone();
+        self.reader = Some(thread::spawn(move || {
+            let size = mem::size_of::<libc::input_event>();
+            let mut buf = vec![0u8; size * 64];
+            let mut tracker = Tracker::default();
+            let mut pressed: Option<String> = None;
+            while !stop.load(Ordering::Acquire) {
+                // time out regularly so that stop is checked
+                let mut fds = [PollFd::new(device.as_raw_fd(), PollFlags::POLLIN)];
+                let ready = poll(&mut fds, 500)
+                    .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                if ready == 0 {
+                    continue
+                }
+                // the kernel only hands out whole events
+                let n = device.read(&mut buf)
+                    .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+                for chunk in buf[..n].chunks_exact(size) {
+                    let event: libc::input_event = unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const _) };
+                    let touch = match tracker.event(event.type_, event.code, event.value) {
+                        Some(touch) => touch,
+                        None => continue,
+                    };
+                    let (x, y) = screen.map(touch.x, touch.y);
+                    let region = if touch.down {
+                        let region = region_at(&regions, x, y).unwrap_or_default();
+                        pressed = Some(region.clone());
+                        region
+                    } else {
+                        pressed.take().unwrap_or_default()
+                    };
+                    tracing::debug!("Touchscreen {} {:?} at ({}, {})",
+                                    if touch.down { "Pressed" } else { "Released" }, region, x, y);
+                    let message = {
+                        let mut state = state.lock().unwrap();
+                        state.region = region;
+                        state.pressed = touch.down;
+                        state.x = x;
+                        state.y = y;
+                        if touch.down {
+                            state.presses += 1;
+                        }
+                        Self::pack_state(&state)
+                    };
+                    sender.blocking_send(message)
+                        .map_err(|e| DecideError::Component { source: e.into() })
+                        .unwrap();
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn change_state(&mut self, _state: Self::State) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, _params: Self::Params) -> decide_protocol::Result<()> {
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        Self::Params {}
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Touchscreen reader thread", self.reader.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Touchscreen");
+        self.stop.store(true, Ordering::Release);
+        if let Some(reader) = self.reader.take() {
+            tokio::task::spawn_blocking(move || reader.join())
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
+
+/// Touch starting or ending at raw device coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Touch {
+    down: bool,
+    x: i32,
+    y: i32,
+}
+
+/// Follows the single-touch events of the device, which multitouch drivers
+/// also emit for the first contact, and reports touches once each frame is
+/// complete
+#[derive(Default)]
+struct Tracker {
+    down: bool,
+    reported: bool,
+    x: i32,
+    y: i32,
+}
+
+impl Tracker {
+    fn event(&mut self, type_: u16, code: u16, value: i32) -> Option<Touch> {
+        match (type_, code) {
+            (EV_ABS, ABS_X) => self.x = value,
+            (EV_ABS, ABS_Y) => self.y = value,
+            (EV_KEY, BTN_TOUCH) => self.down = value != 0,
+            (EV_SYN, SYN_REPORT) if self.down != self.reported => {
+                self.reported = self.down;
+                return Some(Touch { down: self.down, x: self.x, y: self.y })
+            }
+            _ => (),
+        }
+        None
+    }
+}
+
+/// Maps raw device coordinates to screen pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Screen {
+    x_range: (i32, i32),
+    y_range: (i32, i32),
+    width: i32,
+    height: i32,
+    swap_xy: bool,
+    invert_x: bool,
+    invert_y: bool,
+}
+
+impl Screen {
+    fn new(device: &File, config: &Config) -> Self {
+        let mut x: libc::input_absinfo = unsafe { mem::zeroed() };
+        let mut y: libc::input_absinfo = unsafe { mem::zeroed() };
+        unsafe { eviocgabs_x(device.as_raw_fd(), &mut x) }
+            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        unsafe { eviocgabs_y(device.as_raw_fd(), &mut y) }
+            .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        Screen {
+            x_range: (x.minimum, x.maximum),
+            y_range: (y.minimum, y.maximum),
+            width: config.width,
+            height: config.height,
+            swap_xy: config.swap_xy,
+            invert_x: config.invert_x,
+            invert_y: config.invert_y,
+        }
+    }
+
+    fn map(&self, x: i32, y: i32) -> (i32, i32) {
+        let scale = |value: i32, (min, max): (i32, i32)| {
+            (value - min).max(0) as f64 / (max - min).max(1) as f64
+        };
+        let (mut u, mut v) = (scale(x, self.x_range), scale(y, self.y_range));
+        // panels mounted rotated report their axes swapped relative to the display
+        if self.swap_xy {
+            mem::swap(&mut u, &mut v);
+        }
+        if self.invert_x {
+            u = 1.0 - u;
+        }
+        if self.invert_y {
+            v = 1.0 - v;
+        }
+        let pixel = |fraction: f64, size: i32| ((fraction * size as f64) as i32).min(size - 1).max(0);
+        (pixel(u, self.width), pixel(v, self.height))
+    }
+}
+
+/// Name of the first region containing the point, if any
+fn region_at(regions: &[Region], x: i32, y: i32) -> Option<String> {
+    regions.iter()
+        .find(|r| x >= r.x && x < r.x + r.width && y >= r.y && y < r.y + r.height)
+        .map(|r| r.name.clone())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Region {
+    name: String,
+    x: i32, // left edge, pixels
+    y: i32, // top edge, pixels
+    width: i32,
+    height: i32,
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    device: String, // e.g. /dev/input/event0, or a stable /dev/input/by-path link
+    width: i32, // pixels
+    height: i32, // pixels
+    regions: Vec<Region>, // overlapping regions go to the one listed first
+    #[serde(default)]
+    swap_xy: bool,
+    #[serde(default)]
+    invert_x: bool,
+    #[serde(default)]
+    invert_y: bool,
+    #[serde(default)]
+    grab: bool, // take exclusive access to the device
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touches_reported_at_frame_end() {
+        let mut tracker = Tracker::default();
+        assert_eq!(tracker.event(EV_KEY, BTN_TOUCH, 1), None);
+        assert_eq!(tracker.event(EV_ABS, ABS_X, 100), None);
+        assert_eq!(tracker.event(EV_ABS, ABS_Y, 200), None);
+        assert_eq!(tracker.event(EV_SYN, SYN_REPORT, 0), Some(Touch { down: true, x: 100, y: 200 }));
+        // movement while down is not reported
+        tracker.event(EV_ABS, ABS_X, 120);
+        assert_eq!(tracker.event(EV_SYN, SYN_REPORT, 0), None);
+        tracker.event(EV_KEY, BTN_TOUCH, 0);
+        assert_eq!(tracker.event(EV_SYN, SYN_REPORT, 0), Some(Touch { down: false, x: 120, y: 200 }));
+    }
+
+    #[test]
+    fn coordinates_map_to_regions() {
+        let screen = Screen {
+            x_range: (0, 4095),
+            y_range: (0, 4095),
+            width: 800,
+            height: 480,
+            swap_xy: false,
+            invert_x: true,
+            invert_y: false,
+        };
+        assert_eq!(screen.map(0, 0), (799, 0));
+        assert_eq!(screen.map(4095, 4095), (0, 479));
+        let regions = vec![
+            Region { name: "left".into(), x: 0, y: 0, width: 400, height: 480 },
+            Region { name: "right".into(), x: 400, y: 0, width: 400, height: 480 },
+        ];
+        assert_eq!(region_at(&regions, 399, 100), Some("left".into()));
+        assert_eq!(region_at(&regions, 400, 100), Some("right".into()));
+        assert_eq!(region_at(&regions, 800, 100), None);
+    }
+}
@@ -0,0 +1,294 @@
+use gpio_cdev::{Chip, AsyncLineEventHandle,
+                LineHandle,
+                LineRequestFlags,
+                EventRequestFlags,
+                EventType
+};
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use async_trait::async_trait;
+use decide_protocol::{component, Component, ComponentHealth, Resource,
+                   error::{ClientError, DecideError}};
+use prost_types::Any;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use tokio::{self, task::JoinHandle, time::Duration};
+
+/// HC-SR04 style ultrasonic range finder, e.g. aimed along a perch to detect
+/// approaches. The sensor is pinged at a fixed interval, and a state message is
+/// sent whenever the median of the recent readings crosses the near or far
+/// threshold.
+pub struct Ultrasonic {
+    ranger: Arc<Mutex<Ranger>>,
+    interval: Arc<AtomicU32>, // ms
+    state_sender: Sender<Any>,
+    task_handle: Option<JoinHandle<()>>,
+}
+
+/// Median filter over the recent readings, with hysteresis between the thresholds
+struct Ranger {
+    readings: VecDeque<f32>,
+    window: usize,
+    distance: f32, // cm
+    near: bool,
+    near_cm: f32,
+    far_cm: f32,
+    misses: u64,
+}
+
+impl Ranger {
+    fn new(window: usize, near_cm: f32, far_cm: f32) -> Self {
+        Ranger {
+            readings: VecDeque::with_capacity(window),
+            window,
+            distance: 0.0,
+            near: false,
+            near_cm,
+            far_cm,
+            misses: 0,
+        }
+    }
+
+    /// Adds a reading and returns true if the filtered distance crossed a threshold
+    fn push(&mut self, cm: f32) -> bool {
+        if self.readings.len() == self.window {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(cm);
+        let mut sorted: Vec<f32> = self.readings.iter().copied().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        self.distance = sorted[sorted.len() / 2];
+        let near = if self.near { self.distance <= self.far_cm } else { self.distance < self.near_cm };
+        let crossed = near != self.near;
+        self.near = near;
+        crossed
+    }
+
+    fn state(&self, crossed: bool) -> proto::RangeState {
+        proto::RangeState {
+            distance_cm: self.distance,
+            near: self.near,
+            crossed,
+            misses: self.misses,
+        }
+    }
+}
+
+#[component(state = proto::RangeState, params = proto::RangeParams, config = Config)]
+#[async_trait]
+impl Component for Ultrasonic {
+    fn new(config: Self::Config, sender: Sender<Any>) -> Self {
+        if config.window == 0 {
+            tracing::error!("Ultrasonic window must be positive");
+            panic!("invalid ultrasonic window")
+        }
+        Ultrasonic {
+            ranger: Arc::new(Mutex::new(Ranger::new(config.window, config.near, config.far))),
+            interval: Arc::new(AtomicU32::new(config.interval)),
+            state_sender: sender,
+            task_handle: None,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let mut chip = Chip::new(&config.chip)
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let trigger = chip.get_line(config.trigger_offset)
+            .map_err(|e| DecideError::Component { source: e.into() })?
+            .request(LineRequestFlags::OUTPUT, 0, "ultrasonic_trigger")
+            .map_err(|e| DecideError::Component { source: e.into() })?;
+        let events = AsyncLineEventHandle::new(
+            chip.get_line(config.echo_offset)
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "ultrasonic_echo")
+                .map_err(|e| DecideError::Component { source: e.into() })?
+        ).map_err(|e| DecideError::Component { source: e.into() })?;
+        let ranger = self.ranger.clone();
+        let interval = self.interval.clone();
+        let sender = self.state_sender.clone();
+        let report_all = config.report_all;
+        self.task_handle = Some(tokio::spawn(async move {
+            Ultrasonic::ping(trigger, events, ranger, interval, sender, report_all).await
+        }));
+        tracing::info!("Ultrasonic Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        // the distance is measured, so the only valid request is a reset
+        if state != Self::State::default() {
+            tracing::error!("Ultrasonic state cannot be changed by request");
+            return Err(ClientError::InvalidState.into())
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if params.interval_ms == 0 {
+            tracing::error!("Ultrasonic interval must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        if params.near_cm > params.far_cm {
+            tracing::error!("Ultrasonic near_cm must not exceed far_cm");
+            return Err(ClientError::InvalidParams.into())
+        }
+        self.interval.store(params.interval_ms, Ordering::Release);
+        let mut ranger = self.ranger.lock().unwrap();
+        ranger.near_cm = params.near_cm;
+        ranger.far_cm = params.far_cm;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.ranger.lock().unwrap().state(false)
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        let ranger = self.ranger.lock().unwrap();
+        Self::Params {
+            interval_ms: self.interval.load(Ordering::Acquire),
+            near_cm: ranger.near_cm,
+            far_cm: ranger.far_cm,
+        }
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        ComponentHealth::running("Ultrasonic task", self.task_handle.as_ref().is_some_and(|h| h.is_finished()))
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        Resource::lines(&config.chip, &[config.trigger_offset, config.echo_offset])
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Ultrasonic");
+        if let Some(task_handle) = self.task_handle.take() {
+            task_handle.abort();
+            // the task may already have stopped
+            if let Err(e) = task_handle.await {
+                if e.is_panic() {
+                    tracing::error!("Ultrasonic task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Ultrasonic {
+    /// Round-trip cm per ns of echo pulse, at 343 m/s
+    const CM_PER_NS: f32 = 343.0e2 / 1e9 / 2.0;
+    /// Longer pulses mean nothing was in range
+    const MAX_ECHO: u64 = 30_000_000; // ns
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    async fn ping(trigger: LineHandle, mut events: AsyncLineEventHandle, ranger: Arc<Mutex<Ranger>>,
+                  interval: Arc<AtomicU32>, sender: Sender<Any>, report_all: bool) {
+        loop {
+            tokio::time::sleep(Duration::from_millis(interval.load(Ordering::Acquire) as u64)).await;
+            trigger.set_value(1)
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            std::thread::sleep(Duration::from_micros(10));
+            trigger.set_value(0)
+                .map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            let state = match tokio::time::timeout(Self::TIMEOUT, Self::echo(&mut events)).await {
+                Ok(None) => break,
+                Ok(Some(width)) if width <= Self::MAX_ECHO => {
+                    let mut ranger = ranger.lock().unwrap();
+                    let crossed = ranger.push(width as f32 * Self::CM_PER_NS);
+                    if crossed {
+                        tracing::info!("Ultrasonic Target {} at {:.1} cm",
+                                       if ranger.near { "Near" } else { "Far" }, ranger.distance);
+                    }
+                    if !crossed && !report_all {
+                        continue
+                    }
+                    ranger.state(crossed)
+                }
+                _ => {
+                    tracing::trace!("Ultrasonic Ping Missed");
+                    ranger.lock().unwrap().misses += 1;
+                    continue
+                }
+            };
+            sender.send(Self::pack_state(&state)).await.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+        }
+    }
+
+    /// Waits for a complete echo pulse and returns its width in ns. Falling edges
+    /// left over from a timed-out ping are skipped.
+    async fn echo(events: &mut AsyncLineEventHandle) -> Option<u64> {
+        let mut rise = None;
+        while let Some(event) = events.next().await {
+            let event = event.map_err(|e| DecideError::Component { source: e.into() }).unwrap();
+            match (event.event_type(), rise) {
+                (EventType::RisingEdge, _) => rise = Some(event.timestamp()),
+                (EventType::FallingEdge, Some(rise)) => return Some(event.timestamp().saturating_sub(rise)),
+                (EventType::FallingEdge, None) => (),
+            }
+        }
+        None
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    chip: String,
+    trigger_offset: u32,
+    echo_offset: u32, // through a level shifter; the HC-SR04 echo is 5 V
+    #[serde(default = "Config::default_window")]
+    window: usize, // number of readings in the median filter
+    #[serde(default)]
+    report_all: bool, // send every reading, not just threshold crossings
+    #[serde(default = "Config::default_interval")]
+    interval: u32, // ms, initial value of the interval_ms parameter
+    near: f32, // cm, initial value of the near_cm parameter
+    far: f32, // cm, initial value of the far_cm parameter
+}
+
+impl Config {
+    fn default_window() -> usize {
+        5
+    }
+
+    fn default_interval() -> u32 {
+        100
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_rejects_single_outliers() {
+        let mut ranger = Ranger::new(3, 10.0, 20.0);
+        ranger.push(50.0);
+        ranger.push(50.0);
+        assert!(!ranger.push(2.0));
+        assert_eq!(ranger.distance, 50.0);
+        ranger.push(51.0);
+        ranger.push(51.0);
+        assert_eq!(ranger.distance, 51.0);
+    }
+
+    #[test]
+    fn crossings_have_hysteresis() {
+        let mut ranger = Ranger::new(1, 10.0, 20.0);
+        assert!(!ranger.push(30.0));
+        assert!(ranger.push(9.0));
+        assert!(ranger.near);
+        assert!(!ranger.push(15.0));
+        assert!(!ranger.push(9.0));
+        assert!(ranger.push(21.0));
+        assert!(!ranger.near);
+        assert!(!ranger.push(15.0));
+    }
+}
@@ -0,0 +1,234 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}};
+use async_trait::async_trait;
+use prost_types::Any;
+use serde::Deserialize;
+use tokio::{self, sync::mpsc::Sender, time::Duration};
+use decide_protocol::{component, Component, ComponentHealth, Resource, report_fault, error::{ClientError, DecideError}};
+
+/// Eccentric rotating mass vibration motor driven from a PWM channel through a
+/// transistor, as a tactile stimulus. Patterns are trains of bursts at a set
+/// intensity, duration and spacing.
+pub struct Vibration {
+    motor: Option<Arc<Motor>>, // set up in init
+    params: proto::VibrationParams,
+    state_sender: Sender<Any>,
+}
+
+struct Motor {
+    pwm: PathBuf, // channel directory
+    period: u64, // ns
+    running: AtomicBool,
+    bursts: AtomicU32,
+    epoch: AtomicU64, // bumped by every request to cancel the running pattern
+}
+
+impl Motor {
+    fn duty(&self, intensity: f32) -> decide_protocol::Result<()> {
+        let duty = (self.period as f64 * intensity as f64) as u64;
+        write(&self.pwm.join("duty_cycle"), duty.to_string())
+    }
+
+    /// Plays the burst pattern until it finishes or another request takes over
+    async fn play(&self, epoch: u64, params: proto::VibrationParams, sender: &Sender<Any>) -> decide_protocol::Result<()> {
+        let burst = Duration::from_millis(params.burst_ms as u64);
+        let interval = Duration::from_millis(params.interval_ms as u64);
+        let mut bursts = 0;
+        loop {
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                return Ok(())
+            }
+            self.duty(params.intensity)?;
+            tokio::time::sleep(burst).await;
+            if self.epoch.load(Ordering::Acquire) != epoch {
+                return Ok(())
+            }
+            self.duty(0.0)?;
+            bursts += 1;
+            self.bursts.store(bursts, Ordering::Release);
+            if bursts == params.count {
+                break
+            }
+            tokio::time::sleep(interval).await;
+        }
+        self.running.store(false, Ordering::Release);
+        self.send_state(sender).await;
+        tracing::info!("Vibration Finished {:?} Bursts", bursts);
+        Ok(())
+    }
+
+    fn state(&self) -> proto::VibrationState {
+        proto::VibrationState {
+            running: self.running.load(Ordering::Acquire),
+            bursts: self.bursts.load(Ordering::Acquire),
+        }
+    }
+
+    async fn send_state(&self, sender: &Sender<Any>) {
+        sender.send(Vibration::pack_state(&self.state())).await
+            .map_err(|e| DecideError::Component { source: e.into() })
+            .unwrap();
+    }
+}
+
+fn invalid_config(message: &str) -> DecideError {
+    DecideError::Component { source: std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into() }
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> decide_protocol::Result<()> {
+    fs::write(path, contents).map_err(|e| DecideError::Component { source: e.into() })
+}
+
+#[component(state = proto::VibrationState, params = proto::VibrationParams, config = Config)]
+#[async_trait]
+impl Component for Vibration {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self {
+        Vibration {
+            motor: None,
+            params: proto::VibrationParams {
+                intensity: config.intensity,
+                burst_ms: config.burst,
+                interval_ms: config.interval,
+                count: config.count,
+            },
+            state_sender,
+        }
+    }
+
+    async fn init(&mut self, config: Self::Config) -> decide_protocol::Result<()> {
+        let pwm = PathBuf::from(&config.pwm_path);
+        if !pwm.exists() {
+            let channel = pwm.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("pwm"))
+                .ok_or_else(|| invalid_config("Vibration PWM path must end in pwm<channel>"))?;
+            write(&pwm.with_file_name("export"), channel)?;
+        }
+        let period = config.period * 1000;
+        write(&pwm.join("duty_cycle"), "0")?;
+        write(&pwm.join("period"), period.to_string())?;
+        write(&pwm.join("enable"), "1")?;
+        self.motor = Some(Arc::new(Motor {
+            pwm,
+            period,
+            running: AtomicBool::new(false),
+            bursts: AtomicU32::new(0),
+            epoch: AtomicU64::new(0),
+        }));
+        tracing::info!("Vibration Initiated");
+        Ok(())
+    }
+
+    fn change_state(&mut self, state: Self::State) -> decide_protocol::Result<()> {
+        let motor = self.motor.clone().ok_or(ClientError::InvalidState)?;
+        let epoch = motor.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        motor.running.store(state.running, Ordering::Release);
+        let sender = self.state_sender.clone();
+        if state.running {
+            motor.bursts.store(0, Ordering::Release);
+            let params = self.params.clone();
+            tracing::info!("Vibration Started by Request");
+            tokio::spawn(async move {
+                motor.send_state(&sender).await;
+                if let Err(e) = motor.play(epoch, params, &sender).await {
+                    motor.running.store(false, Ordering::Release);
+                    report_fault(&sender, e);
+                }
+            });
+        } else {
+            motor.duty(0.0)?;
+            tracing::info!("Vibration Stopped by Request");
+            tokio::spawn(async move {
+                motor.send_state(&sender).await;
+            });
+        }
+        Ok(())
+    }
+
+    fn set_parameters(&mut self, params: Self::Params) -> decide_protocol::Result<()> {
+        if !(0.0..=1.0).contains(&params.intensity) {
+            tracing::error!("Vibration intensity must be between 0 and 1");
+            return Err(ClientError::InvalidParams.into())
+        }
+        if params.burst_ms == 0 {
+            tracing::error!("Vibration burst duration must be positive");
+            return Err(ClientError::InvalidParams.into())
+        }
+        // applies to the next pattern
+        self.params = params;
+        Ok(())
+    }
+
+    fn get_state(&self) -> Self::State {
+        self.motor.as_ref().map_or_else(Default::default, |motor| motor.state())
+    }
+
+    fn get_parameters(&self) -> Self::Params {
+        self.params.clone()
+    }
+
+    fn healthy(&self) -> ComponentHealth {
+        match &self.motor {
+            Some(motor) if !motor.pwm.exists() =>
+                ComponentHealth::Failed(format!("Vibration PWM channel {:?} has gone", motor.pwm)),
+            _ => ComponentHealth::Healthy,
+        }
+    }
+
+    fn resources(config: &Self::Config) -> Vec<Resource> {
+        vec![Resource::Device(config.pwm_path.clone())]
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::debug!("Shutdown called for Vibration");
+        if let Some(motor) = &self.motor {
+            motor.epoch.fetch_add(1, Ordering::AcqRel);
+            motor.running.store(false, Ordering::Release);
+            if let Err(e) = motor.duty(0.0) {
+                tracing::error!("Vibration could not be stopped: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    pwm_path: String, // /sys/class/pwm/pwmchip0/pwm1, wired to the motor driver
+    #[serde(default = "Config::default_period")]
+    period: u64, // us
+    #[serde(default = "Config::default_intensity")]
+    intensity: f32, // initial value of the intensity parameter
+    #[serde(default = "Config::default_burst")]
+    burst: u32, // ms, initial value of the burst_ms parameter
+    #[serde(default = "Config::default_interval")]
+    interval: u32, // ms, initial value of the interval_ms parameter
+    #[serde(default = "Config::default_count")]
+    count: u32, // initial value of the count parameter
+}
+
+impl Config {
+    fn default_period() -> u64 {
+        50
+    }
+
+    fn default_intensity() -> f32 {
+        1.0
+    }
+
+    fn default_burst() -> u32 {
+        200
+    }
+
+    fn default_interval() -> u32 {
+        200
+    }
+
+    fn default_count() -> u32 {
+        1
+    }
+}
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
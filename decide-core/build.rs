@@ -0,0 +1,104 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+fn main() -> io::Result<()> {
+    // the service is generated only when it is served; its messages come from
+    // decide-protocol
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protobuf_src::protoc());
+        tonic_build::configure()
+            .build_client(false)
+            .extern_path(".decide", "::decide_protocol::proto")
+            .compile(&["proto/grpc.proto"], &["proto/", "../decide-protocol/src/"])?;
+    }
+    register_components()
+}
+
+/// Registers every component in the component crates decide-core depends on,
+/// by writing the `impl_components!` invocation included by `components.rs`. A
+/// component is a type whose `Component` impl has the `#[component]` attribute,
+/// and its name is the driver name used in configs.
+fn register_components() -> io::Result<()> {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let manifest = manifest_dir.join("Cargo.toml");
+    println!("cargo:rerun-if-changed={}", manifest.display());
+    let mut generated = String::new();
+    let mut names = Vec::new();
+    for (krate, path) in component_crates(&fs::read_to_string(&manifest)?) {
+        let src = manifest_dir.join(path).join("src");
+        // a directory is rescanned whenever any file in it changes
+        println!("cargo:rerun-if-changed={}", src.display());
+        let mut sources = Vec::new();
+        rust_sources(&src, &mut sources)?;
+        for source in sources {
+            for name in components(&fs::read_to_string(&source)?) {
+                generated.push_str(&format!("use {}::{};\n", krate.replace('-', "_"), name));
+                names.push(name);
+            }
+        }
+    }
+    generated.push_str(&format!("\nimpl_components!({});\n", names.join(", ")));
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("components.rs");
+    fs::write(out, generated)
+}
+
+/// The dependencies in a manifest with a path under `../components/`
+fn component_crates(manifest: &str) -> Vec<(String, String)> {
+    let mut dependencies = false;
+    manifest
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            if line.starts_with('[') {
+                dependencies = line == "[dependencies]";
+                return None;
+            }
+            let (name, spec) = line.split_once('=').filter(|_| dependencies)?;
+            let path = spec.split("path").nth(1)?.split('"').nth(1)?;
+            path.starts_with("../components/")
+                .then(|| (name.trim().to_string(), path.to_string()))
+        })
+        .collect()
+}
+
+/// The `.rs` files under a directory, in a fixed order
+fn rust_sources(dir: &Path, sources: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            rust_sources(&path, sources)?;
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            sources.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The types in a source file whose `Component` impl has the `#[component]`
+/// attribute. The attribute may be followed by others, such as `#[async_trait]`.
+fn components(source: &str) -> Vec<String> {
+    let mut attribute = false;
+    let mut names = Vec::new();
+    for line in source.lines().map(str::trim) {
+        if line.starts_with("#[component(") || line.starts_with("#[decide_protocol::component(") {
+            attribute = true;
+        } else if let Some(rest) = line.strip_prefix("impl Component for ") {
+            if attribute {
+                let name = rest
+                    .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .next();
+                names.extend(name.map(String::from));
+            }
+            attribute = false;
+        } else if line.starts_with("impl") {
+            attribute = false;
+        }
+    }
+    names
+}
@@ -14,6 +14,7 @@ async fn main() {
         request_type: RequestType::General(GeneralRequest::RequestLock),
         component: None,
         body: vec![],
+        meta: Default::default(),
     };
     let message = Multipart::from(request);
     let reply_sock = req_sock.send(message).await.unwrap();
@@ -0,0 +1,240 @@
+//! Checks the config of the controller without starting any components, for
+//! `decide --check`. Every config file in the config directory is read and
+//! validated, the hardware claimed by each component is looked for on the box,
+//! and no two components may claim the same GPIO line, ALSA device or device
+//! file. Hardware claimed by another process, e.g. a controller that is
+//! already running, is reported as in use.
+use super::{
+    config::ControllerConfig, experiment::ExperimentConfig, federation::FederationConfig,
+    logger::LoggerConfig, schedule::ScheduleConfig, security::SecurityConfig, shutdown_stages,
+    ComponentKind, ComponentsConfig,
+};
+use decide_protocol::{ComponentName, Resource};
+use gpio_cdev::Chip;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What was checked, and what was wrong
+#[derive(Default)]
+pub struct Report {
+    lines: Vec<String>,
+    problems: usize,
+}
+
+impl Report {
+    fn heading(&mut self, text: String) {
+        self.lines.push(text);
+    }
+
+    fn ok(&mut self, text: String) {
+        self.lines.push(format!("    ok     {}", text));
+    }
+
+    fn problem(&mut self, text: String) {
+        self.lines.push(format!("    ERROR  {}", text));
+        self.problems += 1;
+    }
+
+    /// Reports a config file that need not be present
+    fn optional<T>(&mut self, file: &str, config: anyhow::Result<Option<T>>) {
+        match config {
+            Ok(Some(_)) => self.heading(format!("{}: ok", file)),
+            Ok(None) => self.heading(format!("{}: not present", file)),
+            Err(e) => {
+                self.heading(format!("{}:", file));
+                self.problem(format!("{:#}", e));
+            }
+        }
+    }
+
+    /// Whether nothing was wrong
+    pub fn passed(&self) -> bool {
+        self.problems == 0
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        match self.problems {
+            0 => writeln!(f, "the config is valid"),
+            1 => writeln!(f, "1 problem found"),
+            n => writeln!(f, "{} problems found", n),
+        }
+    }
+}
+
+/// Checks the config files in the config directory
+pub fn check() -> Report {
+    let mut report = Report::default();
+    check_components(&mut report);
+    match ControllerConfig::new() {
+        Ok(_) => report.heading(String::from("decide.yml: ok")),
+        Err(e) => {
+            report.heading(String::from("decide.yml:"));
+            report.problem(format!("{:#}", e));
+        }
+    }
+    report.optional("security.yml", SecurityConfig::new());
+    report.optional("logger.yml", LoggerConfig::new());
+    report.optional("experiment.yml", ExperimentConfig::new());
+    report.optional("schedule.yml", ScheduleConfig::new());
+    report.optional("federation.yml", FederationConfig::new());
+    #[cfg(feature = "scripting")]
+    report.optional("experiment.rhai", super::script::Script::new());
+    #[cfg(feature = "http")]
+    report.optional("gateway.yml", super::gateway::GatewayConfig::new());
+    #[cfg(feature = "mqtt")]
+    report.optional("mqtt.yml", super::mqtt::MqttConfig::new());
+    #[cfg(feature = "grpc")]
+    report.optional("grpc.yml", super::grpc::GrpcConfig::new());
+    #[cfg(feature = "otel")]
+    report.optional("telemetry.yml", super::telemetry::TelemetryConfig::new());
+    report
+}
+
+fn check_components(report: &mut Report) {
+    let items = match ComponentsConfig::open().and_then(ComponentsConfig::read) {
+        Ok((ComponentsConfig(items), _)) => items,
+        Err(e) => {
+            report.heading(String::from("components.yml:"));
+            report.problem(format!("{:#}", e));
+            return;
+        }
+    };
+    report.heading(format!("components.yml: {} components", items.len()));
+    let dependencies = items
+        .iter()
+        .map(|(name, item)| (name.clone(), item.depends_on.clone()))
+        .collect();
+    if let Err(e) = shutdown_stages(&dependencies) {
+        report.problem(e.to_string());
+    }
+    let mut names: Vec<&ComponentName> = items.keys().collect();
+    names.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut claims: BTreeMap<Resource, Vec<&ComponentName>> = BTreeMap::new();
+    for name in names {
+        let item = &items[name];
+        report.heading(format!("  {} ({})", name.0, item.driver));
+        let resources = match ComponentKind::resources(&item.driver, item.config.clone()) {
+            Ok(resources) => resources,
+            Err(e) => {
+                report.problem(e.to_string());
+                continue;
+            }
+        };
+        if resources.is_empty() {
+            report.ok(String::from("config"));
+        }
+        for resource in resources {
+            match available(&resource) {
+                Ok(()) => report.ok(resource.to_string()),
+                Err(e) => report.problem(e),
+            }
+            if exclusive(&resource) {
+                claims.entry(resource).or_default().push(name);
+            }
+        }
+    }
+    let conflicts: Vec<_> = claims
+        .into_iter()
+        .filter(|(_, claimants)| claimants.len() > 1)
+        .collect();
+    if !conflicts.is_empty() {
+        report.heading(String::from("  conflicts"));
+    }
+    for (resource, claimants) in conflicts {
+        let claimants: Vec<_> = claimants.iter().map(|name| name.0.as_str()).collect();
+        report.problem(format!(
+            "{} is claimed by {}",
+            resource,
+            claimants.join(", ")
+        ));
+    }
+}
+
+/// Whether no two components may claim the resource. ALSA devices other than
+/// the hardware ones, such as `default`, may be mixed or shared.
+fn exclusive(resource: &Resource) -> bool {
+    match resource {
+        Resource::Pcm { device, .. } => hardware_pcm(device).is_some(),
+        _ => true,
+    }
+}
+
+/// Whether the resource is on the box and not in use
+fn available(resource: &Resource) -> Result<(), String> {
+    match resource {
+        Resource::GpioLine { chip, offset } => {
+            let mut gpiochip =
+                Chip::new(chip).map_err(|e| format!("could not open {}: {}", chip, e))?;
+            if *offset >= gpiochip.num_lines() {
+                return Err(format!(
+                    "{} has no line {}: it has {} lines",
+                    chip,
+                    offset,
+                    gpiochip.num_lines()
+                ));
+            }
+            let info = gpiochip
+                .get_line(*offset)
+                .and_then(|line| line.info())
+                .map_err(|e| format!("could not get {}: {}", resource, e))?;
+            if info.is_used() {
+                return Err(format!(
+                    "{} is in use by {}",
+                    resource,
+                    info.consumer().unwrap_or("the kernel")
+                ));
+            }
+            Ok(())
+        }
+        Resource::Pcm { device, capture } => {
+            let (card, number) = match hardware_pcm(device) {
+                Some(pcm) => pcm,
+                // plugins are resolved by ALSA when the device is opened
+                None => return Ok(()),
+            };
+            let card = if card.chars().all(|c| c.is_ascii_digit()) {
+                format!("card{}", card)
+            } else {
+                card.to_string()
+            };
+            let stream = if *capture { 'c' } else { 'p' };
+            let pcm = PathBuf::from("/proc/asound")
+                .join(card)
+                .join(format!("pcm{}{}", number, stream));
+            if !pcm.exists() {
+                return Err(format!("{} does not exist", resource));
+            }
+            match std::fs::read_to_string(pcm.join("sub0").join("status")) {
+                Ok(status) if status.trim() != "closed" => {
+                    Err(format!("{} is open in another process", resource))
+                }
+                _ => Ok(()),
+            }
+        }
+        Resource::Device(path) => {
+            if Path::new(path).exists() {
+                Ok(())
+            } else {
+                Err(format!("{} does not exist", path))
+            }
+        }
+    }
+}
+
+/// The card and device number of a PCM given as `hw:` or `plughw:`, e.g.
+/// `hw:1,0` or `plughw:Headphones`
+fn hardware_pcm(device: &str) -> Option<(&str, &str)> {
+    let pcm = device
+        .strip_prefix("hw:")
+        .or_else(|| device.strip_prefix("plughw:"))?;
+    let mut fields = pcm.split(',');
+    let card = fields.next()?.trim_start_matches("CARD=");
+    let number = fields.next().unwrap_or("0").trim_start_matches("DEV=");
+    Some((card, number))
+}
@@ -1,15 +1,8 @@
-use lights::Lights;
-use house_light::HouseLight;
-use peckboard::{PeckKeys, PeckLeds};
-use stepper_motor::StepperMotor;
-//use sound::AudioPlayer;
-use sound_alsa::AlsaPlayback;
-
 macro_rules! impl_components {
     ($($component:ident),*) => {
         pub use component_kind::ComponentKind;
         mod component_kind {
-            use decide_protocol::{error::ControllerError, Component, Result};
+            use decide_protocol::{error::ControllerError, proto, Component, ComponentHealth, Registry, Resource, Result};
             use prost_types::Any;
             use serde_value::Value;
             use tokio::sync::mpsc;
@@ -32,11 +25,10 @@ macro_rules! impl_components {
 
             #[cfg(feature = "dummy-mode")]
             mod dummy {
-                use decide_protocol::{Result, Component, error::DecideError};
+                use decide_protocol::{Result, Component, ComponentHealth, error::DecideError};
                 use tokio::sync::mpsc;
                 use async_trait::async_trait;
                 use prost_types::Any;
-                use prost::Message;
 
                 /// non-dummy components
                 mod real {
@@ -59,6 +51,7 @@ macro_rules! impl_components {
                         type Config = <real::$component as Component>::Config;
                         const STATE_TYPE_URL: &'static str = <real::$component as Component>::STATE_TYPE_URL;
                         const PARAMS_TYPE_URL: &'static str = <real::$component as Component>::PARAMS_TYPE_URL;
+                        const FILE_DESCRIPTOR_SET: &'static [u8] = <real::$component as Component>::FILE_DESCRIPTOR_SET;
 
                         fn new(config: Self::Config, state_sender: mpsc::Sender<Any>) -> Self {
                             let state = Self::State::default();
@@ -71,16 +64,15 @@ macro_rules! impl_components {
                             }
                         }
 
-                        async fn init(&mut self, _config: Self::Config) { }
+                        async fn init(&mut self, _config: Self::Config) -> Result<()> {
+                            Ok(())
+                        }
 
                         fn change_state(&mut self, state: Self::State) -> Result<()> {
                             self.state = state.clone();
                             let sender = self.state_sender.clone();
                             tokio::spawn(async move {
-                                sender.send(Any {
-                                    type_url: String::from(Self::STATE_TYPE_URL),
-                                    value: state.encode_to_vec()
-                                }).await.map_err(|e| DecideError::Component{ source: e.into() }).unwrap();
+                                sender.send(Self::pack_state(&state)).await.map_err(|e| DecideError::Component{ source: e.into() }).unwrap();
                             });
                             Ok(())
                         }
@@ -98,6 +90,10 @@ macro_rules! impl_components {
                             self.state.clone()
                         }
 
+                        fn healthy(&self) -> ComponentHealth {
+                            ComponentHealth::Healthy
+                        }
+
                         async fn shutdown(&mut self) {}
                     }
                     )*
@@ -133,6 +129,20 @@ macro_rules! impl_components {
                          )*
                     }
                 }
+                pub fn pause(&mut self) -> Result<()> {
+                    match self {
+                        $(
+                            ComponentKind::$component(t) => t.pause(),
+                         )*
+                    }
+                }
+                pub fn resume(&mut self) -> Result<()> {
+                    match self {
+                        $(
+                            ComponentKind::$component(t) => t.resume(),
+                         )*
+                    }
+                }
                 pub fn get_encoded_parameters(&self) -> Any {
                     match self {
                         $(
@@ -140,10 +150,17 @@ macro_rules! impl_components {
                          )*
                     }
                 }
-                pub async fn init(&mut self, config: Value) {
+                pub fn healthy(&self) -> ComponentHealth {
+                    match self {
+                        $(
+                            ComponentKind::$component(t) => t.healthy(),
+                         )*
+                    }
+                }
+                pub async fn init(&mut self, config: Value) -> Result<()> {
                     match self {
                         $(
-                            ComponentKind::$component(t) => t.init(types::$component::deserialize_config(config).unwrap()).await,
+                            ComponentKind::$component(t) => t.init(types::$component::deserialize_config(config)?).await,
                         )*
                     }
                 }
@@ -156,6 +173,38 @@ macro_rules! impl_components {
                     }
                 }
 
+                /// Registry of the state and parameters types of every driver
+                pub fn registry() -> Registry {
+                    let mut registry = Registry::new();
+                    $(
+                        registry.register_component::<types::$component>();
+                    )*
+                    registry
+                }
+
+                /// Describes the messages and config accepted by a driver
+                pub fn description<S: AsRef<str>>(driver_name: S) -> Result<proto::ComponentDescription> {
+                    let driver_name = driver_name.as_ref();
+                    match driver_name {
+                        $(
+                            stringify!($component) => Ok(types::$component::description()),
+                        )*
+                            _ => Err(ControllerError::UnknownDriver(driver_name.into()).into()),
+                    }
+                }
+
+                /// The hardware a driver would claim with a config, without
+                /// creating the component. Fails if the config is invalid.
+                pub fn resources<S: AsRef<str>>(driver_name: S, config: Value) -> Result<Vec<Resource>> {
+                    let driver_name = driver_name.as_ref();
+                    match driver_name {
+                        $(
+                            stringify!($component) => Ok(types::$component::resources(&types::$component::deserialize_config(config)?)),
+                        )*
+                            _ => Err(ControllerError::UnknownDriver(driver_name.into()).into()),
+                    }
+                }
+
                 pub fn from_name<S: AsRef<str>>(driver_name: S, config: Value, sender: mpsc::Sender<Any>) -> anyhow::Result<Self> {
                     let driver_name = driver_name.as_ref();
                     match driver_name {
@@ -172,4 +221,5 @@ macro_rules! impl_components {
     }
 }
 
-impl_components!(Lights,HouseLight,StepperMotor,PeckLeds,PeckKeys,AlsaPlayback);
+// the components found by build.rs, with a `use` of each
+include!(concat!(env!("OUT_DIR"), "/components.rs"));
@@ -0,0 +1,68 @@
+//! The settings of the controller itself, as opposed to those of its components
+//! and subsystems
+use super::logging::LoggingConfig;
+use decide_protocol::{error::ControllerError, PUB_PORT, REQ_PORT};
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::net::Ipv4Addr;
+use std::{fs::File, io::Read};
+
+/// The settings in `decide.yml`, all of which have defaults
+#[derive(Deserialize, Debug)]
+pub struct ControllerConfig {
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    // the address the sockets are bound to: only clients on the box can
+    // connect, unless it is another, e.g. `0.0.0.0` for a hub to reach it
+    #[serde(default = "default_listen")]
+    listen: Ipv4Addr,
+}
+
+fn default_listen() -> Ipv4Addr {
+    Ipv4Addr::LOCALHOST
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        ControllerConfig {
+            logging: LoggingConfig::default(),
+            listen: default_listen(),
+        }
+    }
+}
+
+impl ControllerConfig {
+    /// Reads `decide.yml` from the config directory, or gives the defaults if
+    /// there is none
+    pub fn new() -> anyhow::Result<Self> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("decide.yml");
+        if !config_file.exists() {
+            return Ok(Self::default());
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        let config: ControllerConfig =
+            serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?;
+        config.logging.validate()?;
+        Ok(config)
+    }
+
+    /// The endpoint of the socket that takes requests
+    pub fn req_endpoint(&self) -> String {
+        format!("tcp://{}:{}", self.listen, REQ_PORT)
+    }
+
+    /// The endpoint of the socket that publishes state messages
+    pub fn pub_endpoint(&self) -> String {
+        format!("tcp://{}:{}", self.listen, PUB_PORT)
+    }
+}
@@ -0,0 +1,316 @@
+//! Runs an experiment on the box itself, from the definition of the states of
+//! its trials in `experiment.yml`, so that simple paradigms keep running without
+//! a client and through network outages
+use super::fields::{describe_components, Field, Messages};
+use super::handle::Handle;
+use decide_protocol::{
+    error::ControllerError, pack, proto, ComponentName, Request, EXPERIMENT_TYPE_URL,
+};
+use directories::ProjectDirs;
+use prost_types::Any;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::{fs::File, io::Read};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// The name the experiment publishes its state under, and makes its requests as
+pub const EXPERIMENT: &str = "experiment";
+
+/// The states of an experiment, as given in `experiment.yml`
+#[derive(Deserialize, Debug)]
+pub struct ExperimentConfig {
+    // the state the experiment starts in
+    initial: String,
+    states: HashMap<String, StateConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StateConfig {
+    // entering the state starts a new trial
+    #[serde(default)]
+    trial: bool,
+    // made in order on entering the state
+    #[serde(default)]
+    actions: Vec<Action>,
+    // the first to fire is taken
+    #[serde(default)]
+    transitions: Vec<Transition>,
+}
+
+/// Sets the parameters, then the state, of a component. Fields that are not
+/// given take their default values.
+#[derive(Deserialize, Debug)]
+pub(crate) struct Action {
+    pub(crate) component: ComponentName,
+    params: Option<HashMap<String, Field>>,
+    state: Option<HashMap<String, Field>>,
+}
+
+/// Fires on a state message of a component, or a time after entering the state
+#[derive(Deserialize, Debug)]
+struct Transition {
+    to: String,
+    on: Option<Event>,
+    // ms
+    after: Option<u64>,
+}
+
+/// A state message of a component with the given values
+#[derive(Deserialize, Debug)]
+struct Event {
+    component: ComponentName,
+    state: HashMap<String, Field>,
+}
+
+impl Action {
+    pub(crate) fn sets_nothing(&self) -> bool {
+        self.params.is_none() && self.state.is_none()
+    }
+
+    /// The requests that carry out the action
+    pub(crate) fn requests(&self, messages: &Messages) -> anyhow::Result<Vec<Request>> {
+        let mut requests = Vec::new();
+        if let Some(params) = &self.params {
+            requests.push(messages.set_parameters(&self.component, params)?);
+        }
+        if let Some(state) = &self.state {
+            requests.push(messages.change_state(&self.component, state)?);
+        }
+        Ok(requests)
+    }
+}
+
+impl ExperimentConfig {
+    /// Reads `experiment.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("experiment.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        let config: ExperimentConfig =
+            serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?;
+        let invalid = |reason: String| Err(ControllerError::InvalidExperiment(reason).into());
+        if !config.states.contains_key(&config.initial) {
+            return invalid(format!(
+                "the initial state {:?} is not defined",
+                config.initial
+            ));
+        }
+        for (name, state) in &config.states {
+            for transition in &state.transitions {
+                if !config.states.contains_key(&transition.to) {
+                    return invalid(format!(
+                        "{:?} has a transition to {:?}, which is not defined",
+                        name, transition.to
+                    ));
+                }
+                if transition.on.is_some() == transition.after.is_some() {
+                    return invalid(format!(
+                        "the transition from {:?} to {:?} needs one of `on` or `after`",
+                        name, transition.to
+                    ));
+                }
+            }
+            if let Some(action) = state.actions.iter().find(|action| action.sets_nothing()) {
+                return invalid(format!(
+                    "an action of {:?} on {:?} sets neither state nor params",
+                    name, action.component
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The components acted on or listened to
+    fn components(&self) -> HashSet<&ComponentName> {
+        self.states
+            .values()
+            .flat_map(|state| {
+                let acted = state.actions.iter().map(|action| &action.component);
+                let heard = state
+                    .transitions
+                    .iter()
+                    .filter_map(|transition| Some(&transition.on.as_ref()?.component));
+                acted.chain(heard)
+            })
+            .collect()
+    }
+}
+
+struct Experiment {
+    config: ExperimentConfig,
+    messages: HashMap<ComponentName, Messages>,
+    // the requests made on entering each state
+    actions: HashMap<String, Vec<Request>>,
+    handle: Handle,
+    state_tx: mpsc::Sender<Any>,
+    current: String,
+    entered: Instant,
+    trial: u64,
+}
+
+/// Runs the experiment until the controller stops publishing. The messages of
+/// the components it uses are checked against the definition first.
+pub async fn run(config: ExperimentConfig, handle: Handle) -> anyhow::Result<()> {
+    let handle = handle.named(EXPERIMENT);
+    let mut publications = handle.subscribe();
+    let messages = describe_components(&handle).await?;
+    if let Some(component) = config
+        .components()
+        .into_iter()
+        .find(|component| !messages.contains_key(component))
+    {
+        return Err(ControllerError::InvalidExperiment(format!(
+            "{:?} is not a component with described messages",
+            component
+        ))
+        .into());
+    }
+    let mut actions = HashMap::new();
+    for (name, state) in &config.states {
+        let mut requests = Vec::new();
+        for action in &state.actions {
+            requests.extend(action.requests(&messages[&action.component]).map_err(|e| {
+                ControllerError::InvalidExperiment(format!("in an action of {:?}: {}", name, e))
+            })?);
+        }
+        for event in state.transitions.iter().filter_map(|t| t.on.as_ref()) {
+            let fields = messages[&event.component].state();
+            if let Some(field) = event.state.keys().find(|field| !fields.contains(field)) {
+                return Err(ControllerError::InvalidExperiment(format!(
+                    "in a transition of {:?}: the state of {:?} has no scalar field {:?}",
+                    name, event.component, field
+                ))
+                .into());
+            }
+        }
+        actions.insert(name.clone(), requests);
+    }
+    let state_tx = handle.publish_as(ComponentName(EXPERIMENT.into())).await?;
+    let initial = config.initial.clone();
+    let mut experiment = Experiment {
+        config,
+        messages,
+        actions,
+        handle,
+        state_tx,
+        current: String::new(),
+        entered: Instant::now(),
+        trial: 0,
+    };
+    experiment.enter(initial).await;
+    loop {
+        let timeout = experiment.config.states[&experiment.current]
+            .transitions
+            .iter()
+            .filter_map(|transition| Some((transition.after?, &transition.to)))
+            .min_by_key(|(after, _)| *after)
+            .map(|(after, to)| {
+                (
+                    experiment.entered + Duration::from_millis(after),
+                    to.clone(),
+                )
+            });
+        let deadline = timeout
+            .as_ref()
+            .map_or(experiment.entered, |(deadline, _)| *deadline);
+        let next = tokio::select! {
+            publication = publications.recv() => match publication {
+                Ok((name, message)) => experiment.fired(&name, &message),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("experiment fell behind and missed {} messages", missed);
+                    None
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = sleep_until(deadline), if timeout.is_some() => timeout.map(|(_, to)| to),
+        };
+        if let Some(state) = next {
+            experiment.enter(state).await;
+        }
+    }
+}
+
+impl Experiment {
+    /// The state to enter, if the message fires a transition of the current one
+    fn fired(&self, name: &ComponentName, message: &proto::HeldMessage) -> Option<String> {
+        if !message.topic.starts_with("state/") {
+            return None;
+        }
+        let state = message.message.as_ref()?.state.as_ref()?;
+        let values = match self.messages.get(name)?.decode_state(state)? {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("experiment could not decode the state of {:?}: {}", name, e);
+                return None;
+            }
+        };
+        self.config.states[&self.current]
+            .transitions
+            .iter()
+            .find(|transition| match &transition.on {
+                Some(event) => {
+                    event.component == *name
+                        && event.state.iter().all(|(field, value)| {
+                            values
+                                .get(field)
+                                .is_some_and(|actual| actual.matches(value))
+                        })
+                }
+                None => false,
+            })
+            .map(|transition| transition.to.clone())
+    }
+
+    /// Enters a state, publishing it and making its requests. Requests that fail
+    /// are logged, and the experiment goes on.
+    async fn enter(&mut self, state: String) {
+        let previous = std::mem::replace(&mut self.current, state.clone());
+        self.entered = Instant::now();
+        if self.config.states[&state].trial {
+            self.trial += 1;
+        }
+        info!(
+            "experiment entering {:?} from {:?} in trial {}",
+            state, previous, self.trial
+        );
+        let message = proto::ExperimentState {
+            state: state.clone(),
+            previous,
+            trial: self.trial,
+        };
+        if self
+            .state_tx
+            .send(pack(EXPERIMENT_TYPE_URL, &message))
+            .await
+            .is_err()
+        {
+            warn!("the state of the experiment could not be published");
+        }
+        for request in &self.actions[&state] {
+            match self.handle.request(request.clone()).await {
+                Ok(proto::Reply {
+                    result: Some(proto::reply::Result::Error(e)),
+                    ..
+                }) => error!(
+                    "experiment {:?} request for {:?} failed: {}",
+                    request.request_type, request.component, e.message
+                ),
+                Err(e) => error!("experiment could not make request: {}", e),
+                Ok(_) => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Federation of the boxes of a rack under one controller, the hub, so that an
+//! experimenter can control all of them through a single endpoint. The
+//! components of each satellite box are reached at the hub as
+//! `<box>/<component>`: their requests are passed on to the satellite, and the
+//! messages they publish are published again by the hub under topics such as
+//! `state/<box>/<component>/<type>`. Satellites are listed in `federation.yml`
+//! or, with the `mdns` feature, found on the network.
+use super::handle::Publications;
+use super::security::decode_key;
+use decide_protocol::{
+    error::{ClientError, ControllerError},
+    proto, ComponentName, GeneralRequest, Request, RequestType, Result,
+};
+use directories::ProjectDirs;
+use futures::{SinkExt, Stream, StreamExt};
+use prost::Message;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs::File, io::Read};
+use tmq::{
+    dealer::Dealer,
+    subscribe::{Subscribe, SubscribeWithoutTopic},
+    Context, FromZmqSocket, Multipart,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// The mDNS service the boxes advertise themselves as
+#[cfg(feature = "mdns")]
+const SERVICE_TYPE: &str = "_decide._tcp.local.";
+
+/// The satellites of the hub, and how this box is found by a hub, as given in
+/// `federation.yml`
+#[derive(Deserialize, Debug)]
+pub struct FederationConfig {
+    // the name this box is advertised as; defaults to the hostname
+    box_id: Option<String>,
+    // advertise this box with mDNS, for a hub to find
+    #[serde(default)]
+    advertise: bool,
+    // the satellites of the hub, by box id
+    #[serde(default)]
+    satellites: BTreeMap<String, SatelliteConfig>,
+    // also take the boxes found with mDNS as satellites
+    #[serde(default)]
+    discover: bool,
+    // how long a satellite has to reply to a request (ms)
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    // the CURVE keys of this box: the public key is advertised, and a hub
+    // connects with both to satellites that have a security config
+    public_key: Option<String>,
+    secret_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SatelliteConfig {
+    host: String,
+    #[serde(default = "default_req_port")]
+    req_port: u16,
+    #[serde(default = "default_pub_port")]
+    pub_port: u16,
+    // the public key of the satellite, if its sockets are encrypted
+    server_key: Option<String>,
+}
+
+fn default_timeout() -> u64 {
+    1000
+}
+
+fn default_req_port() -> u16 {
+    decide_protocol::REQ_PORT
+}
+
+fn default_pub_port() -> u16 {
+    decide_protocol::PUB_PORT
+}
+
+impl FederationConfig {
+    /// Reads `federation.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("federation.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        let config: FederationConfig =
+            serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?;
+        let invalid = |reason: String| ControllerError::InvalidFederation(reason).into();
+        if cfg!(not(feature = "mdns")) && (config.advertise || config.discover) {
+            return Err(invalid(
+                "`advertise` and `discover` need the `mdns` feature".into(),
+            ));
+        }
+        if config.timeout == 0 {
+            return Err(invalid("the timeout must be more than 0 ms".into()));
+        }
+        let keys = config.keys()?;
+        let mut ids = config.box_id.iter().chain(config.satellites.keys());
+        if let Some(id) = ids.find(|id| id.is_empty() || id.contains('/')) {
+            return Err(invalid(format!(
+                "{:?} is not a box id: ids cannot be empty or contain `/`",
+                id
+            )));
+        }
+        for (id, satellite) in &config.satellites {
+            if let Some(key) = &satellite.server_key {
+                decode_key(id, key)?;
+                if keys.is_none() {
+                    return Err(invalid(format!(
+                        "satellite {:?} has a key, so the hub needs `public_key` and `secret_key`",
+                        id
+                    )));
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// The keys of this box, if both are given
+    fn keys(&self) -> anyhow::Result<Option<Keys>> {
+        let public = match &self.public_key {
+            Some(key) => decode_key("this box", key)?,
+            None if self.secret_key.is_some() => {
+                return Err(ControllerError::InvalidFederation(
+                    "`secret_key` is given without `public_key`".into(),
+                )
+                .into())
+            }
+            None => return Ok(None),
+        };
+        match &self.secret_key {
+            Some(key) => Ok(Some(Keys {
+                public,
+                secret: decode_key("this box", key)?,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Keys {
+    public: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+/// A request for a satellite, and where its reply goes
+type Forwarded = (Request, oneshot::Sender<proto::Reply>);
+
+/// The satellites the hub passes requests on to, by box id
+#[derive(Debug, Clone)]
+pub struct Satellites {
+    connected: Arc<Mutex<BTreeMap<String, mpsc::Sender<Forwarded>>>>,
+    timeout: Duration,
+}
+
+impl Satellites {
+    /// Whether a component is one of a satellite's, named `<box>/<component>`
+    pub(crate) fn owns(&self, component: &ComponentName) -> bool {
+        match component.0.split_once('/') {
+            Some((id, _)) => self.connected.lock().unwrap().contains_key(id),
+            None => false,
+        }
+    }
+
+    /// Passes a request for `<box>/<component>` on to the satellite, as a
+    /// request for `<component>`
+    pub(crate) async fn forward(&self, mut request: Request) -> Result<proto::Reply> {
+        let name = request
+            .component
+            .take()
+            .ok_or(ClientError::InvalidComponent)?;
+        let (id, component) = name
+            .0
+            .split_once('/')
+            .ok_or_else(|| ClientError::UnknownComponent(name.clone()))?;
+        let satellite = self
+            .connected
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ClientError::UnknownComponent(name.clone()))?;
+        request.component = Some(ComponentName(component.into()));
+        let mut reply = self.request(id, &satellite, request).await?;
+        if let Some(proto::reply::Result::Error(e)) = &mut reply.result {
+            if e.component == component {
+                e.component = name.0.clone();
+            }
+        }
+        Ok(reply)
+    }
+
+    /// The descriptions of the components of every satellite, named
+    /// `<box>/<component>`. Satellites that do not reply are left out.
+    pub(crate) async fn describe(&self) -> Vec<proto::ComponentDescription> {
+        let satellites: Vec<_> = self
+            .connected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, satellite)| (id.clone(), satellite.clone()))
+            .collect();
+        let mut components = Vec::new();
+        for (id, satellite) in satellites {
+            let request = Request {
+                request_type: RequestType::General(GeneralRequest::DescribeComponents),
+                component: None,
+                body: Vec::new(),
+                meta: Default::default(),
+            };
+            match self.request(&id, &satellite, request).await {
+                Ok(proto::Reply {
+                    result: Some(proto::reply::Result::Components(described)),
+                    ..
+                }) => components.extend(described.components.into_iter().map(|mut component| {
+                    component.name = format!("{}/{}", id, component.name);
+                    component
+                })),
+                Ok(reply) => warn!(
+                    "satellite {:?} did not describe its components: {:?}",
+                    id, reply.result
+                ),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        components
+    }
+
+    async fn request(
+        &self,
+        id: &str,
+        satellite: &mpsc::Sender<Forwarded>,
+        request: Request,
+    ) -> Result<proto::Reply> {
+        let unavailable = |reason: &str| ControllerError::SatelliteUnavailable {
+            satellite: id.into(),
+            reason: reason.into(),
+        };
+        let (reply_tx, reply_rx) = oneshot::channel();
+        satellite
+            .send((request, reply_tx))
+            .await
+            .map_err(|_| unavailable("the hub is no longer connected to it"))?;
+        match timeout(self.timeout, reply_rx).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(unavailable("the hub is no longer connected to it").into()),
+            Err(_) => Err(unavailable("it did not reply in time").into()),
+        }
+    }
+}
+
+/// What the tasks that connect to the satellites share
+#[derive(Clone)]
+struct Federation {
+    context: Context,
+    keys: Option<Keys>,
+    satellites: Satellites,
+    relayed: mpsc::Sender<Multipart>,
+    publications: Publications,
+}
+
+/// Connects to the satellites, and with `discover` to those found later. The
+/// messages they publish are sent to the subsystems, and on the returned stream
+/// to be published by the hub.
+pub(crate) fn start(
+    config: FederationConfig,
+    publications: Publications,
+) -> anyhow::Result<(Satellites, impl Stream<Item = Multipart>)> {
+    let (relayed_tx, relayed_rx) = mpsc::channel(1000);
+    let federation = Federation {
+        context: Context::new(),
+        keys: config.keys()?,
+        satellites: Satellites {
+            connected: Default::default(),
+            timeout: Duration::from_millis(config.timeout),
+        },
+        relayed: relayed_tx,
+        publications,
+    };
+    for (id, satellite) in &config.satellites {
+        federation.connect(id.clone(), satellite)?;
+    }
+    #[cfg(feature = "mdns")]
+    if config.advertise || config.discover {
+        let federation = federation.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mdns(config, federation).await {
+                error!("mDNS stopped: {:#}", e);
+            }
+        });
+    }
+    Ok((federation.satellites, ReceiverStream::new(relayed_rx)))
+}
+
+impl Federation {
+    /// Connects to a satellite, and starts passing on its requests and messages
+    fn connect(&self, id: String, config: &SatelliteConfig) -> anyhow::Result<()> {
+        let server_key = match &config.server_key {
+            Some(key) => Some(decode_key(&id, key)?),
+            None => None,
+        };
+        let endpoint = |port| format!("tcp://{}:{}", config.host, port);
+        let requests: Dealer = self.socket(
+            zmq::DEALER,
+            &endpoint(config.req_port),
+            server_key.as_deref(),
+        )?;
+        let messages = self
+            .socket::<SubscribeWithoutTopic>(
+                zmq::SUB,
+                &endpoint(config.pub_port),
+                server_key.as_deref(),
+            )?
+            .subscribe(b"")?;
+        let (requests_tx, requests_rx) = mpsc::channel(100);
+        self.satellites
+            .connected
+            .lock()
+            .unwrap()
+            .insert(id.clone(), requests_tx);
+        info!("connected to satellite {:?} at {}", id, config.host);
+        tokio::spawn(self.clone().serve(id, requests, requests_rx, messages));
+        Ok(())
+    }
+
+    fn socket<T: FromZmqSocket<T>>(
+        &self,
+        socket_type: zmq::SocketType,
+        endpoint: &str,
+        server_key: Option<&[u8]>,
+    ) -> anyhow::Result<T> {
+        let socket = self.context.socket(socket_type)?;
+        if let Some(server_key) = server_key {
+            let keys = self.keys.as_ref().ok_or_else(|| {
+                ControllerError::InvalidFederation(
+                    "the hub needs `public_key` and `secret_key` to connect to a satellite with a key"
+                        .into(),
+                )
+            })?;
+            socket.set_curve_serverkey(server_key)?;
+            socket.set_curve_publickey(&keys.public)?;
+            socket.set_curve_secretkey(&keys.secret)?;
+        }
+        socket.connect(endpoint)?;
+        Ok(T::from_zmq_socket(socket)?)
+    }
+
+    /// Sends the requests for a satellite and gives the replies to those still
+    /// waiting, which are matched by correlation id, and relays its messages
+    async fn serve(
+        self,
+        id: String,
+        mut requests: Dealer,
+        mut requests_rx: mpsc::Receiver<Forwarded>,
+        mut messages: Subscribe,
+    ) {
+        let mut waiting: HashMap<String, oneshot::Sender<proto::Reply>> = HashMap::new();
+        let mut sent: u64 = 0;
+        loop {
+            tokio::select! {
+                forwarded = requests_rx.recv() => match forwarded {
+                    Some((mut request, reply_tx)) => {
+                        sent += 1;
+                        // the hub gives the reply the correlation id of the client
+                        request.meta.correlation_id = format!("hub-{}", sent);
+                        waiting.retain(|_, reply_tx| !reply_tx.is_closed());
+                        waiting.insert(request.meta.correlation_id.clone(), reply_tx);
+                        let mut request = Multipart::from(request);
+                        request.push_front(tmq::Message::new());
+                        if let Err(e) = requests.send(request).await {
+                            error!("could not send a request to satellite {:?}: {}", id, e);
+                        }
+                    }
+                    None => return,
+                },
+                Some(reply) = requests.next() => match reply {
+                    // the empty frame, the version and the reply
+                    Ok(reply) if reply.len() == 3 => {
+                        match proto::Reply::decode(&*reply[2]) {
+                            Ok(reply) => {
+                                if let Some(reply_tx) = waiting.remove(&reply.correlation_id) {
+                                    let _ = reply_tx.send(reply);
+                                }
+                            }
+                            Err(e) => warn!("satellite {:?} sent a reply that does not decode: {}", id, e),
+                        }
+                    }
+                    Ok(reply) => warn!("satellite {:?} sent a reply of {} frames", id, reply.len()),
+                    Err(e) => error!("could not receive a reply from satellite {:?}: {}", id, e),
+                },
+                Some(message) = messages.next() => match message {
+                    Ok(message) => self.relay(&id, message).await,
+                    Err(e) => error!("could not receive a message from satellite {:?}: {}", id, e),
+                },
+            }
+        }
+    }
+
+    /// Publishes a message of a satellite again, under the name of its box
+    async fn relay(&self, id: &str, message: Multipart) {
+        if message.len() != 2 {
+            warn!(
+                "satellite {:?} published a message of {} frames",
+                id,
+                message.len()
+            );
+            return;
+        }
+        // e.g. `state/peck-keys/PeckKeyState`
+        let (kind, rest) = match message[0].as_str().and_then(|topic| topic.split_once('/')) {
+            Some(topic) => topic,
+            None => {
+                warn!("satellite {:?} published under an invalid topic", id);
+                return;
+            }
+        };
+        let component = rest
+            .rsplit_once('/')
+            .map_or(rest, |(component, _)| component);
+        let name = ComponentName(format!("{}/{}", id, component));
+        let topic = format!("{}/{}/{}", kind, id, rest);
+        match proto::Pub::decode(&*message[1]) {
+            Ok(held) => {
+                // nobody may be subscribed
+                let _ = self.publications.send((
+                    name,
+                    proto::HeldMessage {
+                        topic: topic.clone(),
+                        message: Some(held),
+                    },
+                ));
+            }
+            Err(e) => warn!(
+                "satellite {:?} published a message that does not decode: {}",
+                id, e
+            ),
+        }
+        // the hub may be stopping
+        let _ = self
+            .relayed
+            .send(vec![topic.as_bytes(), &*message[1]].into())
+            .await;
+    }
+}
+
+/// Advertises this box, and with `discover` connects to the boxes found
+#[cfg(feature = "mdns")]
+async fn mdns(config: FederationConfig, federation: Federation) -> anyhow::Result<()> {
+    use super::hostname;
+    use decide_protocol::{PUB_PORT, REQ_PORT};
+    use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+    let box_id = config.box_id.clone().unwrap_or_else(hostname);
+    let daemon = ServiceDaemon::new()?;
+    if config.advertise {
+        let mut properties = HashMap::new();
+        properties.insert(String::from("pub_port"), PUB_PORT.to_string());
+        if let Some(key) = &config.public_key {
+            properties.insert(String::from("server_key"), key.clone());
+        }
+        let host = format!("{}.local.", hostname());
+        let service = ServiceInfo::new(SERVICE_TYPE, &box_id, &host, "", REQ_PORT, properties)?
+            .enable_addr_auto();
+        daemon.register(service)?;
+        info!("advertising this box as {:?}", box_id);
+    }
+    if !config.discover {
+        // the box is advertised for as long as the daemon is kept
+        return futures::future::pending().await;
+    }
+    let events = daemon.browse(SERVICE_TYPE)?;
+    while let Ok(event) = events.recv_async().await {
+        let service = match event {
+            ServiceEvent::ServiceResolved(service) => service,
+            _ => continue,
+        };
+        let id = service
+            .get_fullname()
+            .trim_end_matches(SERVICE_TYPE)
+            .trim_end_matches('.');
+        if id == box_id
+            || federation
+                .satellites
+                .connected
+                .lock()
+                .unwrap()
+                .contains_key(id)
+        {
+            continue;
+        }
+        if id.contains('/') {
+            warn!("found a box named {:?}, which cannot be a satellite", id);
+            continue;
+        }
+        let host = match service.get_addresses().iter().next() {
+            Some(address) => address.to_string(),
+            None => continue,
+        };
+        let satellite = SatelliteConfig {
+            host,
+            req_port: service.get_port(),
+            pub_port: service
+                .get_property_val_str("pub_port")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(PUB_PORT),
+            server_key: service.get_property_val_str("server_key").map(String::from),
+        };
+        if let Err(e) = federation.connect(id.to_string(), &satellite) {
+            error!("could not connect to satellite {:?}: {:#}", id, e);
+        }
+    }
+    Ok(())
+}
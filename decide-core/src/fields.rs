@@ -0,0 +1,394 @@
+//! Reads and writes the scalar fields of state and parameters messages by name,
+//! using the descriptors in the descriptions of the components, so that the
+//! controller can handle messages without their Rust types.
+use super::handle::Handle;
+use decide_protocol::{
+    proto, unpack, ComponentName, ComponentRequest, GeneralRequest, Request, RequestType,
+    EXPERIMENT_TYPE_URL, SCHEDULE_TYPE_URL,
+};
+use prost::bytes::{Buf, BufMut};
+use prost::encoding::{decode_key, decode_varint, encode_key, encode_varint, WireType};
+use prost::Message;
+use prost_types::{
+    field_descriptor_proto::{Label, Type},
+    Any, FieldDescriptorProto, FileDescriptorSet, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// The value of a scalar field. Enums are given by number.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Field {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Field {
+    /// Whether the values are the same, comparing integers and floats by value
+    pub fn matches(&self, other: &Field) -> bool {
+        match (self, other) {
+            (Field::Int(a), Field::Float(b)) | (Field::Float(b), Field::Int(a)) => *a as f64 == *b,
+            _ => self == other,
+        }
+    }
+}
+
+/// The scalar fields of a message type
+#[derive(Debug, Clone)]
+pub struct Fields(Vec<FieldDescriptorProto>);
+
+impl Fields {
+    /// Finds the message named by a type URL among the top-level messages of the
+    /// descriptors
+    pub fn find(descriptors: &FileDescriptorSet, type_url: &str) -> Option<Self> {
+        let name = type_url.rsplit('/').next().unwrap_or_default();
+        descriptors.file.iter().find_map(|file| {
+            let prefix = match file.package() {
+                "" => String::new(),
+                package => format!("{}.", package),
+            };
+            file.message_type
+                .iter()
+                .find(|message| format!("{}{}", prefix, message.name()) == name)
+                .map(|message| {
+                    Fields(
+                        message
+                            .field
+                            .iter()
+                            .filter(|field| {
+                                field.label() != Label::Repeated && scalar(field.r#type())
+                            })
+                            .cloned()
+                            .collect(),
+                    )
+                })
+        })
+    }
+
+    /// Whether the message has a scalar field of this name
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|field| field.name() == name)
+    }
+
+    /// Decodes the scalar fields of a message. Fields missing from the encoding
+    /// have their default values.
+    pub fn decode(&self, mut buf: &[u8]) -> anyhow::Result<HashMap<String, Field>> {
+        let mut values: HashMap<String, Field> = self
+            .0
+            .iter()
+            .map(|field| (field.name().to_string(), default(field.r#type())))
+            .collect();
+        while buf.has_remaining() {
+            let (number, wire_type) = decode_key(&mut buf)?;
+            let field = self.0.iter().find(|field| field.number() as u32 == number);
+            let value = match wire_type {
+                WireType::Varint => {
+                    let value = decode_varint(&mut buf)?;
+                    field.map(|field| match field.r#type() {
+                        Type::Bool => Field::Bool(value != 0),
+                        Type::Sint32 | Type::Sint64 => {
+                            Field::Int((value >> 1) as i64 ^ -((value & 1) as i64))
+                        }
+                        Type::Int32 | Type::Enum => Field::Int(value as i32 as i64),
+                        _ => Field::Int(value as i64),
+                    })
+                }
+                WireType::ThirtyTwoBit => {
+                    anyhow::ensure!(buf.remaining() >= 4, "message is truncated");
+                    let value = buf.get_u32_le();
+                    field.map(|field| match field.r#type() {
+                        Type::Float => Field::Float(f32::from_bits(value) as f64),
+                        Type::Sfixed32 => Field::Int(value as i32 as i64),
+                        _ => Field::Int(value as i64),
+                    })
+                }
+                WireType::SixtyFourBit => {
+                    anyhow::ensure!(buf.remaining() >= 8, "message is truncated");
+                    let value = buf.get_u64_le();
+                    field.map(|field| match field.r#type() {
+                        Type::Double => Field::Float(f64::from_bits(value)),
+                        _ => Field::Int(value as i64),
+                    })
+                }
+                WireType::LengthDelimited => {
+                    let len = decode_varint(&mut buf)? as usize;
+                    anyhow::ensure!(buf.remaining() >= len, "message is truncated");
+                    let value = String::from_utf8_lossy(&buf[..len]).into_owned();
+                    buf.advance(len);
+                    field
+                        .filter(|field| field.r#type() == Type::String)
+                        .map(|_| Field::String(value))
+                }
+                _ => anyhow::bail!("groups are not supported"),
+            };
+            if let (Some(field), Some(value)) = (field, value) {
+                values.insert(field.name().to_string(), value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Encodes a message with the given fields; the others have their default
+    /// values
+    pub fn encode(&self, values: &HashMap<String, Field>) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        for field in &self.0 {
+            let value = match values.get(field.name()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let number = field.number() as u32;
+            match (field.r#type(), value) {
+                (Type::Bool, Field::Bool(value)) => {
+                    encode_key(number, WireType::Varint, &mut buf);
+                    encode_varint(*value as u64, &mut buf);
+                }
+                (Type::Sint32 | Type::Sint64, Field::Int(value)) => {
+                    encode_key(number, WireType::Varint, &mut buf);
+                    encode_varint(((value << 1) ^ (value >> 63)) as u64, &mut buf);
+                }
+                (
+                    Type::Int32 | Type::Int64 | Type::Uint32 | Type::Uint64 | Type::Enum,
+                    Field::Int(value),
+                ) => {
+                    encode_key(number, WireType::Varint, &mut buf);
+                    encode_varint(*value as u64, &mut buf);
+                }
+                (Type::Fixed32 | Type::Sfixed32, Field::Int(value)) => {
+                    encode_key(number, WireType::ThirtyTwoBit, &mut buf);
+                    buf.put_u32_le(*value as u32);
+                }
+                (Type::Fixed64 | Type::Sfixed64, Field::Int(value)) => {
+                    encode_key(number, WireType::SixtyFourBit, &mut buf);
+                    buf.put_u64_le(*value as u64);
+                }
+                (Type::Float, Field::Float(value)) => {
+                    encode_key(number, WireType::ThirtyTwoBit, &mut buf);
+                    buf.put_f32_le(*value as f32);
+                }
+                (Type::Float, Field::Int(value)) => {
+                    encode_key(number, WireType::ThirtyTwoBit, &mut buf);
+                    buf.put_f32_le(*value as f32);
+                }
+                (Type::Double, Field::Float(value)) => {
+                    encode_key(number, WireType::SixtyFourBit, &mut buf);
+                    buf.put_f64_le(*value);
+                }
+                (Type::Double, Field::Int(value)) => {
+                    encode_key(number, WireType::SixtyFourBit, &mut buf);
+                    buf.put_f64_le(*value as f64);
+                }
+                (Type::String, Field::String(value)) => {
+                    encode_key(number, WireType::LengthDelimited, &mut buf);
+                    encode_varint(value.len() as u64, &mut buf);
+                    buf.put_slice(value.as_bytes());
+                }
+                (r#type, value) => {
+                    anyhow::bail!("field {:?} is {:?}, not {:?}", field.name(), r#type, value)
+                }
+            }
+        }
+        if let Some(name) = values.keys().find(|name| !self.contains(name)) {
+            anyhow::bail!("there is no scalar field {:?}", name);
+        }
+        Ok(buf)
+    }
+}
+
+fn scalar(r#type: Type) -> bool {
+    !matches!(r#type, Type::Message | Type::Group | Type::Bytes)
+}
+
+fn default(r#type: Type) -> Field {
+    match r#type {
+        Type::Bool => Field::Bool(false),
+        Type::Float | Type::Double => Field::Float(0.0),
+        Type::String => Field::String(String::new()),
+        _ => Field::Int(0),
+    }
+}
+
+/// The state and parameters messages of a component
+#[derive(Debug)]
+pub struct Messages {
+    state_type: String,
+    state: Fields,
+    params_type: String,
+    params: Fields,
+}
+
+impl Messages {
+    /// The fields of the state message
+    pub fn state(&self) -> &Fields {
+        &self.state
+    }
+
+    /// Decodes a message of the component, if it is a state message
+    pub fn decode_state(&self, state: &Any) -> Option<anyhow::Result<HashMap<String, Field>>> {
+        if state.type_url != self.state_type {
+            return None;
+        }
+        Some(self.state.decode(&state.value))
+    }
+
+    /// Decodes a message of the component, if it is a parameters message
+    pub fn decode_params(&self, params: &Any) -> Option<anyhow::Result<HashMap<String, Field>>> {
+        if params.type_url != self.params_type {
+            return None;
+        }
+        Some(self.params.decode(&params.value))
+    }
+
+    /// A request to change the state of the component to one with the given fields
+    pub fn change_state(
+        &self,
+        component: &ComponentName,
+        values: &HashMap<String, Field>,
+    ) -> anyhow::Result<Request> {
+        let body = proto::StateChange {
+            state: Some(Any {
+                type_url: self.state_type.clone(),
+                value: self.state.encode(values)?,
+            }),
+            ..Default::default()
+        };
+        Ok(component_request(
+            component,
+            ComponentRequest::ChangeState,
+            body.encode_to_vec(),
+        ))
+    }
+
+    /// A request to set the parameters of the component to the given fields
+    pub fn set_parameters(
+        &self,
+        component: &ComponentName,
+        values: &HashMap<String, Field>,
+    ) -> anyhow::Result<Request> {
+        let body = proto::ComponentParams {
+            parameters: Some(Any {
+                type_url: self.params_type.clone(),
+                value: self.params.encode(values)?,
+            }),
+        };
+        Ok(component_request(
+            component,
+            ComponentRequest::SetParameters,
+            body.encode_to_vec(),
+        ))
+    }
+}
+
+fn component_request(
+    component: &ComponentName,
+    request: ComponentRequest,
+    body: Vec<u8>,
+) -> Request {
+    Request {
+        request_type: RequestType::Component(request),
+        component: Some(component.clone()),
+        body,
+        meta: Default::default(),
+    }
+}
+
+/// A message published by a component, with its fields decoded if the messages
+/// of the component are known, for encoding as JSON
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    // when the message was published, in RFC 3339
+    pub time: String,
+    pub component: String,
+    pub topic: String,
+    pub sequence: u64,
+    pub label: String,
+    #[serde(rename = "type")]
+    pub type_url: String,
+    pub state: Option<HashMap<String, Field>>,
+}
+
+impl Event {
+    pub fn new(
+        component: &ComponentName,
+        message: &proto::HeldMessage,
+        messages: &HashMap<ComponentName, Messages>,
+    ) -> anyhow::Result<Self> {
+        let published = message.message.clone().unwrap_or_default();
+        let any = published.state.unwrap_or_default();
+        let state = if any.type_url == EXPERIMENT_TYPE_URL {
+            let experiment = unpack::<proto::ExperimentState>(EXPERIMENT_TYPE_URL, &any)?;
+            Some(HashMap::from([
+                (String::from("state"), Field::String(experiment.state)),
+                (String::from("previous"), Field::String(experiment.previous)),
+                (String::from("trial"), Field::Int(experiment.trial as i64)),
+            ]))
+        } else if any.type_url == SCHEDULE_TYPE_URL {
+            let schedule = unpack::<proto::ScheduleState>(SCHEDULE_TYPE_URL, &any)?;
+            let next = schedule.upcoming.into_iter().next().unwrap_or_default();
+            Some(HashMap::from([
+                (String::from("last"), Field::String(schedule.last)),
+                (String::from("next"), Field::String(next.name)),
+                (
+                    String::from("next_time"),
+                    Field::String(rfc3339(&next.time.unwrap_or_default())?),
+                ),
+            ]))
+        } else {
+            messages
+                .get(component)
+                .and_then(|messages| messages.decode_state(&any))
+                .transpose()?
+        };
+        Ok(Event {
+            time: rfc3339(&published.time.unwrap_or_default())?,
+            component: component.0.clone(),
+            topic: message.topic.clone(),
+            sequence: published.sequence,
+            label: published.label,
+            type_url: any.type_url,
+            state,
+        })
+    }
+}
+
+/// Formats a timestamp in RFC 3339
+pub fn rfc3339(time: &Timestamp) -> anyhow::Result<String> {
+    let time = OffsetDateTime::from_unix_timestamp_nanos(
+        time.seconds as i128 * 1_000_000_000 + time.nanos as i128,
+    )?;
+    Ok(time.format(&Rfc3339)?)
+}
+
+/// Asks the controller for the messages of every component. Components whose
+/// descriptors do not define their messages are left out.
+pub async fn describe_components(
+    handle: &Handle,
+) -> anyhow::Result<HashMap<ComponentName, Messages>> {
+    let request = Request {
+        request_type: RequestType::General(GeneralRequest::DescribeComponents),
+        component: None,
+        body: Vec::new(),
+        meta: Default::default(),
+    };
+    let descriptions = match handle.request(request).await?.result {
+        Some(proto::reply::Result::Components(described)) => described.components,
+        _ => anyhow::bail!("the components could not be described"),
+    };
+    Ok(descriptions
+        .into_iter()
+        .filter_map(|description| {
+            let descriptors = description.descriptors.unwrap_or_default();
+            let messages = Messages {
+                state: Fields::find(&descriptors, &description.state_type)?,
+                params: Fields::find(&descriptors, &description.params_type)?,
+                state_type: description.state_type,
+                params_type: description.params_type,
+            };
+            Some((ComponentName(description.name), messages))
+        })
+        .collect())
+}
@@ -0,0 +1,473 @@
+//! Serves the state and parameters of the components, and their recent messages,
+//! as JSON over HTTP, for dashboards and scripts that do not speak the protocol
+//!
+//! - `GET /components`: the names of the components and their message types
+//! - `GET /components/:name/state` and `GET /components/:name/params`
+//! - `PUT /components/:name/state` and `PUT /components/:name/params`, with the
+//!   fields to set; other fields take their default values
+//! - `GET /events?component=&after=`: the recent state messages of one or all
+//!   components, after a sequence number
+//! - `GET /stream?topics=`: a WebSocket on which the messages published under
+//!   the topics are sent as they are published
+//!
+//! Fields are encoded as in `experiment.yml`, using the descriptors of the
+//! messages. Requests with a bearer token are made as the client it belongs to;
+//! others as `http`.
+use super::fields::{describe_components, Event, Field, Messages};
+use super::handle::Handle;
+use super::security::{Role, Tokens};
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use decide_protocol::{
+    error::ControllerError, proto, proto::error::Code, ComponentName, ComponentRequest,
+    GeneralRequest, Request, RequestType,
+};
+use directories::ProjectDirs;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::{fs::File, io::Read};
+use tokio::sync::{broadcast::error::RecvError, RwLock};
+
+/// The name requests without a token are made as
+pub const HTTP: &str = "http";
+
+/// The address of the gateway, and the clients that may use it, as given in
+/// `gateway.yml`
+#[derive(Deserialize, Debug)]
+pub struct GatewayConfig {
+    address: SocketAddr,
+    #[serde(flatten)]
+    tokens: Tokens,
+}
+
+impl GatewayConfig {
+    /// Reads `gateway.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("gateway.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?)
+    }
+}
+
+/// An error, as returned to the client
+#[derive(Debug)]
+struct Error(StatusCode, String);
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+        (self.0, Json(Body { error: self.1 })).into_response()
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<proto::Error> for Error {
+    fn from(e: proto::Error) -> Self {
+        let status = match Code::from_i32(e.code).unwrap_or(Code::Unknown) {
+            Code::InvalidRequest | Code::InvalidState | Code::InvalidParams => {
+                StatusCode::BAD_REQUEST
+            }
+            Code::NoSuchComponent => StatusCode::NOT_FOUND,
+            Code::Locked | Code::Paused | Code::ConfigMismatch => StatusCode::CONFLICT,
+            Code::Busy | Code::HardwareFault => StatusCode::SERVICE_UNAVAILABLE,
+            Code::Expired => StatusCode::GATEWAY_TIMEOUT,
+            Code::Forbidden => StatusCode::FORBIDDEN,
+            Code::IncompatibleVersion | Code::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Error(status, e.message)
+    }
+}
+
+type Fields = HashMap<String, Field>;
+
+/// Shared by the handlers of the gateway
+struct Gateway {
+    config: GatewayConfig,
+    handle: Handle,
+    // the messages of the components, described again when one is missing
+    messages: RwLock<HashMap<ComponentName, Messages>>,
+}
+
+fn router(gateway: Arc<Gateway>) -> Router {
+    Router::new()
+        .route("/components", get(components))
+        .route("/components/:name/state", get(get_state).put(change_state))
+        .route(
+            "/components/:name/params",
+            get(get_params).put(set_parameters),
+        )
+        .route("/events", get(events))
+        .route("/stream", get(stream))
+        .with_state(gateway)
+}
+
+/// Serves the gateway until the controller stops
+pub async fn run(config: GatewayConfig, handle: Handle) -> anyhow::Result<()> {
+    let messages = describe_components(&handle).await?;
+    let address = config.address;
+    let gateway = Arc::new(Gateway {
+        config,
+        handle,
+        messages: RwLock::new(messages),
+    });
+    info!("serving HTTP on {}", address);
+    axum::Server::try_bind(&address)?
+        .serve(router(gateway).into_make_service())
+        .await?;
+    Ok(())
+}
+
+impl Gateway {
+    /// The client a token belongs to, and its role
+    fn client(&self, token: Option<&str>) -> Result<(&str, Role), Error> {
+        self.config
+            .tokens
+            .client(token, HTTP)
+            .ok_or_else(|| Error(StatusCode::UNAUTHORIZED, "unknown token".into()))
+    }
+
+    /// Makes a request as the client whose token is given, if its role permits
+    async fn request(
+        &self,
+        headers: &HeaderMap,
+        request: Request,
+    ) -> Result<proto::reply::Result, Error> {
+        let (client, role) = self.client(bearer(headers))?;
+        if role < Role::required(request.request_type) {
+            return Err(Error(
+                StatusCode::FORBIDDEN,
+                format!(
+                    "{:?} is not permitted to make {:?} requests",
+                    client, request.request_type
+                ),
+            ));
+        }
+        match self.handle.named(client).request(request).await?.result {
+            Some(proto::reply::Result::Error(e)) => Err(e.into()),
+            Some(result) => Ok(result),
+            None => Err(anyhow::anyhow!("the controller did not reply").into()),
+        }
+    }
+
+    /// Looks up the messages of a component, describing the components again
+    /// if it was added since they were last described
+    async fn with_messages<T>(
+        &self,
+        name: &ComponentName,
+        f: impl FnOnce(&Messages) -> anyhow::Result<T>,
+    ) -> Result<T, Error> {
+        if !self.messages.read().await.contains_key(name) {
+            let messages = describe_components(&self.handle).await?;
+            *self.messages.write().await = messages;
+        }
+        match self.messages.read().await.get(name) {
+            Some(messages) => Ok(f(messages)?),
+            None => Err(Error(
+                StatusCode::NOT_FOUND,
+                format!("{:?} is not a component with described messages", name.0),
+            )),
+        }
+    }
+}
+
+/// The bearer token in the headers of a request, if there is one
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn component_request(name: &ComponentName, request: ComponentRequest) -> Request {
+    Request {
+        request_type: RequestType::Component(request),
+        component: Some(name.clone()),
+        body: Vec::new(),
+        meta: Default::default(),
+    }
+}
+
+fn unexpected(result: proto::reply::Result) -> Error {
+    anyhow::anyhow!("unexpected reply {:?}", result).into()
+}
+
+#[derive(Serialize)]
+struct Description {
+    state_type: String,
+    params_type: String,
+}
+
+async fn components(
+    State(gateway): State<Arc<Gateway>>,
+    headers: HeaderMap,
+) -> Result<Json<HashMap<String, Description>>, Error> {
+    let request = Request {
+        request_type: RequestType::General(GeneralRequest::DescribeComponents),
+        component: None,
+        body: Vec::new(),
+        meta: Default::default(),
+    };
+    match gateway.request(&headers, request).await? {
+        proto::reply::Result::Components(described) => Ok(Json(
+            described
+                .components
+                .into_iter()
+                .map(|description| {
+                    let types = Description {
+                        state_type: description.state_type,
+                        params_type: description.params_type,
+                    };
+                    (description.name, types)
+                })
+                .collect(),
+        )),
+        result => Err(unexpected(result)),
+    }
+}
+
+async fn get_state(
+    State(gateway): State<Arc<Gateway>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Json<Fields>, Error> {
+    let name = ComponentName(name);
+    let request = component_request(&name, ComponentRequest::GetState);
+    match gateway.request(&headers, request).await? {
+        proto::reply::Result::State(state) => {
+            let fields = gateway
+                .with_messages(&name, |messages| {
+                    messages.decode_state(&state).unwrap_or_else(|| {
+                        Err(anyhow::anyhow!("unexpected state {:?}", state.type_url))
+                    })
+                })
+                .await?;
+            Ok(Json(fields))
+        }
+        result => Err(unexpected(result)),
+    }
+}
+
+async fn get_params(
+    State(gateway): State<Arc<Gateway>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Json<Fields>, Error> {
+    let name = ComponentName(name);
+    let request = component_request(&name, ComponentRequest::GetParameters);
+    match gateway.request(&headers, request).await? {
+        proto::reply::Result::Params(params) => {
+            let fields = gateway
+                .with_messages(&name, |messages| {
+                    messages.decode_params(&params).unwrap_or_else(|| {
+                        Err(anyhow::anyhow!(
+                            "unexpected parameters {:?}",
+                            params.type_url
+                        ))
+                    })
+                })
+                .await?;
+            Ok(Json(fields))
+        }
+        result => Err(unexpected(result)),
+    }
+}
+
+async fn change_state(
+    State(gateway): State<Arc<Gateway>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(fields): Json<Fields>,
+) -> Result<StatusCode, Error> {
+    let name = ComponentName(name);
+    let request = gateway
+        .with_messages(&name, |messages| messages.change_state(&name, &fields))
+        .await
+        .map_err(|Error(_, e)| Error(StatusCode::BAD_REQUEST, e))?;
+    gateway.request(&headers, request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_parameters(
+    State(gateway): State<Arc<Gateway>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(fields): Json<Fields>,
+) -> Result<StatusCode, Error> {
+    let name = ComponentName(name);
+    let request = gateway
+        .with_messages(&name, |messages| messages.set_parameters(&name, &fields))
+        .await
+        .map_err(|Error(_, e)| Error(StatusCode::BAD_REQUEST, e))?;
+    gateway.request(&headers, request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    component: Option<String>,
+    #[serde(default)]
+    after: u64,
+}
+
+async fn events(
+    State(gateway): State<Arc<Gateway>>,
+    headers: HeaderMap,
+    Query(query): Query<EventsQuery>,
+) -> Result<Json<Vec<Event>>, Error> {
+    let body = proto::ReplayRequest {
+        component: query.component.unwrap_or_default(),
+        after_sequence: query.after,
+        since: None,
+    };
+    let request = Request {
+        request_type: RequestType::General(GeneralRequest::Replay),
+        component: None,
+        body: body.encode_to_vec(),
+        meta: Default::default(),
+    };
+    match gateway.request(&headers, request).await? {
+        proto::reply::Result::Held(held) => {
+            let messages = gateway.messages.read().await;
+            let events = held
+                .messages
+                .iter()
+                .map(|message| {
+                    let component = message.topic.split('/').nth(1).unwrap_or_default();
+                    Event::new(&ComponentName(component.into()), message, &messages)
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(Json(events))
+        }
+        result => Err(unexpected(result)),
+    }
+}
+
+/// Prefixes of the topics a stream sends the messages of; all of them if empty
+#[derive(Debug, Default)]
+struct Topics(Vec<String>);
+
+impl Topics {
+    fn matches(&self, topic: &str) -> bool {
+        self.0.is_empty() || self.0.iter().any(|prefix| topic.starts_with(prefix))
+    }
+}
+
+/// Sent by the client of a stream to change the topics it receives
+#[derive(Deserialize, Debug)]
+struct Subscription {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(default)]
+    unsubscribe: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    // separated by commas
+    #[serde(default)]
+    topics: String,
+    // for browsers, which cannot set the headers of a WebSocket
+    token: Option<String>,
+}
+
+async fn stream(
+    State(gateway): State<Arc<Gateway>>,
+    headers: HeaderMap,
+    Query(query): Query<StreamQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, Error> {
+    let (client, _) = gateway.client(bearer(&headers).or(query.token.as_deref()))?;
+    info!("{:?} is streaming {:?}", client, query.topics);
+    let topics = Topics(
+        query
+            .topics
+            .split(',')
+            .filter(|topic| !topic.is_empty())
+            .map(String::from)
+            .collect(),
+    );
+    Ok(upgrade.on_upgrade(move |socket| send_events(gateway, socket, topics)))
+}
+
+/// Sends the messages published under the topics, as events, until the client
+/// closes the socket. Clients that fall behind are sent an error saying how
+/// many messages they missed.
+async fn send_events(gateway: Arc<Gateway>, mut socket: WebSocket, mut topics: Topics) {
+    let mut publications = gateway.handle.subscribe();
+    loop {
+        let sent = tokio::select! {
+            publication = publications.recv() => match publication {
+                Ok((name, message)) if topics.matches(&message.topic) => {
+                    let event = Event::new(&name, &message, &*gateway.messages.read().await);
+                    match event.and_then(|event| Ok(serde_json::to_string(&event)?)) {
+                        Ok(json) => socket.send(WsMessage::Text(json)).await,
+                        Err(e) => {
+                            warn!("could not stream a message of {:?}: {}", name, e);
+                            Ok(())
+                        }
+                    }
+                }
+                Ok(_) => Ok(()),
+                Err(RecvError::Lagged(missed)) => {
+                    let error = format!("missed {} messages", missed);
+                    let error = serde_json::json!({ "error": error });
+                    socket.send(WsMessage::Text(error.to_string())).await
+                }
+                Err(RecvError::Closed) => return,
+            },
+            received = socket.recv() => match received {
+                Some(Ok(WsMessage::Text(text))) => {
+                    match serde_json::from_str::<Subscription>(&text) {
+                        Ok(subscription) => {
+                            topics.0.retain(|topic| !subscription.unsubscribe.contains(topic));
+                            topics.0.extend(subscription.subscribe);
+                            Ok(())
+                        }
+                        Err(e) => {
+                            let error = serde_json::json!({ "error": e.to_string() });
+                            socket.send(WsMessage::Text(error.to_string())).await
+                        }
+                    }
+                }
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+                // pings are answered for us
+                Some(Ok(_)) => Ok(()),
+            },
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+}
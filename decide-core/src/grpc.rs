@@ -0,0 +1,314 @@
+//! Serves the requests of the controller most used by clients as a gRPC
+//! service, defined in `proto/grpc.proto`, for clients in languages with good
+//! gRPC support. The messages are those of the protocol.
+use super::handle::Handle;
+use super::security::{Role, Tokens};
+use decide_protocol::{
+    error::ControllerError, proto, proto::error::Code, ComponentName, ComponentRequest,
+    GeneralRequest, Request, RequestType,
+};
+use directories::ProjectDirs;
+use futures::{stream, Stream};
+use prost::Message;
+use prost_types::Any;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::{fs::File, io::Read};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Response, Status};
+
+/// The generated service
+pub mod api {
+    tonic::include_proto!("decide_grpc");
+}
+use api::decide_server::{Decide, DecideServer};
+
+/// The name requests without a token are made as
+pub const GRPC: &str = "grpc";
+
+/// The address of the service, and the clients that may use it, as given in
+/// `grpc.yml`
+#[derive(Deserialize, Debug)]
+pub struct GrpcConfig {
+    address: SocketAddr,
+    #[serde(flatten)]
+    tokens: Tokens,
+}
+
+impl GrpcConfig {
+    /// Reads `grpc.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("grpc.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?)
+    }
+}
+
+/// Serves the service until the controller stops
+pub async fn run(config: GrpcConfig, handle: Handle) -> anyhow::Result<()> {
+    let address = config.address;
+    info!("serving gRPC on {}", address);
+    tonic::transport::Server::builder()
+        .add_service(DecideServer::new(Service { config, handle }))
+        .serve(address)
+        .await?;
+    Ok(())
+}
+
+struct Service {
+    config: GrpcConfig,
+    handle: Handle,
+}
+
+impl Service {
+    /// The client whose bearer token is in the metadata of a request, if it has
+    /// the role
+    fn client<T>(&self, request: &tonic::Request<T>, required: Role) -> Result<Handle, Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let (client, role) = self
+            .config
+            .tokens
+            .client(token, GRPC)
+            .ok_or_else(|| Status::unauthenticated("unknown token"))?;
+        if role < required {
+            return Err(Status::permission_denied(format!(
+                "{:?} needs to be an {:?} for the request",
+                client, required
+            )));
+        }
+        Ok(self.handle.named(client))
+    }
+
+    /// Makes a request of a component as the client of a gRPC request
+    async fn request<T>(
+        &self,
+        from: &tonic::Request<T>,
+        name: String,
+        request: ComponentRequest,
+        body: Vec<u8>,
+    ) -> Result<proto::reply::Result, Status> {
+        let request = Request {
+            request_type: RequestType::Component(request),
+            component: Some(ComponentName(name)),
+            body,
+            meta: Default::default(),
+        };
+        self.send(from, request).await
+    }
+
+    /// Makes a request of the controller as the client of a gRPC request
+    async fn general<T>(
+        &self,
+        from: &tonic::Request<T>,
+        request: GeneralRequest,
+        body: Vec<u8>,
+    ) -> Result<proto::reply::Result, Status> {
+        let request = Request {
+            request_type: RequestType::General(request),
+            component: None,
+            body,
+            meta: Default::default(),
+        };
+        self.send(from, request).await
+    }
+
+    async fn send<T>(
+        &self,
+        from: &tonic::Request<T>,
+        request: Request,
+    ) -> Result<proto::reply::Result, Status> {
+        let handle = self.client(from, Role::required(request.request_type))?;
+        match handle
+            .request(request)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?
+            .result
+        {
+            Some(proto::reply::Result::Error(e)) => Err(status(e)),
+            Some(result) => Ok(result),
+            None => Err(Status::internal("the controller did not reply")),
+        }
+    }
+}
+
+/// The gRPC status for an error of the protocol
+fn status(e: proto::Error) -> Status {
+    let code = match Code::from_i32(e.code).unwrap_or(Code::Unknown) {
+        Code::InvalidRequest | Code::InvalidState | Code::InvalidParams => {
+            tonic::Code::InvalidArgument
+        }
+        Code::NoSuchComponent => tonic::Code::NotFound,
+        Code::Locked | Code::Paused | Code::ConfigMismatch => tonic::Code::FailedPrecondition,
+        Code::Busy | Code::HardwareFault => tonic::Code::Unavailable,
+        Code::Expired => tonic::Code::DeadlineExceeded,
+        Code::Forbidden => tonic::Code::PermissionDenied,
+        Code::IncompatibleVersion | Code::Unknown => tonic::Code::Unknown,
+    };
+    Status::new(code, e.message)
+}
+
+fn unexpected(result: proto::reply::Result) -> Status {
+    Status::internal(format!("unexpected reply {:?}", result))
+}
+
+type Updates = Pin<Box<dyn Stream<Item = Result<proto::HeldMessage, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Decide for Service {
+    async fn get_state(
+        &self,
+        request: tonic::Request<api::Component>,
+    ) -> Result<Response<Any>, Status> {
+        let name = request.get_ref().name.clone();
+        match self
+            .request(&request, name, ComponentRequest::GetState, Vec::new())
+            .await?
+        {
+            proto::reply::Result::State(state) => Ok(Response::new(state)),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    async fn get_parameters(
+        &self,
+        request: tonic::Request<api::Component>,
+    ) -> Result<Response<Any>, Status> {
+        let name = request.get_ref().name.clone();
+        match self
+            .request(&request, name, ComponentRequest::GetParameters, Vec::new())
+            .await?
+        {
+            proto::reply::Result::Params(params) => Ok(Response::new(params)),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    async fn set_state(
+        &self,
+        request: tonic::Request<api::ComponentMessage>,
+    ) -> Result<Response<()>, Status> {
+        let message = request.get_ref();
+        let body = proto::StateChange {
+            state: message.message.clone(),
+            ..Default::default()
+        };
+        let name = message.name.clone();
+        self.request(
+            &request,
+            name,
+            ComponentRequest::ChangeState,
+            body.encode_to_vec(),
+        )
+        .await?;
+        Ok(Response::new(()))
+    }
+
+    async fn set_parameters(
+        &self,
+        request: tonic::Request<api::ComponentMessage>,
+    ) -> Result<Response<()>, Status> {
+        let message = request.get_ref();
+        let body = proto::ComponentParams {
+            parameters: message.message.clone(),
+        };
+        let name = message.name.clone();
+        self.request(
+            &request,
+            name,
+            ComponentRequest::SetParameters,
+            body.encode_to_vec(),
+        )
+        .await?;
+        Ok(Response::new(()))
+    }
+
+    type StateUpdatesStream = Updates;
+
+    /// Ends with an error if the client falls behind, so that it knows to get
+    /// a snapshot
+    async fn state_updates(
+        &self,
+        request: tonic::Request<api::UpdatesRequest>,
+    ) -> Result<Response<Updates>, Status> {
+        let handle = self.client(&request, Role::Observer)?;
+        let topics = request.into_inner().topics;
+        let updates = stream::unfold(Some(handle.subscribe()), move |publications| {
+            let topics = topics.clone();
+            async move {
+                let mut publications = publications?;
+                loop {
+                    match publications.recv().await {
+                        Ok((_, message))
+                            if topics.is_empty()
+                                || topics.iter().any(|topic| message.topic.starts_with(topic)) =>
+                        {
+                            return Some((Ok(message), Some(publications)))
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => {
+                            let lost = format!("missed {} messages", missed);
+                            return Some((Err(Status::data_loss(lost)), None));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(updates)))
+    }
+
+    async fn snapshot(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<proto::Snapshot>, Status> {
+        match self
+            .general(&request, GeneralRequest::Snapshot, Vec::new())
+            .await?
+        {
+            proto::reply::Result::Snapshot(snapshot) => Ok(Response::new(snapshot)),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    async fn replay(
+        &self,
+        request: tonic::Request<proto::ReplayRequest>,
+    ) -> Result<Response<proto::HeldMessages>, Status> {
+        let body = request.get_ref().encode_to_vec();
+        match self.general(&request, GeneralRequest::Replay, body).await? {
+            proto::reply::Result::Held(held) => Ok(Response::new(held)),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    async fn reload(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<proto::ReloadResult>, Status> {
+        match self
+            .general(&request, GeneralRequest::Reload, Vec::new())
+            .await?
+        {
+            proto::reply::Result::Reload(reload) => Ok(Response::new(reload)),
+            result => Err(unexpected(result)),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Access to the components for the subsystems of the controller that run
+//! alongside the sockets, such as the experiment engine
+use super::{Fault, StateStream};
+use anyhow::Context;
+use decide_protocol::{proto, ComponentName, Request};
+use prost_types::Any;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// A request made by a subsystem, with its client id
+pub type InternalRequest = (Vec<u8>, Request, oneshot::Sender<proto::Reply>);
+
+/// A message published by a component, with its name
+pub type Publication = (ComponentName, proto::HeldMessage);
+
+/// Every message published by the components, for the subsystems
+pub type Publications = broadcast::Sender<Publication>;
+
+/// Makes requests of the components, as a client would, and receives what they
+/// publish
+#[derive(Debug, Clone)]
+pub struct Handle {
+    client: Vec<u8>,
+    requests: mpsc::Sender<InternalRequest>,
+    publications: Publications,
+    added: mpsc::Sender<StateStream>,
+}
+
+impl Handle {
+    pub(crate) fn new(
+        requests: mpsc::Sender<InternalRequest>,
+        publications: Publications,
+        added: mpsc::Sender<StateStream>,
+    ) -> Self {
+        Handle {
+            client: b"controller".to_vec(),
+            requests,
+            publications,
+            added,
+        }
+    }
+
+    /// A handle for a subsystem, which makes its requests, and holds leases, as
+    /// the client `name`
+    pub fn named(&self, name: &str) -> Self {
+        Handle {
+            client: name.as_bytes().to_vec(),
+            ..self.clone()
+        }
+    }
+
+    /// Makes a request. Subsystems may make any request, but like clients they
+    /// cannot change components leased to others.
+    pub async fn request(&self, request: Request) -> anyhow::Result<proto::Reply> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.requests
+            .send((self.client.clone(), request, reply_tx))
+            .await
+            .ok()
+            .context("the controller is no longer taking requests")?;
+        Ok(reply_rx.await?)
+    }
+
+    /// Receives the messages published from now on. A subsystem that falls
+    /// behind by more than 1000 messages misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<Publication> {
+        self.publications.subscribe()
+    }
+
+    /// Publishes the messages sent on the returned channel as if they came from
+    /// a component called `name`
+    pub async fn publish_as(&self, name: ComponentName) -> anyhow::Result<mpsc::Sender<Any>> {
+        let (state_tx, state_rx) = mpsc::channel(100);
+        self.added
+            .send((name, (state_rx.into(), Fault::default())))
+            .await
+            .ok()
+            .context("the controller is no longer publishing")?;
+        Ok(state_tx)
+    }
+}
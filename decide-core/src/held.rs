@@ -0,0 +1,51 @@
+use decide_protocol::{proto, ComponentName};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// state messages held for a component before the oldest are dropped
+const MAX_HELD: usize = 10000;
+
+/// State messages held until their consumer acknowledges them, for the
+/// components that have one
+pub type Held = Arc<Mutex<HashMap<ComponentName, HeldQueue>>>;
+
+#[derive(Debug)]
+pub struct HeldQueue {
+    pub consumer: String,
+    messages: VecDeque<proto::HeldMessage>,
+}
+
+impl HeldQueue {
+    pub fn new(consumer: String) -> Self {
+        HeldQueue {
+            consumer,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Holds a published state message. If the consumer has fallen too far
+    /// behind, the oldest message is dropped.
+    pub fn hold(&mut self, component: &ComponentName, topic: String, message: proto::Pub) {
+        if self.messages.len() == MAX_HELD {
+            error!(
+                "{} has not acknowledged {} messages from {:?}; dropping the oldest",
+                self.consumer, MAX_HELD, component
+            );
+            self.messages.pop_front();
+        }
+        self.messages.push_back(proto::HeldMessage {
+            topic,
+            message: Some(message),
+        });
+    }
+
+    /// Drops the messages up to and including a sequence number
+    pub fn acknowledge(&mut self, sequence: u64) {
+        self.messages
+            .retain(|held| held.message.as_ref().is_some_and(|m| m.sequence > sequence));
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &proto::HeldMessage> {
+        self.messages.iter()
+    }
+}
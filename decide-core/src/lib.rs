@@ -11,79 +11,259 @@ All are disabled by default.
   State, Params, and Config, but have no internal logic.
   In other words, they have zero side effects and their
   state will not change unless explicitly set.
+
+## Experiment features
+
+* **scripting** -
+  Runs an experiment written as a Rhai script in
+  `experiment.rhai`, alongside or instead of the states
+  in `experiment.yml`.
+
+## Network features
+
+* **http** -
+  Serves the state and parameters of the components as
+  JSON over HTTP, at the address in `gateway.yml`.
+* **mqtt** -
+  Republishes the messages of the components to the MQTT
+  broker in `mqtt.yml`, and takes commands from it.
+* **grpc** -
+  Serves the requests most used by clients as a gRPC
+  service, at the address in `grpc.yml`.
+* **mdns** -
+  Advertises the box with mDNS for the hub of a
+  federation to find, and lets a hub take the boxes it
+  finds as satellites, as set in `federation.yml`.
+
+## Logging features
+
+* **parquet** -
+  Lets the logger also write each log file it closes as
+  Parquet, with `parquet: true` in `logger.yml`.
+* **otel** -
+  Exports the spans of requests, from the socket through
+  the component to the state it publishes, to the
+  OpenTelemetry collector in `telemetry.yml`.
 */
 use anyhow::Context as AnyhowContext;
 use decide_protocol::{
-    error::{ClientError, ControllerError},
-    proto, ComponentName,
+    error::{ClientError, ControllerError, DecideError},
+    pack, proto, pub_topic, report_fault, unpack, ComponentHealth, ComponentName, PubStamper, Registry,
     ComponentRequest::{self, *},
     GeneralRequest::{self, *},
-    Request, RequestType, Result,
+    Request, RequestType, Result, DECIDE_VERSION, FAULT_TYPE_URL, FEATURES, HEARTBEAT_TYPE_URL,
+    RESTART_TYPE_URL, SUPPORTED_VERSIONS,
 };
 use directories::ProjectDirs;
 use futures::{future, stream, FutureExt, Stream, StreamExt};
+use num_traits::FromPrimitive;
 use prost::Message;
-use prost_types::Any;
-use prost_types::Timestamp;
+use prost_types::{Any, Timestamp};
 use serde::Deserialize;
 use serde_value::Value;
 use sha3::{digest::Update, Digest, Sha3_256};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{collections::HashMap, time::Duration};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs::File, io::Read};
 use tmq::Multipart;
 use tokio::{
-    sync::{mpsc, oneshot},
-    time::timeout,
+    sync::{broadcast, mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::{interval, timeout},
 };
 use tokio_stream::wrappers::ReceiverStream;
 #[macro_use]
 extern crate tracing;
-use tracing::instrument;
+use tracing::{instrument, Instrument, Span};
 
 mod components;
 use components::ComponentKind;
 
+mod supervisor;
+use supervisor::{RestartConfig, Supervision, Supervisor};
+
+mod held;
+use held::{Held, HeldQueue};
+
+mod replay;
+use replay::{Replay, ReplayBuffer};
+
 pub mod run;
 
+pub mod config;
+
+pub mod check;
+
+pub mod logging;
+
+pub mod systemd;
+
+pub mod security;
+use security::Role;
+
+pub mod handle;
+use handle::{Handle, InternalRequest, Publications};
+
+pub mod fields;
+
+pub mod experiment;
+
+pub mod schedule;
+
+pub mod federation;
+use federation::{FederationConfig, Satellites};
+
+#[cfg(feature = "scripting")]
+pub mod script;
+
+pub mod logger;
+
+#[cfg(feature = "http")]
+pub mod gateway;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "otel")]
+pub mod telemetry;
+
+/// The name of the box, for the subsystems that identify it to others
+pub(crate) fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| String::from("decide"))
+}
+
+/// Registry of the state and parameters types of all the component drivers, for
+/// decoding published messages
+pub fn registry() -> Registry {
+    ComponentKind::registry()
+}
+
 static SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+static LEASE_TTL: Duration = Duration::from_secs(30);
+static HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+// how often the health of components is checked for restarts
+static SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+// how long the replies to requests with idempotency keys are kept
+static IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(600);
+
+/// A request of a component, the channel for its reply, and the span it is
+/// handled in
+type RequestBundle = (
+    (ComponentRequest, Vec<u8>),
+    oneshot::Sender<proto::Reply>,
+    Span,
+);
 
-type RequestBundle = ((ComponentRequest, Vec<u8>), oneshot::Sender<proto::Reply>);
+/// The state channel of a component, for publishing
+type StateStream = (ComponentName, (ReceiverStream<Any>, Fault));
+
+/// Description of the failure of a component, if it has failed
+type Fault = Arc<Mutex<Option<String>>>;
+
+/// The last state message published by each component, by topic
+type Published = Arc<Mutex<HashMap<ComponentName, HashMap<String, proto::HeldMessage>>>>;
+
+/// The span of the last change requested of each component, which its state
+/// messages follow from
+type Traced = Arc<Mutex<HashMap<ComponentName, Span>>>;
 
 #[derive(Debug)]
 pub struct ComponentCollection {
-    components: HashMap<ComponentName, mpsc::Sender<RequestBundle>>,
+    components: HashMap<ComponentName, ComponentHandle>,
+    // components that can be shut down together, dependents before dependencies
+    shutdown_stages: Vec<Vec<ComponentName>>,
+    // for publishing the messages of components added at runtime
+    added_tx: mpsc::Sender<StateStream>,
+    held: Held,
+    replay: Replay,
+    published: Published,
+    traced: Traced,
+    publications: Publications,
+    leases: HashMap<ComponentName, Lease>,
+    // replies to requests with idempotency keys, by client and key, and when
+    // they were sent
+    handled: HashMap<(Vec<u8>, String), (Instant, proto::Reply)>,
     locked: bool,
     config_id: String,
+    // roles of the authenticated clients; without a security config, clients are
+    // not authenticated and may make any request
+    roles: Option<HashMap<String, Role>>,
+    // the boxes whose components are reached as `<box>/<component>`, if this
+    // controller is the hub of a federation
+    satellites: Option<Satellites>,
+}
+
+/// Exclusive write access to a component, held by one client until it expires
+#[derive(Debug)]
+struct Lease {
+    client: Vec<u8>,
+    ttl: Duration,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+struct ComponentHandle {
+    request_tx: mpsc::Sender<RequestBundle>,
+    // for publishing the last heartbeat after the component has stopped
+    status_tx: mpsc::Sender<Any>,
+    task: JoinHandle<()>,
+    // the config the component was started with
+    item: ComponentsConfigItem,
+    // whether the component initialized without error, once it has finished
+    ready: watch::Receiver<Option<bool>>,
 }
 
 #[derive(Deserialize, Debug)]
 struct ComponentsConfig(HashMap<ComponentName, ComponentsConfigItem>);
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 struct ComponentsConfigItem {
     driver: String,
     config: Value,
+    // components that must still be running while this one shuts down
+    #[serde(default)]
+    depends_on: Vec<ComponentName>,
+    #[serde(default)]
+    restart: RestartConfig,
+    // consumer that must acknowledge the state messages of the component
+    acknowledged_by: Option<String>,
+    // state messages kept for replay
+    #[serde(default = "ComponentsConfigItem::default_replay_buffer")]
+    replay_buffer: usize,
 }
 
-impl ComponentCollection {
-    #[instrument]
-    pub fn new() -> anyhow::Result<(Self, impl Stream<Item = Multipart>)> {
+impl ComponentsConfigItem {
+    fn default_replay_buffer() -> usize {
+        1000
+    }
+}
+
+impl ComponentsConfig {
+    /// Opens `components.yml` in the config directory
+    fn open() -> Result<File> {
         let config_file = ProjectDirs::from("org", "meliza", "decide")
             .ok_or(ControllerError::NoConfigDir)?
             .config_dir()
             .join("components.yml");
-        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
-            path: Some(config_file),
-            source: e,
-        })?;
-        Self::from_reader(reader)
+        File::open(&config_file).map_err(|e| {
+            ControllerError::ConfigReadError {
+                path: Some(config_file),
+                source: e,
+            }
+            .into()
+        })
     }
 
-    pub fn from_reader<T: Read>(
-        mut config_reader: T,
-    ) -> anyhow::Result<(Self, impl Stream<Item = Multipart>)> {
+    /// Reads the config, and gives it with its identifier
+    fn read<T: Read>(mut config_reader: T) -> Result<(Self, String)> {
         let mut file_buf: Vec<u8> = Vec::new();
         config_reader
             .read_to_end(&mut file_buf)
@@ -94,78 +274,344 @@ impl ComponentCollection {
         let components_config: ComponentsConfig =
             serde_yaml::from_slice(&file_buf[..]).map_err(ControllerError::from)?;
         let config_id = Sha3_256::new().chain(&file_buf).finalize();
-        let config_id = format!("{:x}", config_id);
-        let (components, state_stream): (_, HashMap<_, _>) = components_config
+        Ok((components_config, format!("{:x}", config_id)))
+    }
+}
+
+impl ComponentCollection {
+    #[instrument]
+    pub fn new() -> anyhow::Result<(Self, impl Stream<Item = Multipart>)> {
+        Self::from_reader(ComponentsConfig::open()?)
+    }
+
+    pub fn from_reader<T: Read>(
+        config_reader: T,
+    ) -> anyhow::Result<(Self, impl Stream<Item = Multipart>)> {
+        let (components_config, config_id) = ComponentsConfig::read(config_reader)?;
+        let dependencies = components_config
             .0
-            .into_iter()
-            .map(|(name, item)| {
-                let (request_tx, mut request_rx) = mpsc::channel::<RequestBundle>(100);
-                let (state_tx, state_rx) = mpsc::channel::<Any>(100);
-                let config = item.config.clone();
-                let mut component =
-                    ComponentKind::from_name(&item.driver[..], item.config, state_tx)
-                        .with_context(|| format!("failed to initialize {:?}", name))?;
-                let name_ = name.clone();
-                tokio::spawn(async move {
-                    component.init(config).await;
-                    debug!("initializing {:?}", name_);
-                    while let Some(((request_type, payload), reply_tx)) = request_rx.recv().await {
-                        let reply = execute(&mut component, request_type, payload).await;
-                        reply_tx
-                            .send(reply.into())
-                            .expect("controller dropped a oneshot receiver");
-                        if request_type == ComponentShutdown {
-                            break;
-                        }
-                    }
-                });
-                Ok(((name.clone(), request_tx), (name, state_rx.into())))
+            .iter()
+            .map(|(name, item)| (name.clone(), item.depends_on.clone()))
+            .collect();
+        let shutdown_stages = shutdown_stages(&dependencies)?;
+        let held: HashMap<_, _> = components_config
+            .0
+            .iter()
+            .filter_map(|(name, item)| {
+                let consumer = item.acknowledged_by.clone()?;
+                Some((name.clone(), HeldQueue::new(consumer)))
             })
-            .collect::<anyhow::Result<Vec<_>>>()?
-            .into_iter()
-            .unzip();
+            .collect();
+        let held = Arc::new(Mutex::new(held));
+        let replay: HashMap<_, _> = components_config
+            .0
+            .iter()
+            .map(|(name, item)| (name.clone(), ReplayBuffer::new(item.replay_buffer)))
+            .collect();
+        let replay = Arc::new(Mutex::new(replay));
+        let mut items = components_config.0;
+        let mut components: HashMap<ComponentName, ComponentHandle> = HashMap::new();
+        let mut state_stream = HashMap::new();
+        // dependencies are made before their dependents, which wait for them to
+        // finish initializing
+        for name in shutdown_stages.iter().rev().flatten() {
+            let item = items.remove(name).unwrap();
+            let (state_tx, state_rx) = mpsc::channel::<Any>(100);
+            let component =
+                ComponentKind::from_name(&item.driver[..], item.config.clone(), state_tx.clone())
+                    .with_context(|| format!("failed to initialize {:?}", name))?;
+            let dependencies = item
+                .depends_on
+                .iter()
+                .map(|dependency| (dependency.clone(), components[dependency].ready.clone()))
+                .collect();
+            let (handle, fault) =
+                spawn_component(name.clone(), item, component, state_tx, dependencies);
+            components.insert(name.clone(), handle);
+            state_stream.insert(name.clone(), (state_rx.into(), fault));
+        }
         debug!("components initialized");
-        let pub_stream = build_pub_stream(state_stream);
+        let (added_tx, added_rx) = mpsc::channel(100);
+        let published = Published::default();
+        let traced = Traced::default();
+        let (publications, _) = broadcast::channel(1000);
+        let pub_stream = build_pub_stream(
+            state_stream,
+            added_rx,
+            held.clone(),
+            replay.clone(),
+            published.clone(),
+            traced.clone(),
+            publications.clone(),
+        );
         Ok((
             ComponentCollection {
                 components,
+                shutdown_stages,
+                added_tx,
+                held,
+                replay,
+                published,
+                traced,
+                publications,
+                leases: HashMap::new(),
+                handled: HashMap::new(),
                 config_id,
                 locked: false,
+                roles: None,
+                satellites: None,
             },
             pub_stream,
         ))
     }
 
+    /// Gives the roles of the clients, which are then only permitted the
+    /// requests of their roles
+    pub fn set_roles(&mut self, roles: HashMap<String, Role>) {
+        self.roles = Some(roles);
+    }
+
+    /// Makes this controller the hub of a federation, through which the
+    /// components of the satellites are reached as `<box>/<component>`. Gives
+    /// the messages of the satellites, to be published with those of the
+    /// components.
+    pub fn federate(
+        &mut self,
+        config: FederationConfig,
+    ) -> anyhow::Result<impl Stream<Item = Multipart>> {
+        let (satellites, relayed) = federation::start(config, self.publications.clone())?;
+        self.satellites = Some(satellites);
+        Ok(relayed)
+    }
+
+    /// Waits for every component to finish initializing, giving the names of
+    /// those that failed
+    pub fn initialized(&self) -> impl std::future::Future<Output = Vec<ComponentName>> {
+        let components: Vec<_> = self
+            .components
+            .iter()
+            .map(|(name, handle)| (name.clone(), handle.ready.clone()))
+            .collect();
+        async move {
+            let mut failed = Vec::new();
+            for (name, mut ready) in components {
+                if !initialized(&mut ready).await {
+                    failed.push(name);
+                }
+            }
+            failed
+        }
+    }
+
+    /// Gives the subsystems of the controller access to the components. Their
+    /// requests arrive on the returned channel, to be passed to `handle_internal`.
+    pub fn handle(&self) -> (Handle, mpsc::Receiver<InternalRequest>) {
+        let (requests_tx, requests_rx) = mpsc::channel(100);
+        (
+            Handle::new(requests_tx, self.publications.clone(), self.added_tx.clone()),
+            requests_rx,
+        )
+    }
+
     pub async fn dispatch(&mut self, mut request: Multipart) -> Multipart {
         let client_id = request.pop_front().unwrap();
-        let empty_frame = request.pop_front().unwrap();
-        let reply = proto::Reply::from(self.handle_request(request).await);
-        let mut reply = Multipart::from(reply);
+        let mut empty_frame = request.pop_front().unwrap();
+        // name of the client, if it was authenticated
+        let user = empty_frame.gets("User-Id").map(String::from);
+        // replies are encoded for the version of the client, if it can be served
+        let version = match request.iter().next() {
+            Some(version) if SUPPORTED_VERSIONS.contains(&&version[..]) => version.to_vec(),
+            _ => DECIDE_VERSION.to_vec(),
+        };
+        let reply = match Request::try_from(request) {
+            Ok(request) => {
+                let span = info_span!(
+                    "request",
+                    request_type = ?request.request_type,
+                    component = ?request.component,
+                    correlation_id = %request.meta.correlation_id,
+                    user = ?user,
+                );
+                self.handle_once(request, &client_id, user.as_deref())
+                    .instrument(span)
+                    .await
+            }
+            Err(e) => proto::Reply::from(Err::<proto::Reply, _>(e)),
+        };
+        let mut reply = reply.into_multipart(&version);
         reply.push_front(empty_frame);
         reply.push_front(client_id);
         reply
     }
 
-    async fn handle_request(&mut self, request: Multipart) -> Result<proto::Reply> {
-        let request = Request::try_from(request)?;
-        info!("Received Request {:?} for {:?}", request.request_type, request.component);
+    /// Handles a request, unless the same client sent one with the same
+    /// idempotency key recently, in which case the reply to that one is sent
+    /// again. Keys are chosen by clients, so the same key from different
+    /// clients names different requests. The reply carries the correlation id
+    /// of the request.
+    async fn handle_once(
+        &mut self,
+        request: Request,
+        client: &[u8],
+        user: Option<&str>,
+    ) -> proto::Reply {
+        let proto::RequestMeta {
+            correlation_id,
+            idempotency_key,
+        } = request.meta.clone();
+        self.handled
+            .retain(|_, (handled, _)| handled.elapsed() < IDEMPOTENCY_WINDOW);
+        let key = (client.to_vec(), idempotency_key);
+        let reply = match self.handled.get(&key) {
+            Some((_, reply)) if !key.1.is_empty() => {
+                info!("Repeated Request {:?}, sending the first reply", key.1);
+                reply.clone()
+            }
+            _ => {
+                let reply = proto::Reply::from(self.handle_request(request, client, user).await);
+                if !key.1.is_empty() {
+                    self.handled.insert(key, (Instant::now(), reply.clone()));
+                }
+                reply
+            }
+        };
+        proto::Reply {
+            correlation_id,
+            ..reply
+        }
+    }
+
+    async fn handle_request(
+        &mut self,
+        request: Request,
+        client: &[u8],
+        user: Option<&str>,
+    ) -> Result<proto::Reply> {
+        info!(
+            "Received Request {:?} for {:?}",
+            request.request_type, request.component
+        );
+        self.authorize(request.request_type, user)?;
+        self.route(request, client).await
+    }
+
+    /// Handles a request from a subsystem of the controller, which is not
+    /// subject to the roles of clients
+    pub async fn handle_internal(&mut self, request: Request, client: &[u8]) -> proto::Reply {
+        debug!(
+            "Received internal Request {:?} for {:?}",
+            request.request_type, request.component
+        );
+        let span = info_span!(
+            "internal_request",
+            request_type = ?request.request_type,
+            component = ?request.component,
+            client = %String::from_utf8_lossy(client),
+        );
+        proto::Reply::from(self.route(request, client).instrument(span).await)
+    }
+
+    async fn route(&mut self, request: Request, client: &[u8]) -> Result<proto::Reply> {
         match request.request_type {
-            RequestType::General(req) => self.handle_general(req, request.body).await,
-            RequestType::Component(req) => self.handle_component(req, request).await,
+            RequestType::General(req) => self.handle_general(req, request.body, client).await,
+            RequestType::Component(req) => match &self.satellites {
+                Some(satellites)
+                    if request
+                        .component
+                        .as_ref()
+                        .is_some_and(|name| satellites.owns(name)) =>
+                {
+                    satellites.forward(request).await
+                }
+                _ => self.handle_component(req, request, client).await,
+            },
         }
     }
 
+    /// Checks that the role of a client permits a request. Authenticated clients
+    /// without a role are observers.
+    fn authorize(&self, request_type: RequestType, user: Option<&str>) -> Result<()> {
+        let roles = match &self.roles {
+            Some(roles) => roles,
+            None => return Ok(()),
+        };
+        let user = user.unwrap_or_default();
+        let role = roles.get(user).copied().unwrap_or(Role::Observer);
+        if role < Role::required(request_type) {
+            error!(
+                "{:?} is not permitted to make {:?} requests",
+                user, request_type
+            );
+            return Err(ClientError::Forbidden {
+                client: user.into(),
+                request: format!("{:?}", request_type),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
     async fn handle_general(
         &mut self,
         request_type: GeneralRequest,
         payload: Vec<u8>,
+        client: &[u8],
     ) -> Result<proto::Reply> {
         Ok(match request_type {
             RequestLock => {
                 self.request_lock(proto::Config::decode(&*payload).map_err(ClientError::from)?)?
             }
             ReleaseLock => self.release_lock()?,
-            Shutdown => self.shutdown().await?,
+            Shutdown => {
+                for name in self.components.keys() {
+                    self.check_lease(name, client)?;
+                }
+                self.shutdown().await?
+            }
+            DescribeComponents => self.describe_components().await?,
+            BatchChangeState => {
+                self.batch_change_state(
+                    proto::BatchStateChange::decode(&*payload).map_err(ClientError::from)?,
+                    client,
+                )
+                .await?
+            }
+            AcquireLease => self.acquire_lease(
+                proto::Lease::decode(&*payload).map_err(ClientError::from)?,
+                client,
+            )?,
+            RenewLease => self.renew_lease(client),
+            PauseAll => self.pause_all(Pause).await,
+            ResumeAll => self.pause_all(Resume).await,
+            Acknowledge => self.acknowledge(
+                proto::Acknowledgement::decode(&*payload).map_err(ClientError::from)?,
+            )?,
+            Snapshot => self.snapshot().await?,
+            Replay => {
+                self.replay(proto::ReplayRequest::decode(&*payload).map_err(ClientError::from)?)?
+            }
+            GetUnacknowledged => self.unacknowledged(
+                proto::Acknowledgement::decode(&*payload).map_err(ClientError::from)?,
+            ),
+            AddComponent => {
+                self.add_component(
+                    proto::ComponentSpec::decode(&*payload).map_err(ClientError::from)?,
+                )
+                .await?
+            }
+            RemoveComponent => {
+                self.remove_component(
+                    proto::ComponentSpec::decode(&*payload).map_err(ClientError::from)?,
+                    client,
+                )
+                .await?
+            }
+            Reload => self.reload().await?,
+            Hello => self.hello(proto::Hello::decode(&*payload).map_err(ClientError::from)?)?,
+            ReleaseLease => {
+                self.leases.retain(|_, lease| lease.client != client);
+                proto::reply::Result::Ok(())
+            }
         }
         .into())
     }
@@ -174,17 +620,43 @@ impl ComponentCollection {
         &mut self,
         request_type: ComponentRequest,
         mut request: Request,
+        client: &[u8],
     ) -> Result<proto::Reply> {
         let component_name = request.component.take().unwrap();
-        let component_tx = self
+        match request_type {
+            ChangeState | ResetState | SetParameters | ComponentShutdown | Reinitialize => {
+                self.check_lease(&component_name, client)?
+            }
+            // pausing is for safety, so it is open to every client
+            GetState | GetParameters | Describe | Pause | Resume => (),
+        }
+        self.request_component(&component_name, request_type, request.body)
+            .await
+    }
+
+    async fn request_component(
+        &self,
+        component_name: &ComponentName,
+        request_type: ComponentRequest,
+        body: Vec<u8>,
+    ) -> Result<proto::Reply> {
+        let component_tx = &self
             .components
-            .get_mut(&component_name)
-            .ok_or(ClientError::UnknownComponent(component_name))?;
+            .get(component_name)
+            .ok_or_else(|| ClientError::UnknownComponent(component_name.clone()))?
+            .request_tx;
+        if matches!(request_type, ChangeState | ResetState | SetParameters) {
+            let mut traced = self.traced.lock().unwrap();
+            traced.insert(component_name.clone(), Span::current());
+        }
         let (reply_tx, reply_rx) = oneshot::channel();
         component_tx
-            .send(((request_type, request.body), reply_tx))
+            .send(((request_type, body), reply_tx, Span::current()))
             .await
-            .expect("could not talk over mpsc");
+            .map_err(|_| ControllerError::ComponentFault {
+                component: component_name.clone(),
+                reason: String::from("component is no longer running"),
+            })?;
         Ok(reply_rx.await.map_err(ControllerError::from)?)
     }
 
@@ -203,27 +675,1014 @@ impl ComponentCollection {
         }
     }
 
+    /// Answers the greeting of a client with the versions and features of the
+    /// protocol the controller supports
+    fn hello(&self, hello: proto::Hello) -> Result<proto::reply::Result> {
+        info!(
+            "client greeted with version {} and features {:?}",
+            hello.version, hello.features
+        );
+        if !SUPPORTED_VERSIONS.contains(&hello.version.as_bytes()) {
+            return Err(ClientError::IncompatibleVersion(hello.version.into_bytes()).into());
+        }
+        let missing: Vec<_> = hello
+            .features
+            .iter()
+            .filter(|feature| !FEATURES.contains(&feature.as_str()))
+            .collect();
+        if !missing.is_empty() {
+            warn!(
+                "client expects features this controller lacks: {:?}",
+                missing
+            );
+        }
+        let version_string = |version: &[u8]| String::from_utf8_lossy(version).into_owned();
+        Ok(proto::reply::Result::Hello(proto::Hello {
+            version: version_string(DECIDE_VERSION),
+            features: FEATURES.iter().map(|&feature| feature.into()).collect(),
+            supported_versions: SUPPORTED_VERSIONS
+                .iter()
+                .map(|v| version_string(v))
+                .collect(),
+        }))
+    }
+
+    /// Refuses requests that would change a component leased to another client
+    fn check_lease(
+        &self,
+        component: &ComponentName,
+        client: &[u8],
+    ) -> core::result::Result<(), ClientError> {
+        match self.leases.get(component) {
+            Some(lease) if lease.client != client && lease.expires > Instant::now() => {
+                Err(ClientError::Leased(component.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Leases components to a client, or none of them if any is leased to
+    /// another client
+    fn acquire_lease(
+        &mut self,
+        lease: proto::Lease,
+        client: &[u8],
+    ) -> Result<proto::reply::Result> {
+        let ttl = match lease.ttl {
+            Some(ttl) => Duration::try_from(ttl).map_err(|_| ClientError::InvalidLeaseTtl)?,
+            None => LEASE_TTL,
+        };
+        let names: Vec<_> = if lease.components.is_empty() {
+            self.components.keys().cloned().collect()
+        } else {
+            lease.components.into_iter().map(ComponentName).collect()
+        };
+        for name in &names {
+            if !self.components.contains_key(name) {
+                return Err(ClientError::UnknownComponent(name.clone()).into());
+            }
+            self.check_lease(name, client)?;
+        }
+        let expires = Instant::now() + ttl;
+        for name in names {
+            info!("leased {:?} for {:?}", name, ttl);
+            let client = client.to_vec();
+            self.leases.insert(
+                name,
+                Lease {
+                    client,
+                    ttl,
+                    expires,
+                },
+            );
+        }
+        Ok(proto::reply::Result::Ok(()))
+    }
+
+    /// Extends the leases of a client by their durations
+    fn renew_lease(&mut self, client: &[u8]) -> proto::reply::Result {
+        let now = Instant::now();
+        for lease in self
+            .leases
+            .values_mut()
+            .filter(|lease| lease.client == client)
+        {
+            lease.expires = now + lease.ttl;
+        }
+        proto::reply::Result::Ok(())
+    }
+
+    /// Drops the held state messages of a component that its consumer has received
+    fn acknowledge(&self, ack: proto::Acknowledgement) -> Result<proto::reply::Result> {
+        let name = ComponentName(ack.component);
+        let mut held = self.held.lock().unwrap();
+        match held.get_mut(&name) {
+            Some(queue) if queue.consumer == ack.consumer => {
+                queue.acknowledge(ack.sequence);
+                Ok(proto::reply::Result::Ok(()))
+            }
+            _ => Err(ClientError::NotConsumer {
+                component: name,
+                consumer: ack.consumer,
+            }
+            .into()),
+        }
+    }
+
+    /// The state messages held for a consumer, from all its components, oldest first
+    fn unacknowledged(&self, ack: proto::Acknowledgement) -> proto::reply::Result {
+        let held = self.held.lock().unwrap();
+        let mut messages: Vec<_> = held
+            .values()
+            .filter(|queue| queue.consumer == ack.consumer)
+            .flat_map(|queue| queue.messages().cloned())
+            .collect();
+        messages.sort_by_key(|held| {
+            let monotonic = held.message.as_ref().and_then(|m| m.monotonic.clone());
+            monotonic.map(|d| (d.seconds, d.nanos))
+        });
+        proto::reply::Result::Held(proto::HeldMessages { messages })
+    }
+
+    /// The last state messages of every component, with the sequence numbers
+    /// clients resume the stream from. Components that have not published a state
+    /// message are asked for their current state.
+    async fn snapshot(&self) -> Result<proto::reply::Result> {
+        let mut names: Vec<_> = self.components.keys().cloned().collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        // states are got first, so that a message published meanwhile is used
+        // instead, and none are missed
+        let mut current = Vec::new();
+        for name in &names {
+            // a component that is faulted or cannot be reached contributes only its
+            // published messages, rather than failing the whole snapshot
+            match self
+                .request_component(name, GetState, Vec::new())
+                .await
+                .map(|reply| reply.result)
+            {
+                Ok(Some(proto::reply::Result::State(state))) => current.push(Some(state)),
+                _ => current.push(None),
+            }
+        }
+        let published = self.published.lock().unwrap();
+        let mut snapshot = proto::Snapshot::default();
+        for (name, state) in names.iter().zip(current) {
+            let mut messages: Vec<_> = published
+                .get(name)
+                .map(|messages| messages.values().cloned().collect())
+                .unwrap_or_default();
+            messages.sort_by(|a, b| a.topic.cmp(&b.topic));
+            let sequence = messages
+                .iter()
+                .filter_map(|held| held.message.as_ref())
+                .map(|message| message.sequence)
+                .max()
+                .unwrap_or_default();
+            if messages.is_empty() {
+                if let Some(state) = state {
+                    messages.push(proto::HeldMessage {
+                        topic: pub_topic("state", name, &state.type_url),
+                        message: Some(proto::Pub {
+                            time: Some(Timestamp::from(SystemTime::now())),
+                            state: Some(state),
+                            ..Default::default()
+                        }),
+                    });
+                }
+            }
+            snapshot.sequences.insert(name.0.clone(), sequence);
+            snapshot.states.extend(messages);
+        }
+        Ok(proto::reply::Result::Snapshot(snapshot))
+    }
+
+    /// The state messages kept for replay that were published since a sequence
+    /// number or time, oldest first
+    fn replay(&self, request: proto::ReplayRequest) -> Result<proto::reply::Result> {
+        let replay = self.replay.lock().unwrap();
+        let buffers: Vec<_> = if request.component.is_empty() {
+            replay.values().collect()
+        } else {
+            let name = ComponentName(request.component.clone());
+            match replay.get(&name) {
+                Some(buffer) => vec![buffer],
+                None => return Err(ClientError::UnknownComponent(name).into()),
+            }
+        };
+        let mut messages: Vec<_> = buffers
+            .iter()
+            .flat_map(|buffer| {
+                buffer
+                    .since(request.after_sequence, &request.since)
+                    .cloned()
+            })
+            .collect();
+        messages.sort_by_key(|held| {
+            let monotonic = held.message.as_ref().and_then(|m| m.monotonic.clone());
+            monotonic.map(|d| (d.seconds, d.nanos))
+        });
+        Ok(proto::reply::Result::Held(proto::HeldMessages { messages }))
+    }
+
     fn release_lock(&mut self) -> Result<proto::reply::Result> {
         self.locked = false;
         Ok(proto::reply::Result::Ok(()))
     }
 
-    async fn shutdown(&mut self) -> Result<proto::reply::Result> {
-        future::join_all(self.components.iter().map(|(name, component_tx)| {
-            let (reply_tx, _reply_rx) = oneshot::channel();
+    async fn describe_components(&mut self) -> Result<proto::reply::Result> {
+        let mut names: Vec<_> = self.components.keys().cloned().collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut components = Vec::new();
+        for name in names {
+            match self
+                .request_component(&name, Describe, Vec::new())
+                .await?
+                .result
+            {
+                Some(proto::reply::Result::Components(described)) => {
+                    components.extend(described.components)
+                }
+                Some(proto::reply::Result::Error(e)) => {
+                    return Err(ControllerError::ComponentFault {
+                        component: name,
+                        reason: e.message,
+                    }
+                    .into())
+                }
+                _ => unreachable!("components reply to describe requests with descriptions"),
+            }
+        }
+        if let Some(satellites) = &self.satellites {
+            components.extend(satellites.describe().await);
+        }
+        Ok(proto::reply::Result::Components(
+            proto::ComponentDescriptions { components },
+        ))
+    }
 
-            let name = name.clone();
-            timeout(
-                SHUTDOWN_TIMEOUT,
-                component_tx.send(((ComponentShutdown, Vec::new()), reply_tx)),
+    /// Changes the states of several components at once. The changes are checked
+    /// and the previous states saved first; if any change is then refused, the
+    /// ones already made are undone by restoring the previous states.
+    async fn batch_change_state(
+        &self,
+        batch: proto::BatchStateChange,
+        client: &[u8],
+    ) -> Result<proto::reply::Result> {
+        let names: Vec<_> = batch
+            .changes
+            .iter()
+            .map(|change| ComponentName(change.component.clone()))
+            .collect();
+        let mut results: Vec<_> = names
+            .iter()
+            .map(|name| proto::ComponentResult {
+                component: name.0.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        for ((result, name), change) in results.iter_mut().zip(&names).zip(&batch.changes) {
+            let refused = if expired(&batch.not_after) {
+                Some(ClientError::Expired)
+            } else if !self.components.contains_key(name) {
+                Some(ClientError::UnknownComponent(name.clone()))
+            } else if change.state.is_none() {
+                Some(ClientError::NoState)
+            } else if !seen.insert(name) {
+                Some(ClientError::RepeatedComponent(name.clone()))
+            } else {
+                self.check_lease(name, client).err()
+            };
+            result.error = refused.map(|e| DecideError::from(e).into());
+        }
+        let previous = if results.iter().all(|result| result.error.is_none()) {
+            future::join_all(
+                names
+                    .iter()
+                    .map(|name| self.request_component(name, GetState, Vec::new())),
             )
-            .map(|r| r.map_err(|_| ControllerError::ShutdownTimeout { component: name }))
+            .await
+        } else {
+            Vec::new()
+        };
+        let previous: Vec<_> = previous
+            .into_iter()
+            .zip(results.iter_mut())
+            .filter_map(|(reply, result)| match reply.map(|reply| reply.result) {
+                Ok(Some(proto::reply::Result::State(state))) => Some(state),
+                reply => {
+                    result.error = Some(reply_error(reply));
+                    None
+                }
+            })
+            .collect();
+        if previous.len() != names.len() {
+            return Ok(proto::reply::Result::Batch(proto::BatchResult {
+                applied: false,
+                results,
+            }));
+        }
+        // sent together, so that the components change as close to simultaneously
+        // as they can
+        let not_after = batch.not_after;
+        let replies = future::join_all(names.iter().zip(batch.changes).map(|(name, change)| {
+            let body = proto::StateChange {
+                state: change.state,
+                not_after: not_after.clone(),
+            };
+            self.request_component(name, ChangeState, body.encode_to_vec())
         }))
         .await;
+        let mut changed = Vec::new();
+        for (i, reply) in replies.into_iter().enumerate() {
+            match reply.map(|reply| reply.result) {
+                Ok(Some(proto::reply::Result::Ok(()))) => changed.push(i),
+                reply => results[i].error = Some(reply_error(reply)),
+            }
+        }
+        let applied = changed.len() == names.len();
+        if !applied {
+            warn!(
+                "a batch state change was refused; rolling back {} changes",
+                changed.len()
+            );
+            let replies = future::join_all(changed.iter().map(|&i| {
+                let body = proto::StateChange {
+                    state: Some(previous[i].clone()),
+                    not_after: None,
+                };
+                self.request_component(&names[i], ChangeState, body.encode_to_vec())
+            }))
+            .await;
+            for (&i, reply) in changed.iter().zip(replies) {
+                match reply.map(|reply| reply.result) {
+                    Ok(Some(proto::reply::Result::Ok(()))) => results[i].rolled_back = true,
+                    reply => {
+                        let mut e = reply_error(reply);
+                        e.message = format!("could not be rolled back: {}", e.message);
+                        results[i].error = Some(e);
+                    }
+                }
+            }
+        }
+        Ok(proto::reply::Result::Batch(proto::BatchResult {
+            applied,
+            results,
+        }))
+    }
+
+    /// Pauses or resumes every component. The reply says which components, if
+    /// any, failed to.
+    async fn pause_all(&self, request_type: ComponentRequest) -> proto::reply::Result {
+        let mut names: Vec<_> = self.components.keys().collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        let replies = future::join_all(
+            names
+                .iter()
+                .map(|name| self.request_component(name, request_type, Vec::new())),
+        )
+        .await;
+        let results: Vec<_> = names
+            .iter()
+            .zip(replies)
+            .map(|(name, reply)| proto::ComponentResult {
+                component: name.0.clone(),
+                error: match reply.map(|reply| reply.result) {
+                    Ok(Some(proto::reply::Result::Ok(()))) => None,
+                    reply => Some(reply_error(reply)),
+                },
+                rolled_back: false,
+            })
+            .collect();
+        let applied = results.iter().all(|result| result.error.is_none());
+        if !applied {
+            warn!("not every component could be {:?}d", request_type);
+        }
+        proto::reply::Result::Batch(proto::BatchResult { applied, results })
+    }
+
+    /// Shuts the components down in stages, so that none stops before the
+    /// components that depend on it. Components that do not finish shutting down
+    /// in time are aborted. Every component publishes an offline heartbeat as its
+    /// last message.
+    async fn shutdown(&mut self) -> Result<proto::reply::Result> {
+        for stage in &self.shutdown_stages {
+            future::join_all(
+                stage
+                    .iter()
+                    .map(|name| stop_component(name, &self.components[name])),
+            )
+            .await;
+        }
+        Ok(proto::reply::Result::Ok(()))
+    }
+
+    /// Changes the config identifier after a component is added or removed, so
+    /// that it no longer matches the config file
+    fn amend_config_id(&mut self, spec: &proto::ComponentSpec) {
+        let config_id = Sha3_256::new()
+            .chain(&self.config_id)
+            .chain(spec.encode_to_vec())
+            .finalize();
+        self.config_id = format!("{:x}", config_id);
+    }
+
+    /// The components each component depends on
+    fn dependencies(&self) -> HashMap<ComponentName, Vec<ComponentName>> {
+        self.components
+            .iter()
+            .map(|(name, handle)| (name.clone(), handle.item.depends_on.clone()))
+            .collect()
+    }
+
+    /// Starts a component while the controller is running
+    async fn add_component(&mut self, spec: proto::ComponentSpec) -> Result<proto::reply::Result> {
+        if self.locked {
+            return Err(ClientError::AlreadyLocked.into());
+        }
+        let name = ComponentName(spec.name.clone());
+        if self.components.contains_key(&name) {
+            return Err(ClientError::ComponentExists(name).into());
+        }
+        // checks that the driver exists
+        ComponentKind::description(&spec.driver)?;
+        let item = ComponentsConfigItem {
+            driver: spec.driver.clone(),
+            config: serde_yaml::from_str(&spec.config).map_err(ControllerError::from)?,
+            depends_on: spec
+                .depends_on
+                .iter()
+                .map(|d| ComponentName(d.clone()))
+                .collect(),
+            restart: if spec.restart.is_empty() {
+                RestartConfig::default()
+            } else {
+                serde_yaml::from_str(&spec.restart).map_err(ControllerError::from)?
+            },
+            acknowledged_by: Some(spec.acknowledged_by.clone()).filter(|c| !c.is_empty()),
+            replay_buffer: match spec.replay_buffer {
+                0 => ComponentsConfigItem::default_replay_buffer(),
+                n => n as usize,
+            },
+        };
+        let mut dependencies = self.dependencies();
+        dependencies.insert(name.clone(), item.depends_on.clone());
+        let shutdown_stages = shutdown_stages(&dependencies)?;
+        info!("adding {:?} with driver {}", name, item.driver);
+        self.start_component(name, item).await?;
+        self.shutdown_stages = shutdown_stages;
+        self.amend_config_id(&spec);
+        Ok(proto::reply::Result::Ok(()))
+    }
+
+    /// Makes the driver of a component on a blocking thread, so that a
+    /// constructor that fails on missing hardware does not bring down the
+    /// controller, and starts its task. The components it depends on must be
+    /// running.
+    async fn start_component(
+        &mut self,
+        name: ComponentName,
+        item: ComponentsConfigItem,
+    ) -> Result<()> {
+        let dependencies = item
+            .depends_on
+            .iter()
+            .map(|dependency| match self.components.get(dependency) {
+                Some(handle) => Ok((dependency.clone(), handle.ready.clone())),
+                None => Err(ControllerError::UnknownDependency {
+                    component: name.clone(),
+                    dependency: dependency.clone(),
+                }),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let (state_tx, state_rx) = mpsc::channel::<Any>(100);
+        let (driver, config, sender) = (item.driver.clone(), item.config.clone(), state_tx.clone());
+        let component =
+            tokio::task::spawn_blocking(move || ComponentKind::from_name(driver, config, sender))
+                .await
+                .map_err(|e| DecideError::Component { source: e.into() })?
+                .map_err(|e| {
+                    e.downcast::<DecideError>()
+                        .unwrap_or_else(|e| DecideError::Component { source: e })
+                })?;
+        if let Some(consumer) = &item.acknowledged_by {
+            // messages held from an earlier component of the same name are kept
+            let mut held = self.held.lock().unwrap();
+            let queue = held
+                .entry(name.clone())
+                .or_insert_with(|| HeldQueue::new(consumer.clone()));
+            queue.consumer = consumer.clone();
+        }
+        // as are the messages kept for replay
+        self.replay
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_insert_with(|| ReplayBuffer::new(item.replay_buffer))
+            .resize(item.replay_buffer);
+        let (handle, fault) =
+            spawn_component(name.clone(), item, component, state_tx, dependencies);
+        if self
+            .added_tx
+            .send((name.clone(), (state_rx.into(), fault)))
+            .await
+            .is_err()
+        {
+            warn!("messages from {:?} will not be published", name);
+        }
+        self.components.insert(name, handle);
+        Ok(())
+    }
+
+    /// Reads `components.yml` again and brings the components in line with it:
+    /// those no longer in it are shut down and removed, those whose config
+    /// changed are shut down and started again with the new config, and new
+    /// ones are added, while the others keep running. Nothing is changed unless
+    /// the whole config is valid; a component that then fails to start is
+    /// reported and left out.
+    pub async fn reload(&mut self) -> Result<proto::reply::Result> {
+        use proto::component_reload::Action;
+        if self.locked {
+            return Err(ClientError::AlreadyLocked.into());
+        }
+        let (ComponentsConfig(mut items), config_id) =
+            ComponentsConfig::read(ComponentsConfig::open()?)?;
+        let dependencies = items
+            .iter()
+            .map(|(name, item)| (name.clone(), item.depends_on.clone()))
+            .collect();
+        let stages = shutdown_stages(&dependencies)?;
+        for item in items.values() {
+            ComponentKind::description(&item.driver)?;
+        }
+        let mut results = Vec::new();
+        // dependents are shut down before their dependencies
+        for name in self.shutdown_stages.clone().iter().flatten() {
+            let action = match items.get(name) {
+                Some(item) if *item == self.components[name].item => {
+                    items.remove(name);
+                    Action::Unchanged
+                }
+                Some(_) => Action::Restarted,
+                None => Action::Removed,
+            };
+            if action != Action::Unchanged {
+                info!("reload: shutting down {:?}", name);
+                let handle = self.components.remove(name).unwrap();
+                stop_component(name, &handle).await;
+            }
+            if action == Action::Removed {
+                self.leases.remove(name);
+                self.published.lock().unwrap().remove(name);
+                self.traced.lock().unwrap().remove(name);
+            }
+            results.push((name.clone(), action, None));
+        }
+        // and started after them
+        for name in stages.iter().rev().flatten() {
+            let item = match items.remove(name) {
+                Some(item) => item,
+                None => continue,
+            };
+            info!("reload: starting {:?} with driver {}", name, item.driver);
+            let error = self.start_component(name.clone(), item).await.err();
+            if let Some(e) = &error {
+                error!("reload: could not start {:?}: {}", name, e);
+            }
+            match results.iter_mut().find(|(result, _, _)| result == name) {
+                Some(result) => result.2 = error,
+                None => results.push((name.clone(), Action::Added, error)),
+            }
+        }
+        self.shutdown_stages = shutdown_stages(&self.dependencies())?;
+        // the running components only match the file if they all started
+        let failed: Vec<_> = results
+            .iter()
+            .filter(|(_, _, error)| error.is_some())
+            .map(|(name, _, _)| name.0.as_str())
+            .collect();
+        self.config_id = if failed.is_empty() {
+            config_id
+        } else {
+            let config_id = Sha3_256::new().chain(config_id).chain(failed.join(","));
+            format!("{:x}", config_id.finalize())
+        };
+        results.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+        Ok(proto::reply::Result::Reload(proto::ReloadResult {
+            components: results
+                .into_iter()
+                .map(|(name, action, error)| proto::ComponentReload {
+                    component: name.0,
+                    action: action as i32,
+                    error: error.map(proto::Error::from),
+                })
+                .collect(),
+        }))
+    }
+
+    /// Shuts down a component and removes it from the controller. Components
+    /// that others depend on cannot be removed.
+    async fn remove_component(
+        &mut self,
+        spec: proto::ComponentSpec,
+        client: &[u8],
+    ) -> Result<proto::reply::Result> {
+        if self.locked {
+            return Err(ClientError::AlreadyLocked.into());
+        }
+        let name = ComponentName(spec.name.clone());
+        if !self.components.contains_key(&name) {
+            return Err(ClientError::UnknownComponent(name).into());
+        }
+        self.check_lease(&name, client)?;
+        let mut dependents: Vec<_> = self
+            .components
+            .iter()
+            .filter(|(_, handle)| handle.item.depends_on.contains(&name))
+            .map(|(dependent, _)| dependent.clone())
+            .collect();
+        if !dependents.is_empty() {
+            dependents.sort_by(|a, b| a.0.cmp(&b.0));
+            return Err(ClientError::HasDependents {
+                component: name,
+                dependents,
+            }
+            .into());
+        }
+        info!("removing {:?}", name);
+        let handle = self.components.remove(&name).unwrap();
+        stop_component(&name, &handle).await;
+        self.leases.remove(&name);
+        self.published.lock().unwrap().remove(&name);
+        self.traced.lock().unwrap().remove(&name);
+        self.shutdown_stages = shutdown_stages(&self.dependencies())?;
+        self.amend_config_id(&spec);
         Ok(proto::reply::Result::Ok(()))
     }
 }
 
+/// Asks a component to shut down, aborting its task if it does not in time, and
+/// publishes that it is offline
+async fn stop_component(name: &ComponentName, handle: &ComponentHandle) {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let stopped = timeout(SHUTDOWN_TIMEOUT, async {
+        handle
+            .request_tx
+            .send(((ComponentShutdown, Vec::new()), reply_tx, Span::current()))
+            .await
+            .ok()?;
+        reply_rx.await.ok()
+    })
+    .await;
+    let reason = match stopped {
+        Ok(Some(_)) => String::from("shut down"),
+        Ok(None) => String::from("stopped before shutdown"),
+        Err(_) => {
+            let e = ControllerError::ShutdownTimeout {
+                component: name.clone(),
+            };
+            error!("{}; aborting it", e);
+            handle.task.abort();
+            String::from("aborted after failing to shut down in time")
+        }
+    };
+    let offline = proto::Heartbeat::from(ComponentHealth::Offline(reason));
+    let offline = pack(HEARTBEAT_TYPE_URL, &offline);
+    if handle.status_tx.send(offline).await.is_err() {
+        warn!("could not publish that {:?} is offline", name);
+    }
+}
+
+/// Describes why a component did not act on a request
+fn reply_error(reply: Result<Option<proto::reply::Result>>) -> proto::Error {
+    match reply {
+        Ok(Some(proto::reply::Result::Error(e))) => e,
+        Ok(other) => proto::Error {
+            message: format!("unexpected reply {:?}", other),
+            ..Default::default()
+        },
+        Err(e) => e.into(),
+    }
+}
+
+/// Groups the components into stages for shutting down. Each stage holds the
+/// components that no component in a later stage depends on.
+fn shutdown_stages(
+    dependencies: &HashMap<ComponentName, Vec<ComponentName>>,
+) -> Result<Vec<Vec<ComponentName>>> {
+    for (name, depends_on) in dependencies {
+        if let Some(dependency) = depends_on.iter().find(|d| !dependencies.contains_key(d)) {
+            return Err(ControllerError::UnknownDependency {
+                component: name.clone(),
+                dependency: dependency.clone(),
+            }
+            .into());
+        }
+    }
+    let mut remaining: Vec<&ComponentName> = dependencies.keys().collect();
+    let mut stages = Vec::new();
+    while !remaining.is_empty() {
+        let (mut stage, rest): (Vec<_>, Vec<_>) = remaining.iter().partition(|&&name| {
+            !remaining
+                .iter()
+                .any(|other| dependencies[*other].contains(name))
+        });
+        if stage.is_empty() {
+            let cycle = rest.into_iter().cloned().collect();
+            return Err(ControllerError::DependencyCycle(cycle).into());
+        }
+        stage.sort_by(|a, b| a.0.cmp(&b.0));
+        stages.push(stage.into_iter().cloned().collect());
+        remaining = rest;
+    }
+    Ok(stages)
+}
+
+/// Starts the task that owns a component: it initializes the component, then
+/// acts on its requests and sends its heartbeats until it is shut down
+fn spawn_component(
+    name: ComponentName,
+    item: ComponentsConfigItem,
+    mut component: ComponentKind,
+    state_tx: mpsc::Sender<Any>,
+    dependencies: Vec<(ComponentName, watch::Receiver<Option<bool>>)>,
+) -> (ComponentHandle, Fault) {
+    let (request_tx, mut request_rx) = mpsc::channel::<RequestBundle>(100);
+    let (ready_tx, ready) = watch::channel(None);
+    let status_tx = state_tx.clone();
+    let fault: Fault = Default::default();
+    let config = item.config.clone();
+    let driver = item.driver.clone();
+    let fault_ = fault.clone();
+    let mut supervisor = Supervisor::new(item.restart.clone());
+    let task = tokio::spawn(async move {
+        let mut failed = Vec::new();
+        for (dependency, mut ready) in dependencies {
+            if !initialized(&mut ready).await {
+                failed.push(dependency);
+            }
+        }
+        debug!("initializing {:?}", name);
+        let result = if failed.is_empty() {
+            init(&mut component, &config).await
+        } else {
+            Err(anyhow::anyhow!(
+                "dependencies {:?} failed to initialize",
+                failed
+            ))
+        };
+        ready_tx.send_replace(Some(result.is_ok()));
+        if let Err(e) = result {
+            record_fault(&fault_, &status_tx, e);
+        }
+        // None after a reinitialization that failed to make a new instance
+        let mut component = Some(component);
+        // changes are refused while paused
+        let mut paused = false;
+        let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+        let mut supervise = interval(SUPERVISE_INTERVAL);
+        loop {
+            let ((request_type, payload), reply_tx, span) = tokio::select! {
+                request = request_rx.recv() => match request {
+                    Some(request) => request,
+                    None => break,
+                },
+                _ = heartbeat.tick() => {
+                    let health = health(&fault_, &component);
+                    let beat = pack(HEARTBEAT_TYPE_URL, &proto::Heartbeat::from(health));
+                    if status_tx.try_send(beat).is_err() {
+                        warn!("dropped a heartbeat from {:?}", name);
+                    }
+                    continue;
+                }
+                _ = supervise.tick() => {
+                    let reason = match health(&fault_, &component) {
+                        ComponentHealth::Failed(reason) => Some(reason),
+                        _ => None,
+                    };
+                    let restart = match supervisor.check(reason.is_some(), Instant::now()) {
+                        Supervision::Wait => continue,
+                        Supervision::Restart(attempt) => {
+                            warn!("restarting {:?}, attempt {}: {:?}", name, attempt, reason);
+                            let restarted =
+                                reinitialize(&name, &mut component, &driver, &config, &status_tx, &fault_).await;
+                            proto::Restart { attempt, succeeded: restarted.is_ok(), ..Default::default() }
+                        }
+                        Supervision::GiveUp(attempt) => {
+                            error!("gave up restarting {:?} after {} attempts", name, attempt);
+                            proto::Restart { attempt, gave_up: true, ..Default::default() }
+                        }
+                    };
+                    let restart = proto::Restart { reason: reason.unwrap_or_default(), ..restart };
+                    if status_tx.send(pack(RESTART_TYPE_URL, &restart)).await.is_err() {
+                        warn!("could not publish the restart of {:?}", name);
+                    }
+                    continue;
+                }
+            };
+            let reason = fault_.lock().unwrap().clone();
+            let reply = if request_type == Reinitialize {
+                reinitialize(&name, &mut component, &driver, &config, &status_tx, &fault_).await
+            } else if request_type == Describe {
+                describe(
+                    &name,
+                    &driver,
+                    &config,
+                    reason.is_some(),
+                    component.is_some(),
+                    paused,
+                )
+            } else if paused && matches!(request_type, ChangeState | ResetState | SetParameters) {
+                Err(ClientError::Paused.into())
+            } else {
+                match (reason, request_type, component.as_mut()) {
+                    (Some(reason), ChangeState | ResetState | SetParameters, _) => {
+                        Err(ControllerError::ComponentFault {
+                            component: name.clone(),
+                            reason,
+                        }
+                        .into())
+                    }
+                    (_, _, Some(component)) => {
+                        let span = info_span!(parent: &span, "execute", component = ?name);
+                        let reply = execute(component, request_type, payload).instrument(span);
+                        let reply = AssertUnwindSafe(reply).catch_unwind().await;
+                        reply.unwrap_or_else(|panic| {
+                            Err(ControllerError::ComponentFault {
+                                component: name.clone(),
+                                reason: record_fault(&fault_, &status_tx, panic_error(panic)),
+                            }
+                            .into())
+                        })
+                    }
+                    (_, ComponentShutdown, None) => Ok(proto::reply::Result::Ok(()).into()),
+                    (reason, _, None) => Err(ControllerError::ComponentFault {
+                        component: name.clone(),
+                        reason: reason.unwrap_or_default(),
+                    }
+                    .into()),
+                }
+            };
+            // a refused pause or resume leaves the component as it was
+            match request_type {
+                Pause if reply.is_ok() => paused = true,
+                Resume if reply.is_ok() => paused = false,
+                _ => (),
+            }
+            // the requester may have stopped waiting for the reply
+            let _ = reply_tx.send(proto::Reply::from(reply).for_component(&name));
+            if request_type == ComponentShutdown {
+                break;
+            }
+        }
+    });
+    let handle = ComponentHandle {
+        request_tx,
+        status_tx: state_tx,
+        task,
+        item,
+        ready,
+    };
+    (handle, fault)
+}
+
+/// The health of a component, as known to the task that owns it
+fn health(fault: &Fault, component: &Option<ComponentKind>) -> ComponentHealth {
+    match (fault.lock().unwrap().clone(), component) {
+        (Some(reason), _) => ComponentHealth::Failed(reason),
+        (None, Some(component)) => component.healthy(),
+        (None, None) => ComponentHealth::Failed(String::from("not running")),
+    }
+}
+
+/// Waits for a component to finish initializing, and returns whether it did so
+/// without error
+async fn initialized(ready: &mut watch::Receiver<Option<bool>>) -> bool {
+    loop {
+        if let Some(initialized) = *ready.borrow() {
+            return initialized;
+        }
+        if ready.changed().await.is_err() {
+            return false;
+        }
+    }
+}
+
+/// Initializes a component, turning a panic into an error
+async fn init(component: &mut ComponentKind, config: &Value) -> anyhow::Result<()> {
+    AssertUnwindSafe(component.init(config.clone()))
+        .catch_unwind()
+        .await
+        .map_err(panic_error)?
+        .map_err(anyhow::Error::from)
+}
+
+/// Whether the deadline of a request has passed
+fn expired(not_after: &Option<Timestamp>) -> bool {
+    let now = Timestamp::from(SystemTime::now());
+    match not_after {
+        Some(deadline) => (now.seconds, now.nanos) > (deadline.seconds, deadline.nanos),
+        None => false,
+    }
+}
+
+/// Describes a panic caught in a component, so that it can be recorded as a fault
+fn panic_error(panic: Box<dyn std::any::Any + Send>) -> anyhow::Error {
+    let message = match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map(|&m| m.into()).unwrap_or_default(),
+    };
+    anyhow::anyhow!("panicked: {}", message)
+}
+
+/// Records and publishes the failure of a component, and returns its description.
+/// The fault is recorded here as well, so that no request slips in before it has
+/// been published.
+fn record_fault(fault: &Fault, state_tx: &mpsc::Sender<Any>, error: impl Into<anyhow::Error>) -> String {
+    let error = error.into();
+    let reason = format!("{:#}", error);
+    *fault.lock().unwrap() = Some(reason.clone());
+    report_fault(state_tx, error);
+    reason
+}
+
+/// Replaces a component with a new instance made from its config. The old
+/// instance is shut down and dropped first, so that it releases its hardware
+/// before the new one acquires it. Clears any fault if the new instance starts.
+async fn reinitialize(
+    name: &ComponentName,
+    component: &mut Option<ComponentKind>,
+    driver: &str,
+    config: &Value,
+    state_tx: &mpsc::Sender<Any>,
+    fault: &Fault,
+) -> Result<proto::Reply> {
+    info!("reinitializing {:?}", name);
+    if let Some(mut old) = component.take() {
+        // the new instance is built even if the old one panics on the way out
+        if let Err(panic) = AssertUnwindSafe(old.shutdown()).catch_unwind().await {
+            error!(
+                "{:?} panicked while shutting down: {}",
+                name,
+                panic_error(panic)
+            );
+        }
+    }
+    let fresh = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        ComponentKind::from_name(driver, config.clone(), state_tx.clone())
+    }));
+    let result = match fresh.map_err(panic_error).and_then(|fresh| fresh) {
+        Ok(mut fresh) => {
+            let result = init(&mut fresh, config).await;
+            *component = Some(fresh);
+            result
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            *fault.lock().unwrap() = None;
+            Ok(proto::reply::Result::Ok(()).into())
+        }
+        Err(e) => Err(ControllerError::ComponentFault {
+            component: name.clone(),
+            reason: record_fault(fault, state_tx, e),
+        }
+        .into()),
+    }
+}
+
+/// Describes a component, listing the requests it will act on as things stand
+fn describe(
+    name: &ComponentName,
+    driver: &str,
+    config: &Value,
+    faulted: bool,
+    running: bool,
+    paused: bool,
+) -> Result<proto::Reply> {
+    let mut description = ComponentKind::description(driver)?;
+    description.name = name.0.clone();
+    description.driver = driver.into();
+    description.config = serde_yaml::to_string(config).unwrap_or_default();
+    description.requests = (0..=u8::MAX)
+        .filter_map(ComponentRequest::from_u8)
+        .filter(|request| match request {
+            ComponentShutdown | Reinitialize | Describe => true,
+            ChangeState | ResetState | SetParameters => running && !faulted && !paused,
+            GetState | GetParameters | Pause | Resume => running,
+        })
+        .map(|request| format!("{:?}", request))
+        .collect();
+    Ok(
+        proto::reply::Result::Components(proto::ComponentDescriptions {
+            components: vec![description],
+        })
+        .into(),
+    )
+}
+
 async fn execute(
     component: &mut ComponentKind,
     request_type: ComponentRequest,
@@ -232,6 +1691,9 @@ async fn execute(
     Ok(match request_type {
         ChangeState => {
             let state_change = proto::StateChange::decode(&*payload).map_err(ClientError::from)?;
+            if expired(&state_change.not_after) {
+                return Err(ClientError::Expired.into());
+            }
             component.decode_and_change_state(state_change.state.ok_or(ClientError::NoState)?)?;
             proto::reply::Result::Ok(())
         }
@@ -257,31 +1719,106 @@ async fn execute(
             component.shutdown().await;
             proto::reply::Result::Ok(())
         }
+        Pause => {
+            component.pause()?;
+            proto::reply::Result::Ok(())
+        }
+        Resume => {
+            component.resume()?;
+            proto::reply::Result::Ok(())
+        }
+        // handled by the component task, which can replace the component
+        Reinitialize | Describe => {
+            unreachable!(
+                "{:?} requests are not executed on a component",
+                request_type
+            )
+        }
     }
     .into())
 }
 
-fn build_pub_stream<I>(state_stream: I) -> impl Stream<Item = Multipart>
+fn build_pub_stream<I>(
+    state_stream: I,
+    added: mpsc::Receiver<StateStream>,
+    held: Held,
+    replay: Replay,
+    published: Published,
+    traced: Traced,
+    publications: Publications,
+) -> impl Stream<Item = Multipart>
 where
-    I: IntoIterator<Item = (ComponentName, ReceiverStream<Any>)>,
+    I: IntoIterator<Item = StateStream>,
 {
-    stream::select_all(
-        state_stream
-            .into_iter()
-            .map(|(name, state_rx)| state_rx.map(move |state| (name.clone(), state))),
-    )
-    .map(|(name, state)| {
-        let topic = String::from("state/") + &name.0;
-        let pub_message = proto::Pub {
-            state: Some(state),
-            time: Some(Timestamp {
-                seconds: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("time went backwards")
-                    .as_secs() as i64,
-                nanos: 0,
-            }),
-        };
-        Multipart::from(vec![topic.as_bytes(), &pub_message.encode_to_vec()[..]])
-    })
+    let registry = ComponentKind::registry();
+    let start = Instant::now();
+    let mut stampers: HashMap<ComponentName, PubStamper> = HashMap::new();
+    stream::iter(state_stream)
+        .chain(ReceiverStream::new(added))
+        .map(|(name, (state_rx, fault))| {
+            state_rx.map(move |state| (name.clone(), state, fault.clone()))
+        })
+        .flatten_unordered(None)
+        .map(move |(name, state, fault)| {
+            let stamper = stampers
+                .entry(name.clone())
+                .or_insert_with(|| PubStamper::new(start));
+            // heartbeats and faults have their own topics; faults also stop the
+            // component taking requests
+            let type_url = state.type_url.clone();
+            let (kind, pub_message) = if type_url == HEARTBEAT_TYPE_URL {
+                ("heartbeat", stamper.stamp(state, String::new()))
+            } else if type_url == FAULT_TYPE_URL {
+                let reason = unpack::<proto::Fault>(FAULT_TYPE_URL, &state)
+                    .map(|fault| fault.error)
+                    .unwrap_or_default();
+                error!("component {:?} faulted: {}", name, reason);
+                *fault.lock().unwrap() = Some(reason.clone());
+                ("error", stamper.stamp(state, reason))
+            } else {
+                match registry.decode(&state) {
+                    Ok(decoded) => trace!("{:?} published {:?}", name, decoded),
+                    Err(e) => warn!("{:?} published a state that does not decode: {}", name, e),
+                }
+                ("state", stamper.state(state))
+            };
+            let topic = pub_topic(kind, &name, &type_url);
+            // heartbeats and faults are not traced
+            let span = if kind == "state" {
+                let span = info_span!("publish", component = ?name, topic = %topic);
+                if let Some(request) = traced.lock().unwrap().get(&name) {
+                    span.follows_from(request);
+                }
+                span
+            } else {
+                Span::none()
+            };
+            let _entered = span.enter();
+            if kind == "state" {
+                if let Some(queue) = held.lock().unwrap().get_mut(&name) {
+                    queue.hold(&name, topic.clone(), pub_message.clone());
+                }
+                if let Some(buffer) = replay.lock().unwrap().get_mut(&name) {
+                    buffer.push(topic.clone(), pub_message.clone());
+                }
+                let latest = proto::HeldMessage {
+                    topic: topic.clone(),
+                    message: Some(pub_message.clone()),
+                };
+                let mut published = published.lock().unwrap();
+                published
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(topic.clone(), latest);
+            }
+            // nobody may be subscribed
+            let _ = publications.send((
+                name.clone(),
+                proto::HeldMessage {
+                    topic: topic.clone(),
+                    message: Some(pub_message.clone()),
+                },
+            ));
+            Multipart::from(vec![topic.as_bytes(), &pub_message.encode_to_vec()[..]])
+        })
 }
@@ -0,0 +1,334 @@
+//! Writes the state messages published by the components to files on the box,
+//! so that the data of an experiment are kept while no client is connected or
+//! the network is down, and can be collected later
+use super::fields::{describe_components, Event, Field, Messages};
+use super::handle::Handle;
+use super::hostname;
+use decide_protocol::{
+    error::ControllerError, proto, ComponentName, EXPERIMENT_TYPE_URL, HEARTBEAT_TYPE_URL,
+};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use time::{macros::format_description, OffsetDateTime};
+use tokio::sync::broadcast::error::RecvError;
+
+/// The name the logger makes its requests as
+pub const LOGGER: &str = "logger";
+
+// the file being written has this extension added until it is closed
+const PARTIAL: &str = "partial";
+
+/// Where the messages are logged, and the metadata added to each record, as
+/// given in `logger.yml`
+#[derive(Deserialize, Debug)]
+pub struct LoggerConfig {
+    // defaults to `log` in the data directory
+    dir: Option<PathBuf>,
+    // defaults to the hostname
+    #[serde(rename = "box")]
+    box_name: Option<String>,
+    #[serde(default)]
+    experiment: String,
+    #[serde(default)]
+    subject: String,
+    // a file is closed once it is larger than this (MB), or older (hours)
+    #[serde(default = "default_rotate_mb")]
+    rotate_mb: u64,
+    #[serde(default = "default_rotate_hours")]
+    rotate_hours: u64,
+    // also write each closed file as Parquet
+    #[serde(default)]
+    parquet: bool,
+}
+
+fn default_rotate_mb() -> u64 {
+    64
+}
+
+fn default_rotate_hours() -> u64 {
+    24
+}
+
+/// A message as logged, one per line
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    #[serde(rename = "box")]
+    box_name: String,
+    experiment: String,
+    subject: String,
+    // the trial of the experiment running on the box, if there is one
+    trial: u64,
+    #[serde(flatten)]
+    event: Event,
+}
+
+impl LoggerConfig {
+    /// Reads `logger.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("logger.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        let config: LoggerConfig =
+            serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?;
+        let invalid =
+            |reason: &str| Err(ControllerError::InvalidLoggerConfig(reason.into()).into());
+        if config.rotate_mb == 0 || config.rotate_hours == 0 {
+            return invalid("files must be rotated after more than 0 MB and 0 hours");
+        }
+        if config.parquet && !cfg!(feature = "parquet") {
+            return invalid("the controller was built without the `parquet` feature");
+        }
+        Ok(config)
+    }
+
+    /// The directory the files are written to
+    fn dir(&self) -> anyhow::Result<PathBuf> {
+        match &self.dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(ProjectDirs::from("org", "meliza", "decide")
+                .ok_or(ControllerError::NoConfigDir)?
+                .data_dir()
+                .join("log")),
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    opened: Instant,
+    written: u64,
+}
+
+struct Logger {
+    config: LoggerConfig,
+    dir: PathBuf,
+    box_name: String,
+    messages: HashMap<ComponentName, Messages>,
+    trial: u64,
+    file: Option<LogFile>,
+}
+
+/// Logs every message but the heartbeats until the controller stops publishing.
+/// Messages that cannot be written are logged as errors, and the logger goes on.
+pub async fn run(config: LoggerConfig, handle: Handle) -> anyhow::Result<()> {
+    let handle = handle.named(LOGGER);
+    let mut publications = handle.subscribe();
+    let messages = describe_components(&handle).await?;
+    let mut logger = Logger::new(config, messages)?;
+    loop {
+        match publications.recv().await {
+            Ok((name, message)) => {
+                if let Err(e) = logger.log(&name, &message) {
+                    error!("could not log a message of {:?}: {}", name, e);
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("the logger fell behind and missed {} messages", missed)
+            }
+            Err(RecvError::Closed) => return logger.close(),
+        }
+    }
+}
+
+impl Logger {
+    /// Creates the directory, and closes any files left open by an earlier run
+    fn new(
+        config: LoggerConfig,
+        messages: HashMap<ComponentName, Messages>,
+    ) -> anyhow::Result<Self> {
+        let dir = config.dir()?;
+        fs::create_dir_all(&dir)?;
+        let box_name = config.box_name.clone().unwrap_or_else(hostname);
+        let logger = Logger {
+            config,
+            dir,
+            box_name,
+            messages,
+            trial: 0,
+            file: None,
+        };
+        for entry in fs::read_dir(&logger.dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == PARTIAL)
+            {
+                logger.finish(&path)?;
+            }
+        }
+        info!("logging to {:?}", logger.dir);
+        Ok(logger)
+    }
+
+    fn log(&mut self, name: &ComponentName, message: &proto::HeldMessage) -> anyhow::Result<()> {
+        let event = Event::new(name, message, &self.messages)?;
+        if event.type_url == HEARTBEAT_TYPE_URL {
+            return Ok(());
+        }
+        if event.type_url == EXPERIMENT_TYPE_URL {
+            if let Some(Field::Int(trial)) =
+                event.state.as_ref().and_then(|state| state.get("trial"))
+            {
+                self.trial = *trial as u64;
+            }
+        }
+        let record = Record {
+            box_name: self.box_name.clone(),
+            experiment: self.config.experiment.clone(),
+            subject: self.config.subject.clone(),
+            trial: self.trial,
+            event,
+        };
+        self.write(&record)
+    }
+
+    /// Writes a record, rotating the file first if it is too large or old.
+    /// Each record is flushed, so that at most the last is lost if the box
+    /// loses power.
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        let rotate = self.file.as_ref().is_some_and(|file| {
+            file.written >= self.config.rotate_mb * 1_000_000
+                || file.opened.elapsed() >= Duration::from_secs(self.config.rotate_hours * 3600)
+        });
+        if rotate {
+            self.close()?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(self.open()?),
+        };
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.writer.write_all(&line)?;
+        file.writer.flush()?;
+        file.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn open(&self) -> anyhow::Result<LogFile> {
+        let opened = OffsetDateTime::now_utc().format(format_description!(
+            "[year][month][day]-[hour][minute][second]"
+        ))?;
+        let path = self
+            .dir
+            .join(format!("{}-{}.jsonl.{}", self.box_name, opened, PARTIAL));
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        debug!("opened log file {:?}", path);
+        Ok(LogFile {
+            path,
+            writer: BufWriter::new(file),
+            opened: Instant::now(),
+            written: 0,
+        })
+    }
+
+    /// Closes the current file, if there is one
+    fn close(&mut self) -> anyhow::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.writer.flush()?;
+            file.writer.get_ref().sync_all()?;
+            self.finish(&file.path)?;
+        }
+        Ok(())
+    }
+
+    /// Gives a closed file its final name, so that it can be collected, and
+    /// writes it as Parquet if asked
+    fn finish(&self, partial: &Path) -> anyhow::Result<()> {
+        let path = partial.with_extension("");
+        fs::rename(partial, &path)?;
+        info!("closed log file {:?}", path);
+        #[cfg(feature = "parquet")]
+        if self.config.parquet {
+            parquet_file::write(&path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_file {
+    use super::Record;
+    use arrow::array::{ArrayRef, StringArray, UInt64Array};
+    use arrow::datatypes::{Field as Column, Schema};
+    use arrow::record_batch::RecordBatch;
+    use parquet::arrow::ArrowWriter;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// Writes the records of a JSON-lines file to a Parquet file beside it.
+    /// The fields of the messages are kept as JSON in the `state` column.
+    pub fn write(jsonl: &Path) -> anyhow::Result<()> {
+        let mut records = Vec::new();
+        for line in BufReader::new(File::open(jsonl)?).lines() {
+            let line = line?;
+            // the last line of a file left open by a crash may be cut off
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!("skipping a record of {:?}: {}", jsonl, e),
+            }
+        }
+        let strings = |column: fn(&Record) -> &str| -> ArrayRef {
+            Arc::new(StringArray::from_iter_values(records.iter().map(column)))
+        };
+        let numbers = |column: fn(&Record) -> u64| -> ArrayRef {
+            Arc::new(UInt64Array::from_iter_values(records.iter().map(column)))
+        };
+        let states = records
+            .iter()
+            .map(|record| serde_json::to_string(&record.event.state))
+            .collect::<Result<Vec<_>, _>>()?;
+        let columns = vec![
+            ("time", strings(|record| &record.event.time)),
+            ("box", strings(|record| &record.box_name)),
+            ("experiment", strings(|record| &record.experiment)),
+            ("subject", strings(|record| &record.subject)),
+            ("trial", numbers(|record| record.trial)),
+            ("component", strings(|record| &record.event.component)),
+            ("topic", strings(|record| &record.event.topic)),
+            ("sequence", numbers(|record| record.event.sequence)),
+            ("label", strings(|record| &record.event.label)),
+            ("type", strings(|record| &record.event.type_url)),
+            ("state", Arc::new(StringArray::from(states)) as ArrayRef),
+        ];
+        let schema = Arc::new(Schema::new(
+            columns
+                .iter()
+                .map(|(name, column)| Column::new(*name, column.data_type().clone(), false))
+                .collect::<Vec<_>>(),
+        ));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            columns.into_iter().map(|(_, column)| column).collect(),
+        )?;
+        let path = jsonl.with_extension("parquet");
+        let mut writer = ArrowWriter::try_new(File::create(&path)?, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        debug!("wrote {:?}", path);
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+//! The log of the controller itself, as configured in the `logging` section of
+//! `decide.yml`: the level of each module, whether messages are written for
+//! people or as JSON, and the files on the box they are kept in, so that the log
+//! survives a reboot. This is not the record of the messages of the components,
+//! which is kept by the `logger`.
+use decide_protocol::error::ControllerError;
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use time::{macros::format_description, OffsetDateTime, UtcOffset};
+use tracing::Subscriber;
+use tracing_subscriber::{
+    filter::EnvFilter,
+    fmt::{self, time::FormatTime},
+    registry::LookupSpan,
+    Layer,
+};
+
+// the file being written; closed files are named for when they were closed
+const CURRENT: &str = "decide.log";
+
+/// How log messages are written
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Human,
+    Json,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoggingConfig {
+    // the level of every module not in `modules`
+    #[serde(default = "default_level")]
+    level: String,
+    // e.g. `decide_core::experiment: debug`
+    #[serde(default)]
+    modules: BTreeMap<String, String>,
+    // of the messages written to the terminal
+    #[serde(default = "default_terminal_format")]
+    format: Format,
+    // also write the log to files on the box
+    file: Option<FileConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FileConfig {
+    // defaults to `controller-log` in the data directory
+    dir: Option<PathBuf>,
+    #[serde(default = "default_file_format")]
+    format: Format,
+    // a file is closed once it is larger than this (MB), or older (hours)
+    #[serde(default = "default_rotate_mb")]
+    rotate_mb: u64,
+    #[serde(default = "default_rotate_hours")]
+    rotate_hours: u64,
+    // the number of closed files kept; older ones are removed
+    #[serde(default = "default_keep")]
+    keep: usize,
+}
+
+fn default_level() -> String {
+    String::from("info")
+}
+
+fn default_terminal_format() -> Format {
+    Format::Human
+}
+
+fn default_file_format() -> Format {
+    Format::Json
+}
+
+fn default_rotate_mb() -> u64 {
+    16
+}
+
+fn default_rotate_hours() -> u64 {
+    24
+}
+
+fn default_keep() -> usize {
+    10
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: default_level(),
+            modules: BTreeMap::new(),
+            format: default_terminal_format(),
+            file: None,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        self.levels()?;
+        if let Some(file) = &self.file {
+            if file.rotate_mb == 0 || file.rotate_hours == 0 {
+                return Err(ControllerError::InvalidControllerConfig(
+                    "log files must be rotated after more than 0 MB and 0 hours".into(),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn levels(&self) -> anyhow::Result<EnvFilter> {
+        let directives = std::iter::once(self.level.clone())
+            .chain(
+                self.modules
+                    .iter()
+                    .map(|(module, level)| format!("{}={}", module, level)),
+            )
+            .collect::<Vec<_>>()
+            .join(",");
+        EnvFilter::try_new(directives).map_err(|e| {
+            ControllerError::InvalidControllerConfig(format!("the log levels: {}", e)).into()
+        })
+    }
+
+    /// The levels of the modules, unless they are given in `DECIDE_LOG`
+    pub fn filter(&self) -> anyhow::Result<EnvFilter> {
+        match EnvFilter::try_from_env("DECIDE_LOG") {
+            Ok(filter) => Ok(filter),
+            Err(_) => self.levels(),
+        }
+    }
+
+    /// The layers that write the log to the terminal and, if asked, to files
+    pub fn layers<S>(&self) -> anyhow::Result<Vec<Box<dyn Layer<S> + Send + Sync>>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let terminal = match self.format {
+            Format::Human => fmt::layer()
+                .pretty()
+                .with_thread_names(true)
+                .with_timer(local_time())
+                .boxed(),
+            Format::Json => fmt::layer().json().with_thread_names(true).boxed(),
+        };
+        let mut layers = vec![terminal];
+        if let Some(config) = &self.file {
+            let writer = Mutex::new(RotatingFile::open(config)?);
+            layers.push(match config.format {
+                Format::Human => fmt::layer()
+                    .with_ansi(false)
+                    .with_thread_names(true)
+                    .with_timer(local_time())
+                    .with_writer(writer)
+                    .boxed(),
+                Format::Json => fmt::layer()
+                    .json()
+                    .with_thread_names(true)
+                    .with_writer(writer)
+                    .boxed(),
+            });
+        }
+        Ok(layers)
+    }
+}
+
+impl FileConfig {
+    fn dir(&self) -> anyhow::Result<PathBuf> {
+        match &self.dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(ProjectDirs::from("org", "meliza", "decide")
+                .ok_or(ControllerError::NoConfigDir)?
+                .data_dir()
+                .join("controller-log")),
+        }
+    }
+}
+
+/// The local time, for people to read
+fn local_time() -> impl FormatTime + Send + Sync + 'static {
+    let offset = UtcOffset::current_local_offset()
+        .unwrap_or_else(|_| UtcOffset::from_hms(-5, 0, 0).unwrap());
+    fmt::time::OffsetTime::new(
+        offset,
+        format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+    )
+}
+
+/// A log file that is closed and replaced once it is too large or old
+struct RotatingFile {
+    dir: PathBuf,
+    file: File,
+    opened: Instant,
+    written: u64,
+    max_bytes: u64,
+    max_age: Duration,
+    keep: usize,
+}
+
+impl RotatingFile {
+    /// Creates the directory, and closes the file left open by an earlier run
+    fn open(config: &FileConfig) -> anyhow::Result<Self> {
+        let dir = config.dir()?;
+        fs::create_dir_all(&dir)?;
+        if dir.join(CURRENT).exists() {
+            close(&dir)?;
+        }
+        prune(&dir, config.keep)?;
+        Ok(RotatingFile {
+            file: create(&dir)?,
+            dir,
+            opened: Instant::now(),
+            written: 0,
+            max_bytes: config.rotate_mb * 1_000_000,
+            max_age: Duration::from_secs(config.rotate_hours * 3600),
+            keep: config.keep,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        close(&self.dir)?;
+        self.file = create(&self.dir)?;
+        self.opened = Instant::now();
+        self.written = 0;
+        prune(&self.dir, self.keep)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written >= self.max_bytes || self.opened.elapsed() >= self.max_age {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn create(dir: &Path) -> io::Result<File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(CURRENT))
+}
+
+/// Gives the current file the name of the time it was closed, which sorts
+fn close(dir: &Path) -> io::Result<()> {
+    let now = OffsetDateTime::now_utc();
+    let closed = format!(
+        "decide-{:04}{:02}{:02}-{:02}{:02}{:02}.log",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    fs::rename(dir.join(CURRENT), dir.join(closed))
+}
+
+/// Removes all but the newest closed files
+fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut closed = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("decide-") && name.ends_with(".log") {
+            closed.push(name);
+        }
+    }
+    closed.sort();
+    let removed = closed.len().saturating_sub(keep);
+    for name in &closed[..removed] {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(())
+}
@@ -1,31 +1,91 @@
 use anyhow::Context;
-use decide_core::{run, ComponentCollection};
-use tracing_subscriber::filter::EnvFilter;
-use time;
+use decide_core::{
+    check,
+    config::ControllerConfig,
+    experiment::{self, ExperimentConfig},
+    federation::FederationConfig,
+    logger::{self, LoggerConfig},
+    run, schedule::{self, ScheduleConfig}, security::SecurityConfig, ComponentCollection,
+};
+use futures::{stream, FutureExt, StreamExt};
+use tracing_subscriber::prelude::*;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> anyhow::Result<()> {
-    let timer_fmt = time::format_description::parse(
-        "[year]-[month padding:zero]-[day padding:zero] [hour]:[minute]:[second]",
-    ).expect(" Setting Timer Format ");
-    let timer_offset = time::UtcOffset::current_local_offset()
-        .unwrap_or_else(|_| time::UtcOffset::from_hms(-5, 0, 0).unwrap());
-    let timer =
-        tracing_subscriber::fmt::time::OffsetTime::new(timer_offset, timer_fmt);
-    let filter = EnvFilter::try_from_env("DECIDE_LOG")
-        .or_else(|_| EnvFilter::try_new("info"))
-        .unwrap();
-    tracing_subscriber::fmt()
-        .pretty()
-        .with_thread_names(true)
-        .with_env_filter(filter)
-        .with_timer(timer)
-        // enable everything
-        // sets this to be the default, global collector for this application.
-        .init();
+    if std::env::args().any(|arg| arg == "--check") {
+        let report = check::check();
+        print!("{}", report);
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
+    let config = ControllerConfig::new().context("could not read controller config")?;
+    let subscriber = tracing_subscriber::registry()
+        .with(config.logging.filter()?)
+        .with(config.logging.layers().context("could not open the log file")?);
+    #[cfg(feature = "otel")]
+    let subscriber = {
+        use decide_core::telemetry::{self, TelemetryConfig};
+        let config = TelemetryConfig::new().context("could not read telemetry config")?;
+        let layer = config.map(|config| telemetry::layer(&config)).transpose();
+        subscriber.with(layer.context("could not start exporting spans")?)
+    };
+    // sets this to be the default, global collector for this application.
+    subscriber.init();
 
-    let (components, state_stream) =
+    let (mut components, state_stream) =
         ComponentCollection::new().context("could not initialize controller")?;
-    let res = run::launch_decide(components, state_stream)?;
-    res.await
+    let state_stream = match FederationConfig::new().context("could not read federation config")? {
+        Some(federation) => {
+            let relayed = components
+                .federate(federation)
+                .context("could not connect to the satellites")?;
+            stream::select(state_stream, relayed).boxed()
+        }
+        None => state_stream.boxed(),
+    };
+    let security = SecurityConfig::new().context("could not read security config")?;
+    let mut subsystems: Vec<run::Subsystem> = Vec::new();
+    if let Some(logger) = LoggerConfig::new().context("could not read logger config")? {
+        subsystems.push(Box::new(|handle| logger::run(logger, handle).boxed()));
+    }
+    if let Some(experiment) =
+        ExperimentConfig::new().context("could not read experiment definition")?
+    {
+        subsystems.push(Box::new(|handle| experiment::run(experiment, handle).boxed()));
+    }
+    if let Some(schedule) = ScheduleConfig::new().context("could not read schedule")? {
+        subsystems.push(Box::new(|handle| schedule::run(schedule, handle).boxed()));
+    }
+    #[cfg(feature = "scripting")]
+    {
+        use decide_core::script::{self, Script};
+        if let Some(script) = Script::new().context("could not read experiment script")? {
+            subsystems.push(Box::new(|handle| script::run(script, handle).boxed()));
+        }
+    }
+    #[cfg(feature = "http")]
+    {
+        use decide_core::gateway::{self, GatewayConfig};
+        if let Some(gateway) = GatewayConfig::new().context("could not read gateway config")? {
+            subsystems.push(Box::new(|handle| gateway::run(gateway, handle).boxed()));
+        }
+    }
+    #[cfg(feature = "mqtt")]
+    {
+        use decide_core::mqtt::{self, MqttConfig};
+        if let Some(mqtt) = MqttConfig::new().context("could not read MQTT config")? {
+            subsystems.push(Box::new(|handle| mqtt::run(mqtt, handle).boxed()));
+        }
+    }
+    #[cfg(feature = "grpc")]
+    {
+        use decide_core::grpc::{self, GrpcConfig};
+        if let Some(grpc) = GrpcConfig::new().context("could not read gRPC config")? {
+            subsystems.push(Box::new(|handle| grpc::run(grpc, handle).boxed()));
+        }
+    }
+    let res = run::launch_decide(components, state_stream, &config, security, subsystems)?;
+    let res = res.await;
+    #[cfg(feature = "otel")]
+    decide_core::telemetry::shutdown();
+    res
 }
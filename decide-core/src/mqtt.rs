@@ -0,0 +1,192 @@
+//! Republishes the messages of the components to an MQTT broker, and takes
+//! changes to their state and parameters from it, for labs that run Home
+//! Assistant, Node-RED or the like
+//!
+//! Each message is published as JSON under `<prefix>/<topic>`, e.g.
+//! `decide/box-3/state/peck-keys/PeckKeyState`; state messages are retained, so
+//! that subscribers get the latest at once. If commands are enabled, the fields
+//! published to `<prefix>/control/<component>/state` or `.../params` are set,
+//! and the outcome is published to the same topic with `/result` added.
+use super::fields::{describe_components, Event, Field, Messages};
+use super::handle::Handle;
+use super::hostname;
+use decide_protocol::{error::ControllerError, proto, ComponentName};
+use directories::ProjectDirs;
+use rumqttc::{AsyncClient, Event as MqttEvent, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{fs::File, io::Read};
+use tokio::sync::broadcast::error::RecvError;
+
+/// The name commands are made as
+pub const MQTT: &str = "mqtt";
+
+// how long to wait before reconnecting to the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The broker, and what is published to it, as given in `mqtt.yml`
+#[derive(Deserialize, Debug)]
+pub struct MqttConfig {
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    // defaults to the hostname, which is also used in the default prefix
+    client_id: Option<String>,
+    // the root of the topic tree; defaults to `decide/<client_id>`
+    prefix: Option<String>,
+    // take changes of state and parameters from the broker
+    #[serde(default)]
+    commands: bool,
+    // do not republish heartbeats
+    #[serde(default = "default_true")]
+    skip_heartbeats: bool,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl MqttConfig {
+    /// Reads `mqtt.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("mqtt.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?)
+    }
+}
+
+/// Bridges the controller and the broker until the controller stops publishing.
+/// The connection to the broker is retried for as long as it is down; messages
+/// published meanwhile are not sent.
+pub async fn run(config: MqttConfig, handle: Handle) -> anyhow::Result<()> {
+    let handle = handle.named(MQTT);
+    let mut publications = handle.subscribe();
+    let mut messages = describe_components(&handle).await?;
+    let client_id = config.client_id.clone().unwrap_or_else(hostname);
+    let prefix = config
+        .prefix
+        .clone()
+        .unwrap_or_else(|| format!("decide/{}", client_id));
+    let control = format!("{}/control/", prefix);
+    let mut options = MqttOptions::new(client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    info!("bridging to MQTT broker {}:{}", config.host, config.port);
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => match event {
+                Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                    info!("connected to MQTT broker");
+                    if config.commands {
+                        let commands = format!("{}+/+", control);
+                        if let Err(e) = client.try_subscribe(commands, QoS::AtLeastOnce) {
+                            error!("could not subscribe to MQTT commands: {}", e);
+                        }
+                    }
+                }
+                Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                    if let Some(command) = publish.topic.strip_prefix(&control) {
+                        let result = execute(&handle, &mut messages, command, &publish.payload);
+                        let result = command_result(result.await);
+                        let topic = format!("{}/result", publish.topic);
+                        if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, false, result) {
+                            warn!("could not publish the result of an MQTT command: {}", e);
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("MQTT connection failed: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            },
+            publication = publications.recv() => match publication {
+                Ok((name, message)) => {
+                    if config.skip_heartbeats && message.topic.starts_with("heartbeat/") {
+                        continue;
+                    }
+                    let payload = match Event::new(&name, &message, &messages)
+                        .and_then(|event| Ok(serde_json::to_vec(&event)?))
+                    {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            warn!("could not republish a message of {:?}: {}", name, e);
+                            continue;
+                        }
+                    };
+                    let topic = format!("{}/{}", prefix, message.topic);
+                    let retain = message.topic.starts_with("state/");
+                    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+                        warn!("could not republish a message of {:?}: {}", name, e);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("the MQTT bridge fell behind and missed {} messages", missed)
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Sets the state or parameters of a component, as in `<component>/state`, to
+/// the fields in a JSON payload. The components are described again if the
+/// component was added since they were last described.
+async fn execute(
+    handle: &Handle,
+    messages: &mut HashMap<ComponentName, Messages>,
+    command: &str,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let (name, kind) = command
+        .split_once('/')
+        .ok_or_else(|| anyhow::anyhow!("unknown command {:?}", command))?;
+    let name = &ComponentName(name.into());
+    if !messages.contains_key(name) {
+        *messages = describe_components(handle).await?;
+    }
+    let messages = messages.get(name).ok_or_else(|| {
+        anyhow::anyhow!("{:?} is not a component with described messages", name.0)
+    })?;
+    let fields: HashMap<String, Field> = serde_json::from_slice(payload)?;
+    let request = match kind {
+        "state" => messages.change_state(name, &fields)?,
+        "params" => messages.set_parameters(name, &fields)?,
+        _ => anyhow::bail!("commands set `state` or `params`, not {:?}", kind),
+    };
+    match handle.request(request).await?.result {
+        Some(proto::reply::Result::Error(e)) => anyhow::bail!(e.message),
+        _ => Ok(()),
+    }
+}
+
+fn command_result(result: anyhow::Result<()>) -> Vec<u8> {
+    let result = match result {
+        Ok(()) => serde_json::json!({ "ok": true }),
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    };
+    result.to_string().into_bytes()
+}
@@ -0,0 +1,62 @@
+use decide_protocol::{proto, ComponentName};
+use prost_types::Timestamp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// State messages kept after they are published, so that clients that lost
+/// some can have them sent again
+pub type Replay = Arc<Mutex<HashMap<ComponentName, ReplayBuffer>>>;
+
+/// The last state messages of a component, oldest first
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    messages: VecDeque<proto::HeldMessage>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ReplayBuffer {
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Changes the number of messages kept, dropping the oldest if there are
+    /// now too many
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.truncate();
+    }
+
+    pub fn push(&mut self, topic: String, message: proto::Pub) {
+        self.messages.push_back(proto::HeldMessage {
+            topic,
+            message: Some(message),
+        });
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.messages.len() > self.capacity {
+            self.messages.pop_front();
+        }
+    }
+
+    /// The messages with a greater sequence number, published at or after a time
+    pub fn since<'a>(
+        &'a self,
+        after_sequence: u64,
+        since: &'a Option<Timestamp>,
+    ) -> impl Iterator<Item = &'a proto::HeldMessage> {
+        self.messages.iter().filter(move |held| {
+            held.message.as_ref().is_some_and(|message| {
+                let time = message.time.as_ref().map(|t| (t.seconds, t.nanos));
+                message.sequence > after_sequence
+                    && since
+                        .as_ref()
+                        .is_none_or(|t| time >= Some((t.seconds, t.nanos)))
+            })
+        })
+    }
+}
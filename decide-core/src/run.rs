@@ -1,40 +1,86 @@
-use super::ComponentCollection;
-use decide_protocol::{PUB_ENDPOINT, REQ_ENDPOINT};
+use super::handle::{Handle, InternalRequest};
+use super::security::{self, SecurityConfig};
+use super::{config::ControllerConfig, systemd, ComponentCollection};
 use futures::{
-    future::{self, Future, FutureExt},
+    future::{self, BoxFuture, Future, FutureExt},
     SinkExt, Stream, StreamExt,
 };
-use tmq::{Context, Multipart};
-use tokio::sync::oneshot;
+use tmq::{publish::Publish, router::Router, Context, Multipart};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::interval;
+
+/// A task run alongside the sockets with access to the components, such as the
+/// experiment engine. The controller stops when one returns.
+pub type Subsystem = Box<dyn FnOnce(Handle) -> BoxFuture<'static, anyhow::Result<()>> + Send>;
 
 pub fn launch_decide<S>(
-    components: ComponentCollection,
+    mut components: ComponentCollection,
     state_stream: S,
-) -> Result<impl Future<Output = anyhow::Result<()>>, oneshot::error::RecvError>
+    config: &ControllerConfig,
+    security: Option<SecurityConfig>,
+    subsystems: Vec<Subsystem>,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<()>>>
 where
     S: Stream<Item = Multipart> + Unpin + Send + 'static,
 {
+    // the sockets share a context with the authenticator
+    let context = Context::new();
+    if let Some(security) = &security {
+        security.authenticate(&context)?;
+        components.set_roles(security.roles());
+    }
+    let publish_sock = security::bind(
+        &context,
+        zmq::PUB,
+        &config.pub_endpoint(),
+        security.as_ref(),
+    )?;
+    let router_sock = security::bind(
+        &context,
+        zmq::ROUTER,
+        &config.req_endpoint(),
+        security.as_ref(),
+    )?;
+    let (handle, internal_rx) = components.handle();
+    let initialized = components.initialized();
+    tokio::spawn(async move {
+        let failed = initialized.await;
+        if failed.is_empty() {
+            systemd::ready("components initialized");
+        } else {
+            systemd::ready(&format!("components {:?} failed to initialize", failed));
+        }
+    });
     let (tx_pub, rx_pub) = oneshot::channel();
     tokio::spawn(async move {
         tx_pub
-            .send(process_pubs(state_stream).await)
+            .send(process_pubs(publish_sock, state_stream).await)
             .expect("failed to send result");
     });
     let (tx_req, rx_req) = oneshot::channel();
     tokio::spawn(async move {
         tx_req
-            .send(process_requests(components).await)
+            .send(process_requests(router_sock, internal_rx, components).await)
             .expect("failed to send result");
     });
+    let mut results = vec![rx_pub, rx_req];
+    for subsystem in subsystems {
+        let (tx_sub, rx_sub) = oneshot::channel();
+        let task = subsystem(handle.clone());
+        tokio::spawn(async move {
+            tx_sub.send(task.await).expect("failed to send result");
+        });
+        results.push(rx_sub);
+    }
     // collect errors using oneshot receivers
-    Ok(future::select_all(vec![rx_pub, rx_req]).map(|(res, _, _)| res?))
+    Ok(future::select_all(results).map(|(res, _, _)| res?))
 }
 
-async fn process_pubs<S>(mut state_stream: S) -> anyhow::Result<()>
+async fn process_pubs<S>(mut publish_sock: Publish, mut state_stream: S) -> anyhow::Result<()>
 where
     S: Stream<Item = Multipart> + Unpin + Send + 'static,
 {
-    let mut publish_sock = tmq::publish(&Context::new()).bind(PUB_ENDPOINT)?;
     while let Some(state_update) = state_stream.next().await {
         trace!(
             "sending pub message {:?} on topic {:?}",
@@ -47,11 +93,47 @@ where
     Ok(())
 }
 
-async fn process_requests(mut components: ComponentCollection) -> anyhow::Result<()> {
-    let mut router_sock = tmq::router(&Context::new()).bind(REQ_ENDPOINT)?;
-    while let Some(request) = router_sock.next().await {
-        let reply = components.dispatch(request?).await;
-        router_sock.send(reply).await?;
+/// Processes requests until the sockets close, or until systemd or another
+/// process asks the controller to stop, when the components are shut down.
+/// `SIGHUP` reloads the config of the components, as the reload request does.
+/// While requests are processed, systemd's watchdog is kept from restarting the
+/// controller.
+async fn process_requests(
+    mut router_sock: Router,
+    mut internal_rx: mpsc::Receiver<InternalRequest>,
+    mut components: ComponentCollection,
+) -> anyhow::Result<()> {
+    let mut watchdog = systemd::watchdog_interval().map(interval);
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            request = router_sock.next() => match request {
+                Some(request) => {
+                    let reply = components.dispatch(request?).await;
+                    router_sock.send(reply).await?;
+                }
+                None => return Ok(()),
+            },
+            Some((client, request, reply_tx)) = internal_rx.recv() => {
+                // the subsystem may have stopped waiting
+                let _ = reply_tx.send(components.handle_internal(request, &client).await);
+            }
+            _ = async { watchdog.as_mut().unwrap().tick().await }, if watchdog.is_some() => {
+                systemd::watchdog()
+            }
+            _ = hangup.recv() => {
+                info!("reloading the components on SIGHUP");
+                if let Err(e) = components.reload().await {
+                    error!("could not reload the components: {}", e);
+                }
+            }
+            _ = terminate.recv() => {
+                info!("shutting down the components on SIGTERM");
+                systemd::stopping();
+                components.shutdown().await?;
+                return Ok(());
+            }
+        }
     }
-    Ok(())
 }
@@ -0,0 +1,422 @@
+//! Changes the state and parameters of components at times of day, or at times
+//! relative to sunrise and sunset, as given in `schedule.yml`: lights on and
+//! off, free-feeding periods, the start and end of sessions. The time each
+//! event last fired is kept in the data directory, so that an event is not
+//! fired twice for the same time across a restart. When the controller starts,
+//! the last time of each event that catches up is fired again, in order, so
+//! that the components are put back in the state the schedule had them in.
+use super::experiment::Action;
+use super::fields::describe_components;
+use super::handle::Handle;
+use decide_protocol::{
+    error::ControllerError, pack, proto, ComponentName, Request, SCHEDULE_TYPE_URL,
+};
+use directories::ProjectDirs;
+use prost_types::{Any, Timestamp};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::{fs::File, io::Read};
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+/// The name the scheduler publishes its state under, and makes its requests as
+pub const SCHEDULE: &str = "schedule";
+
+// how often the clock is checked, so that events fire on time even if the clock
+// of the box is set while the controller runs
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+// how late an event that does not catch up may still fire
+const LATE: Duration = Duration::minutes(1);
+
+/// The events of the schedule, as given in `schedule.yml`
+#[derive(Deserialize, Debug)]
+pub struct ScheduleConfig {
+    // hours from UTC of the times of day, which do not follow daylight saving
+    // time, e.g. -5
+    utc_offset: f64,
+    // of the box, in degrees, for events relative to sunrise and sunset
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    events: Vec<EventConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventConfig {
+    name: String,
+    at: At,
+    // made in order when the event fires
+    actions: Vec<Action>,
+    // fire the event when the controller starts, and if it was missed while the
+    // clock of the box was wrong; otherwise it fires only on time
+    #[serde(default = "default_catch_up")]
+    catch_up: bool,
+}
+
+fn default_catch_up() -> bool {
+    true
+}
+
+/// When an event fires each day: a time of day, e.g. `07:30`, or a time
+/// relative to sunrise or sunset, e.g. `sunset`, `sunrise+00:30` or `sunset-1:00`
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "String")]
+enum At {
+    Clock(Time),
+    Sunrise(Duration),
+    Sunset(Duration),
+}
+
+impl TryFrom<String> for At {
+    type Error = String;
+
+    fn try_from(at: String) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "{:?} is not a time of day, or a time relative to sunrise or sunset",
+                at
+            )
+        };
+        let relative = |rest: &str| {
+            if rest.is_empty() {
+                return Some(Duration::ZERO);
+            }
+            let (sign, time) = match (rest.strip_prefix('+'), rest.strip_prefix('-')) {
+                (Some(time), _) => (1, time),
+                (_, Some(time)) => (-1, time),
+                _ => return None,
+            };
+            let (h, m, s) = hms(time)?;
+            Some(Duration::seconds(sign * (h * 3600 + m * 60 + s)))
+        };
+        if let Some(rest) = at.strip_prefix("sunrise") {
+            return relative(rest).map(At::Sunrise).ok_or_else(invalid);
+        }
+        if let Some(rest) = at.strip_prefix("sunset") {
+            return relative(rest).map(At::Sunset).ok_or_else(invalid);
+        }
+        hms(&at)
+            .and_then(|(h, m, s)| Time::from_hms(h as u8, m as u8, s as u8).ok())
+            .map(At::Clock)
+            .ok_or_else(invalid)
+    }
+}
+
+/// Hours, minutes and optional seconds, e.g. `7:30` or `07:30:15`
+fn hms(time: &str) -> Option<(i64, i64, i64)> {
+    let fields = time
+        .split(':')
+        .map(|field| field.parse::<i64>().ok().filter(|n| (0..100).contains(n)))
+        .collect::<Option<Vec<_>>>()?;
+    match fields[..] {
+        [h, m] if m < 60 => Some((h, m, 0)),
+        [h, m, s] if m < 60 && s < 60 => Some((h, m, s)),
+        _ => None,
+    }
+}
+
+impl ScheduleConfig {
+    /// Reads `schedule.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("schedule.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        let config: ScheduleConfig =
+            serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?;
+        let invalid = |reason: String| Err(ControllerError::InvalidSchedule(reason).into());
+        if config.offset().is_none() {
+            return invalid(format!("{} is not an offset from UTC", config.utc_offset));
+        }
+        let mut names = HashSet::new();
+        for event in &config.events {
+            if !names.insert(&event.name) {
+                return invalid(format!("there are two events named {:?}", event.name));
+            }
+            if matches!(event.at, At::Sunrise(_) | At::Sunset(_)) && config.location().is_none() {
+                return invalid(format!(
+                    "{:?} is relative to the sun, which needs the latitude and longitude",
+                    event.name
+                ));
+            }
+            if event.actions.is_empty() {
+                return invalid(format!("{:?} has no actions", event.name));
+            }
+            if let Some(action) = event.actions.iter().find(|action| action.sets_nothing()) {
+                return invalid(format!(
+                    "an action of {:?} on {:?} sets neither state nor params",
+                    event.name, action.component
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    fn offset(&self) -> Option<UtcOffset> {
+        UtcOffset::from_whole_seconds((self.utc_offset * 3600.0).round() as i32).ok()
+    }
+
+    fn location(&self) -> Option<(f64, f64)> {
+        Some((self.latitude?, self.longitude?))
+    }
+
+    /// The time an event fires on a day, if it does on that day: the sun does
+    /// not rise or set on some days near the poles
+    fn time(&self, at: At, date: Date) -> Option<OffsetDateTime> {
+        let offset = self.offset()?;
+        match at {
+            At::Clock(time) => Some(PrimitiveDateTime::new(date, time).assume_offset(offset)),
+            At::Sunrise(after) => Some(sun_times(date, self.location()?)?.0 + after),
+            At::Sunset(after) => Some(sun_times(date, self.location()?)?.1 + after),
+        }
+    }
+
+    /// The times an event fires on the days around `now`
+    fn times(&self, at: At, now: OffsetDateTime) -> Vec<OffsetDateTime> {
+        let today = now
+            .to_offset(self.offset().unwrap_or(UtcOffset::UTC))
+            .date();
+        (-2..=2)
+            .filter_map(|days| self.time(at, today + Duration::days(days)))
+            .collect()
+    }
+
+    /// The last time an event fired, up to a day before `now`
+    fn last(&self, at: At, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        self.times(at, now)
+            .into_iter()
+            .filter(|time| *time <= now && *time > now - Duration::DAY)
+            .max()
+    }
+
+    /// The next time an event fires
+    fn next(&self, at: At, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        self.times(at, now)
+            .into_iter()
+            .filter(|time| *time > now)
+            .min()
+    }
+}
+
+/// The times of sunrise and sunset on a day, from the sunrise equation, or none
+/// if the sun does not rise or set
+fn sun_times(
+    date: Date,
+    (latitude, longitude): (f64, f64),
+) -> Option<(OffsetDateTime, OffsetDateTime)> {
+    const J2000: f64 = 2451545.0;
+    let noon = f64::from(date.to_julian_day()) - J2000 - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = J2000 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * 23.4397f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if hour_angle.abs() > 1.0 {
+        return None;
+    }
+    let half_day = hour_angle.acos().to_degrees() / 360.0;
+    let time = |julian: f64| {
+        OffsetDateTime::from_unix_timestamp(((julian - 2440587.5) * 86400.0).round() as i64).ok()
+    };
+    Some((time(transit - half_day)?, time(transit + half_day)?))
+}
+
+/// The file the times the events last fired are kept in
+fn fired_file() -> anyhow::Result<PathBuf> {
+    Ok(ProjectDirs::from("org", "meliza", "decide")
+        .ok_or(ControllerError::NoConfigDir)?
+        .data_dir()
+        .join("schedule.json"))
+}
+
+struct Scheduler {
+    config: ScheduleConfig,
+    // the requests made when each event fires
+    actions: HashMap<String, Vec<Request>>,
+    // the time each event last fired for
+    fired: HashMap<String, OffsetDateTime>,
+    handle: Handle,
+    state_tx: mpsc::Sender<Any>,
+}
+
+/// Runs the schedule until the controller stops. The messages of the components
+/// it uses are checked against the schedule first.
+pub async fn run(config: ScheduleConfig, handle: Handle) -> anyhow::Result<()> {
+    let handle = handle.named(SCHEDULE);
+    let messages = describe_components(&handle).await?;
+    let mut actions = HashMap::new();
+    for event in &config.events {
+        let mut requests = Vec::new();
+        for action in &event.actions {
+            let messages = messages.get(&action.component).ok_or_else(|| {
+                ControllerError::InvalidSchedule(format!(
+                    "{:?} is not a component with described messages",
+                    action.component
+                ))
+            })?;
+            requests.extend(action.requests(messages).map_err(|e| {
+                ControllerError::InvalidSchedule(format!("in an action of {:?}: {}", event.name, e))
+            })?);
+        }
+        actions.insert(event.name.clone(), requests);
+    }
+    let fired = match std::fs::read(fired_file()?) {
+        Ok(contents) => serde_json::from_slice::<HashMap<String, i64>>(&contents)?
+            .into_iter()
+            .filter_map(|(name, time)| {
+                Some((name, OffsetDateTime::from_unix_timestamp(time).ok()?))
+            })
+            .collect(),
+        Err(_) => HashMap::new(),
+    };
+    let state_tx = handle.publish_as(ComponentName(SCHEDULE.into())).await?;
+    let mut scheduler = Scheduler {
+        config,
+        actions,
+        fired,
+        handle,
+        state_tx,
+    };
+    scheduler.check(true).await;
+    loop {
+        let now = OffsetDateTime::now_utc();
+        let until_next = scheduler
+            .config
+            .events
+            .iter()
+            .filter_map(|event| scheduler.config.next(event.at, now))
+            .min()
+            .and_then(|next| std::time::Duration::try_from(next - now).ok())
+            .unwrap_or(CHECK_INTERVAL);
+        sleep(until_next.min(CHECK_INTERVAL)).await;
+        scheduler.check(false).await;
+    }
+}
+
+impl Scheduler {
+    /// Fires the events that are due, in the order of their times. When the
+    /// controller starts, the events that catch up fire for their last time
+    /// even if they already have.
+    async fn check(&mut self, starting: bool) {
+        let now = OffsetDateTime::now_utc();
+        let mut due: Vec<(OffsetDateTime, String)> = Vec::new();
+        for event in &self.config.events {
+            let time = match self.config.last(event.at, now) {
+                Some(time) => time,
+                None => continue,
+            };
+            let fired = self
+                .fired
+                .get(&event.name)
+                .is_some_and(|fired| *fired >= time);
+            let on_time = !fired && now - time <= LATE;
+            if on_time || (event.catch_up && (starting || !fired)) {
+                due.push((time, event.name.clone()));
+            } else if !fired {
+                warn!("schedule missed {:?} at {}", event.name, time);
+                self.fired.insert(event.name.clone(), time);
+            }
+        }
+        due.sort();
+        for (time, name) in &due {
+            self.fire(name, *time).await;
+        }
+        if starting || !due.is_empty() {
+            let last = due.last().map(|(_, name)| name.clone()).unwrap_or_default();
+            self.publish(last, now).await;
+        }
+    }
+
+    /// Makes the requests of an event, and keeps the time it fired for.
+    /// Requests that fail are logged, and the schedule goes on.
+    async fn fire(&mut self, name: &str, time: OffsetDateTime) {
+        info!("schedule firing {:?} for {}", name, time);
+        for request in &self.actions[name] {
+            match self.handle.request(request.clone()).await {
+                Ok(proto::Reply {
+                    result: Some(proto::reply::Result::Error(e)),
+                    ..
+                }) => error!(
+                    "schedule {:?} request for {:?} failed: {}",
+                    request.request_type, request.component, e.message
+                ),
+                Err(e) => error!("schedule could not make request: {}", e),
+                Ok(_) => (),
+            }
+        }
+        self.fired.insert(name.into(), time);
+        let fired: HashMap<&String, i64> = self
+            .fired
+            .iter()
+            .map(|(name, time)| (name, time.unix_timestamp()))
+            .collect();
+        let saved = fired_file().and_then(|path| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            Ok(std::fs::write(path, serde_json::to_vec(&fired)?)?)
+        });
+        if let Err(e) = saved {
+            warn!("the times the schedule fired could not be saved: {}", e);
+        }
+    }
+
+    /// Publishes the next time of each event, soonest first
+    async fn publish(&self, last: String, now: OffsetDateTime) {
+        let timestamp = |time: OffsetDateTime| Timestamp {
+            seconds: time.unix_timestamp(),
+            nanos: 0,
+        };
+        let mut upcoming: Vec<_> = self
+            .config
+            .events
+            .iter()
+            .filter_map(|event| {
+                let time = self.config.next(event.at, now)?;
+                Some((time, &event.name))
+            })
+            .collect();
+        upcoming.sort();
+        let message = proto::ScheduleState {
+            last,
+            upcoming: upcoming
+                .into_iter()
+                .map(|(time, name)| proto::ScheduledEvent {
+                    name: name.clone(),
+                    time: Some(timestamp(time)),
+                    fired: self.fired.get(name).map(|fired| timestamp(*fired)),
+                })
+                .collect(),
+        };
+        if self
+            .state_tx
+            .send(pack(SCHEDULE_TYPE_URL, &message))
+            .await
+            .is_err()
+        {
+            warn!("the state of the schedule could not be published");
+        }
+    }
+}
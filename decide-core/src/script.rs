@@ -0,0 +1,321 @@
+//! Runs an experiment written as a Rhai script in `experiment.rhai`, for
+//! paradigms that outgrow the states of `experiment.yml`. The script can only
+//! reach the components through the functions registered here:
+//!
+//! - `subscribe(component)`: pass the state messages of the component to the
+//!   script's `on_state(component, state)` function
+//! - `set_state(component, #{field: value})` and `set_params(component, ...)`:
+//!   change the state or parameters of a component; other fields take their
+//!   default values
+//! - `after(ms, name)` and `cancel(name)`: call the script's `on_timer(name)`
+//!   function after a time, unless cancelled first
+//! - `log(text)`, or `print(text)`: write to the log of the controller
+//!
+//! The top-level statements, and then the script's `on_start()` function, run
+//! once when the controller starts. Functions share `this`, a map kept between
+//! calls.
+use super::fields::{describe_components, Field, Messages};
+use super::handle::Handle;
+use decide_protocol::{error::ControllerError, proto, ComponentName, Request};
+use directories::ProjectDirs;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST, FLOAT, INT};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// The name the script makes its requests as
+pub const SCRIPT: &str = "script";
+
+// limits on one call of the script, so that a runaway script cannot stall the
+// controller
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+
+/// The source of an experiment script
+#[derive(Debug)]
+pub struct Script {
+    source: String,
+}
+
+impl Script {
+    /// Reads `experiment.rhai` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let script_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("experiment.rhai");
+        if !script_file.exists() {
+            return Ok(None);
+        }
+        let source = std::fs::read_to_string(&script_file).map_err(|e| {
+            ControllerError::ConfigReadError {
+                path: Some(script_file),
+                source: e,
+            }
+        })?;
+        Ok(Some(Script { source }))
+    }
+
+    pub fn from_source(source: String) -> Self {
+        Script { source }
+    }
+}
+
+/// What the script asked for in a call, carried out in order once it returns
+enum Call {
+    Request(Request),
+    After(u64, String),
+    Cancel(String),
+}
+
+#[derive(Default)]
+struct Calls {
+    calls: Vec<Call>,
+    subscribed: HashSet<ComponentName>,
+}
+
+type Shared = Arc<Mutex<Calls>>;
+
+struct Runtime {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    this: Dynamic,
+    calls: Shared,
+    messages: Arc<HashMap<ComponentName, Messages>>,
+    // when each timer is due, in the order they were set
+    timers: BinaryHeap<Reverse<(Instant, u64, String)>>,
+    timers_set: u64,
+    handle: Handle,
+}
+
+/// Runs the script until the controller stops publishing. A script that does
+/// not compile, or whose top-level statements fail, stops the controller; errors
+/// in its functions are logged.
+pub async fn run(script: Script, handle: Handle) -> anyhow::Result<()> {
+    let handle = handle.named(SCRIPT);
+    let mut publications = handle.subscribe();
+    let messages = Arc::new(describe_components(&handle).await?);
+    let calls = Shared::default();
+    let engine = engine(messages.clone(), calls.clone());
+    let ast = engine
+        .compile(&script.source)
+        .map_err(|e| ControllerError::InvalidExperiment(format!("the script: {}", e)))?;
+    let mut runtime = Runtime {
+        engine,
+        ast,
+        scope: Scope::new(),
+        this: Dynamic::from_map(Map::new()),
+        calls,
+        messages,
+        timers: BinaryHeap::new(),
+        timers_set: 0,
+        handle,
+    };
+    runtime
+        .engine
+        .run_ast_with_scope(&mut runtime.scope, &runtime.ast)
+        .map_err(|e| ControllerError::InvalidExperiment(format!("the script failed: {}", e)))?;
+    runtime.call("on_start", ());
+    runtime.carry_out().await;
+    loop {
+        let due = runtime.timers.peek().map(|Reverse((due, _, _))| *due);
+        tokio::select! {
+            publication = publications.recv() => match publication {
+                Ok((name, message)) => runtime.published(&name, &message).await,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("the script fell behind and missed {} messages", missed)
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                runtime.fire().await
+            }
+        }
+    }
+}
+
+impl Runtime {
+    /// Calls a function of the script, if it defines one
+    fn call(&mut self, function: &str, args: impl FuncArgs) {
+        if !self.ast.iter_functions().any(|f| f.name == function) {
+            return;
+        }
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.this);
+        if let Err(e) = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut self.scope,
+            &self.ast,
+            function,
+            args,
+        ) {
+            error!("script function {} failed: {}", function, e);
+        }
+    }
+
+    async fn published(&mut self, name: &ComponentName, message: &proto::HeldMessage) {
+        if !message.topic.starts_with("state/")
+            || !self.calls.lock().unwrap().subscribed.contains(name)
+        {
+            return;
+        }
+        let state = match message.message.as_ref().and_then(|m| m.state.as_ref()) {
+            Some(state) => state,
+            None => return,
+        };
+        let values = match self.messages.get(name).and_then(|m| m.decode_state(state)) {
+            Some(Ok(values)) => values,
+            Some(Err(e)) => {
+                warn!("the script could not decode the state of {:?}: {}", name, e);
+                return;
+            }
+            None => return,
+        };
+        let state: Map = values
+            .into_iter()
+            .map(|(field, value)| (field.into(), dynamic(value)))
+            .collect();
+        self.call("on_state", (name.0.clone(), state));
+        self.carry_out().await;
+    }
+
+    async fn fire(&mut self) {
+        if let Some(Reverse((_, _, name))) = self.timers.pop() {
+            self.call("on_timer", (name,));
+            self.carry_out().await;
+        }
+    }
+
+    /// Carries out what the script asked for. Requests that fail are logged.
+    async fn carry_out(&mut self) {
+        let calls = std::mem::take(&mut self.calls.lock().unwrap().calls);
+        for call in calls {
+            match call {
+                Call::After(after, name) => {
+                    self.timers_set += 1;
+                    let due = Instant::now() + Duration::from_millis(after);
+                    self.timers.push(Reverse((due, self.timers_set, name)));
+                }
+                Call::Cancel(name) => self.timers.retain(|Reverse((_, _, timer))| *timer != name),
+                Call::Request(request) => match self.handle.request(request.clone()).await {
+                    Ok(proto::Reply {
+                        result: Some(proto::reply::Result::Error(e)),
+                        ..
+                    }) => error!(
+                        "script {:?} request for {:?} failed: {}",
+                        request.request_type, request.component, e.message
+                    ),
+                    Err(e) => error!("script could not make request: {}", e),
+                    Ok(_) => (),
+                },
+            }
+        }
+    }
+}
+
+/// An engine with the functions of the API, limited so that the script cannot
+/// run away
+fn engine(messages: Arc<HashMap<ComponentName, Messages>>, calls: Shared) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(MAX_CALL_LEVELS);
+    engine.disable_symbol("eval");
+    engine.on_print(|text| info!("script: {}", text));
+    engine.on_debug(|text, _, position| debug!("script at {}: {}", position, text));
+    engine.register_fn("log", |text: &str| info!("script: {}", text));
+
+    let (known, shared) = (messages.clone(), calls.clone());
+    engine.register_fn(
+        "subscribe",
+        move |component: &str| -> Result<(), Box<EvalAltResult>> {
+            let component = ComponentName(component.into());
+            if !known.contains_key(&component) {
+                return Err(format!("{:?} is not a component", component.0).into());
+            }
+            shared.lock().unwrap().subscribed.insert(component);
+            Ok(())
+        },
+    );
+    let (known, shared) = (messages.clone(), calls.clone());
+    engine.register_fn(
+        "set_state",
+        move |component: &str, state: Map| -> Result<(), Box<EvalAltResult>> {
+            let component = ComponentName(component.into());
+            let request = known
+                .get(&component)
+                .ok_or_else(|| format!("{:?} is not a component", component.0))?
+                .change_state(&component, &fields(state)?)
+                .map_err(|e| e.to_string())?;
+            shared.lock().unwrap().calls.push(Call::Request(request));
+            Ok(())
+        },
+    );
+    let (known, shared) = (messages, calls.clone());
+    engine.register_fn(
+        "set_params",
+        move |component: &str, params: Map| -> Result<(), Box<EvalAltResult>> {
+            let component = ComponentName(component.into());
+            let request = known
+                .get(&component)
+                .ok_or_else(|| format!("{:?} is not a component", component.0))?
+                .set_parameters(&component, &fields(params)?)
+                .map_err(|e| e.to_string())?;
+            shared.lock().unwrap().calls.push(Call::Request(request));
+            Ok(())
+        },
+    );
+    let shared = calls.clone();
+    engine.register_fn(
+        "after",
+        move |ms: INT, name: &str| -> Result<(), Box<EvalAltResult>> {
+            if ms < 0 {
+                return Err("a timer cannot be set in the past".into());
+            }
+            let call = Call::After(ms as u64, name.into());
+            shared.lock().unwrap().calls.push(call);
+            Ok(())
+        },
+    );
+    engine.register_fn("cancel", move |name: &str| {
+        calls.lock().unwrap().calls.push(Call::Cancel(name.into()));
+    });
+    engine
+}
+
+/// The fields of a map from the script
+fn fields(map: Map) -> Result<HashMap<String, Field>, Box<EvalAltResult>> {
+    map.into_iter()
+        .map(|(name, value)| {
+            let field = if value.is::<bool>() {
+                Field::Bool(value.cast())
+            } else if value.is::<INT>() {
+                Field::Int(value.cast())
+            } else if value.is::<FLOAT>() {
+                Field::Float(value.cast())
+            } else if value.is_string() {
+                Field::String(value.into_string()?)
+            } else {
+                return Err(format!(
+                    "field {} is a {}, not a bool, number or string",
+                    name,
+                    value.type_name()
+                )
+                .into());
+            };
+            Ok((name.to_string(), field))
+        })
+        .collect()
+}
+
+fn dynamic(field: Field) -> Dynamic {
+    match field {
+        Field::Bool(value) => Dynamic::from(value),
+        Field::Int(value) => Dynamic::from(value as INT),
+        Field::Float(value) => Dynamic::from(value as FLOAT),
+        Field::String(value) => Dynamic::from(value),
+    }
+}
@@ -0,0 +1,208 @@
+use decide_protocol::{
+    error::ControllerError,
+    ComponentRequest::*,
+    GeneralRequest::*,
+    RequestType::{self, *},
+};
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::{fs::File, io::Read};
+use tmq::{Context, FromZmqSocket};
+
+// where libzmq looks for the handler that authenticates connections
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+/// CURVE keys of the controller and of the clients allowed to connect to it, as
+/// given in `security.yml`. Keys are Z85-encoded, as made by `curve_keygen`.
+#[derive(Deserialize, Debug)]
+pub struct SecurityConfig {
+    secret_key: String,
+    // names of the clients and their public keys
+    clients: HashMap<String, String>,
+    // clients without a role are observers
+    #[serde(default)]
+    roles: HashMap<String, Role>,
+}
+
+/// What a client may do. Each role may also make the requests of the roles
+/// before it.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// reads the state, parameters and descriptions of components
+    Observer,
+    /// changes the state and parameters of components, and runs experiments
+    Experimenter,
+    /// shuts down, adds and removes components, and shuts down the controller
+    Admin,
+}
+
+impl Role {
+    /// The role a client needs to make a request
+    pub fn required(request_type: RequestType) -> Self {
+        match request_type {
+            Component(GetState | GetParameters | Describe)
+            | General(DescribeComponents | Hello | GetUnacknowledged | Snapshot | Replay) => {
+                Role::Observer
+            }
+            Component(ComponentShutdown)
+            | General(Shutdown | AddComponent | RemoveComponent | Reload) => Role::Admin,
+            _ => Role::Experimenter,
+        }
+    }
+}
+
+/// Bearer tokens of the clients of the HTTP and gRPC interfaces, which have no
+/// CURVE keys, and their roles
+#[derive(Deserialize, Debug)]
+pub struct Tokens {
+    // names of the clients, and their tokens and roles
+    #[serde(default)]
+    clients: HashMap<String, TokenClient>,
+    // the role of requests without a token
+    #[serde(default = "default_anonymous")]
+    anonymous: Role,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenClient {
+    token: String,
+    role: Role,
+}
+
+fn default_anonymous() -> Role {
+    Role::Observer
+}
+
+impl Tokens {
+    /// The client a token belongs to, and its role, if the token is known.
+    /// Requests without a token are made as `anonymous`.
+    pub fn client<'a>(
+        &'a self,
+        token: Option<&str>,
+        anonymous: &'a str,
+    ) -> Option<(&'a str, Role)> {
+        match token {
+            Some(token) => self
+                .clients
+                .iter()
+                .find(|(_, client)| client.token == token)
+                .map(|(name, client)| (name.as_str(), client.role)),
+            None => Some((anonymous, self.anonymous)),
+        }
+    }
+}
+
+impl SecurityConfig {
+    /// Reads `security.yml` from the config directory. Without one, the sockets
+    /// are neither encrypted nor authenticated.
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("security.yml");
+        if !config_file.exists() {
+            warn!("no security config: the controller is open to anyone who can reach it");
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        let config: SecurityConfig =
+            serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?;
+        decode_key("the controller", &config.secret_key)?;
+        for (client, key) in &config.clients {
+            decode_key(client, key)?;
+        }
+        for client in config.roles.keys() {
+            if !config.clients.contains_key(client) {
+                warn!("{:?} is given a role, but has no key", client);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Roles of the clients, by the names that are the `User-Id` of their messages
+    pub fn roles(&self) -> HashMap<String, Role> {
+        self.roles.clone()
+    }
+
+    /// Starts the thread that checks the keys of connecting clients. Must be
+    /// called once for the context, before any socket is bound.
+    pub fn authenticate(&self, context: &Context) -> anyhow::Result<()> {
+        let clients: HashMap<Vec<u8>, String> = self
+            .clients
+            .iter()
+            .map(|(client, key)| Ok((decode_key(client, key)?, client.clone())))
+            .collect::<anyhow::Result<_>>()?;
+        let handler = context.socket(zmq::REP)?;
+        handler.bind(ZAP_ENDPOINT)?;
+        std::thread::Builder::new()
+            .name("authenticator".into())
+            .spawn(move || loop {
+                if let Err(e) = handle_zap(&handler, &clients) {
+                    error!("could not authenticate client: {}", e);
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// Binds a socket of a context, as a CURVE server if there is a security config
+pub fn bind<T: FromZmqSocket<T>>(
+    context: &Context,
+    socket_type: zmq::SocketType,
+    endpoint: &str,
+    security: Option<&SecurityConfig>,
+) -> anyhow::Result<T> {
+    let socket = context.socket(socket_type)?;
+    if let Some(security) = security {
+        socket.set_curve_server(true)?;
+        socket.set_curve_secretkey(&decode_key("the controller", &security.secret_key)?)?;
+    }
+    socket.bind(endpoint)?;
+    Ok(T::from_zmq_socket(socket)?)
+}
+
+pub(crate) fn decode_key(owner: &str, key: &str) -> anyhow::Result<Vec<u8>> {
+    match zmq::z85_decode(key) {
+        Ok(key) if key.len() == 32 => Ok(key),
+        _ => Err(ControllerError::InvalidKey(owner.into()).into()),
+    }
+}
+
+/// Answers one ZAP request. Clients are accepted if their public key is listed,
+/// and their name becomes the `User-Id` of their messages.
+fn handle_zap(handler: &zmq::Socket, clients: &HashMap<Vec<u8>, String>) -> anyhow::Result<()> {
+    // version, request id, domain, address, routing id, mechanism, credentials
+    let request = handler.recv_multipart(0)?;
+    if request.len() < 7 {
+        anyhow::bail!("malformed ZAP request of {} frames", request.len());
+    }
+    let (status, text, user) = match clients.get(&request[6]) {
+        Some(client) if request[5] == b"CURVE" => ("200", "OK", &client[..]),
+        _ => ("400", "unknown client key", ""),
+    };
+    if status != "200" {
+        warn!(
+            "refused connection from {}",
+            String::from_utf8_lossy(&request[3])
+        );
+    }
+    let reply: [&[u8]; 6] = [
+        b"1.0",
+        &request[1],
+        status.as_bytes(),
+        text.as_bytes(),
+        user.as_bytes(),
+        b"",
+    ];
+    handler.send_multipart(reply.iter(), 0)?;
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+// a component healthy for this long has its restarts forgotten
+static RESTART_RESET: Duration = Duration::from_secs(60);
+
+/// When a failed component is restarted
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// left faulted until a client reinitializes it
+    #[default]
+    Never,
+    /// restarted with backoff, until `max_restarts` in a row have failed
+    OnFailure,
+    /// restarted with backoff, however often it fails
+    Always,
+}
+
+/// The restart policy of a component, as given in its config
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RestartConfig {
+    #[serde(default)]
+    pub policy: RestartPolicy,
+    #[serde(default = "RestartConfig::default_backoff")]
+    pub backoff: u64, // ms before the first restart, doubled for each one after
+    #[serde(default = "RestartConfig::default_max_backoff")]
+    pub max_backoff: u64, // ms
+    #[serde(default = "RestartConfig::default_max_restarts")]
+    pub max_restarts: u32, // for on-failure
+}
+
+impl RestartConfig {
+    fn default_backoff() -> u64 {
+        1000
+    }
+
+    fn default_max_backoff() -> u64 {
+        60000
+    }
+
+    fn default_max_restarts() -> u32 {
+        5
+    }
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        RestartConfig {
+            policy: RestartPolicy::default(),
+            backoff: Self::default_backoff(),
+            max_backoff: Self::default_max_backoff(),
+            max_restarts: Self::default_max_restarts(),
+        }
+    }
+}
+
+/// What to do about a component, as its health is checked
+#[derive(Debug, PartialEq)]
+pub enum Supervision {
+    Wait,
+    /// restart it now; the attempt is counted from 1
+    Restart(u32),
+    /// the policy allows no more restarts
+    GiveUp(u32),
+}
+
+/// Decides when to restart a failed component, following its restart policy
+#[derive(Debug)]
+pub struct Supervisor {
+    config: RestartConfig,
+    attempts: u32, // restarts in a row
+    due: Option<Instant>, // when the next restart is due, if the component has failed
+    healthy_since: Option<Instant>,
+    gave_up: bool,
+}
+
+impl Supervisor {
+    pub fn new(config: RestartConfig) -> Self {
+        Supervisor {
+            config,
+            attempts: 0,
+            due: None,
+            healthy_since: None,
+            gave_up: false,
+        }
+    }
+
+    /// Called at regular intervals with whether the component has failed
+    pub fn check(&mut self, failed: bool, now: Instant) -> Supervision {
+        if !failed {
+            self.due = None;
+            self.gave_up = false;
+            let since = *self.healthy_since.get_or_insert(now);
+            if now.duration_since(since) >= RESTART_RESET {
+                self.attempts = 0;
+            }
+            return Supervision::Wait;
+        }
+        self.healthy_since = None;
+        if self.config.policy == RestartPolicy::Never || self.gave_up {
+            return Supervision::Wait;
+        }
+        if self.config.policy == RestartPolicy::OnFailure && self.attempts >= self.config.max_restarts {
+            self.gave_up = true;
+            return Supervision::GiveUp(self.attempts);
+        }
+        let backoff = self.backoff();
+        let due = *self.due.get_or_insert(now + backoff);
+        if now < due {
+            return Supervision::Wait;
+        }
+        self.due = None;
+        self.attempts += 1;
+        Supervision::Restart(self.attempts)
+    }
+
+    fn backoff(&self) -> Duration {
+        let backoff = self
+            .config
+            .backoff
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(self.config.max_backoff);
+        Duration::from_millis(backoff)
+    }
+}
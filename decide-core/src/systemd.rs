@@ -0,0 +1,39 @@
+//! Tells systemd how the controller is doing when it runs as a `Type=notify`
+//! service: ready once the components have initialized, alive for as long as
+//! requests are being processed, and stopping while the components shut down.
+//! Outside of systemd there is no one to tell, and nothing is sent.
+use sd_notify::NotifyState;
+use std::time::Duration;
+
+/// Tells systemd that the controller is ready, with a status line for
+/// `systemctl status`
+pub fn ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Tells systemd that the controller is still processing requests
+pub fn watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// Tells systemd that the controller is shutting down
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// How often to tell systemd that the controller is alive: half the timeout in
+/// `WatchdogSec=`, if the watchdog is enabled
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec) / 2)
+    } else {
+        None
+    }
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("could not notify systemd: {}", e);
+    }
+}
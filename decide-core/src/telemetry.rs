@@ -0,0 +1,82 @@
+//! Exports the spans of the controller to an OpenTelemetry collector over OTLP,
+//! so that a request can be followed from the socket, through the component
+//! that executes it, to the state messages that follow from it. The spans are:
+//!
+//! - `request` and `internal_request`: a request from a client or a subsystem,
+//!   with its type, component and correlation id
+//! - `execute`: the request carried out by the component, within the request
+//! - `publish`: a state message, following from the last change requested of
+//!   the component
+use super::hostname;
+use decide_protocol::error::ControllerError;
+use directories::ProjectDirs;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use serde::Deserialize;
+use std::{fs::File, io::Read};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// The collector, as given in `telemetry.yml`
+#[derive(Deserialize, Debug)]
+pub struct TelemetryConfig {
+    // the OTLP/gRPC endpoint of the collector, e.g. `http://localhost:4317`
+    endpoint: String,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+}
+
+fn default_service_name() -> String {
+    String::from("decide")
+}
+
+impl TelemetryConfig {
+    /// Reads `telemetry.yml` from the config directory, if there is one
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let config_file = ProjectDirs::from("org", "meliza", "decide")
+            .ok_or(ControllerError::NoConfigDir)?
+            .config_dir()
+            .join("telemetry.yml");
+        if !config_file.exists() {
+            return Ok(None);
+        }
+        let reader = File::open(&config_file).map_err(|e| ControllerError::ConfigReadError {
+            path: Some(config_file),
+            source: e,
+        })?;
+        Self::from_reader(reader).map(Some)
+    }
+
+    pub fn from_reader<T: Read>(config_reader: T) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_reader(config_reader).map_err(ControllerError::from)?)
+    }
+}
+
+/// A layer that exports spans in batches to the collector. The spans carry
+/// the hostname, so that the boxes of a lab can be told apart.
+pub fn layer<S>(config: &TelemetryConfig) -> anyhow::Result<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let resource = Resource::new(vec![
+        KeyValue::new("service.name", config.service_name.clone()),
+        KeyValue::new("host.name", hostname()),
+    ]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Exports the spans not yet exported, before the controller exits
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
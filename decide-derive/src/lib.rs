@@ -0,0 +1,262 @@
+/*!
+Fills in the boilerplate of `decide_protocol::Component` implementations. Use it
+through `decide_protocol::component`:
+
+```ignore
+#[decide_protocol::component(state = proto::CueState, params = proto::CueParams, config = Config)]
+#[async_trait]
+impl Component for CueLed {
+    fn new(config: Self::Config, state_sender: Sender<Any>) -> Self { ... }
+    ...
+}
+```
+
+This adds the `State`, `Params` and `Config` types, the type URLs, which are
+`type.googleapis.com/` followed by the name of the message unless given as
+`state_type_url` and `params_type_url`, and the descriptors written by the build
+script to `file_descriptor_set.bin` in `OUT_DIR`. It must come before
+`#[async_trait]`.
+
+The attribute also registers the component with the controller. decide-core's
+build script finds it in the component crates decide-core depends on and adds the
+type to `ComponentKind` under its own name, which is the driver name used in
+configs. A new component crate only has to be added to decide-core's
+dependencies, and must export the type from its root.
+*/
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_quote, Ident, ItemImpl, LitStr, Token, Type,
+};
+
+struct Args {
+    state: Type,
+    params: Type,
+    config: Type,
+    state_type_url: LitStr,
+    params_type_url: LitStr,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let (mut state, mut params, mut config) = (None, None, None);
+        let (mut state_type_url, mut params_type_url) = (None, None);
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match key.to_string().as_str() {
+                "state" => state = Some(input.parse()?),
+                "params" => params = Some(input.parse()?),
+                "config" => config = Some(input.parse()?),
+                "state_type_url" => state_type_url = Some(input.parse()?),
+                "params_type_url" => params_type_url = Some(input.parse()?),
+                _ => return Err(syn::Error::new(key.span(), "unknown argument")),
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        let state: Type = state.ok_or_else(|| input.error("missing `state`"))?;
+        let params: Type = params.ok_or_else(|| input.error("missing `params`"))?;
+        let config = config.ok_or_else(|| input.error("missing `config`"))?;
+        let state_type_url = match state_type_url {
+            Some(url) => url,
+            None => type_url(&state)?,
+        };
+        let params_type_url = match params_type_url {
+            Some(url) => url,
+            None => type_url(&params)?,
+        };
+        Ok(Args {
+            state,
+            params,
+            config,
+            state_type_url,
+            params_type_url,
+        })
+    }
+}
+
+/// The type URL of a message, from the last segment of its path
+fn type_url(message: &Type) -> syn::Result<LitStr> {
+    match message {
+        Type::Path(path) => {
+            let name = &path.path.segments.last().unwrap().ident;
+            Ok(LitStr::new(
+                &format!("type.googleapis.com/{}", name),
+                name.span(),
+            ))
+        }
+        _ => Err(syn::Error::new_spanned(
+            message,
+            "the type URL of this message must be given",
+        )),
+    }
+}
+
+#[proc_macro_attribute]
+pub fn component(args: TokenStream, input: TokenStream) -> TokenStream {
+    expand(args.into(), input.into())
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand(
+    args: proc_macro2::TokenStream,
+    input: proc_macro2::TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let Args {
+        state,
+        params,
+        config,
+        state_type_url,
+        params_type_url,
+    } = syn::parse2(args)?;
+    let mut item: ItemImpl = syn::parse2(input)?;
+    let boilerplate: ItemImpl = parse_quote! {
+        impl Component {
+            type State = #state;
+            type Params = #params;
+            type Config = #config;
+            const STATE_TYPE_URL: &'static str = #state_type_url;
+            const PARAMS_TYPE_URL: &'static str = #params_type_url;
+            const FILE_DESCRIPTOR_SET: &'static [u8] =
+                include_bytes!(concat!(env!("OUT_DIR"), "/file_descriptor_set.bin"));
+        }
+    };
+    item.items.splice(0..0, boilerplate.items);
+    Ok(quote!(#item))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMPL: &str =
+        "impl Component for CueLed { fn healthy(&self) -> ComponentHealth { todo!() } }";
+
+    fn expanded(args: proc_macro2::TokenStream) -> syn::Result<String> {
+        expand(args, IMPL.parse().unwrap()).map(|tokens| tokens.to_string())
+    }
+
+    fn error(args: proc_macro2::TokenStream) -> String {
+        expanded(args).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn type_urls_default_to_the_message_names() {
+        let expected = quote! {
+            impl Component for CueLed {
+                type State = proto::CueState;
+                type Params = proto::CueParams;
+                type Config = Config;
+                const STATE_TYPE_URL: &'static str = "type.googleapis.com/CueState";
+                const PARAMS_TYPE_URL: &'static str = "type.googleapis.com/CueParams";
+                const FILE_DESCRIPTOR_SET: &'static [u8] =
+                    include_bytes!(concat!(env!("OUT_DIR"), "/file_descriptor_set.bin"));
+                fn healthy(&self) -> ComponentHealth { todo!() }
+            }
+        };
+        let args = quote!(
+            state = proto::CueState,
+            params = proto::CueParams,
+            config = Config
+        );
+        assert_eq!(expanded(args).unwrap(), expected.to_string());
+    }
+
+    #[test]
+    fn type_urls_can_be_given() {
+        let expected = quote! {
+            impl Component for CueLed {
+                type State = State;
+                type Params = Params;
+                type Config = LightsConfig;
+                const STATE_TYPE_URL: &'static str = "melizalab.org/proto/lights_state";
+                const PARAMS_TYPE_URL: &'static str = "melizalab.org/proto/lights_params";
+                const FILE_DESCRIPTOR_SET: &'static [u8] =
+                    include_bytes!(concat!(env!("OUT_DIR"), "/file_descriptor_set.bin"));
+                fn healthy(&self) -> ComponentHealth { todo!() }
+            }
+        };
+        let args = quote! {
+            state = State, params = Params, config = LightsConfig,
+            state_type_url = "melizalab.org/proto/lights_state",
+            params_type_url = "melizalab.org/proto/lights_params"
+        };
+        assert_eq!(expanded(args).unwrap(), expected.to_string());
+    }
+
+    #[test]
+    fn one_type_url_can_be_given() {
+        let args = quote! {
+            state = State, params = proto::Params, config = Config,
+            state_type_url = "melizalab.org/proto/lights_state"
+        };
+        let expanded = expanded(args).unwrap();
+        assert!(expanded
+            .contains(r#"STATE_TYPE_URL : & 'static str = "melizalab.org/proto/lights_state""#));
+        assert!(
+            expanded.contains(r#"PARAMS_TYPE_URL : & 'static str = "type.googleapis.com/Params""#)
+        );
+    }
+
+    #[test]
+    fn arguments_can_come_in_any_order_with_a_trailing_comma() {
+        let ordered = quote!(
+            state = proto::CueState,
+            params = proto::CueParams,
+            config = Config
+        );
+        let shuffled = quote!(
+            config = Config,
+            params = proto::CueParams,
+            state = proto::CueState,
+        );
+        assert_eq!(expanded(shuffled).unwrap(), expanded(ordered).unwrap());
+    }
+
+    #[test]
+    fn unknown_arguments_are_rejected() {
+        let args = quote!(
+            state = State,
+            params = Params,
+            config = Config,
+            driver = CueLed
+        );
+        assert_eq!(error(args), "unknown argument");
+    }
+
+    #[test]
+    fn missing_arguments_are_rejected() {
+        assert!(error(quote!(params = Params, config = Config)).contains("missing `state`"));
+        assert!(error(quote!(state = State, config = Config)).contains("missing `params`"));
+        assert!(error(quote!(state = State, params = Params)).contains("missing `config`"));
+    }
+
+    #[test]
+    fn messages_without_a_name_need_a_type_url() {
+        let args = quote!(state = (u32, u32), params = Params, config = Config);
+        assert_eq!(error(args), "the type URL of this message must be given");
+        let args = quote!(
+            state = (u32, u32),
+            params = Params,
+            config = Config,
+            state_type_url = "pair"
+        );
+        assert!(expanded(args).is_ok());
+    }
+
+    #[test]
+    fn only_impl_blocks_are_accepted() {
+        let args = quote!(state = State, params = Params, config = Config);
+        assert!(expand(
+            args,
+            quote!(
+                struct CueLed;
+            )
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! GPIO lines of the character device interface, through `gpio_cdev`
+use super::{DigitalInput, DigitalOutputs, Edge, EdgeInput, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use gpio_cdev::{
+    AsyncLineEventHandle, Chip, EventRequestFlags, EventType, LineHandle, LineRequestFlags,
+    MultiLineHandle,
+};
+
+/// Claims output lines of a chip, which start low
+pub fn outputs(chip: &mut Chip, offsets: &[u32], consumer: &str) -> Result<MultiLineHandle> {
+    let defaults = vec![0; offsets.len()];
+    Ok(chip
+        .get_lines(offsets)?
+        .request(LineRequestFlags::OUTPUT, &defaults, consumer)?)
+}
+
+/// Claims an input line of a chip
+pub fn input(chip: &mut Chip, offset: u32, consumer: &str) -> Result<LineHandle> {
+    Ok(chip
+        .get_line(offset)?
+        .request(LineRequestFlags::INPUT, 0, consumer)?)
+}
+
+/// Claims an input line of a chip, to watch both its edges. `flags` are added
+/// to `INPUT`, e.g. to bias the line.
+pub fn edges(
+    chip: &mut Chip,
+    offset: u32,
+    flags: LineRequestFlags,
+    consumer: &str,
+) -> Result<AsyncLineEventHandle> {
+    let events = chip.get_line(offset)?.events(
+        LineRequestFlags::INPUT | flags,
+        EventRequestFlags::BOTH_EDGES,
+        consumer,
+    )?;
+    Ok(AsyncLineEventHandle::new(events)?)
+}
+
+impl DigitalOutputs for MultiLineHandle {
+    fn set(&self, values: &[u8]) -> Result<()> {
+        Ok(self.set_values(values)?)
+    }
+}
+
+impl DigitalInput for LineHandle {
+    fn get(&self) -> Result<u8> {
+        Ok(self.get_value()?)
+    }
+}
+
+impl DigitalInput for AsyncLineEventHandle {
+    fn get(&self) -> Result<u8> {
+        Ok(self.as_ref().get_value()?)
+    }
+}
+
+#[async_trait]
+impl EdgeInput for AsyncLineEventHandle {
+    async fn next_edge(&mut self) -> Option<Result<Edge>> {
+        let event = match self.next().await? {
+            Ok(event) => event,
+            Err(e) => return Some(Err(e.into())),
+        };
+        Some(Ok(match event.event_type() {
+            EventType::RisingEdge => Edge::Rising,
+            EventType::FallingEdge => Edge::Falling,
+        }))
+    }
+}
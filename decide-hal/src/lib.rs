@@ -0,0 +1,83 @@
+/*!
+    `decide-hal` defines the hardware used by components as traits, so that
+    components can be written and tested without a BeagleBone. `cdev`, `pwm`
+    and `linux` implement them for the hardware of the rigs, and `mock` in
+    memory.
+*/
+use async_trait::async_trait;
+
+pub mod cdev;
+pub mod linux;
+pub mod mock;
+pub mod pwm;
+
+pub type Result<T> = anyhow::Result<T>;
+
+/// Output lines that are set together, e.g. the lines of a motor coil
+pub trait DigitalOutputs: Send + Sync {
+    /// Sets the lines, in the order they were requested
+    fn set(&self, values: &[u8]) -> Result<()>;
+}
+
+/// An input line that is read when needed
+pub trait DigitalInput: Send + Sync {
+    fn get(&self) -> Result<u8>;
+}
+
+/// A change in the level of an input line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// An input line that reports its edges as they happen
+#[async_trait]
+pub trait EdgeInput: DigitalInput {
+    /// Waits for the next edge, or returns None if the line is no longer watched
+    async fn next_edge(&mut self) -> Option<Result<Edge>>;
+}
+
+/// A PWM channel. Times are in ns.
+pub trait Pwm: Send + Sync {
+    fn set_period(&self, period: u64) -> Result<()>;
+    fn set_duty_cycle(&self, duty_cycle: u64) -> Result<()>;
+    fn enable(&self, enabled: bool) -> Result<()>;
+}
+
+/// A device on an I2C bus
+pub trait I2c: Send {
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+    fn read(&mut self, data: &mut [u8]) -> Result<()>;
+    /// Writes, e.g. a register address, then reads the reply
+    fn write_read(&mut self, write: &[u8], read: &mut [u8]) -> Result<()> {
+        self.write(write)?;
+        self.read(read)
+    }
+}
+
+/// A device on a SPI bus
+pub trait Spi: Send {
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+    /// Writes and reads at the same time; the buffers should be the same length
+    fn transfer(&mut self, write: &[u8], read: &mut [u8]) -> Result<()>;
+}
+
+impl<T: DigitalOutputs + ?Sized> DigitalOutputs for Box<T> {
+    fn set(&self, values: &[u8]) -> Result<()> {
+        (**self).set(values)
+    }
+}
+
+impl<T: DigitalInput + ?Sized> DigitalInput for Box<T> {
+    fn get(&self) -> Result<u8> {
+        (**self).get()
+    }
+}
+
+#[async_trait]
+impl<T: EdgeInput + ?Sized> EdgeInput for Box<T> {
+    async fn next_edge(&mut self) -> Option<Result<Edge>> {
+        (**self).next_edge().await
+    }
+}
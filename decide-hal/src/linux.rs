@@ -0,0 +1,43 @@
+//! I2C and SPI devices of the Linux userspace interfaces
+use super::{I2c, Result, Spi};
+use i2cdev::{core::I2CDevice, linux::LinuxI2CDevice};
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use std::io::Write;
+
+/// Opens the device at `address` on an I2C bus, e.g. `/dev/i2c-1`
+pub fn i2c(bus: &str, address: u16) -> Result<LinuxI2CDevice> {
+    Ok(LinuxI2CDevice::new(bus, address)?)
+}
+
+/// Opens a SPI device, e.g. `/dev/spidev0.0`, in mode 0 with 8-bit words
+pub fn spi(device: &str, speed_hz: u32) -> Result<Spidev> {
+    let mut spi = Spidev::open(device)?;
+    let options = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(speed_hz)
+        .mode(SpiModeFlags::SPI_MODE_0)
+        .build();
+    spi.configure(&options)?;
+    Ok(spi)
+}
+
+impl I2c for LinuxI2CDevice {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        Ok(I2CDevice::write(self, data)?)
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        Ok(I2CDevice::read(self, data)?)
+    }
+}
+
+impl Spi for Spidev {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        Ok(self.write_all(data)?)
+    }
+
+    fn transfer(&mut self, write: &[u8], read: &mut [u8]) -> Result<()> {
+        let mut transfer = SpidevTransfer::read_write(write, read);
+        Ok(Spidev::transfer(self, &mut transfer)?)
+    }
+}
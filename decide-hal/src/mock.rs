@@ -0,0 +1,254 @@
+//! In-memory hardware for tests and simulations. Clones share their state, so
+//! a test can keep one to drive or inspect the hardware it gave a component.
+use super::{DigitalInput, DigitalOutputs, Edge, EdgeInput, I2c, Pwm, Result, Spi};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Output lines that record every set of values
+#[derive(Debug, Clone, Default)]
+pub struct MockOutputs(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl MockOutputs {
+    /// The values set so far, oldest first
+    pub fn history(&self) -> Vec<Vec<u8>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// The values set last, if any
+    pub fn last(&self) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().last().cloned()
+    }
+}
+
+impl DigitalOutputs for MockOutputs {
+    fn set(&self, values: &[u8]) -> Result<()> {
+        self.0.lock().unwrap().push(values.to_vec());
+        Ok(())
+    }
+}
+
+/// An input line whose level is set by the test
+#[derive(Debug, Clone, Default)]
+pub struct MockInput(Arc<AtomicU8>);
+
+impl MockInput {
+    pub fn new(level: u8) -> Self {
+        MockInput(Arc::new(AtomicU8::new(level)))
+    }
+
+    pub fn set(&self, level: u8) {
+        self.0.store(level, Ordering::SeqCst);
+    }
+}
+
+impl DigitalInput for MockInput {
+    fn get(&self) -> Result<u8> {
+        Ok(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// An input line that reports the edges made by its `MockLine`
+#[derive(Debug)]
+pub struct MockEdgeInput {
+    level: MockInput,
+    edges: mpsc::UnboundedReceiver<Edge>,
+}
+
+/// Drives a `MockEdgeInput`. The input stops when this is dropped.
+#[derive(Debug, Clone)]
+pub struct MockLine {
+    level: MockInput,
+    edges: mpsc::UnboundedSender<Edge>,
+}
+
+/// Returns an edge input starting at `level` and the line that drives it
+pub fn edges(level: u8) -> (MockEdgeInput, MockLine) {
+    let level = MockInput::new(level);
+    let (tx, rx) = mpsc::unbounded_channel();
+    (
+        MockEdgeInput {
+            level: level.clone(),
+            edges: rx,
+        },
+        MockLine { level, edges: tx },
+    )
+}
+
+impl MockLine {
+    /// Sets the level of the line, which makes an edge if it changed
+    pub fn set(&self, level: u8) {
+        let old = self.level.0.swap(level, Ordering::SeqCst);
+        if old != level {
+            let edge = if level > old {
+                Edge::Rising
+            } else {
+                Edge::Falling
+            };
+            let _ = self.edges.send(edge);
+        }
+    }
+}
+
+impl DigitalInput for MockEdgeInput {
+    fn get(&self) -> Result<u8> {
+        self.level.get()
+    }
+}
+
+#[async_trait]
+impl EdgeInput for MockEdgeInput {
+    async fn next_edge(&mut self) -> Option<Result<Edge>> {
+        self.edges.recv().await.map(Ok)
+    }
+}
+
+/// A PWM channel that keeps its settings
+#[derive(Debug, Clone, Default)]
+pub struct MockPwm {
+    period: Arc<AtomicU64>,
+    duty_cycle: Arc<AtomicU64>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl MockPwm {
+    pub fn period(&self) -> u64 {
+        self.period.load(Ordering::SeqCst)
+    }
+
+    pub fn duty_cycle(&self) -> u64 {
+        self.duty_cycle.load(Ordering::SeqCst)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+}
+
+impl Pwm for MockPwm {
+    fn set_period(&self, period: u64) -> Result<()> {
+        self.period.store(period, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_duty_cycle(&self, duty_cycle: u64) -> Result<()> {
+        self.duty_cycle.store(duty_cycle, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn enable(&self, enabled: bool) -> Result<()> {
+        self.enabled.store(enabled, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bus {
+    written: Vec<Vec<u8>>,
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl Bus {
+    fn reply(&mut self, data: &mut [u8]) -> Result<()> {
+        let reply = self
+            .replies
+            .pop_front()
+            .ok_or_else(|| anyhow::anyhow!("no reply queued"))?;
+        anyhow::ensure!(
+            reply.len() == data.len(),
+            "queued reply is {} bytes but {} were read",
+            reply.len(),
+            data.len()
+        );
+        data.copy_from_slice(&reply);
+        Ok(())
+    }
+}
+
+/// A bus device that records what is written to it and answers reads with
+/// queued replies. Reading without a reply queued is an error.
+#[derive(Debug, Clone, Default)]
+pub struct MockBus(Arc<Mutex<Bus>>);
+
+/// An I2C device in memory
+pub type MockI2c = MockBus;
+/// A SPI device in memory
+pub type MockSpi = MockBus;
+
+impl MockBus {
+    /// Queues the reply to a read or transfer
+    pub fn queue_reply(&self, data: &[u8]) {
+        self.0.lock().unwrap().replies.push_back(data.to_vec());
+    }
+
+    /// The data written so far, one entry per write or transfer
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.0.lock().unwrap().written.clone()
+    }
+}
+
+impl I2c for MockBus {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.0.lock().unwrap().written.push(data.to_vec());
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        self.0.lock().unwrap().reply(data)
+    }
+}
+
+impl Spi for MockBus {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.0.lock().unwrap().written.push(data.to_vec());
+        Ok(())
+    }
+
+    fn transfer(&mut self, write: &[u8], read: &mut [u8]) -> Result<()> {
+        let mut bus = self.0.lock().unwrap();
+        bus.written.push(write.to_vec());
+        bus.reply(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs_record_history() {
+        let outputs = MockOutputs::default();
+        let lines: Box<dyn DigitalOutputs> = Box::new(outputs.clone());
+        lines.set(&[1, 0]).unwrap();
+        lines.set(&[0, 1]).unwrap();
+        assert_eq!(outputs.history(), vec![vec![1, 0], vec![0, 1]]);
+        assert_eq!(outputs.last(), Some(vec![0, 1]));
+    }
+
+    #[tokio::test]
+    async fn line_makes_edges_on_changes() {
+        let (mut input, line) = edges(0);
+        line.set(1);
+        line.set(1);
+        line.set(0);
+        drop(line);
+        assert_eq!(input.get().unwrap(), 0);
+        assert_eq!(input.next_edge().await.unwrap().unwrap(), Edge::Rising);
+        assert_eq!(input.next_edge().await.unwrap().unwrap(), Edge::Falling);
+        assert!(input.next_edge().await.is_none());
+    }
+
+    #[test]
+    fn i2c_answers_with_queued_replies() {
+        let bus = MockI2c::default();
+        let mut device: Box<dyn I2c> = Box::new(bus.clone());
+        bus.queue_reply(&[0x12, 0x34]);
+        let mut data = [0; 2];
+        device.write_read(&[0x05], &mut data).unwrap();
+        assert_eq!(data, [0x12, 0x34]);
+        assert_eq!(bus.written(), vec![vec![0x05]]);
+        assert!(device.read(&mut data).is_err());
+    }
+}
@@ -0,0 +1,39 @@
+//! PWM channels of the sysfs interface
+use super::{Pwm, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A channel of a PWM chip, e.g. /sys/class/pwm/pwmchip0/pwm1
+pub struct SysfsPwm {
+    path: PathBuf,
+}
+
+impl SysfsPwm {
+    /// Exports a channel of a chip, unless it already is
+    pub fn export<P: AsRef<Path>>(chip: P, channel: u32) -> Result<Self> {
+        let chip = chip.as_ref();
+        let path = chip.join(format!("pwm{}", channel));
+        if !path.exists() {
+            fs::write(chip.join("export"), channel.to_string())?;
+        }
+        Ok(SysfsPwm { path })
+    }
+
+    fn write(&self, attribute: &str, value: String) -> Result<()> {
+        Ok(fs::write(self.path.join(attribute), value)?)
+    }
+}
+
+impl Pwm for SysfsPwm {
+    fn set_period(&self, period: u64) -> Result<()> {
+        self.write("period", period.to_string())
+    }
+
+    fn set_duty_cycle(&self, duty_cycle: u64) -> Result<()> {
+        self.write("duty_cycle", duty_cycle.to_string())
+    }
+
+    fn enable(&self, enabled: bool) -> Result<()> {
+        self.write("enable", (enabled as u8).to_string())
+    }
+}
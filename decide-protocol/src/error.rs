@@ -1,7 +1,9 @@
-use super::ComponentName;
+use super::{proto, ComponentName, SUPPORTED_VERSIONS};
 use prost::DecodeError;
+use proto::error::Code;
 use serde_value::DeserializerError;
 use serde_yaml::Error as YamlError;
+use std::collections::HashMap;
 use thiserror::Error;
 use tokio::sync::oneshot;
 
@@ -54,6 +56,170 @@ pub enum ClientError {
     IncompatibleVersion(Vec<u8>),
     #[error("`Any` protobuf type mismatch: found {actual}, expected {expected}")]
     WrongAnyProtoType { actual: String, expected: String },
+    #[error("no message type is registered for `Any` type {0}")]
+    UnknownTypeUrl(String),
+    #[error("component `{0:?}` is changed more than once in the batch")]
+    RepeatedComponent(ComponentName),
+    #[error("component `{0:?}` is locked: it is leased to another client")]
+    Leased(ComponentName),
+    #[error("the duration of a lease cannot be negative")]
+    InvalidLeaseTtl,
+    #[error("the component is still acting on an earlier request")]
+    Busy,
+    #[error("component `{0:?}` already exists")]
+    ComponentExists(ComponentName),
+    #[error("component `{component:?}` cannot be removed while {dependents:?} depend on it")]
+    HasDependents {
+        component: ComponentName,
+        dependents: Vec<ComponentName>,
+    },
+    #[error("the deadline of the request passed before it could be carried out")]
+    Expired,
+    #[error("the component is paused")]
+    Paused,
+    #[error("`{consumer}` does not acknowledge the state messages of component `{component:?}`")]
+    NotConsumer {
+        component: ComponentName,
+        consumer: String,
+    },
+    #[error("client `{client}` is not permitted to make {request} requests")]
+    Forbidden { client: String, request: String },
+}
+
+impl DecideError {
+    /// Machine-readable kind of the error, for clients
+    pub fn code(&self) -> Code {
+        match self {
+            DecideError::Client { source } => match source {
+                ClientError::InvalidState => Code::InvalidState,
+                ClientError::InvalidParams => Code::InvalidParams,
+                ClientError::UnknownComponent(_) => Code::NoSuchComponent,
+                ClientError::AlreadyLocked | ClientError::Leased(_) => Code::Locked,
+                ClientError::ConfigIdMismatch { .. } => Code::ConfigMismatch,
+                ClientError::Busy => Code::Busy,
+                ClientError::Expired => Code::Expired,
+                ClientError::Paused => Code::Paused,
+                ClientError::Forbidden { .. } => Code::Forbidden,
+                ClientError::IncompatibleVersion(_) => Code::IncompatibleVersion,
+                ClientError::InvalidVersion
+                | ClientError::InvalidComponent
+                | ClientError::InvalidRequestType(_)
+                | ClientError::MessageDecodingError(_)
+                | ClientError::NoState
+                | ClientError::NoParameters
+                | ClientError::BadMultipartLen(_)
+                | ClientError::WrongAnyProtoType { .. }
+                | ClientError::UnknownTypeUrl(_)
+                | ClientError::RepeatedComponent(_)
+                | ClientError::InvalidLeaseTtl
+                | ClientError::ComponentExists(_)
+                | ClientError::HasDependents { .. }
+                | ClientError::NotConsumer { .. } => Code::InvalidRequest,
+            },
+            DecideError::Component { .. } => Code::HardwareFault,
+            DecideError::Controller { source } => match source {
+                ControllerError::ComponentFault { .. } | ControllerError::OneshotRecvDropped(_) => {
+                    Code::HardwareFault
+                }
+                ControllerError::ShutdownTimeout { .. }
+                | ControllerError::SatelliteUnavailable { .. } => Code::Busy,
+                ControllerError::NoConfigDir
+                | ControllerError::ConfigReadError { .. }
+                | ControllerError::YamlParseError(_)
+                | ControllerError::ConfigDeserializationError { .. }
+                | ControllerError::UnknownDriver(_)
+                | ControllerError::UnknownDependency { .. }
+                | ControllerError::DependencyCycle(_)
+                | ControllerError::InvalidKey(_)
+                | ControllerError::InvalidExperiment(_)
+                | ControllerError::InvalidLoggerConfig(_)
+                | ControllerError::InvalidControllerConfig(_)
+                | ControllerError::InvalidSchedule(_)
+                | ControllerError::InvalidFederation(_) => Code::Unknown,
+            },
+        }
+    }
+
+    /// The component the error concerns, if it names one
+    pub fn component(&self) -> Option<&ComponentName> {
+        match self {
+            DecideError::Client {
+                source:
+                    ClientError::UnknownComponent(component)
+                    | ClientError::Leased(component)
+                    | ClientError::RepeatedComponent(component)
+                    | ClientError::ComponentExists(component)
+                    | ClientError::HasDependents { component, .. }
+                    | ClientError::NotConsumer { component, .. },
+            }
+            | DecideError::Controller {
+                source:
+                    ControllerError::ComponentFault { component, .. }
+                    | ControllerError::ShutdownTimeout { component },
+            } => Some(component),
+            _ => None,
+        }
+    }
+}
+
+impl From<DecideError> for proto::Error {
+    fn from(e: DecideError) -> Self {
+        let mut details = HashMap::new();
+        match &e {
+            DecideError::Client { source } => match source {
+                ClientError::InvalidRequestType(request_type) => {
+                    details.insert("request_type".into(), request_type.to_string());
+                }
+                ClientError::ConfigIdMismatch { client, controller } => {
+                    details.insert("client".into(), client.clone());
+                    details.insert("controller".into(), controller.clone());
+                }
+                ClientError::WrongAnyProtoType { actual, expected } => {
+                    details.insert("actual".into(), actual.clone());
+                    details.insert("expected".into(), expected.clone());
+                }
+                ClientError::UnknownTypeUrl(type_url) => {
+                    details.insert("type_url".into(), type_url.clone());
+                }
+                ClientError::HasDependents { dependents, .. } => {
+                    let dependents: Vec<_> = dependents.iter().map(|c| c.0.as_str()).collect();
+                    details.insert("dependents".into(), dependents.join(","));
+                }
+                ClientError::NotConsumer { consumer, .. } => {
+                    details.insert("consumer".into(), consumer.clone());
+                }
+                ClientError::Forbidden { client, .. } => {
+                    details.insert("client".into(), client.clone());
+                }
+                ClientError::IncompatibleVersion(_) => {
+                    let supported: Vec<_> = SUPPORTED_VERSIONS
+                        .iter()
+                        .map(|version| String::from_utf8_lossy(version))
+                        .collect();
+                    details.insert("supported_versions".into(), supported.join(","));
+                }
+                _ => (),
+            },
+            DecideError::Component { source } => {
+                details.insert("cause".into(), format!("{:#}", source));
+            }
+            DecideError::Controller { source } => match source {
+                ControllerError::ComponentFault { reason, .. } => {
+                    details.insert("reason".into(), reason.clone());
+                }
+                ControllerError::SatelliteUnavailable { satellite, .. } => {
+                    details.insert("satellite".into(), satellite.clone());
+                }
+                _ => (),
+            },
+        }
+        proto::Error {
+            code: e.code() as i32,
+            message: e.to_string(),
+            component: e.component().map(|c| c.0.clone()).unwrap_or_default(),
+            details,
+        }
+    }
 }
 
 /*#[derive(Error, Debug)]
@@ -142,4 +308,30 @@ pub enum ControllerError {
     UnknownDriver(String),
     #[error("component {component:?} failed to shutdown before timeout period")]
     ShutdownTimeout { component: ComponentName },
+    #[error("component {component:?} depends on {dependency:?}, which is not configured")]
+    UnknownDependency {
+        component: ComponentName,
+        dependency: ComponentName,
+    },
+    #[error("components {0:?} depend on each other")]
+    DependencyCycle(Vec<ComponentName>),
+    #[error("the CURVE key of {0} is not a Z85-encoded 32-byte key")]
+    InvalidKey(String),
+    #[error("invalid experiment definition: {0}")]
+    InvalidExperiment(String),
+    #[error("invalid logger config: {0}")]
+    InvalidLoggerConfig(String),
+    #[error("invalid controller config: {0}")]
+    InvalidControllerConfig(String),
+    #[error("invalid schedule: {0}")]
+    InvalidSchedule(String),
+    #[error("invalid federation config: {0}")]
+    InvalidFederation(String),
+    #[error("satellite {satellite:?} is unavailable: {reason}")]
+    SatelliteUnavailable { satellite: String, reason: String },
+    #[error("component {component:?} has faulted: {reason}")]
+    ComponentFault {
+        component: ComponentName,
+        reason: String,
+    },
 }
@@ -2,19 +2,49 @@ use super::{error::ClientError, proto, ComponentName, DecideError, Result};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
 use prost::Message as ProstMessage;
+use prost_types::{Any, Duration, Timestamp};
 use std::convert::TryFrom;
+use std::time::{Instant, SystemTime};
 use tmq::Multipart;
 
-pub const DECIDE_VERSION: &[u8] = b"DCDC01";
+pub const DECIDE_VERSION: &[u8] = b"DCDC02";
+
+/// Versions of the protocol the controller can serve. Clients of DCDC01 are
+/// sent errors as plain strings.
+pub const SUPPORTED_VERSIONS: &[&[u8]] = &[b"DCDC01", DECIDE_VERSION];
+
+/// Optional features of the protocol, as exchanged in `Hello` messages
+pub const FEATURES: &[&str] = &[
+    "heartbeats",
+    "pub-sequence",
+    "reinitialize",
+    "describe",
+    "batch-change-state",
+    "leases",
+    "structured-errors",
+    "request-meta",
+    "runtime-components",
+    "restart-policies",
+    "deadlines",
+    "acknowledged-delivery",
+    "pause",
+    "snapshot",
+    "replay",
+    "reload",
+];
 
 pub const REQ_ENDPOINT: &str = "tcp://127.0.0.1:7897";
 pub const PUB_ENDPOINT: &str = "tcp://127.0.0.1:7898";
+pub const REQ_PORT: u16 = 7897;
+pub const PUB_PORT: u16 = 7898;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Request {
     pub request_type: RequestType,
     pub component: Option<ComponentName>,
     pub body: Vec<u8>,
+    /// correlation id and idempotency key; sent only if either is set
+    pub meta: proto::RequestMeta,
 }
 
 use RequestType::*;
@@ -59,6 +89,10 @@ pub enum ComponentRequest {
     SetParameters = 0x10,
     GetParameters = 0x11,
     ComponentShutdown = 0x12,
+    Reinitialize = 0x13,
+    Describe = 0x14,
+    Pause = 0x15,
+    Resume = 0x16,
 }
 
 #[derive(Debug, Clone, Copy, ToPrimitive, FromPrimitive, PartialEq)]
@@ -66,12 +100,28 @@ pub enum GeneralRequest {
     RequestLock = 0x20,
     ReleaseLock = 0x21,
     Shutdown = 0x22,
+    DescribeComponents = 0x23,
+    BatchChangeState = 0x24,
+    AcquireLease = 0x25,
+    RenewLease = 0x26,
+    ReleaseLease = 0x27,
+    Hello = 0x28,
+    AddComponent = 0x29,
+    RemoveComponent = 0x2A,
+    Acknowledge = 0x2B,
+    GetUnacknowledged = 0x2C,
+    PauseAll = 0x2D,
+    ResumeAll = 0x2E,
+    Snapshot = 0x2F,
+    Replay = 0x30,
+    Reload = 0x31,
 }
 
 impl From<proto::reply::Result> for proto::Reply {
     fn from(result: proto::reply::Result) -> Self {
         proto::Reply {
             result: Some(result),
+            ..Default::default()
         }
     }
 }
@@ -80,9 +130,10 @@ impl From<Result<proto::reply::Result>> for proto::Reply {
     fn from(result: Result<proto::reply::Result>) -> Self {
         proto::Reply {
             result: Some(match result {
-                Err(e) => proto::reply::Result::Error(e.to_string()),
+                Err(e) => proto::reply::Result::Error(e.into()),
                 Ok(r) => r,
             }),
+            ..Default::default()
         }
     }
 }
@@ -91,7 +142,8 @@ impl From<Result<proto::Reply>> for proto::Reply {
     fn from(result: Result<proto::Reply>) -> Self {
         match result {
             Err(e) => proto::Reply {
-                result: Some(proto::reply::Result::Error(e.to_string())),
+                result: Some(proto::reply::Result::Error(e.into())),
+                ..Default::default()
             },
             Ok(r) => r,
         }
@@ -100,19 +152,20 @@ impl From<Result<proto::Reply>> for proto::Reply {
 
 impl From<Request> for Multipart {
     fn from(request: Request) -> Self {
-        match request.component {
-            None => Multipart::from(vec![
-                DECIDE_VERSION,
-                &[request.request_type.to_u8().unwrap()],
-                &request.body,
-            ]),
-            Some(component) => Multipart::from(vec![
-                DECIDE_VERSION,
-                &[request.request_type.to_u8().unwrap()],
-                &request.body,
-                component.0.as_bytes(),
-            ]),
+        let mut multipart = Multipart::from(vec![
+            DECIDE_VERSION,
+            &[request.request_type.to_u8().unwrap()],
+            &request.body,
+        ]);
+        let component = request.component.map(|c| c.0).unwrap_or_default();
+        if request.meta != proto::RequestMeta::default() {
+            // general requests have an empty component frame
+            multipart.push_back(component.as_bytes().to_vec().into());
+            multipart.push_back(request.meta.encode_to_vec().into());
+        } else if !component.is_empty() {
+            multipart.push_back(component.as_bytes().to_vec().into());
         }
+        multipart
     }
 }
 
@@ -124,27 +177,32 @@ impl TryFrom<Multipart> for Request {
             return Err(ClientError::BadMultipartLen(zmq_message.len()).into());
         }
         let version = zmq_message.pop_front().unwrap().to_vec();
-        if version.as_slice() != DECIDE_VERSION {
+        if !SUPPORTED_VERSIONS.contains(&version.as_slice()) {
             return Err(ClientError::IncompatibleVersion(version).into());
         }
         let request_type = (*zmq_message.pop_front().unwrap())[0];
         let request_type = RequestType::try_from(request_type)?;
         let body = zmq_message.pop_front().unwrap().to_vec();
+        let component = zmq_message.pop_front();
         let component = match request_type {
             General(_) => None,
             Component(_) => Some(
-                zmq_message
-                    .pop_front()
+                component
                     .ok_or_else(|| ClientError::BadMultipartLen(zmq_message.len()))?
                     .as_str()
                     .ok_or(ClientError::InvalidComponent)?
                     .into(),
             ),
         };
+        let meta = match zmq_message.pop_front() {
+            Some(meta) => proto::RequestMeta::decode(&*meta).map_err(ClientError::from)?,
+            None => proto::RequestMeta::default(),
+        };
         Ok(Request {
             request_type,
             body,
             component,
+            meta,
         })
     }
 }
@@ -163,12 +221,83 @@ impl From<Multipart> for proto::Reply {
     }
 }
 
+impl proto::Reply {
+    /// Encodes the reply for a client of an older version of the protocol
+    pub fn into_multipart(self, version: &[u8]) -> Multipart {
+        let reply = match self.result {
+            Some(proto::reply::Result::Error(e)) if version == b"DCDC01" => proto::Reply {
+                result: Some(proto::reply::Result::LegacyError(e.message)),
+                ..self
+            },
+            _ => self,
+        };
+        vec![version, &reply.encode_to_vec()].into()
+    }
+
+    /// Names the component in an error reply that does not already name one
+    pub fn for_component(mut self, component: &ComponentName) -> Self {
+        if let Some(proto::reply::Result::Error(e)) = &mut self.result {
+            if e.component.is_empty() {
+                e.component = component.0.clone();
+            }
+        }
+        self
+    }
+}
+
 impl From<proto::Pub> for Multipart {
     fn from(pub_message: proto::Pub) -> Self {
         vec![DECIDE_VERSION, &pub_message.encode_to_vec()].into()
     }
 }
 
+/// Topic of a PUB message: the kind of message, the component, and the type of
+/// message it carries, e.g. `state/house-lights/LightsState`. Subscribing to a
+/// prefix such as `state/house-lights/` selects the messages of one kind from one
+/// component; zeromq drops the others before they are sent.
+pub fn pub_topic(kind: &str, component: &ComponentName, type_url: &str) -> String {
+    let group = type_url.rsplit('/').next().unwrap_or_default();
+    format!("{}/{}/{}", kind, component.0, group)
+}
+
+/// Wraps the messages published for one component in `Pub` envelopes, stamped
+/// with the wall-clock and monotonic time and numbered in sequence
+#[derive(Debug, Clone)]
+pub struct PubStamper {
+    start: Instant,
+    sequence: u64,
+}
+
+impl PubStamper {
+    /// `start` is the zero of the monotonic time; it should be shared by all
+    /// components so their messages can be ordered
+    pub fn new(start: Instant) -> Self {
+        PubStamper { start, sequence: 0 }
+    }
+
+    /// Stamps a state message, advancing the sequence number
+    pub fn state(&mut self, state: Any) -> proto::Pub {
+        self.sequence += 1;
+        self.stamp(state, String::new())
+    }
+
+    /// Stamps a heartbeat or error message, which carries the sequence number of
+    /// the last state message
+    pub fn stamp(&self, state: Any, label: String) -> proto::Pub {
+        let monotonic = self.start.elapsed();
+        proto::Pub {
+            time: Some(Timestamp::from(SystemTime::now())),
+            state: Some(state),
+            label,
+            sequence: self.sequence,
+            monotonic: Some(Duration {
+                seconds: monotonic.as_secs() as i64,
+                nanos: monotonic.subsec_nanos() as i32,
+            }),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,6 +308,7 @@ mod tests {
             request_type: Component(ComponentRequest::ChangeState),
             component: Some(ComponentName("test".into())),
             body: vec![],
+            meta: Default::default(),
         };
         let multipart = Multipart::from(req.clone());
         assert_eq!(req, Request::try_from(multipart).unwrap());
@@ -190,8 +320,90 @@ mod tests {
             request_type: General(GeneralRequest::RequestLock),
             component: None,
             body: vec![],
+            meta: Default::default(),
         };
         let multipart = Multipart::from(req.clone());
         assert_eq!(req, Request::try_from(multipart).unwrap());
     }
+
+    #[test]
+    fn request_meta_sent_when_set() {
+        let general = Request {
+            request_type: General(GeneralRequest::BatchChangeState),
+            component: None,
+            body: vec![1, 2],
+            meta: proto::RequestMeta {
+                correlation_id: "42".into(),
+                idempotency_key: "feed-0001".into(),
+            },
+        };
+        let multipart = Multipart::from(general.clone());
+        assert_eq!(multipart.len(), 5);
+        assert_eq!(general, Request::try_from(multipart).unwrap());
+        let component = Request {
+            request_type: Component(ComponentRequest::ChangeState),
+            component: Some(ComponentName("feeder".into())),
+            ..general
+        };
+        let multipart = Multipart::from(component.clone());
+        assert_eq!(component, Request::try_from(multipart).unwrap());
+    }
+
+    #[test]
+    fn topics_grouped_by_message_type() {
+        let topic = pub_topic(
+            "state",
+            &ComponentName("stepper".into()),
+            "type.googleapis.com/StepperStats",
+        );
+        assert_eq!(topic, "state/stepper/StepperStats");
+        assert!(topic.starts_with("state/stepper/"));
+    }
+
+    #[test]
+    fn state_messages_numbered_in_sequence() {
+        let mut stamper = PubStamper::new(Instant::now());
+        let first = stamper.state(Any::default());
+        let heartbeat = stamper.stamp(Any::default(), String::new());
+        let second = stamper.state(Any::default());
+        assert_eq!(
+            (first.sequence, heartbeat.sequence, second.sequence),
+            (1, 1, 2)
+        );
+        let elapsed = |message: &proto::Pub| {
+            let monotonic = message.monotonic.clone().unwrap();
+            (monotonic.seconds, monotonic.nanos)
+        };
+        assert!(elapsed(&first) <= elapsed(&second));
+        assert!(second.time.unwrap().seconds > 0);
+    }
+
+    #[test]
+    fn older_clients_served() {
+        let req = Request {
+            request_type: Component(ComponentRequest::GetState),
+            component: Some(ComponentName("test".into())),
+            body: vec![],
+            meta: Default::default(),
+        };
+        let with_version = |version: &[u8]| {
+            let mut multipart = Multipart::from(req.clone());
+            multipart.pop_front();
+            multipart.push_front(version.to_vec().into());
+            Request::try_from(multipart)
+        };
+        assert_eq!(req, with_version(b"DCDC01").unwrap());
+        assert!(with_version(b"DCDC99").is_err());
+
+        let reply = proto::Reply::from(Err::<proto::reply::Result, _>(
+            ClientError::InvalidState.into(),
+        ));
+        let mut multipart = reply.into_multipart(b"DCDC01");
+        assert_eq!(&*multipart.pop_front().unwrap(), b"DCDC01");
+        let reply = proto::Reply::decode(&*multipart.pop_front().unwrap()).unwrap();
+        assert!(matches!(
+            reply.result,
+            Some(proto::reply::Result::LegacyError(_))
+        ));
+    }
 }
@@ -1,11 +1,16 @@
 use super::{
-    error::{ClientError, ControllerError},
+    error::{ControllerError, DecideError},
+    proto,
+    registry::{pack, unpack},
     Result,
 };
 use async_trait::async_trait;
 use prost::Message as ProstMessage;
 use prost_types::Any;
-use serde::{de::DeserializeOwned, Deserialize};
+use serde::{
+    de::{self, DeserializeOwned, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
 use serde_value::{DeserializerError, Value, ValueDeserializer};
 use tokio::sync::mpsc;
 
@@ -16,13 +21,21 @@ pub trait Component {
     type Config: DeserializeOwned + Send;
     const STATE_TYPE_URL: &'static str;
     const PARAMS_TYPE_URL: &'static str;
+    /// Encoded `FileDescriptorSet` of the state and parameters messages, as
+    /// written by `prost_build::Config::file_descriptor_set_path`
+    const FILE_DESCRIPTOR_SET: &'static [u8];
 
     fn new(config: Self::Config, state_sender: mpsc::Sender<Any>) -> Self;
-    async fn init(&mut self, config: Self::Config);
+    /// Sets up the hardware and starts any tasks or threads. An error leaves the
+    /// component faulted, without affecting the rest of the controller.
+    async fn init(&mut self, config: Self::Config) -> Result<()>;
     fn change_state(&mut self, state: Self::State) -> Result<()>;
     fn set_parameters(&mut self, params: Self::Params) -> Result<()>;
     fn get_state(&self) -> Self::State;
     fn get_parameters(&self) -> Self::Params;
+    /// Checks that the tasks, threads and hardware handles of the component are
+    /// still working. Called for every heartbeat, so it must not block.
+    fn healthy(&self) -> ComponentHealth;
     async fn shutdown(&mut self);
     fn deserialize_config(config: Value) -> Result<Self::Config> {
         let deserializer: ValueDeserializer<DeserializerError> = ValueDeserializer::new(config);
@@ -30,43 +43,229 @@ pub trait Component {
             .map_err(|e| ControllerError::ConfigDeserializationError { source: e })?;
         Ok(config)
     }
+    /// Packs a state message of the component, e.g. to send it on the state channel
+    fn pack_state(state: &Self::State) -> Any {
+        pack(Self::STATE_TYPE_URL, state)
+    }
+    /// Packs a state message of the component and sends it on the state channel
+    async fn send_state(state_sender: &mpsc::Sender<Any>, state: &Self::State) -> Result<()> {
+        state_sender
+            .send(Self::pack_state(state))
+            .await
+            .map_err(|e| DecideError::Component { source: e.into() })
+    }
+    fn pack_params(params: &Self::Params) -> Any {
+        pack(Self::PARAMS_TYPE_URL, params)
+    }
     fn get_encoded_parameters(&self) -> Any {
-        Any {
-            value: self.get_parameters().encode_to_vec(),
-            type_url: Self::PARAMS_TYPE_URL.into(),
-        }
+        Self::pack_params(&self.get_parameters())
     }
     fn get_encoded_state(&self) -> Any {
-        Any {
-            value: self.get_state().encode_to_vec(),
-            type_url: Self::STATE_TYPE_URL.into(),
+        Self::pack_state(&self.get_state())
+    }
+    /// Describes the messages and config the component accepts. The name,
+    /// driver, config in use and accepted requests are filled in by the controller.
+    fn description() -> proto::ComponentDescription {
+        proto::ComponentDescription {
+            state_type: Self::STATE_TYPE_URL.into(),
+            params_type: Self::PARAMS_TYPE_URL.into(),
+            descriptors: prost_types::FileDescriptorSet::decode(Self::FILE_DESCRIPTOR_SET).ok(),
+            config_fields: config_fields::<Self::Config>()
+                .iter()
+                .map(|&field| field.into())
+                .collect(),
+            ..Default::default()
         }
     }
+    /// The hardware the component would claim with a config, so that configs
+    /// can be checked without starting any components. Components that claim
+    /// nothing another component could also claim need not say.
+    fn resources(_config: &Self::Config) -> Vec<Resource> {
+        Vec::new()
+    }
     fn reset_state(&mut self) -> Result<()> {
         self.change_state(Self::State::default())
     }
+    /// Puts the outputs of the component in a safe state, e.g. while an animal is
+    /// handled mid-session. Inputs should go on publishing. By default the state
+    /// is reset, which switches most outputs off.
+    fn pause(&mut self) -> Result<()> {
+        self.reset_state()
+    }
+    /// Undoes `pause`. Outputs stay as they are until clients change them.
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
     fn decode_and_change_state(&mut self, message: Any) -> Result<()> {
-        if message.type_url != Self::STATE_TYPE_URL {
-            return Err(ClientError::WrongAnyProtoType {
-                actual: message.type_url,
-                expected: Self::STATE_TYPE_URL.into(),
-            }
-            .into());
-        }
-        self.change_state(
-            Self::State::decode(&*message.value).map_err(ClientError::MessageDecodingError)?,
-        )
+        self.change_state(unpack(Self::STATE_TYPE_URL, &message)?)
     }
     fn decode_and_set_parameters(&mut self, message: Any) -> Result<()> {
-        if message.type_url != Self::PARAMS_TYPE_URL {
-            return Err(ClientError::WrongAnyProtoType {
-                actual: message.type_url,
-                expected: Self::PARAMS_TYPE_URL.into(),
-            }
-            .into());
+        self.set_parameters(unpack(Self::PARAMS_TYPE_URL, &message)?)
+    }
+}
+
+/// Names of the fields of a config struct, found by asking it to deserialize
+/// itself. Empty if the config is not a plain struct.
+pub fn config_fields<C: DeserializeOwned>() -> &'static [&'static str] {
+    let mut fields = &[][..];
+    let _ = C::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> core::result::Result<V::Value, Self::Error> {
+        Err(de::Error::custom("config is not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> core::result::Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("only the field names are wanted"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Hardware claimed by a component, which no other component may claim
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Resource {
+    /// a line of a GPIO chip, e.g. `/dev/gpiochip0`
+    GpioLine { chip: String, offset: u32 },
+    /// an ALSA PCM, e.g. `hw:1,0`; `capture` if it is recorded from
+    Pcm { device: String, capture: bool },
+    /// any other device file, e.g. a serial port or a PWM channel
+    Device(String),
+}
+
+impl Resource {
+    pub fn line(chip: &str, offset: u32) -> Self {
+        Resource::GpioLine {
+            chip: chip.into(),
+            offset,
+        }
+    }
+
+    /// Lines of the same chip
+    pub fn lines<'a>(chip: &str, offsets: impl IntoIterator<Item = &'a u32>) -> Vec<Self> {
+        offsets
+            .into_iter()
+            .map(|&offset| Resource::line(chip, offset))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resource::GpioLine { chip, offset } => write!(f, "{} line {}", chip, offset),
+            Resource::Pcm {
+                device,
+                capture: false,
+            } => write!(f, "ALSA playback {}", device),
+            Resource::Pcm {
+                device,
+                capture: true,
+            } => write!(f, "ALSA capture {}", device),
+            Resource::Device(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+/// Health of a component, as published in its heartbeats
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComponentHealth {
+    Healthy,
+    /// still running, but something needs attention
+    Degraded(String),
+    /// a task or thread has stopped
+    Failed(String),
+    /// shut down by the controller
+    Offline(String),
+}
+
+impl ComponentHealth {
+    /// Healthy, unless the named task or thread has `finished`
+    pub fn running(worker: &str, finished: bool) -> Self {
+        if finished {
+            ComponentHealth::Failed(format!("{} has stopped", worker))
+        } else {
+            ComponentHealth::Healthy
+        }
+    }
+}
+
+impl From<ComponentHealth> for proto::Heartbeat {
+    fn from(health: ComponentHealth) -> Self {
+        use proto::heartbeat::Health;
+        let (health, reason) = match health {
+            ComponentHealth::Healthy => (Health::Healthy, String::new()),
+            ComponentHealth::Degraded(reason) => (Health::Degraded, reason),
+            ComponentHealth::Failed(reason) => (Health::Failed, reason),
+            ComponentHealth::Offline(reason) => (Health::Offline, reason),
+        };
+        proto::Heartbeat {
+            health: health as i32,
+            reason,
+        }
+    }
+}
+
+/// Type URL of the `Heartbeat` messages published by the controller
+pub const HEARTBEAT_TYPE_URL: &str = "type.googleapis.com/decide.Heartbeat";
+
+/// Type URL of the `Fault` messages sent on the state channel
+pub const FAULT_TYPE_URL: &str = "type.googleapis.com/decide.Fault";
+
+/// Type URL of the `Restart` messages published by the controller
+pub const RESTART_TYPE_URL: &str = "type.googleapis.com/decide.Restart";
+
+/// Type URL of the `ExperimentState` messages published by the controller
+pub const EXPERIMENT_TYPE_URL: &str = "type.googleapis.com/decide.ExperimentState";
+
+/// Type URL of the `ScheduleState` messages published by the controller
+pub const SCHEDULE_TYPE_URL: &str = "type.googleapis.com/decide.ScheduleState";
+
+/// Reports an error that stops a task or thread of a component, instead of
+/// panicking. The controller publishes it on the `error` topic and marks the
+/// component as faulted. This does not block, so it can be called from tasks and
+/// threads alike; if the state channel is full the report is only logged.
+pub fn report_fault(state_sender: &mpsc::Sender<Any>, error: impl Into<anyhow::Error>) {
+    let error = format!("{:#}", error.into());
+    let fault = pack(FAULT_TYPE_URL, &proto::Fault { error });
+    if let Err(e) = state_sender.try_send(fault) {
+        tracing::error!("could not report component fault to the controller: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_fields_listed() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Config {
+            chip: String,
+            #[serde(default)]
+            offset: u32,
         }
-        self.set_parameters(
-            Self::Params::decode(&*message.value).map_err(ClientError::MessageDecodingError)?,
-        )
+        assert_eq!(config_fields::<Config>(), &["chip", "offset"]);
+        assert!(config_fields::<u32>().is_empty());
     }
 }
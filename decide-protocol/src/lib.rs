@@ -12,11 +12,22 @@ pub mod proto {
 
 mod external;
 pub use external::{
-    ComponentRequest, GeneralRequest, Request, RequestType, PUB_ENDPOINT, REQ_ENDPOINT,
+    pub_topic, ComponentRequest, GeneralRequest, PubStamper, Request, RequestType, DECIDE_VERSION,
+    FEATURES, PUB_ENDPOINT, PUB_PORT, REQ_ENDPOINT, REQ_PORT, SUPPORTED_VERSIONS,
 };
 
 mod internal;
-pub use internal::Component;
+pub use internal::{
+    config_fields, report_fault, Component, ComponentHealth, Resource, EXPERIMENT_TYPE_URL,
+    FAULT_TYPE_URL, HEARTBEAT_TYPE_URL, RESTART_TYPE_URL, SCHEDULE_TYPE_URL,
+};
+
+/// Fills in the types, type URLs and descriptors of a `Component`
+/// implementation; see `decide_derive`
+pub use decide_derive::component;
+
+mod registry;
+pub use registry::{pack, unpack, Registry};
 
 pub type Result<T> = core::result::Result<T, DecideError>;
 
@@ -0,0 +1,132 @@
+use super::{
+    error::ClientError, internal::Component, proto, EXPERIMENT_TYPE_URL, FAULT_TYPE_URL,
+    HEARTBEAT_TYPE_URL, RESTART_TYPE_URL, SCHEDULE_TYPE_URL,
+};
+use prost::{DecodeError, Message};
+use prost_types::Any;
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+/// Encodes a message into an `Any` with its type URL
+pub fn pack<M: Message>(type_url: &str, message: &M) -> Any {
+    Any {
+        value: message.encode_to_vec(),
+        type_url: type_url.into(),
+    }
+}
+
+/// Decodes an `Any`, checking first that it has the expected type URL
+pub fn unpack<M: Message + Default>(type_url: &str, message: &Any) -> Result<M, ClientError> {
+    if message.type_url != type_url {
+        return Err(ClientError::WrongAnyProtoType {
+            actual: message.type_url.clone(),
+            expected: type_url.into(),
+        });
+    }
+    Ok(M::decode(&*message.value)?)
+}
+
+type Decoder = fn(&[u8]) -> Result<Box<dyn Debug + Send>, DecodeError>;
+
+fn decode_boxed<M: Message + Default + 'static>(value: &[u8]) -> Result<Box<dyn Debug + Send>, DecodeError> {
+    Ok(Box::new(M::decode(value)?))
+}
+
+/// Maps type URLs to the message types they stand for, so that any state or
+/// parameters message can be decoded without knowing in advance where it came
+/// from. The heartbeat, fault, restart and experiment messages of the protocol
+/// are always registered.
+#[derive(Clone)]
+pub struct Registry {
+    decoders: HashMap<String, Decoder>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry {
+            decoders: HashMap::new(),
+        };
+        registry.register::<proto::Heartbeat>(HEARTBEAT_TYPE_URL);
+        registry.register::<proto::Fault>(FAULT_TYPE_URL);
+        registry.register::<proto::Restart>(RESTART_TYPE_URL);
+        registry.register::<proto::ExperimentState>(EXPERIMENT_TYPE_URL);
+        registry.register::<proto::ScheduleState>(SCHEDULE_TYPE_URL);
+        registry
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the message type for a type URL, replacing any earlier one
+    pub fn register<M: Message + Default + 'static>(&mut self, type_url: &str) {
+        self.decoders.insert(type_url.into(), decode_boxed::<M>);
+    }
+
+    /// Registers the state and parameters types of a component
+    pub fn register_component<C: Component>(&mut self)
+    where
+        C::State: 'static,
+        C::Params: 'static,
+    {
+        self.register::<C::State>(C::STATE_TYPE_URL);
+        self.register::<C::Params>(C::PARAMS_TYPE_URL);
+    }
+
+    pub fn contains(&self, type_url: &str) -> bool {
+        self.decoders.contains_key(type_url)
+    }
+
+    /// Decodes a message of any registered type
+    pub fn decode(&self, message: &Any) -> Result<Box<dyn Debug + Send>, ClientError> {
+        let decoder = self
+            .decoders
+            .get(&message.type_url)
+            .ok_or_else(|| ClientError::UnknownTypeUrl(message.type_url.clone()))?;
+        Ok(decoder(&message.value)?)
+    }
+}
+
+impl Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.decoders.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_checked_against_type_urls() {
+        let fault = proto::Fault {
+            error: String::from("line gone"),
+        };
+        let message = pack(FAULT_TYPE_URL, &fault);
+        assert_eq!(unpack::<proto::Fault>(FAULT_TYPE_URL, &message).unwrap(), fault);
+        assert!(matches!(
+            unpack::<proto::Heartbeat>(HEARTBEAT_TYPE_URL, &message),
+            Err(ClientError::WrongAnyProtoType { .. })
+        ));
+        let registry = Registry::new();
+        assert_eq!(
+            format!("{:?}", registry.decode(&message).unwrap()),
+            format!("{:?}", fault)
+        );
+        let unknown = pack("type.googleapis.com/Unknown", &fault);
+        assert!(matches!(
+            registry.decode(&unknown),
+            Err(ClientError::UnknownTypeUrl(_))
+        ));
+        let garbled = Any {
+            value: vec![0xff],
+            ..message
+        };
+        assert!(matches!(
+            registry.decode(&garbled),
+            Err(ClientError::MessageDecodingError(_))
+        ));
+    }
+}